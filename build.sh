@@ -10,17 +10,43 @@ while getopts ":s" opt; do
 done
 echo "Building OpenSprinkler..."
 
-if [ "$1" == "demo" ]; then
+# optional features can be left out, e.g. CXXFLAGS="-DDISABLE_MQTT -DDISABLE_IFTTT" ./build.sh
+LIBS="-lpthread -lz"
+if [[ "$CXXFLAGS" != *DISABLE_MQTT* ]]; then
+	LIBS="$LIBS -lmosquitto"
+fi
+if [[ "$CXXFLAGS" != *DISABLE_ARGON2* ]]; then
+	LIBS="$LIBS -largon2"
+fi
+
+SOURCES="main.cpp OpenSprinkler.cpp program.cpp opensprinkler_server.cpp utils.cpp weather.cpp gpio.cpp etherport.cpp mqtt.cpp cli.cpp control.cpp display.cpp sim.cpp calendar.cpp webui.cpp lang.cpp timer.cpp rf.cpp budget.cpp debuglog.cpp errors.cpp"
+
+if [ "$1" == "lib" ]; then
+	# static library without main(), for programs that embed the controller (see main.h)
+	echo "Compiling library..."
+	mkdir -p obj
+	for src in $SOURCES; do
+		g++ -c -fPIC -DOSPI -DOS_LIBRARY $CXXFLAGS -o obj/${src%.cpp}.o $src || exit 1
+	done
+	rm -f libopensprinkler.a
+	ar rcs libopensprinkler.a obj/*.o
+	echo "Link with: libopensprinkler.a $LIBS"
+	exit 0
+elif [ "$1" == "demo" ]; then
 	echo "Installing required libraries..."
 	apt-get install -y libmosquitto-dev
+	apt-get install -y zlib1g-dev
+	apt-get install -y libargon2-dev
 	echo "Compiling firmware..."
-	g++ -o OpenSprinkler -DDEMO -m32 main.cpp OpenSprinkler.cpp program.cpp opensprinkler_server.cpp utils.cpp weather.cpp gpio.cpp etherport.cpp mqtt.cpp -lpthread -lmosquitto
+	g++ -o OpenSprinkler -DDEMO -m32 $CXXFLAGS $SOURCES $LIBS
 else
 	echo "Installing required libraries..."
 	apt-get install -y libmosquitto-dev
+	apt-get install -y zlib1g-dev
+	apt-get install -y libargon2-dev
 	apt-get install -y wiringpi
 	echo "Compiling firmware..."
-	g++ -o OpenSprinkler -DOSPI main.cpp OpenSprinkler.cpp program.cpp opensprinkler_server.cpp utils.cpp weather.cpp gpio.cpp etherport.cpp mqtt.cpp -lpthread -lmosquitto
+	g++ -o OpenSprinkler -DOSPI $CXXFLAGS $SOURCES $LIBS
 fi
 
 if [ ! "$SILENT" = true ] && [ -f OpenSprinkler.launch ] && [ ! -f /etc/init.d/OpenSprinkler.sh ]; then
@@ -24,14 +24,27 @@
 #include "OpenSprinkler.h"
 #include "opensprinkler_server.h"
 #include "gpio.h"
+#include "sim.h"
+#include "weather.h"
+#include "rf.h"
+#include "main.h"
+#include <pthread.h>
+#if !defined(DISABLE_ARGON2)
+#include <argon2.h>
+#endif
 
 /** Declare static data members */
 OSMqtt OpenSprinkler::mqtt;
+OSControl OpenSprinkler::control;
+OSDisplay OpenSprinkler::display;
 NVConData OpenSprinkler::nvdata;
 ConStatus OpenSprinkler::status;
 ConStatus OpenSprinkler::old_status;
 byte OpenSprinkler::hw_type;
 byte OpenSprinkler::hw_rev;
+byte OpenSprinkler::hw_version = OS_HW_VERSION;
+char OpenSprinkler::hw_model[HW_MODEL_SIZE + 1];
+char OpenSprinkler::device_uuid[DEVICE_UUID_SIZE + 1];
 
 byte OpenSprinkler::nboards;
 byte OpenSprinkler::nstations;
@@ -46,6 +59,11 @@ ulong OpenSprinkler::sensor2_on_timer;
 ulong OpenSprinkler::sensor2_off_timer;
 ulong OpenSprinkler::sensor2_active_lasttime;
 ulong OpenSprinkler::raindelay_on_lasttime;
+byte OpenSprinkler::vswitch_pending;
+ulong OpenSprinkler::vswitch_lasttime[MAX_NUM_VSWITCHES];
+byte OpenSprinkler::sensor_override[2] = {0xFF, 0xFF};
+ulong OpenSprinkler::sensor_override_until[2];
+byte OpenSprinkler::pswitch_override;
 
 ulong OpenSprinkler::flowcount_log_start;
 ulong OpenSprinkler::flowcount_rt;
@@ -65,11 +83,15 @@ byte OpenSprinkler::attrib_igrd[MAX_NUM_BOARDS];
 byte OpenSprinkler::attrib_dis[MAX_NUM_BOARDS];
 byte OpenSprinkler::attrib_seq[MAX_NUM_BOARDS];
 byte OpenSprinkler::attrib_spe[MAX_NUM_BOARDS];
+byte OpenSprinkler::attrib_grp[MAX_NUM_STATIONS];
+byte OpenSprinkler::attrib_pri[MAX_NUM_STATIONS];
+byte OpenSprinkler::attrib_mdel[MAX_NUM_STATIONS];
+RainDelayWindow OpenSprinkler::rdwins[MAX_RD_WINDOWS];
+byte OpenSprinkler::spe_sids[MAX_NUM_STATIONS];
+byte OpenSprinkler::nspe = 0;
+byte OpenSprinkler::spe_status[MAX_NUM_STATIONS];
+byte OpenSprinkler::spe_value[MAX_NUM_STATIONS];
 
-extern char tmp_buffer[];
-extern char ether_buffer[];
-
-// TODO future: LCD define for Linux-based systems
 
 /** Option json names (stored in PROGMEM to reduce RAM usage) */
 // IMPORTANT: each json name is strictly 5 characters
@@ -140,7 +162,47 @@ const char iopt_json_names[] PROGMEM =
 	"subn3"
 	"subn4"
 	"wimod"
-	"reset";
+	"reset"
+	"dispt"
+	"dispa"
+	"vsw1p"
+	"vsw2p"
+	"vsw3p"
+	"vsw4p"
+	"sn1pg"
+	"sn2pg"
+	"cschn"
+	"csgan"
+	"csmin"
+	"csmax"
+	"wttmo"
+	"wtrty"
+	"wtfbk"
+	"wwbeg"
+	"wwend"
+	"qovfl"
+	"sovlp"
+	"lang\0"
+	"units"
+	"mqsnm"
+	"nfpls"
+	"sprfi"
+	"rfrpt"
+	"fchrs"
+	"expzn"
+	"mtonm"
+	"mtofm"
+	"mtnm2"
+	"mtfm2"
+	"setup"
+	"pwhsh"
+	"pwchk"
+	"guest"
+	"dryrn"
+	"frost"
+	"frprg"
+	"rpfwv"
+	"ife2\0";
 
 // for String options
 /*
@@ -225,7 +287,47 @@ const char iopt_prompts[] PROGMEM =
 	"Subnet mask3:   "
 	"Subnet mask4:   "
 	"WiFi mode?      "
-	"Factory reset?  ";
+	"Factory reset?  "
+	"Display type:   "
+	"Display address:"
+	"Vswitch1 prog:  "
+	"Vswitch2 prog:  "
+	"Vswitch3 prog:  "
+	"Vswitch4 prog:  "
+	"Sn1 switch prog:"
+	"Sn2 switch prog:"
+	"Cur. sense chn: "
+	"Cur. sense gain:"
+	"Min current:    "
+	"Max current:    "
+	"Weather timeout:"
+	"Weather retries:"
+	"Keep last wl:   "
+	"Water win start:"
+	"Water win end:  "
+	"Queue overflow: "
+	"Station overlap:"
+	"Language:       "
+	"Unit system:    "
+	"MQTT sta. names:"
+	"No-flow pulses: "
+	"Spe. refresh(s):"
+	"RF repeats:     "
+	"Forecast hours: "
+	"Expander zones: "
+	"Mas1  on (min): "
+	"Mas1 off (min): "
+	"Mas2  on (min): "
+	"Mas2 off (min): "
+	"Setup steps:    "
+	"Password hash:  "
+	"Password rules: "
+	"Guest access:   "
+	"Dry run:        "
+	"Frost temp (F): "
+	"Frost program:  "
+	"Report fw ver.: "
+	"IFTTT Enable 2: ";
 
 // string options do not have prompts
 
@@ -294,7 +396,47 @@ const byte iopt_max[] PROGMEM = {
 	255,
 	255,
 	255,
-	1};
+	1,
+	2,
+	127,
+	255, // program number or PSWITCH_RUNONCE
+	255,
+	255,
+	255,
+	255,
+	255,
+	8,
+	255,
+	255,
+	255,
+	30,
+	10,
+	1,
+	96,
+	96,
+	3,
+	2,
+	NUM_LANGUAGES - 1,
+	1,
+	1,
+	255,
+	255,
+	255,
+	FORECAST_MAX_HOURS,
+	MAX_EXP_ZONES,
+	MASTER_ADJ_MIN_MAX,
+	MASTER_ADJ_MIN_MAX,
+	MASTER_ADJ_MIN_MAX,
+	MASTER_ADJ_MIN_MAX,
+	255,
+	1,
+	1,
+	1,
+	1,
+	FROST_MAX_TEMP,
+	255,
+	255,
+	255};
 
 // string options do not have maximum values
 
@@ -363,7 +505,47 @@ byte OpenSprinkler::iopts[] = {
 	255, // subnet mask 3
 	0,
 	WIFI_MODE_AP, // wifi mode
-	0			  // reset
+	0,			  // reset
+	0,			  // display type (see DISPLAY_TYPE macro defines)
+	0x27,		  // display i2c address
+	0,			  // virtual switch 1 program (0 means not mapped)
+	0,			  // virtual switch 2 program
+	0,			  // virtual switch 3 program
+	0,			  // virtual switch 4 program
+	1,			  // sensor 1 program switch program (program 1)
+	2,			  // sensor 2 program switch program (program 2)
+	0,			  // current sense ADC channel (0 means disabled, otherwise channel+1)
+	10,			  // current sense gain (mA per 10 mV)
+	5,			  // minimum station current (in 10 mA, 0 disables the check)
+	0,			  // maximum total current (in 10 mA, 0 disables the check)
+	3,			  // weather request timeout (in seconds)
+	3,			  // consecutive weather failures before notifying
+	0,			  // keep the last water level when weather calls keep failing
+	0,			  // watering window start (in 15 minute units, equal to the end: no window)
+	0,			  // watering window end (in 15 minute units)
+	0,			  // run-time queue overflow policy (0: reject new runs)
+	0,			  // overlapping runs of the same station (0: stack them)
+	LANGUAGE_EN,  // language of notifications and the built-in web UI
+	UNIT_SYSTEM_IMPERIAL, // unit system of flow volumes and rates
+	0,					  // MQTT station topics by name (0: by index)
+	1,					  // runs with fewer flow pulses raise a no-flow alert (0: no alerts)
+	1,					  // seconds between special station refreshes (0 is taken as 1)
+	RF_DEFAULT_REPEAT,	  // times an RF code is sent (0 is taken as the default)
+	0,					  // hours of rain forecast checked by programs (0: no forecast)
+	SHIFT_REGISTER_LINES, // zones of each expander (8 or 16, 0 is taken as 8)
+	MASTER_ADJ_MIN_ZERO,  // master on adjusted time in minutes (-120 to 120, 0: use the seconds option)
+	MASTER_ADJ_MIN_ZERO,  // master off adjusted time in minutes
+	MASTER_ADJ_MIN_ZERO,  // master2 on adjusted time in minutes
+	MASTER_ADJ_MIN_ZERO,  // master2 off adjusted time in minutes
+	SETUP_COMPLETE,		  // first-boot setup steps taken (a factory reset starts over)
+	0,					  // store the password as an argon2id hash (0: as the key clients send)
+	0,					  // reject malformed and well-known password keys (0: accept any)
+	0,					  // allow guests to read the json outputs (0: no guest access)
+	0,					  // dry run: schedule and log as usual, but never switch valves (0: off)
+	0,					  // frost temperature in F, below which programs do not start (0: no frost protection)
+	0,					  // program run while frost lasts (0: none, 255: the run-once template)
+	0,					  // firmware version reported to the weather service and other servers (0: this firmware)
+	0					  // IFTTT enable bits of the notification types from NOTIFY_STATION_ON on (type >> 8)
 };
 
 /** String option values (stored in RAM) */
@@ -380,6 +562,14 @@ const char *OpenSprinkler::sopts[] = {
 	DEFAULT_EMPTY_STRING,
 	DEFAULT_EMPTY_STRING,
 	DEFAULT_EMPTY_STRING,
+	DEFAULT_EMPTY_STRING,
+	DEFAULT_EMPTY_STRING,
+	DEFAULT_EMPTY_STRING,
+	DEFAULT_EMPTY_STRING,
+	DEFAULT_EMPTY_STRING,
+	DEFAULT_EMPTY_STRING,
+	DEFAULT_EMPTY_STRING,
+	DEFAULT_EMPTY_STRING,
 	DEFAULT_EMPTY_STRING};
 
 /** Weekday strings (stored in PROGMEM to reduce RAM usage) */
@@ -408,13 +598,20 @@ time_t OpenSprinkler::now_tz()
 #include "utils.h"
 #include "opensprinkler_server.h"
 
-/** Initialize network with the given mac address and http port */
-byte OpenSprinkler::start_network()
+/** Return the http port the web server listens on */
+uint16_t OpenSprinkler::get_http_port()
 {
-	unsigned int port = (unsigned int)(iopts[IOPT_HTTPPORT_1] << 8) + (unsigned int)iopts[IOPT_HTTPPORT_0];
 #if defined(DEMO)
-	port = 80;
+	return 80;
+#else
+	return (uint16_t)(iopts[IOPT_HTTPPORT_1] << 8) + (uint16_t)iopts[IOPT_HTTPPORT_0];
 #endif
+}
+
+/** Initialize network with the given mac address and http port */
+byte OpenSprinkler::start_network()
+{
+	unsigned int port = get_http_port();
 	if (m_server)
 	{
 		delete m_server;
@@ -430,6 +627,43 @@ bool OpenSprinkler::network_connected(void)
 	return true;
 }
 
+/** Detect the board model and hardware version
+ * The model is read from the device tree. On OSPi, the revision of an OpenSprinkler Pi hat
+ * is read from its ID EEPROM, which the kernel exposes under /proc/device-tree/hat.
+ */
+void OpenSprinkler::detect_hardware()
+{
+	hw_model[0] = 0;
+	hw_version = OS_HW_VERSION;
+	FILE *fp = fopen("/proc/device-tree/model", "r");
+	if (fp)
+	{
+		size_t n = fread(hw_model, 1, HW_MODEL_SIZE, fp);
+		hw_model[n] = 0;
+		fclose(fp);
+	}
+#if defined(OSPI)
+	char buf[32];
+	fp = fopen("/proc/device-tree/hat/product", "r");
+	if (!fp)
+		return;
+	bool ospi_hat = fgets(buf, sizeof(buf), fp) && strstr(buf, "OpenSprinkler");
+	fclose(fp);
+	if (!ospi_hat)
+		return;
+	fp = fopen("/proc/device-tree/hat/product_ver", "r");
+	if (!fp)
+		return;
+	if (fgets(buf, sizeof(buf), fp))
+	{
+		ulong rev = strtoul(buf, NULL, 16);
+		if (rev < 0x40)
+			hw_version = OSPI_HW_VERSION_BASE + rev;
+	}
+	fclose(fp);
+#endif
+}
+
 // Return mac of first recognised interface and fallback to software mac
 // Note: on OSPi, operating system handles interface allocation so 'wired' ignored
 bool OpenSprinkler::load_hardware_mac(byte *mac, bool wired)
@@ -483,18 +717,24 @@ void OpenSprinkler::reboot_dev(uint8_t cause)
 void OpenSprinkler::update_dev()
 {
 	char cmd[1000];
-	sprintf(cmd, "cd %s & ./updater.sh", get_runtime_path());
+	sprintf(cmd, "cd %s && ./updater.sh", get_runtime_path());
 	system(cmd);
 }
 // end network init functions
 
 // extern void flow_isr();
 
+// shift register pins, opened once so that close_valves_on_crash() needs no file access
+static int sr_latch_fd = -1;
+static int sr_clock_fd = -1;
+static int sr_data_fd = -1;
+
 /** Initialize pins, controller variables, LCD */
 void OpenSprinkler::begin()
 {
 	hw_type = HW_TYPE_UNKNOWN;
 	hw_rev = 0;
+	detect_hardware();
 
 	// shift register setup
 	pinMode(PIN_SR_OE, OUTPUT);
@@ -505,6 +745,9 @@ void OpenSprinkler::begin()
 
 	pinMode(PIN_SR_CLOCK, OUTPUT);
 	pinMode(PIN_SR_DATA, OUTPUT);
+	sr_latch_fd = gpio_fd_open(PIN_SR_LATCH);
+	sr_clock_fd = gpio_fd_open(PIN_SR_CLOCK);
+	sr_data_fd = gpio_fd_open(PIN_SR_DATA);
 
 	// Reset all stations
 	clear_all_station_bits();
@@ -531,17 +774,18 @@ void OpenSprinkler::begin()
 	nvdata.reboot_cause = REBOOT_CAUSE_POWERON;
 
 	nboards = 1;
-	nstations = nboards * 8;
+	nstations = nboards * SHIFT_REGISTER_LINES;
 
 	// set rf data pin
 	pinModeExt(PIN_RFTX, OUTPUT);
 	digitalWriteExt(PIN_RFTX, LOW);
 
-	DEBUG_PRINTLN(get_runtime_path());
+	DEBUG_PRINTLN(get_data_path());
 }
 
 /** Apply all station bits
  * !!! This will activate/deactivate valves !!!
+ * In dry run mode all valves are kept closed.
  */
 void OpenSprinkler::apply_all_station_bits()
 {
@@ -552,39 +796,92 @@ void OpenSprinkler::apply_all_station_bits()
 	// from the highest bit to the lowest
 	for (bid = 0; bid <= MAX_EXT_BOARDS; bid++)
 	{
-		if (status.enabled)
+		if (status.enabled && !iopts[IOPT_DRY_RUN])
 			sbits = station_bits[MAX_EXT_BOARDS - bid];
 		else
 			sbits = 0;
 
-		for (s = 0; s < 8; s++)
+		for (s = 0; s < SHIFT_REGISTER_LINES; s++)
 		{
 			digitalWrite(PIN_SR_CLOCK, LOW);
-			digitalWrite(PIN_SR_DATA, (sbits & ((byte)1 << (7 - s))) ? HIGH : LOW);
+			digitalWrite(PIN_SR_DATA, (sbits & ((byte)1 << (SHIFT_REGISTER_LINES - 1 - s))) ? HIGH : LOW);
 			digitalWrite(PIN_SR_CLOCK, HIGH);
 		}
 	}
 
 	digitalWrite(PIN_SR_LATCH, HIGH);
 
-	if (iopts[IOPT_SPE_AUTO_REFRESH])
+	if (iopts[IOPT_SPE_AUTO_REFRESH] && !iopts[IOPT_DRY_RUN])
 	{
 		// handle refresh of RF and remote stations
 		// we refresh the station that's next in line
-		static byte next_sid_to_refresh = MAX_NUM_STATIONS >> 1;
-		static byte lastnow = 0;
-		byte _now = (now() & 0xFF);
-		if (lastnow != _now)
-		{ // perform this no more than once per second
-			lastnow = _now;
-			next_sid_to_refresh = (next_sid_to_refresh + 1) % MAX_NUM_STATIONS;
-			bid = next_sid_to_refresh >> 3;
-			s = next_sid_to_refresh & 0x07;
-			switch_special_station(next_sid_to_refresh, (station_bits[bid] >> s) & 0x01);
+		// only special stations are visited, see index_special_stations()
+		static byte next_spe_to_refresh = 0;
+		static ulong last_refresh = 0;
+		ulong _now = now();
+		if (nspe > 0 && _now - last_refresh >= spe_refresh_interval())
+		{
+			last_refresh = _now;
+			next_spe_to_refresh = (next_spe_to_refresh + 1) % nspe;
+			byte sid = spe_sids[next_spe_to_refresh];
+			if (sid < nstations)
+			{
+				bid = sid >> 3;
+				s = sid & 0x07;
+				switch_special_station(sid, (station_bits[bid] >> s) & 0x01);
+			}
 		}
 	}
 }
 
+/** Close all valves of the shift register from a fatal signal handler
+ * Only the pins opened by begin() are written, without locks or other file access, so this is
+ * async-signal-safe. Special stations cannot be closed here, as their commands go through
+ * files, locks and the worker threads, which the crashing process cannot rely on.
+ */
+void OpenSprinkler::close_valves_on_crash()
+{
+	if (sr_latch_fd < 0 || sr_clock_fd < 0 || sr_data_fd < 0)
+		return;
+	gpio_write_quiet(sr_latch_fd, LOW);
+	gpio_write_quiet(sr_data_fd, LOW);
+	for (int i = 0; i < MAX_NUM_BOARDS * SHIFT_REGISTER_LINES; i++)
+	{
+		gpio_write_quiet(sr_clock_fd, LOW);
+		gpio_write_quiet(sr_clock_fd, HIGH);
+	}
+	gpio_write_quiet(sr_latch_fd, HIGH);
+}
+
+/** Apply the on and off delays (in minutes) of a binary sensor
+ * A change of the reading starts the on or off timer, which holds the time the change takes effect.
+ * The active state follows the reading once the reading has stayed the same until then.
+ * Both timers are 0 when no change is pending.
+ */
+static byte sensor_delayed_state(byte reading, byte active, ulong curr_time, byte on_delay, byte off_delay,
+								 ulong &on_timer, ulong &off_timer)
+{
+	if (reading == active)
+	{ // nothing pending, a reading that changed back before its delay is ignored
+		on_timer = 0;
+		off_timer = 0;
+		return active;
+	}
+	ulong &timer = reading ? on_timer : off_timer;
+	if (!timer)
+	{
+		// add minimum of 5 seconds delay
+		ulong delay_time = (ulong)(reading ? on_delay : off_delay) * 60;
+		timer = curr_time + (delay_time > 5 ? delay_time : 5);
+		(reading ? off_timer : on_timer) = 0;
+		return active;
+	}
+	if (curr_time < timer)
+		return active;
+	timer = 0;
+	return reading;
+}
+
 /** Read rain sensor status */
 void OpenSprinkler::detect_binarysensor_status(ulong curr_time)
 {
@@ -595,39 +892,20 @@ void OpenSprinkler::detect_binarysensor_status(ulong curr_time)
 			pinModeExt(PIN_SENSOR1, INPUT_PULLUP); // this seems necessary for OS 3.2
 		byte val = digitalReadExt(PIN_SENSOR1);
 		status.sensor1 = (val == iopts[IOPT_SENSOR1_OPTION]) ? 0 : 1;
-		if (status.sensor1)
-		{
-			if (!sensor1_on_timer)
-			{
-				// add minimum of 5 seconds on delay
-				ulong delay_time = (ulong)iopts[IOPT_SENSOR1_ON_DELAY] * 60;
-				sensor1_on_timer = curr_time + (delay_time > 5 ? delay_time : 5);
-				sensor1_off_timer = 0;
-			}
-			else
-			{
-				if (curr_time > sensor1_on_timer)
-				{
-					status.sensor1_active = 1;
-				}
-			}
-		}
-		else
-		{
-			if (!sensor1_off_timer)
-			{
-				ulong delay_time = (ulong)iopts[IOPT_SENSOR1_OFF_DELAY] * 60;
-				sensor1_off_timer = curr_time + (delay_time > 5 ? delay_time : 5);
-				sensor1_on_timer = 0;
-			}
-			else
-			{
-				if (curr_time > sensor1_off_timer)
-				{
-					status.sensor1_active = 0;
-				}
-			}
-		}
+		status.sensor1_active = sensor_delayed_state(status.sensor1, status.sensor1_active, curr_time,
+													 iopts[IOPT_SENSOR1_ON_DELAY], iopts[IOPT_SENSOR1_OFF_DELAY],
+													 sensor1_on_timer, sensor1_off_timer);
+		if (sensor_overridden(0, curr_time))
+			status.sensor1 = status.sensor1_active = sensor_override[0];
+	}
+	else if (status.sensor1_active)
+	{
+		// sensor is no longer configured as a binary sensor, deactivate it
+		// so that the main loop closes the active period in the log
+		status.sensor1_active = 0;
+		sensor1_on_timer = 0;
+		sensor1_off_timer = 0;
+		sensor_override[0] = 0xFF;
 	}
 
 #if defined(PIN_SENSOR2)
@@ -637,39 +915,20 @@ void OpenSprinkler::detect_binarysensor_status(ulong curr_time)
 			pinModeExt(PIN_SENSOR2, INPUT_PULLUP); // this seems necessary for OS 3.2
 		byte val = digitalReadExt(PIN_SENSOR2);
 		status.sensor2 = (val == iopts[IOPT_SENSOR2_OPTION]) ? 0 : 1;
-		if (status.sensor2)
-		{
-			if (!sensor2_on_timer)
-			{
-				// add minimum of 5 seconds on delay
-				ulong delay_time = (ulong)iopts[IOPT_SENSOR2_ON_DELAY] * 60;
-				sensor2_on_timer = curr_time + (delay_time > 5 ? delay_time : 5);
-				sensor2_off_timer = 0;
-			}
-			else
-			{
-				if (curr_time > sensor2_on_timer)
-				{
-					status.sensor2_active = 1;
-				}
-			}
-		}
-		else
-		{
-			if (!sensor2_off_timer)
-			{
-				ulong delay_time = (ulong)iopts[IOPT_SENSOR2_OFF_DELAY] * 60;
-				sensor2_off_timer = curr_time + (delay_time > 5 ? delay_time : 5);
-				sensor2_on_timer = 0;
-			}
-			else
-			{
-				if (curr_time > sensor2_off_timer)
-				{
-					status.sensor2_active = 0;
-				}
-			}
-		}
+		status.sensor2_active = sensor_delayed_state(status.sensor2, status.sensor2_active, curr_time,
+													 iopts[IOPT_SENSOR2_ON_DELAY], iopts[IOPT_SENSOR2_OFF_DELAY],
+													 sensor2_on_timer, sensor2_off_timer);
+		if (sensor_overridden(1, curr_time))
+			status.sensor2 = status.sensor2_active = sensor_override[1];
+	}
+	else if (status.sensor2_active)
+	{
+		// sensor is no longer configured as a binary sensor, deactivate it
+		// so that the main loop closes the active period in the log
+		status.sensor2_active = 0;
+		sensor2_on_timer = 0;
+		sensor2_off_timer = 0;
+		sensor_override[1] = 0xFF;
 	}
 
 #endif
@@ -688,7 +947,7 @@ byte OpenSprinkler::detect_programswitch_status(ulong curr_time)
 		sensor1_hist = (sensor1_hist << 1) | status.sensor1;
 		// basic noise filtering: only trigger if sensor matches pattern:
 		// i.e. two consecutive lows followed by two consecutive highs
-		if ((sensor1_hist & 0b1111) == 0b0011)
+		if ((sensor1_hist & 0b1111) == 0b0011 || (pswitch_override & 0x01))
 		{
 			ret |= 0x01;
 		}
@@ -701,15 +960,121 @@ byte OpenSprinkler::detect_programswitch_status(ulong curr_time)
 			pinModeExt(PIN_SENSOR2, INPUT_PULLUP);									  // this seems necessary for OS 3.2
 		status.sensor2 = (digitalReadExt(PIN_SENSOR2) != iopts[IOPT_SENSOR2_OPTION]); // is sensor activated?
 		sensor2_hist = (sensor2_hist << 1) | status.sensor2;
-		if ((sensor2_hist & 0b1111) == 0b0011)
+		if ((sensor2_hist & 0b1111) == 0b0011 || (pswitch_override & 0x02))
 		{
 			ret |= 0x02;
 		}
 	}
 #endif
+	pswitch_override = 0;
 	return ret;
 }
 
+/** Force the state of a sensor (index starting from 0), to test notifications and skip logic
+ * A rain or soil sensor is held at state (1: active, 0: inactive) for the given minutes,
+ * a negative state ends the override. A program switch sensor is triggered once.
+ * Returns an HTML result code.
+ */
+byte OpenSprinkler::override_sensor(byte idx, int8_t state, uint16_t minutes, ulong curr_time)
+{
+	if (idx > 1)
+		return HTML_DATA_OUTOFBOUND;
+	byte type = iopts[idx ? IOPT_SENSOR2_TYPE : IOPT_SENSOR1_TYPE];
+	if (type == SENSOR_TYPE_PSWITCH)
+	{
+		pswitch_override |= (1 << idx);
+		return HTML_SUCCESS;
+	}
+	if (type != SENSOR_TYPE_RAIN && type != SENSOR_TYPE_SOIL)
+		return HTML_NOT_PERMITTED;
+	if (state < 0)
+	{
+		sensor_override[idx] = 0xFF;
+		sensor_override_until[idx] = 0;
+		return HTML_SUCCESS;
+	}
+	if (state > 1 || minutes == 0 || minutes > MAX_SENSOR_OVERRIDE)
+		return HTML_DATA_OUTOFBOUND;
+	sensor_override[idx] = state;
+	sensor_override_until[idx] = curr_time + minutes * 60UL;
+	return HTML_SUCCESS;
+}
+
+/** Check if a sensor (index starting from 0) is overridden, ending the override when it expires */
+bool OpenSprinkler::sensor_overridden(byte idx, ulong curr_time)
+{
+	if (sensor_override[idx] == 0xFF)
+		return false;
+	if (curr_time >= sensor_override_until[idx])
+	{
+		sensor_override[idx] = 0xFF;
+		sensor_override_until[idx] = 0;
+		return false;
+	}
+	return true;
+}
+
+/** Trigger a virtual program switch (index starting from 0)
+ * Triggers that follow the previous one too closely are ignored,
+ * just like the noise filtering of physical switches */
+bool OpenSprinkler::trigger_vswitch(byte idx, ulong curr_time)
+{
+	if (idx >= MAX_NUM_VSWITCHES)
+		return false;
+	if (vswitch_lasttime[idx] && curr_time < vswitch_lasttime[idx] + VSWITCH_MIN_INTERVAL)
+		return false;
+	vswitch_lasttime[idx] = curr_time;
+	vswitch_pending |= (1 << idx);
+	return true;
+}
+
+/** Return and clear virtual program switch status */
+byte OpenSprinkler::detect_vswitch_status()
+{
+	byte ret = vswitch_pending;
+	vswitch_pending = 0;
+	return ret;
+}
+
+/** Read valve current (in mA)
+ * Returns 0 and clears has_curr_sense if current sensing is disabled or the ADC cannot be read */
+uint16_t OpenSprinkler::read_current()
+{
+	byte chn = iopts[IOPT_CURRENT_CHANNEL];
+	status.has_curr_sense = 0;
+	if (!chn)
+		return 0;
+#if defined(OSPI)
+	char path[64];
+	long raw = 0;
+	float scale = 1.0; // mV per raw unit, if the ADC does not provide a scale
+	sprintf(path, CURRENT_SENSE_PATH "in_voltage%d_raw", chn - 1);
+	FILE *fp = fopen(path, "r");
+	if (!fp)
+		return 0;
+	int n = fscanf(fp, "%ld", &raw);
+	fclose(fp);
+	if (n != 1)
+		return 0;
+	sprintf(path, CURRENT_SENSE_PATH "in_voltage%d_scale", chn - 1);
+	fp = fopen(path, "r");
+	if (fp)
+	{
+		if (fscanf(fp, "%f", &scale) != 1)
+			scale = 1.0;
+		fclose(fp);
+	}
+	if (raw < 0)
+		raw = 0;
+	ulong curr = (ulong)(raw * scale * iopts[IOPT_CURRENT_GAIN] / 10);
+	status.has_curr_sense = 1;
+	return (curr > 0xFFFF) ? 0xFFFF : (uint16_t)curr;
+#else
+	status.has_curr_sense = 1;
+	return sim_read_current();
+#endif
+}
+
 void OpenSprinkler::sensor_resetall()
 {
 	sensor1_on_timer = 0;
@@ -800,8 +1165,7 @@ void OpenSprinkler::get_station_name(byte sid, char tmp[])
 /** Set station name */
 void OpenSprinkler::set_station_name(byte sid, char tmp[])
 {
-	// TODO: store the right size
-	tmp[STATION_NAME_SIZE] = 0;
+	utf8_truncate(tmp, STATION_NAME_SIZE);
 	file_write_block(STATIONS_FILENAME, tmp, (uint32_t)sid * sizeof(StationData) + offsetof(StationData, name), STATION_NAME_SIZE);
 }
 
@@ -811,6 +1175,47 @@ byte OpenSprinkler::get_station_type(byte sid)
 	return file_read_byte(STATIONS_FILENAME, (uint32_t)sid * sizeof(StationData) + offsetof(StationData, type));
 }
 
+/** Get station metadata, all empty if none is stored */
+void OpenSprinkler::get_station_meta(byte sid, StationMeta *meta)
+{
+	memset(meta, 0, sizeof(StationMeta));
+	if (sid >= MAX_NUM_STATIONS)
+		return;
+	file_read_block(STNMETA_FILENAME, meta, (uint32_t)sid * sizeof(StationMeta), sizeof(StationMeta));
+}
+
+/** Set station metadata
+ * Stations before sid that have none yet are stored as empty.
+ */
+void OpenSprinkler::set_station_meta(byte sid, StationMeta *meta)
+{
+	if (sid >= MAX_NUM_STATIONS)
+		return;
+	file_write_block(STNMETA_FILENAME, meta, (uint32_t)sid * sizeof(StationMeta), sizeof(StationMeta));
+}
+
+/** Get station group name */
+void OpenSprinkler::get_group_name(byte gid, char tmp[])
+{
+	tmp[0] = 0;
+	tmp[GROUP_NAME_SIZE] = 0;
+	if (gid == 0 || gid > MAX_NUM_GROUPS)
+		return;
+	file_read_block(GROUPS_FILENAME, tmp, (uint32_t)(gid - 1) * GROUP_NAME_SIZE, GROUP_NAME_SIZE);
+}
+
+/** Set station group name */
+void OpenSprinkler::set_group_name(byte gid, char tmp[])
+{
+	if (gid == 0 || gid > MAX_NUM_GROUPS)
+		return;
+	// fill the remaining space with zeros, so unused groups read back as empty names
+	tmp[GROUP_NAME_SIZE] = 0;
+	byte len = strlen(tmp);
+	memset(tmp + len, 0, GROUP_NAME_SIZE - len);
+	file_write_block(GROUPS_FILENAME, tmp, (uint32_t)(gid - 1) * GROUP_NAME_SIZE, GROUP_NAME_SIZE);
+}
+
 /** Get station attribute */
 /*void OpenSprinkler::get_station_attrib(byte sid, StationAttrib *attrib); {
 	file_read_block(STATIONS_FILENAME, attrib, (uint32_t)sid*sizeof(StationData)+offsetof(StationData, attrib), sizeof(StationAttrib));
@@ -825,7 +1230,7 @@ void OpenSprinkler::attribs_save()
 	byte ty = STN_TYPE_STANDARD;
 	for (bid = 0; bid < MAX_NUM_BOARDS; bid++)
 	{
-		for (s = 0; s < 8; s++, sid++)
+		for (s = 0; s < SHIFT_REGISTER_LINES; s++, sid++)
 		{
 			at.mas = (attrib_mas[bid] >> s) & 1;
 			at.igs = (attrib_igs[bid] >> s) & 1;
@@ -834,8 +1239,11 @@ void OpenSprinkler::attribs_save()
 			at.igrd = (attrib_igrd[bid] >> s) & 1;
 			at.dis = (attrib_dis[bid] >> s) & 1;
 			at.seq = (attrib_seq[bid] >> s) & 1;
-			at.gid = 0;
-			file_write_block(STATIONS_FILENAME, &at, (uint32_t)sid * sizeof(StationData) + offsetof(StationData, attrib), 1); // attribte bits are 1 byte long
+			at.unused = 0;
+			at.gid = attrib_grp[sid];
+			at.pri = attrib_pri[sid];
+			at.mdelay = attrib_mdel[sid];
+			file_write_block(STATIONS_FILENAME, &at, (uint32_t)sid * sizeof(StationData) + offsetof(StationData, attrib), 3); // attribute bits, group id, priority and master delay
 			if (attrib_spe[bid] >> s == 0)
 			{
 				// if station special bit is 0, make sure to write type STANDARD
@@ -843,6 +1251,7 @@ void OpenSprinkler::attribs_save()
 			}
 		}
 	}
+	index_special_stations();
 }
 
 /** Load all station attribs from file (backward compatibility) */
@@ -860,10 +1269,13 @@ void OpenSprinkler::attribs_load()
 	memset(attrib_dis, 0, nboards);
 	memset(attrib_seq, 0, nboards);
 	memset(attrib_spe, 0, nboards);
+	memset(attrib_grp, 0, MAX_NUM_STATIONS);
+	memset(attrib_pri, 0, MAX_NUM_STATIONS);
+	memset(attrib_mdel, 0, MAX_NUM_STATIONS);
 
 	for (bid = 0; bid < MAX_NUM_BOARDS; bid++)
 	{
-		for (s = 0; s < 8; s++, sid++)
+		for (s = 0; s < SHIFT_REGISTER_LINES; s++, sid++)
 		{
 			file_read_block(STATIONS_FILENAME, &at, (uint32_t)sid * sizeof(StationData) + offsetof(StationData, attrib), sizeof(StationAttrib));
 			attrib_mas[bid] |= (at.mas << s);
@@ -873,6 +1285,9 @@ void OpenSprinkler::attribs_load()
 			attrib_igrd[bid] |= (at.igrd << s);
 			attrib_dis[bid] |= (at.dis << s);
 			attrib_seq[bid] |= (at.seq << s);
+			attrib_grp[sid] = at.gid;
+			attrib_pri[sid] = at.pri;
+			attrib_mdel[sid] = at.mdelay;
 			file_read_block(STATIONS_FILENAME, &ty, (uint32_t)sid * sizeof(StationData) + offsetof(StationData, type), 1);
 			if (ty != STN_TYPE_STANDARD)
 			{
@@ -880,12 +1295,165 @@ void OpenSprinkler::attribs_load()
 			}
 		}
 	}
+	index_special_stations();
 }
 
-/** verify if a string matches password */
+/** Rebuild the index of special stations
+ * Called whenever station attributes are loaded or saved, so the auto refresh
+ * only visits stations that are not of the standard type.
+ */
+void OpenSprinkler::index_special_stations()
+{
+	nspe = 0;
+	for (byte sid = 0; sid < MAX_NUM_STATIONS; sid++)
+	{
+		if (get_station_type(sid) != STN_TYPE_STANDARD)
+			spe_sids[nspe++] = sid;
+	}
+}
+
+/** Seconds between two special station refreshes */
+byte OpenSprinkler::spe_refresh_interval()
+{
+	return iopts[IOPT_SPE_REFRESH_INTERVAL] ? iopts[IOPT_SPE_REFRESH_INTERVAL] : 1;
+}
+
+/** Compare two keys in a time that does not depend on where they differ */
+static bool key_equal(const char *a, const char *b)
+{
+	size_t la = strlen(a), lb = strlen(b);
+	byte diff = (la != lb);
+	for (size_t i = 0; i < la; i++)
+		diff |= a[i] ^ b[i % (lb ? lb : 1)];
+	return !diff;
+}
+
+static char verified_key[PASSWORD_KEY_SIZE + 1]; // last key that matched the password hash
+
+/** Verify a password key
+ * A hashed password is checked with argon2id, which takes a while, so the last key
+ * that matched is kept in memory. A password stored as the key itself is compared,
+ * and hashed on this first successful login if password hashing is enabled.
+ */
 byte OpenSprinkler::password_verify(char *pw)
 {
-	return (file_cmp_block(SOPTS_FILENAME, pw, SOPT_PASSWORD * MAX_SOPTS_SIZE) == 0) ? 1 : 0;
+	char stored[MAX_SOPTS_SIZE + 1];
+	sopt_load(SOPT_PASSWORD, stored);
+	if (!strncmp(stored, PASSWORD_HASH_PREFIX, strlen(PASSWORD_HASH_PREFIX)))
+	{
+#if defined(DISABLE_ARGON2)
+		return 0;
+#else
+		if (verified_key[0] && key_equal(pw, verified_key))
+			return 1;
+		if (argon2id_verify(stored, pw, strlen(pw)) != ARGON2_OK)
+			return 0;
+		strncpy(verified_key, pw, PASSWORD_KEY_SIZE);
+		verified_key[PASSWORD_KEY_SIZE] = 0;
+		return 1;
+#endif
+	}
+	if (!key_equal(pw, stored))
+		return 0;
+	if (iopts[IOPT_PASSWORD_HASH])
+		password_save(pw);
+	return 1;
+}
+
+/** Verify a guest key
+ * This is false if guest access is off or no guest key is set.
+ */
+byte OpenSprinkler::guest_verify(char *pw)
+{
+	if (!iopts[IOPT_GUEST])
+		return 0;
+	char key[MAX_SOPTS_SIZE + 1];
+	sopt_load(SOPT_GUEST_KEY, key);
+	return (key[0] && key_equal(pw, key)) ? 1 : 0;
+}
+
+/** Whether guests need no key, i.e. guest access is on without a guest key */
+bool OpenSprinkler::guest_open()
+{
+	if (!iopts[IOPT_GUEST])
+		return false;
+	char key[MAX_SOPTS_SIZE + 1];
+	sopt_load(SOPT_GUEST_KEY, key);
+	return !key[0];
+}
+
+/** Store a new password key
+ * With password hashing enabled, an argon2id hash of the key is stored instead of the key.
+ */
+void OpenSprinkler::password_save(const char *key)
+{
+	verified_key[0] = 0;
+#if !defined(DISABLE_ARGON2)
+	if (iopts[IOPT_PASSWORD_HASH])
+	{
+		byte salt[PASSWORD_HASH_SALT_SIZE];
+		char encoded[MAX_SOPTS_SIZE];
+		random_bytes(salt, sizeof(salt));
+		if (argon2id_hash_encoded(PASSWORD_HASH_TIME, PASSWORD_HASH_MEMORY, 1, key, strlen(key), salt, sizeof(salt),
+								  PASSWORD_HASH_SIZE, encoded, sizeof(encoded)) == ARGON2_OK)
+		{
+			sopt_save(SOPT_PASSWORD, encoded);
+			return;
+		}
+		DEBUG_PRINTLN(F("password hash failed, the key is stored"));
+	}
+#endif
+	sopt_save(SOPT_PASSWORD, key);
+}
+
+/** Check a new password key against the password rules
+ * With the rules enabled, the key must be an md5 hash in hex and not that of a
+ * well-known password. Clients only send the key, so the password itself cannot be checked.
+ */
+bool OpenSprinkler::password_acceptable(const char *key)
+{
+	if (!key[0])
+		return false;
+	if (!iopts[IOPT_PASSWORD_CHECK])
+		return true;
+	if (strlen(key) != PASSWORD_KEY_SIZE || strspn(key, "0123456789abcdefABCDEF") != PASSWORD_KEY_SIZE)
+		return false;
+	static const char *weak[] = {
+		DEFAULT_PASSWORD,
+		"d41d8cd98f00b204e9800998ecf8427e", // (empty)
+		"5f4dcc3b5aa765d61d8327deb882cf99", // password
+		"e10adc3949ba59abbe56e057f20f883e", // 123456
+		"25d55ad283aa400af464c76d713c07ad", // 12345678
+		"25f9e794323b453885f5181f1b624d0b", // 123456789
+		"81dc9bdb52d04dc20036dbd8313ed055", // 1234
+		"21232f297a57a5a743894a0e4a801fc3", // admin
+		"d8578edf8458ce06fbc5bb76a58c5ca4", // qwerty
+		"50f87675ac231ba2bf11315f8cfdf182"	// opensprinkler
+	};
+	for (byte i = 0; i < sizeof(weak) / sizeof(weak[0]); i++)
+	{
+		if (!strcasecmp(key, weak[i]))
+			return false;
+	}
+	return true;
+}
+
+/** Get the key sent to remote stations
+ * This is the remote key if one is set, otherwise the password of this controller,
+ * which remote stations are assumed to share. A hashed password cannot be sent,
+ * so without a remote key the key is empty and false is returned.
+ */
+bool OpenSprinkler::get_remote_key(char *buf)
+{
+	sopt_load(SOPT_REMOTE_KEY, buf);
+	if (!buf[0])
+		sopt_load(SOPT_PASSWORD, buf);
+	if (!strncmp(buf, PASSWORD_HASH_PREFIX, strlen(PASSWORD_HASH_PREFIX)))
+	{
+		buf[0] = 0;
+		return false;
+	}
+	return true;
 }
 
 // ==================
@@ -895,19 +1463,33 @@ byte OpenSprinkler::password_verify(char *pw)
 /** Index of today's weekday (Monday is 0) */
 byte OpenSprinkler::weekday_today()
 {
-	// return ((byte)weekday()+5)%7; // Time::weekday() assumes Sunday is 1
+	return weekday_index(now_tz());
+}
 
-	return 0;
-	// TODO future: is this function needed for RPI?
+/** Index of the weekday of t (Monday is 0), the bit of that day in weekday bits
+ * t is a local time, see now_tz()
+ */
+byte OpenSprinkler::weekday_index(time_t t)
+{
+	return (byte)((t / 86400L + WEEKDAY_EPOCH) % 7);
 }
 
-/** Switch special station */
+/** Switch special station
+ * RF codes and HTTP requests are sent by their own threads, so this never blocks.
+ * spe_status tracks whether the latest command to the station went out.
+ * In dry run mode no command is sent, except to turn off a station that was turned on before.
+ */
 void OpenSprinkler::switch_special_station(byte sid, byte value)
 {
 	// check if this is a special station
 	byte stype = get_station_type(sid);
 	if (stype != STN_TYPE_STANDARD)
 	{
+		if (iopts[IOPT_DRY_RUN] && (value || !spe_value[sid]))
+		{
+			dry_run_log("special station %d not switched %s", sid + 1, value ? "on" : "off");
+			return;
+		}
 		// read station data
 		StationData *pdata = (StationData *)tmp_buffer;
 		get_station_data(sid, pdata);
@@ -915,21 +1497,22 @@ void OpenSprinkler::switch_special_station(byte sid, byte value)
 		{
 
 		case STN_TYPE_RF:
-			switch_rfstation((RFStationData *)pdata->sped, value);
+			switch_rfstation((RFStationData *)pdata->sped, value, sid);
 			break;
 
 		case STN_TYPE_REMOTE:
-			switch_remotestation((RemoteStationData *)pdata->sped, value);
+			switch_remotestation((RemoteStationData *)pdata->sped, value, sid);
 			break;
 
 		case STN_TYPE_GPIO:
-			switch_gpiostation((GPIOStationData *)pdata->sped, value);
+			set_spe_status(sid, switch_gpiostation((GPIOStationData *)pdata->sped, value, sid) ? SPE_STATUS_SENT : SPE_STATUS_FAILED);
 			break;
 
 		case STN_TYPE_HTTP:
-			switch_httpstation((HTTPStationData *)pdata->sped, value);
+			switch_httpstation((HTTPStationData *)pdata->sped, value, sid);
 			break;
 		}
+		spe_value[sid] = value;
 	}
 }
 
@@ -982,96 +1565,65 @@ void OpenSprinkler::clear_all_station_bits()
 	}
 }
 
-int rf_gpio_fd = -1;
-
-/** Transmit one RF signal bit */
-void transmit_rfbit(ulong lenH, ulong lenL)
-{
-	gpio_write(rf_gpio_fd, 1);
-	delayMicrosecondsHard(lenH);
-	gpio_write(rf_gpio_fd, 0);
-	delayMicrosecondsHard(lenL);
-}
-
-/** Transmit RF signal */
-void send_rfsignal(ulong code, ulong len)
-{
-	ulong len3 = len * 3;
-	ulong len31 = len * 31;
-	for (byte n = 0; n < 15; n++)
-	{
-		int i = 23;
-		// send code
-		while (i >= 0)
-		{
-			if ((code >> i) & 1)
-			{
-				transmit_rfbit(len3, len);
-			}
-			else
-			{
-				transmit_rfbit(len, len3);
-			}
-			i--;
-		};
-		// send sync
-		transmit_rfbit(len, len31);
-	}
-}
-
 /** Switch RF station
  * This function takes a RF code,
  * parses it into signals and timing,
- * and sends it out through RF transmitter.
+ * and has the RF thread send it out through RF transmitter.
  */
-void OpenSprinkler::switch_rfstation(RFStationData *data, bool turnon)
+void OpenSprinkler::switch_rfstation(RFStationData *data, bool turnon, byte sid)
 {
 	ulong on, off;
 	uint16_t length = parse_rfstation_code(data, &on, &off);
-
-	// pre-open gpio file to minimize overhead
-	rf_gpio_fd = gpio_fd_open(PIN_RFTX);
-	send_rfsignal(turnon ? on : off, length);
-	gpio_fd_close(rf_gpio_fd);
-	rf_gpio_fd = -1;
+	byte repeat = iopts[IOPT_RF_REPEAT] ? iopts[IOPT_RF_REPEAT] : RF_DEFAULT_REPEAT;
+	rf_transmit(turnon ? on : off, length, repeat, sid);
 }
 
 /** Switch GPIO station
  * Special data for GPIO Station is three bytes of ascii decimal (not hex)
  * First two bytes are zero padded GPIO pin number.
  * Third byte is either 0 or 1 for active low (GND) or high (+5V) relays
+ * An optional four more bytes are the zero padded length of a pulse in milliseconds:
+ * the station is then switched on and off alike by a pulse on the pin, for momentary
+ * and latching relays. Without them (or with 0000) the pin holds the station state.
+ * As a pulse toggles the relay, refreshes that do not change the station state send none.
  */
-void OpenSprinkler::switch_gpiostation(GPIOStationData *data, bool turnon)
+bool OpenSprinkler::switch_gpiostation(GPIOStationData *data, bool turnon, byte sid)
 {
 	byte gpio = (data->pin[0] - '0') * 10 + (data->pin[1] - '0');
 	byte activeState = data->active - '0';
 
+	uint16_t pulse = 0;
+	for (byte i = 0; i < sizeof(data->pulse) && data->pulse[i] >= '0' && data->pulse[i] <= '9'; i++)
+		pulse = pulse * 10 + data->pulse[i] - '0';
+	if (pulse)
+		return (turnon == spe_value[sid]) || gpio_pulse(gpio, activeState, pulse);
+
 	pinMode(gpio, OUTPUT);
 	if (turnon)
 		digitalWrite(gpio, activeState);
 	else
 		digitalWrite(gpio, 1 - activeState);
+	return true;
 }
 
-/** Callback function for switching remote station */
-void remote_http_callback(char *buffer)
-{
-	/*
-		DEBUG_PRINTLN(buffer);
-	*/
-}
-
-int8_t OpenSprinkler::send_http_request(const char *server, uint16_t port, char *p, void (*callback)(char *), uint16_t timeout)
+/** Send an HTTP request and read the response into buf
+ * Does not touch any shared buffer, so it can be called from a worker thread
+ */
+int8_t OpenSprinkler::fetch_http(const char *server, uint16_t port, const char *p, char *buf, uint16_t buflen, uint16_t timeout)
 {
 	EthernetClient etherClient;
 	EthernetClient *client = &etherClient;
-	struct hostent *host;
-	host = gethostbyname(server);
-	if (!host)
+	struct addrinfo hints, *res;
+	memset(&hints, 0, sizeof(hints));
+	hints.ai_family = AF_INET;
+	if (getaddrinfo(server, NULL, &hints, &res) || !res)
 	{
 		return HTTP_RQT_CONNECT_ERR;
 	}
-	if (!client->connect((uint8_t *)host->h_addr, port))
+	uint8_t ip[4];
+	memcpy(ip, &((struct sockaddr_in *)res->ai_addr)->sin_addr, 4);
+	freeaddrinfo(res);
+	if (!client->connect(ip, port))
 	{
 		DEBUG_PRINT(F("Cannot connect to "));
 		DEBUG_PRINT(server);
@@ -1086,31 +1638,198 @@ int8_t OpenSprinkler::send_http_request(const char *server, uint16_t port, char
 		len = ETHER_BUFFER_SIZE;
 	if (client->connected())
 	{
-		client->write((uint8_t *)p, len);
+		client->write((const uint8_t *)p, len);
 	}
-	memset(ether_buffer, 0, ETHER_BUFFER_SIZE);
+	memset(buf, 0, buflen);
 	uint32_t stoptime = millis() + timeout;
+	int pos = 0;
 
 	while (client->connected())
 	{
-		int len = client->read((uint8_t *)ether_buffer, ETHER_BUFFER_SIZE);
-		if (len <= 0)
-			continue;
 		if (millis() > stoptime)
 		{
 			client->stop();
 			return HTTP_RQT_TIMEOUT;
 		}
+		// responses may arrive in several packets, append them to the buffer
+		int len = client->read((uint8_t *)buf + pos, buflen - 1 - pos);
+		if (len <= 0)
+			continue;
+		pos += len;
 	}
 
 	client->stop();
-	if (strlen(ether_buffer) == 0)
+	if (strlen(buf) == 0)
 		return HTTP_RQT_EMPTY_RETURN;
-	if (callback)
-		callback(ether_buffer);
 	return HTTP_RQT_SUCCESS;
 }
 
+int8_t OpenSprinkler::send_http_request(const char *server, uint16_t port, char *p, void (*callback)(char *), uint16_t timeout)
+{
+	int8_t ret = fetch_http(server, port, p, ether_buffer, ETHER_BUFFER_SIZE, timeout);
+	if (ret == HTTP_RQT_SUCCESS && callback)
+		callback(ether_buffer);
+	return ret;
+}
+
+// outgoing requests whose response is not needed are sent by a worker thread in the order they were posted,
+// so that a slow or unreachable remote controller does not hold up the main loop
+struct WorkerJob
+{
+	byte sid; // special station switched by the request, 0xFF if none
+	char server[MAX_SOPTS_SIZE + 1];
+	uint16_t port;
+	uint16_t timeout;
+	bool check_result; // the response must be a json result of success, e.g. {"result":1}
+	char request[ETHER_BUFFER_SIZE + 1];
+};
+static WorkerJob job_queue[HTTP_QUEUE_SIZE];
+static byte job_queue_head = 0; // index of the next job to run
+static byte job_queue_len = 0;
+static bool worker_started = false;
+static pthread_mutex_t job_mutex = PTHREAD_MUTEX_INITIALIZER;
+static pthread_cond_t job_cond = PTHREAD_COND_INITIALIZER;
+
+/** Set the status of the latest command to a special station
+ * The status is set by the main loop, the worker thread and the RF transmit thread,
+ * so it is guarded by job_mutex. The worker sets it directly, as it holds the mutex.
+ */
+void OpenSprinkler::set_spe_status(byte sid, byte status)
+{
+	if (sid >= MAX_NUM_STATIONS)
+		return;
+	pthread_mutex_lock(&job_mutex);
+	spe_status[sid] = status;
+	pthread_mutex_unlock(&job_mutex);
+}
+
+/** Get the status of the latest command to a special station */
+byte OpenSprinkler::get_spe_status(byte sid)
+{
+	if (sid >= MAX_NUM_STATIONS)
+		return SPE_STATUS_NONE;
+	pthread_mutex_lock(&job_mutex);
+	byte status = spe_status[sid];
+	pthread_mutex_unlock(&job_mutex);
+	return status;
+}
+
+static void *job_worker(void *)
+{
+	WorkerJob r;
+	char response[ETHER_BUFFER_SIZE];
+	for (;;)
+	{
+		pthread_mutex_lock(&job_mutex);
+		while (job_queue_len == 0)
+			pthread_cond_wait(&job_cond, &job_mutex);
+		memcpy(&r, &job_queue[job_queue_head], sizeof(WorkerJob));
+		pthread_mutex_unlock(&job_mutex);
+
+		int8_t ret = OpenSprinkler::fetch_http(r.server, r.port, r.request, response, sizeof(response), r.timeout);
+		if (ret == HTTP_RQT_SUCCESS && r.check_result && !strstr(response, "\"result\":1}"))
+			ret = HTTP_RQT_BAD_STATUS;
+		if (ret != HTTP_RQT_SUCCESS)
+		{
+			DEBUG_PRINT(F("http request to "));
+			DEBUG_PRINT(r.server);
+			DEBUG_PRINT(F(" failed: "));
+			DEBUG_PRINTLN(ret);
+		}
+
+		// the job stays in the queue until it is done, which keeps the order of requests to the same server
+		pthread_mutex_lock(&job_mutex);
+		if (r.sid < MAX_NUM_STATIONS) // job_mutex is held, see set_spe_status()
+			OpenSprinkler::spe_status[r.sid] = (ret == HTTP_RQT_SUCCESS) ? SPE_STATUS_SENT : SPE_STATUS_FAILED;
+		job_queue_head = (job_queue_head + 1) % HTTP_QUEUE_SIZE;
+		job_queue_len--;
+		pthread_mutex_unlock(&job_mutex);
+	}
+	return NULL;
+}
+
+/** Reserve the next job in the queue
+ * Returns NULL if the worker cannot be started or the queue is full, otherwise the queue
+ * is left locked until the job is filled in and handed to the worker with post_job().
+ * A station switched by the job is marked pending, or failed if there is no room for it.
+ */
+static WorkerJob *reserve_job(byte sid)
+{
+	WorkerJob *r = NULL;
+	pthread_mutex_lock(&job_mutex);
+	if (!worker_started)
+	{
+		pthread_t thread;
+		if (pthread_create(&thread, NULL, job_worker, NULL) == 0)
+		{
+			pthread_detach(thread);
+			worker_started = true;
+		}
+	}
+	if (worker_started && job_queue_len < HTTP_QUEUE_SIZE)
+	{
+		r = &job_queue[(job_queue_head + job_queue_len) % HTTP_QUEUE_SIZE];
+		memset(r, 0, offsetof(WorkerJob, request) + 1);
+		r->sid = sid;
+	}
+	if (sid < MAX_NUM_STATIONS) // job_mutex is held, see set_spe_status()
+		OpenSprinkler::spe_status[sid] = r ? SPE_STATUS_PENDING : SPE_STATUS_FAILED;
+	if (!r)
+		pthread_mutex_unlock(&job_mutex);
+	return r;
+}
+
+/** Wait until the worker has sent all queued requests
+ * Used at shutdown, so that requests such as turning off remote stations are not lost when the process exits.
+ * Each queued request is given twice its timeout. Returns false if requests are left.
+ */
+bool OpenSprinkler::drain_http_requests()
+{
+	pthread_mutex_lock(&job_mutex);
+	ulong timeout = 0;
+	for (byte i = 0; i < job_queue_len; i++)
+		timeout += 2UL * job_queue[(job_queue_head + i) % HTTP_QUEUE_SIZE].timeout;
+	ulong start = millis();
+	while (job_queue_len > 0 && millis() - start < timeout)
+	{
+		pthread_mutex_unlock(&job_mutex);
+		delay(10);
+		pthread_mutex_lock(&job_mutex);
+	}
+	bool drained = (job_queue_len == 0);
+	pthread_mutex_unlock(&job_mutex);
+	return drained;
+}
+
+/** Hand a reserved job to the worker */
+static void post_job()
+{
+	job_queue_len++;
+	pthread_cond_signal(&job_cond);
+	pthread_mutex_unlock(&job_mutex);
+}
+
+/** Queue an HTTP request for the worker thread
+ * Returns HTTP_RQT_NOT_RECEIVED once queued, as the response arrives later and is discarded.
+ * If the request switches a special station, the worker records in spe_status whether it went out,
+ * and with check_result whether the server reported success.
+ */
+int8_t OpenSprinkler::post_http_request(const char *server, uint16_t port, const char *p, byte sid, uint16_t timeout, bool check_result)
+{
+	WorkerJob *r = reserve_job(sid);
+	if (!r)
+		return worker_started ? HTTP_RQT_QUEUE_FULL : HTTP_RQT_CONNECT_ERR;
+	strncpy(r->server, server, MAX_SOPTS_SIZE);
+	r->server[MAX_SOPTS_SIZE] = 0;
+	r->port = port;
+	r->timeout = timeout;
+	r->check_result = check_result;
+	strncpy(r->request, p, ETHER_BUFFER_SIZE);
+	r->request[ETHER_BUFFER_SIZE] = 0;
+	post_job();
+	return HTTP_RQT_NOT_RECEIVED;
+}
+
 int8_t OpenSprinkler::send_http_request(uint32_t ip4, uint16_t port, char *p, void (*callback)(char *), uint16_t timeout)
 {
 	char server[20];
@@ -1125,14 +1844,40 @@ int8_t OpenSprinkler::send_http_request(char *server_with_port, char *p, void (*
 	return send_http_request(server, (port == NULL) ? 80 : atoi(port), p, callback, timeout);
 }
 
+/** Firmware version reported to other systems, e.g. 219 for 2.1.9
+ * The reported version option overrides it for weather services that only know earlier firmwares.
+ */
+byte OpenSprinkler::reported_fw_version()
+{
+	return iopts[IOPT_REPORTED_FW_VERSION] ? iopts[IOPT_REPORTED_FW_VERSION] : (byte)OS_FW_VERSION;
+}
+
+/** Append the rest of an outgoing request head to buf, which holds the request line up to the path
+ * Adds the protocol, Host and a User-Agent naming the firmware, e.g. OpenSprinkler/2.1.9 (9),
+ * so that servers can tell which firmware sent a request. With identify, the device UUID is added
+ * too; it is only sent to the weather service and remote controllers, not to user given urls.
+ * The caller adds any further headers, and the blank line that ends the head.
+ */
+void OpenSprinkler::http_request_head(char *buf, size_t len, const char *host, bool identify)
+{
+	size_t n = strlen(buf);
+	byte v = reported_fw_version();
+	n += snprintf(buf + n, len - n, " HTTP/1.0\r\nHost: %s\r\nUser-Agent: " HTTP_USER_AGENT "/%d.%d.%d (%d)\r\n",
+				  host, v / 100, (v / 10) % 10, v % 10, OS_FW_MINOR);
+	if (identify && n < len)
+		snprintf(buf + n, len - n, "X-Device-Id: %s\r\n", device_uuid);
+}
+
 /** Switch remote station
  * This function takes a remote station code,
  * parses it into remote IP, port, station index,
  * and makes a HTTP GET request.
- * The remote controller is assumed to have the same
- * password as the main controller
+ * The remote controller is authenticated with get_remote_key(): in the url, as the original
+ * firmware expects, or for REMOTE_PROTO_JSON in an Authorization header, so that the key
+ * stays out of logged urls. The json result of such a remote is also checked.
+ * Without a key to send (a hashed password and no rkey) the command fails.
  */
-void OpenSprinkler::switch_remotestation(RemoteStationData *data, bool turnon)
+void OpenSprinkler::switch_remotestation(RemoteStationData *data, bool turnon, byte sid)
 {
 	RemoteStationData copy;
 	memcpy((char *)&copy, (char *)data, sizeof(RemoteStationData));
@@ -1146,28 +1891,53 @@ void OpenSprinkler::switch_remotestation(RemoteStationData *data, bool turnon)
 	ip[2] = (ip4 >> 8) & 0xff;
 	ip[3] = ip4 & 0xff;
 
-	// use tmp_buffer starting at a later location
-	// because remote station data is loaded at the beginning
-	char *p = tmp_buffer;
+	char p[TMP_BUFFER_SIZE + MAX_SOPTS_SIZE];
 	BufferFiller bf = p;
 	// if auto refresh is enabled, we give a fixed duration each time, and auto refresh will renew it periodically
 	// if no auto refresh, we will give the maximum allowed duration, and station will be turned off when off command is sent
-	uint16_t timer = iopts[IOPT_SPE_AUTO_REFRESH] ? 4 * MAX_NUM_STATIONS : 64800;
-	bf.emit_p(PSTR("GET /cm?pw=$O&sid=$D&en=$D&t=$D"),
-			  SOPT_PASSWORD,
-			  (int)hex2ulong(copy.sid, sizeof(copy.sid)),
-			  turnon, timer);
-	bf.emit_p(PSTR(" HTTP/1.0\r\nHOST: $D.$D.$D.$D\r\n\r\n"),
-			  ip[0], ip[1], ip[2], ip[3]);
+	// a refresh cycle visits every special station once, the timer covers four cycles
+	ulong timer = MAX_WATER_TIME;
+	if (iopts[IOPT_SPE_AUTO_REFRESH])
+	{
+		timer = 4UL * (nspe ? nspe : 1) * spe_refresh_interval();
+		if (timer > MAX_WATER_TIME)
+			timer = MAX_WATER_TIME;
+	}
+	char key[MAX_SOPTS_SIZE + 1];
+	if (!get_remote_key(key))
+	{
+		DEBUG_PRINTLN(F("remote station: the password is hashed, set rkey"));
+		set_spe_status(sid, SPE_STATUS_FAILED);
+		return;
+	}
+	char server[20];
+	sprintf(server, "%d.%d.%d.%d", ip[0], ip[1], ip[2], ip[3]);
+	bool json = (copy.proto == REMOTE_PROTO_JSON);
+	if (json)
+	{
+		bf.emit_p(PSTR("GET /cm?sid=$D&en=$D&t=$D"),
+				  (int)hex2ulong(copy.sid, sizeof(copy.sid)), turnon, (int)timer);
+		http_request_head(p, sizeof(p), server, true);
+		snprintf(p + strlen(p), sizeof(p) - strlen(p), "Authorization: Bearer %s\r\n\r\n", key);
+	}
+	else
+	{
+		bf.emit_p(PSTR("GET /cm?pw=$S&sid=$D&en=$D&t=$D"),
+				  key,
+				  (int)hex2ulong(copy.sid, sizeof(copy.sid)),
+				  turnon, (int)timer);
+		http_request_head(p, sizeof(p), server, true);
+		strcat(p, "\r\n");
+	}
 
-	send_http_request(ip4, port, p, remote_http_callback);
+	post_http_request(server, port, p, sid, 3000, json);
 }
 
 /** Switch http station
  * This function takes an http station code,
  * parses it into a server name and two HTTP GET requests.
  */
-void OpenSprinkler::switch_httpstation(HTTPStationData *data, bool turnon)
+void OpenSprinkler::switch_httpstation(HTTPStationData *data, bool turnon, byte sid)
 {
 
 	HTTPStationData copy;
@@ -1179,15 +1949,20 @@ void OpenSprinkler::switch_httpstation(HTTPStationData *data, bool turnon)
 	char *off_cmd = strtok(NULL, ",");
 	char *cmd = turnon ? on_cmd : off_cmd;
 
-	char *p = tmp_buffer;
+	char p[TMP_BUFFER_SIZE + MAX_SOPTS_SIZE];
 	BufferFiller bf = p;
 
 	if (cmd == NULL || server == NULL)
+	{
+		set_spe_status(sid, SPE_STATUS_FAILED);
 		return; // proceed only if cmd and server are valid
+	}
 
-	bf.emit_p(PSTR("GET /$S HTTP/1.0\r\nHOST: $S\r\n\r\n"), cmd, server);
+	bf.emit_p(PSTR("GET /$S"), cmd);
+	http_request_head(p, sizeof(p), server, false);
+	strcat(p, "\r\n");
 
-	send_http_request(server, atoi(port), p, remote_http_callback);
+	post_http_request(server, (port == NULL) ? 80 : atoi(port), p, sid);
 }
 
 /** Prepare factory reset */
@@ -1204,6 +1979,7 @@ void OpenSprinkler::factory_reset()
 	DEBUG_PRINT("factory reset...");
 
 	// 1. reset integer options (by saving default values)
+	iopts[IOPT_SETUP] = 0;
 	iopts_save();
 	// reset string options by first wiping the file clean then write default values
 	memset(tmp_buffer, 0, MAX_SOPTS_SIZE);
@@ -1250,12 +2026,27 @@ void OpenSprinkler::factory_reset()
 
 	// 3. write non-volatile controller status
 	nvdata.reboot_cause = REBOOT_CAUSE_RESET;
+	nvdata.wl_stop_time = 0;
 	nvdata_save();
 	last_reboot_cause = nvdata.reboot_cause;
 
 	// 4. write program data: just need to write a program counter: 0
 	file_write_byte(PROG_FILENAME, 0, 0);
 
+	// write empty station group names
+	memset(tmp_buffer, 0, GROUP_NAME_SIZE);
+	for (int i = 0; i < MAX_NUM_GROUPS; i++)
+	{
+		file_write_block(GROUPS_FILENAME, tmp_buffer, (ulong)GROUP_NAME_SIZE * i, GROUP_NAME_SIZE);
+	}
+
+	// remove scheduled tasks and the last run record
+	remove_file(TASKS_FILENAME);
+	remove_file(LASTRUN_FILENAME);
+	remove_file(BUDGET_FILENAME);
+	remove_file(STNMETA_FILENAME);
+	remove_file(RUNONCE_FILENAME);
+
 	// 5. write 'done' file
 	file_write_byte(DONE_FILENAME, 0, 1);
 }
@@ -1281,6 +2072,51 @@ void OpenSprinkler::options_setup()
 		nvdata_save();
 		attribs_load();
 	}
+	rdwindows_load();
+	load_device_uuid();
+}
+
+/** Fill a buffer with random bytes */
+void OpenSprinkler::random_bytes(byte *b, int n)
+{
+	FILE *fp = fopen("/dev/urandom", "rb");
+	if (!fp || fread(b, 1, n, fp) != (size_t)n)
+	{
+		srand(time(NULL) ^ getpid());
+		for (int i = 0; i < n; i++)
+			b[i] = rand() & 0xFF;
+	}
+	if (fp)
+		fclose(fp);
+}
+
+/** Load the device UUID, generating a random (version 4) one if there is none yet
+ * It is kept in the string options, so it stays the same until a factory reset.
+ */
+void OpenSprinkler::load_device_uuid()
+{
+	sopt_load(SOPT_DEVICE_UUID, tmp_buffer);
+	if (strlen(tmp_buffer) == DEVICE_UUID_SIZE)
+	{
+		strcpy(device_uuid, tmp_buffer);
+		return;
+	}
+
+	byte b[16];
+	random_bytes(b, sizeof(b));
+	b[6] = (b[6] & 0x0F) | 0x40; // version 4
+	b[8] = (b[8] & 0x3F) | 0x80; // RFC 4122 variant
+	sprintf(device_uuid, "%02x%02x%02x%02x-%02x%02x-%02x%02x-%02x%02x-%02x%02x%02x%02x%02x%02x",
+			b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7], b[8], b[9], b[10], b[11], b[12], b[13], b[14], b[15]);
+	sopt_save(SOPT_DEVICE_UUID, device_uuid);
+}
+
+/** Get the device name, DEFAULT_DEVICE_NAME if none is set */
+void OpenSprinkler::get_device_name(char *buf)
+{
+	sopt_load(SOPT_DEVICE_NAME, buf);
+	if (!buf[0])
+		strcpy(buf, DEFAULT_DEVICE_NAME);
 }
 
 /** Load non-volatile controller status data from file */
@@ -1296,15 +2132,33 @@ void OpenSprinkler::nvdata_save()
 	file_write_block(NVCON_FILENAME, &nvdata, 0, sizeof(NVConData));
 }
 
+/** Count the boards from the expander options
+ * A board is SHIFT_REGISTER_LINES stations, so a 16-zone expander counts as two boards.
+ */
+byte OpenSprinkler::expander_boards(byte ext, byte zones)
+{
+	if (zones < SHIFT_REGISTER_LINES)
+		zones = SHIFT_REGISTER_LINES;
+	return ext * (zones / SHIFT_REGISTER_LINES);
+}
+
+/** Update the number of boards and stations */
+void OpenSprinkler::update_nboards()
+{
+	byte ext = expander_boards(iopts[IOPT_EXT_BOARDS], iopts[IOPT_EXP_ZONES]);
+	nboards = 1 + ((ext > MAX_EXT_BOARDS) ? MAX_EXT_BOARDS : ext);
+	nstations = nboards * SHIFT_REGISTER_LINES;
+}
+
 /** Load integer options from file */
 void OpenSprinkler::iopts_load()
 {
 	file_read_block(IOPTS_FILENAME, iopts, 0, NUM_IOPTS);
-	nboards = iopts[IOPT_EXT_BOARDS] + 1;
-	nstations = nboards * 8;
+	update_nboards();
 	status.enabled = iopts[IOPT_DEVICE_ENABLE];
 	iopts[IOPT_FW_VERSION] = OS_FW_VERSION;
 	iopts[IOPT_FW_MINOR] = OS_FW_MINOR;
+	iopts[IOPT_HW_VERSION] = hw_version;
 	/* Reject the former default 50.97.210.169 NTP IP address as
 	 * it no longer works, yet is carried on by people's saved
 	 * configs when they upgrade from older versions.
@@ -1323,8 +2177,7 @@ void OpenSprinkler::iopts_load()
 void OpenSprinkler::iopts_save()
 {
 	file_write_block(IOPTS_FILENAME, iopts, 0, NUM_IOPTS);
-	nboards = iopts[IOPT_EXT_BOARDS] + 1;
-	nstations = nboards * 8;
+	update_nboards();
 	status.enabled = iopts[IOPT_DEVICE_ENABLE];
 }
 
@@ -1396,3 +2249,115 @@ void OpenSprinkler::raindelay_stop()
 	nvdata.rd_stop_time = 0;
 	nvdata_save();
 }
+
+/** Set the watering percentage
+ * A change is saved and flagged for the weather update notification.
+ * While the water scale is overridden, the new scale is kept for when the override ends.
+ */
+void OpenSprinkler::set_water_scale(byte scale)
+{
+	if (nvdata.wl_stop_time)
+	{
+		if (scale != nvdata.wl_saved)
+		{
+			nvdata.wl_saved = scale;
+			nvdata_save();
+		}
+		return;
+	}
+	if (scale == iopts[IOPT_WATER_PERCENTAGE])
+		return;
+	iopts[IOPT_WATER_PERCENTAGE] = scale;
+	iopts_save();
+	weather_update_flag |= WEATHER_UPDATE_WL;
+}
+
+/** Override the watering percentage until stop_time
+ * The override supersedes the weather adjustment, the scale from before
+ * (or the latest one from the weather service) is restored when it ends.
+ */
+void OpenSprinkler::wl_override_start(byte scale, ulong stop_time)
+{
+	if (!nvdata.wl_stop_time)
+		nvdata.wl_saved = iopts[IOPT_WATER_PERCENTAGE];
+	nvdata.wl_stop_time = 0;
+	set_water_scale(scale);
+	nvdata.wl_stop_time = stop_time;
+	nvdata_save();
+}
+
+/** End the water scale override */
+void OpenSprinkler::wl_override_stop()
+{
+	if (!nvdata.wl_stop_time)
+		return;
+	nvdata.wl_stop_time = 0;
+	nvdata_save();
+	set_water_scale(nvdata.wl_saved);
+}
+
+/** Set the external ip reported by the weather service
+ * A change is flagged for the weather update notification.
+ * Returns true if the ip changed, the caller saves nvdata.
+ */
+bool OpenSprinkler::set_external_ip(uint32_t ip)
+{
+	if (ip == nvdata.external_ip)
+		return false;
+	nvdata.external_ip = ip;
+	weather_update_flag |= WEATHER_UPDATE_EIP;
+	return true;
+}
+
+/** Load the scheduled rain delay windows into RAM, as they are checked every second */
+void OpenSprinkler::rdwindows_load()
+{
+	memset(rdwins, 0, sizeof(rdwins));
+	for (byte wid = 0; wid < MAX_RD_WINDOWS; wid++)
+		file_read_block(RDWIN_FILENAME, &rdwins[wid], (ulong)wid * sizeof(RainDelayWindow), sizeof(RainDelayWindow));
+}
+
+/** Get scheduled rain delay window */
+void OpenSprinkler::get_rdwindow(byte wid, RainDelayWindow *win)
+{
+	memset(win, 0, sizeof(RainDelayWindow));
+	if (wid >= MAX_RD_WINDOWS)
+		return;
+	*win = rdwins[wid];
+}
+
+/** Set scheduled rain delay window */
+void OpenSprinkler::set_rdwindow(byte wid, RainDelayWindow *win)
+{
+	if (wid >= MAX_RD_WINDOWS)
+		return;
+	file_write_block(RDWIN_FILENAME, win, (ulong)wid * sizeof(RainDelayWindow), sizeof(RainDelayWindow));
+	rdwins[wid] = *win;
+}
+
+/** Return the end time of the rain delay window covering curr_time, 0 if none
+ * A window may extend past midnight, so occurrences starting yesterday are checked too */
+ulong OpenSprinkler::rdwindow_end(ulong curr_time)
+{
+	ulong end = 0;
+	RainDelayWindow win;
+	ulong midnight = curr_time - curr_time % 86400L;
+	for (byte wid = 0; wid < MAX_RD_WINDOWS; wid++)
+	{
+		get_rdwindow(wid, &win);
+		if (!win.days || !win.dur)
+			continue;
+		for (byte d = 0; d < 2; d++)
+		{
+			ulong day = midnight - d * 86400L;
+			byte wd = weekday_index(day);
+			if (!(win.days & (1 << wd)))
+				continue;
+			ulong st = day + (ulong)win.start * 60;
+			ulong et = st + (ulong)win.dur * 60;
+			if (curr_time >= st && curr_time < et && et > end)
+				end = et;
+		}
+	}
+	return end;
+}
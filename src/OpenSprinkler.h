@@ -28,6 +28,8 @@
 #include "utils.h"
 #include "gpio.h"
 #include "mqtt.h"
+#include "control.h"
+#include "display.h"
 #include <time.h>
 #include <string.h>
 #include <unistd.h>
@@ -43,6 +45,8 @@ struct NVConData
 	uint32_t rd_stop_time; // rain delay stop time
 	uint32_t external_ip;  // external ip
 	uint8_t reboot_cause;  // reboot cause
+	uint32_t wl_stop_time; // water scale override stop time (0: no override)
+	uint8_t wl_saved;	   // water scale to return to when the override ends
 };
 
 struct StationAttrib
@@ -56,11 +60,21 @@ struct StationAttrib
 	byte igrd : 1; // ignore rain delay
 	byte unused : 1;
 
-	byte gid : 4; // group id: reserved for the future
-	byte dummy : 4;
-	byte reserved[2]; // reserved bytes for the future
+	byte gid : 4; // group id (0 means no group)
+	byte pri : 4; // priority when the run-time queue overflows (0 is the lowest)
+	byte mdelay;  // delay (in seconds) from the master turning on until the station opens, to build up pressure
+	byte reserved[1]; // reserved bytes for the future
 };					  // total is 4 bytes so far
 
+/** Scheduled rain delay window, e.g. every Monday 6:00-12:00 */
+struct RainDelayWindow
+{
+	byte days;	  // weekday bits (bit 0 is Monday), 0 means the window is not used
+	byte reserved;
+	uint16_t start; // start time (in minutes from midnight)
+	uint16_t dur;	// duration (in minutes)
+};
+
 /** Station data structure */
 struct StationData
 {
@@ -70,6 +84,17 @@ struct StationData
 	byte sped[STATION_SPECIAL_DATA_SIZE]; // special station data
 };
 
+/** Station metadata, stored in STNMETA_FILENAME
+ * Strings are freeform and always terminated, so they hold one character less than their field.
+ */
+struct StationMeta
+{
+	char notes[STATION_NOTES_SIZE];
+	char emitter[STATION_EMITTER_SIZE]; // e.g. rotor, spray, drip
+	char photo[STATION_PHOTO_SIZE];		// path or url of a photo of the station
+	uint16_t flow;						// rated flow in 1/100 of the volume unit per minute (0: unknown)
+};
+
 /** RF station data structures - Must fit in STATION_SPECIAL_DATA_SIZE */
 struct RFStationData
 {
@@ -84,6 +109,7 @@ struct RemoteStationData
 	byte ip[8];
 	byte port[4];
 	byte sid[2];
+	byte proto; // optional, REMOTE_PROTO_JSON for remotes running this firmware, 0 for the original protocol
 };
 
 /** GPIO station data structures - Must fit in STATION_SPECIAL_DATA_SIZE */
@@ -91,6 +117,7 @@ struct GPIOStationData
 {
 	byte pin[2];
 	byte active;
+	byte pulse[4]; // optional, pulse length in milliseconds
 };
 
 /** HTTP station data structures - Must fit in STATION_SPECIAL_DATA_SIZE */
@@ -118,6 +145,7 @@ struct ConStatus
 	byte sensor1_active : 1;   // sensor1 active bit (when set, sensor1 is activated)
 	byte sensor2_active : 1;   // sensor2 active bit (when set, sensor2 is activated)
 	byte req_mqtt_restart : 1; // request mqtt restart
+	byte frost : 1;			   // frost protection is suppressing program starts
 };
 
 extern const char iopt_json_names[];
@@ -128,6 +156,8 @@ class OpenSprinkler
 public:
 	// data members
 	static OSMqtt mqtt;
+	static OSControl control;
+	static OSDisplay display;
 
 	static NVConData nvdata;
 	static ConStatus status;
@@ -135,6 +165,9 @@ public:
 	static byte nboards, nstations;
 	static byte hw_type; // hardware type
 	static byte hw_rev;	 // hardware minor
+	static byte hw_version; // hardware version, detected at startup
+	static char hw_model[HW_MODEL_SIZE + 1]; // board model, e.g. Raspberry Pi 4 Model B
+	static char device_uuid[DEVICE_UUID_SIZE + 1]; // generated once, identifies the controller to other systems
 
 	static byte iopts[];		// integer options
 	static const char *sopts[]; // string options
@@ -149,6 +182,14 @@ public:
 	static byte attrib_dis[];
 	static byte attrib_seq[];
 	static byte attrib_spe[];
+	static byte attrib_grp[]; // group id of each station
+	static byte attrib_pri[]; // priority of each station
+	static byte attrib_mdel[]; // master delay of each station (in seconds)
+	static byte spe_sids[];	  // index of special stations, refreshed by the auto refresh
+	static byte nspe;		  // number of special stations
+	static byte spe_status[]; // status of the latest command to each special station (see SPE_STATUS macro defines), set by several threads
+	static byte spe_value[];  // value of the latest command to each special station
+	static RainDelayWindow rdwins[]; // scheduled rain delay windows, see rdwindows_load()
 
 	// variables for time keeping
 	static ulong sensor1_on_timer;		  // time when sensor1 is detected on last time
@@ -158,6 +199,11 @@ public:
 	static ulong sensor2_off_timer;		  // time when sensor2 is detected off last time
 	static ulong sensor2_active_lasttime; // most recent time sensor1 is activated
 	static ulong raindelay_on_lasttime;	  // time when the most recent rain delay started
+	static byte vswitch_pending;		  // virtual program switches triggered since the last check
+	static byte sensor_override[];		  // forced active state of each binary sensor, 0xFF if not overridden
+	static ulong sensor_override_until[]; // time when the override of each binary sensor ends
+	static byte pswitch_override;		  // program switch sensors triggered through the API since the last check
+	static ulong vswitch_lasttime[];	  // time when each virtual program switch was last triggered
 	static ulong flowcount_rt;			  // flow count (for computing real-time flow rate)
 	static ulong flowcount_log_start;	  // starting flow count (for logging)
 
@@ -173,9 +219,11 @@ public:
 	static void reboot_dev(uint8_t);								 // reboot the microcontroller
 	static void begin();											 // initialization, must call this function before calling other functions
 	static byte start_network();									 // initialize network with the given mac and port
+	static uint16_t get_http_port();								 // http port of the web server
 	static byte start_ether();										 // initialize ethernet with the given mac and port
 	static bool network_connected();								 // check if the network is up
 	static bool load_hardware_mac(byte *buffer, bool wired = false); // read hardware mac address
+	static void detect_hardware();									 // detect board model and hardware version
 	static time_t now_tz();
 	// -- station names and attributes
 	static void get_station_data(byte sid, StationData *data); // get station data
@@ -183,53 +231,91 @@ public:
 	static void get_station_name(byte sid, char buf[]);		   // get station name
 	static void set_station_name(byte sid, char buf[]);		   // set station name
 	static byte get_station_type(byte sid);					   // get station type
+	static void get_station_meta(byte sid, StationMeta *meta); // get station metadata
+	static void set_station_meta(byte sid, StationMeta *meta); // set station metadata
+	static void get_group_name(byte gid, char buf[]);		   // get station group name
+	static void set_group_name(byte gid, char buf[]);		   // set station group name
 	// static StationAttrib get_station_attrib(byte sid); // get station attribute
 	static void attribs_save();														  // repackage attrib bits and save (backward compatibility)
 	static void attribs_load();														  // load and repackage attrib bits (backward compatibility)
+	static void index_special_stations();											  // rebuild the index of special stations
+	static byte spe_refresh_interval();												  // seconds between special station refreshes
 	static uint16_t parse_rfstation_code(RFStationData *data, ulong *on, ulong *off); // parse rf code into on/off/time sections
-	static void switch_rfstation(RFStationData *data, bool turnon);					  // switch rf station
-	static void switch_remotestation(RemoteStationData *data, bool turnon);			  // switch remote station
-	static void switch_gpiostation(GPIOStationData *data, bool turnon);				  // switch gpio station
-	static void switch_httpstation(HTTPStationData *data, bool turnon);				  // switch http station
+	static void switch_rfstation(RFStationData *data, bool turnon, byte sid);		  // switch rf station
+	static void switch_remotestation(RemoteStationData *data, bool turnon, byte sid); // switch remote station
+	static bool switch_gpiostation(GPIOStationData *data, bool turnon, byte sid);	  // switch gpio station
+	static void switch_httpstation(HTTPStationData *data, bool turnon, byte sid);	  // switch http station
 
 	// -- options and data storeage
 	static void nvdata_load();
 	static void nvdata_save();
 
 	static void options_setup();
+	static void random_bytes(byte *b, int n);
+	static void load_device_uuid();
+	static void get_device_name(char *buf);
 	static void pre_factory_reset();
 	static void factory_reset();
 	static void iopts_load();
 	static void iopts_save();
+	static byte expander_boards(byte ext, byte zones); // boards of ext expanders with the given zones each
+	static void update_nboards();
 	static bool sopt_save(byte oid, const char *buf);
 	static void sopt_load(byte oid, char *buf);
 	static String sopt_load(byte oid);
 
-	static byte password_verify(char *pw); // verify password
+	static byte password_verify(char *pw);			   // verify password
+	static byte guest_verify(char *pw);				   // verify the guest key
+	static bool guest_open();						   // guests need no key
+	static void password_save(const char *key);		   // store a new password key, hashed if enabled
+	static bool password_acceptable(const char *key); // check a new password key against the password rules
+	static bool get_remote_key(char *buf);			   // key sent to remote stations
 
 	// -- controller operation
 	static void enable();							// enable controller operation
 	static void disable();							// disable controller operation, all stations will be closed immediately
 	static void raindelay_start();					// start raindelay
 	static void raindelay_stop();					// stop rain delay
+	static void wl_override_start(byte scale, ulong stop_time); // hold the water scale until stop_time
+	static void wl_override_stop();					// end the water scale override
+	static void set_water_scale(byte scale);		// set watering percentage, flag the change for notification
+	static bool set_external_ip(uint32_t ip);		// set external ip, flag the change for notification
+	static void rdwindows_load();									// load scheduled rain delay windows
+	static void get_rdwindow(byte wid, RainDelayWindow *win);		// get scheduled rain delay window
+	static void set_rdwindow(byte wid, RainDelayWindow *win);		// set scheduled rain delay window
+	static ulong rdwindow_end(ulong curr_time);					// end time of the active rain delay window (0 if none)
 	static void detect_binarysensor_status(ulong);	// update binary (rain, soil) sensor status
 	static byte detect_programswitch_status(ulong); // get program switch status
+	static bool trigger_vswitch(byte, ulong);		// trigger a virtual program switch
+	static byte detect_vswitch_status();			// get and clear virtual program switch status
+	static byte override_sensor(byte, int8_t, uint16_t, ulong); // force the state of a sensor for testing
+	static bool sensor_overridden(byte, ulong);		// check (and expire) a sensor override
 	static void sensor_resetall();
 
 	static uint16_t read_current();	  // read current sensing value
 	static uint16_t baseline_current; // resting state current
 
 	static int detect_exp();	 // detect the number of expansion boards
-	static byte weekday_today(); // returns index of today's weekday (Monday is 0)
+	static byte weekday_today();		   // returns index of today's weekday (Monday is 0)
+	static byte weekday_index(time_t t); // returns index of the weekday of t (Monday is 0)
 
 	static byte set_station_bit(byte sid, byte value);		  // set station bit of one station (sid->station index, value->0/1)
 	static void switch_special_station(byte sid, byte value); // swtich special station
+	static void set_spe_status(byte sid, byte status);		  // set the command status of a special station, from any thread
+	static byte get_spe_status(byte sid);					  // get the command status of a special station
 	static void clear_all_station_bits();					  // clear all station bits
 	static void apply_all_station_bits();					  // apply all station bits (activate/deactive values)
+	static void close_valves_on_crash();					  // close the shift register valves, async-signal-safe
 
+	static byte reported_fw_version();
+	static void http_request_head(char *buf, size_t len, const char *host, bool identify); // protocol, Host and User-Agent of an outgoing request
+	static int8_t fetch_http(const char *server, uint16_t port, const char *p, char *buf, uint16_t buflen, uint16_t timeout);
 	static int8_t send_http_request(uint32_t ip4, uint16_t port, char *p, void (*callback)(char *) = NULL, uint16_t timeout = 3000);
 	static int8_t send_http_request(const char *server, uint16_t port, char *p, void (*callback)(char *) = NULL, uint16_t timeout = 3000);
 	static int8_t send_http_request(char *server_with_port, char *p, void (*callback)(char *) = NULL, uint16_t timeout = 3000);
+	static int8_t post_http_request(const char *server, uint16_t port, const char *p, byte sid = 0xFF, uint16_t timeout = 3000,
+									bool check_result = false); // send in the background, the response is discarded
+	static bool drain_http_requests();							// wait for the requests sent in the background
 	static byte engage_booster;
 };
 
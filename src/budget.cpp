@@ -0,0 +1,146 @@
+/* OpenSprinkler Unified (RPI/LINUX) Firmware
+ * Copyright (C) 2015 by Ray Wang (ray@opensprinkler.com)
+ *
+ * Station moisture budget: a water balance of each station's root zone,
+ * drawn down by the daily ET from the weather service and refilled by rain and irrigation
+ *
+ * This file is part of the OpenSprinkler library
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see
+ * <http://www.gnu.org/licenses/>.
+ */
+
+#include <stdlib.h>
+#include "OpenSprinkler.h"
+#include "utils.h"
+#include "budget.h"
+#include "main.h"
+
+/** Get the accounting state */
+void budget_get_state(BudgetState *st)
+{
+	memset(st, 0, sizeof(BudgetState));
+	file_read_block(BUDGET_FILENAME, st, 0, sizeof(BudgetState));
+}
+
+/** Get the budget of a station, all zero if it has none */
+void budget_get(byte sid, StationBudget *b)
+{
+	memset(b, 0, sizeof(StationBudget));
+	if (sid >= MAX_NUM_STATIONS)
+		return;
+	file_read_block(BUDGET_FILENAME, b, sizeof(BudgetState) + (ulong)sid * sizeof(StationBudget), sizeof(StationBudget));
+}
+
+/** Set the budget of a station */
+void budget_set(byte sid, StationBudget *b)
+{
+	if (sid >= MAX_NUM_STATIONS)
+		return;
+	if (!file_exists(BUDGET_FILENAME))
+	{
+		// the state and all stations start at zero
+		BudgetState st;
+		memset(&st, 0, sizeof(st));
+		file_write_block(BUDGET_FILENAME, &st, 0, sizeof(st));
+		StationBudget none;
+		memset(&none, 0, sizeof(none));
+		for (byte i = 0; i < MAX_NUM_STATIONS; i++)
+			file_write_block(BUDGET_FILENAME, &none, sizeof(BudgetState) + (ulong)i * sizeof(StationBudget), sizeof(none));
+	}
+	if (b->deficit > BUDGET_MAX_DEFICIT)
+		b->deficit = BUDGET_MAX_DEFICIT;
+	file_write_block(BUDGET_FILENAME, b, sizeof(BudgetState) + (ulong)sid * sizeof(StationBudget), sizeof(StationBudget));
+}
+
+/** Read a depth in inches from a field of the weather raw data, in 1/100 inch */
+static bool raw_depth(const char *raw, const char *field, uint16_t *v)
+{
+	const char *start, *end;
+	if (!json_field_find(raw, field, &start, &end))
+		return false;
+	float f = atof(start);
+	if (f < 0)
+		f = 0;
+	*v = (uint16_t)(f * 100 + 0.5f);
+	return true;
+}
+
+/** Account the ET and rain of a successful weather call
+ * The weather service reports the ET of the past day (eto) and its rainfall (p),
+ * so they are accounted once per day, with the first call that reports them.
+ */
+void budget_weather(const char *raw, ulong curr_time)
+{
+	if (!file_exists(BUDGET_FILENAME))
+		return;
+	BudgetState st;
+	budget_get_state(&st);
+	uint32_t day = curr_time / 86400L;
+	if (st.day == day)
+		return;
+	uint16_t eto = 0, rain = 0;
+	if (!raw_depth(raw, "eto", &eto))
+		return; // only the ETo adjustment method reports ET
+	raw_depth(raw, "p", &rain);
+
+	st.day = day;
+	st.eto = eto;
+	st.rain = rain;
+	file_write_block(BUDGET_FILENAME, &st, 0, sizeof(st));
+
+	StationBudget b;
+	for (byte sid = 0; sid < MAX_NUM_STATIONS; sid++)
+	{
+		budget_get(sid, &b);
+		if (!b.rate)
+			continue;
+		long deficit = (long)b.deficit + eto - rain;
+		b.deficit = (deficit < 0) ? 0 : deficit;
+		budget_set(sid, &b);
+	}
+}
+
+/** Account the water a station has applied in duration seconds */
+void budget_irrigated(byte sid, ulong duration)
+{
+	StationBudget b;
+	budget_get(sid, &b);
+	if (!b.rate)
+		return;
+	ulong applied = duration * b.rate / 3600;
+	b.deficit = (applied >= b.deficit) ? 0 : b.deficit - applied;
+	budget_set(sid, &b);
+}
+
+/** Set the water time of a station from its budget, water_time holds the program duration
+ * The run is skipped (0) until the deficit reaches the allowed depletion, then it refills
+ * the deficit, up to BUDGET_MAX_SCALE percent of the program duration.
+ * Returns false, leaving water_time unchanged, if the station does not use the budget.
+ */
+bool budget_water_time(byte sid, ulong *water_time)
+{
+	StationBudget b;
+	budget_get(sid, &b);
+	if (!b.rate)
+		return false;
+	ulong max_time = *water_time * BUDGET_MAX_SCALE / 100;
+	if (b.deficit < b.capacity || !b.deficit)
+		*water_time = 0;
+	else
+		*water_time = (ulong)b.deficit * 3600 / b.rate;
+	if (*water_time > max_time)
+		*water_time = max_time;
+	return true;
+}
@@ -0,0 +1,54 @@
+/* OpenSprinkler Unified (RPI/LINUX) Firmware
+ * Copyright (C) 2015 by Ray Wang (ray@opensprinkler.com)
+ *
+ * Station moisture budget (water balance) header file
+ *
+ * This file is part of the OpenSprinkler library
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see
+ * <http://www.gnu.org/licenses/>.
+ */
+
+#ifndef _BUDGET_H
+#define _BUDGET_H
+
+#define BUDGET_MAX_DEFICIT 1000 // largest deficit kept (in 1/100 inch)
+#define BUDGET_MAX_SCALE 250	// longest budget water time (in percent of the program duration)
+
+/** Accounting state, stored at the start of BUDGET_FILENAME */
+struct BudgetState
+{
+	uint32_t day;  // day (since epoch) whose ET has been accounted
+	uint16_t eto;  // ET of that day
+	uint16_t rain; // rainfall of that day
+};
+
+/** Moisture budget of a station, stored in BUDGET_FILENAME after BudgetState
+ * Depths are in 1/100 inch, as the weather service reports them.
+ */
+struct StationBudget
+{
+	uint16_t rate;	   // precipitation rate (per hour), 0 if the station does not use the budget
+	uint16_t capacity; // allowed depletion: runs are skipped until the deficit reaches it
+	uint16_t deficit;  // water missing in the root zone
+};
+
+void budget_get_state(BudgetState *st);
+void budget_get(byte sid, StationBudget *b);
+void budget_set(byte sid, StationBudget *b);
+void budget_weather(const char *raw, ulong curr_time);
+void budget_irrigated(byte sid, ulong duration);
+bool budget_water_time(byte sid, ulong *water_time);
+
+#endif // _BUDGET_H
@@ -0,0 +1,224 @@
+/* OpenSprinkler Unified (RPI/LINUX) Firmware
+ * Copyright (C) 2015 by Ray Wang (ray@opensprinkler.com)
+ *
+ * Calendar functions
+ * Feb 2015 @ OpenSprinkler.com
+ *
+ * This file is part of the OpenSprinkler library
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see
+ * <http://www.gnu.org/licenses/>.
+ */
+
+#include <stdlib.h>
+#include "OpenSprinkler.h"
+#include "utils.h"
+#include "opensprinkler_server.h"
+#include "calendar.h"
+#include "weather.h"
+#include "main.h"
+
+ulong cal_lasttime = 0;			// time of the last calendar fetch
+ulong cal_success_lasttime = 0; // time of the last successful calendar fetch
+int cal_errCode = HTTP_RQT_NOT_RECEIVED;
+byte cal_ndays = 0;							// number of days in cal_days
+static uint16_t cal_days[CALENDAR_MAX_DAYS]; // days (since 1970-01-01) with matching events
+static uint16_t cal_today;					// day the calendar was parsed on
+
+/** Days since 1970-01-01 of a civil date */
+static long days_from_civil(int y, int m, int d)
+{
+	y -= m <= 2;
+	long era = (y >= 0 ? y : y - 399) / 400;
+	long yoe = y - era * 400;
+	long doy = (153 * (m + (m > 2 ? -3 : 9)) + 2) / 5 + d - 1;
+	long doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+	return era * 146097 + doe - 719468;
+}
+
+/** Parse an iCal date value (YYYYMMDD or YYYYMMDDTHHMMSS), returns days since 1970-01-01 or -1
+ * end: the value is an exclusive end, so a date-time past midnight covers that day too */
+static long parse_ical_day(const char *v, bool end)
+{
+	for (byte i = 0; i < 8; i++)
+	{
+		if (v[i] < '0' || v[i] > '9')
+			return -1;
+	}
+	int y = (v[0] - '0') * 1000 + (v[1] - '0') * 100 + (v[2] - '0') * 10 + (v[3] - '0');
+	int m = (v[4] - '0') * 10 + (v[5] - '0');
+	int d = (v[6] - '0') * 10 + (v[7] - '0');
+	long day = days_from_civil(y, m, d);
+	if (end && v[8] == 'T' && strncmp(v + 9, "000000", 6) != 0)
+		day++;
+	return day;
+}
+
+/** Add the days [first, last) to the list of suppressed days */
+static void calendar_add_days(long first, long last)
+{
+	for (long day = first; day < last; day++)
+	{
+		if (day < cal_today || day >= cal_today + CALENDAR_MAX_DAYS)
+			continue;
+		byte i;
+		for (i = 0; i < cal_ndays; i++)
+		{
+			if (cal_days[i] == day)
+				break;
+		}
+		if (i == cal_ndays && cal_ndays < CALENDAR_MAX_DAYS)
+			cal_days[cal_ndays++] = (uint16_t)day;
+	}
+}
+
+/** Case-insensitive substring search */
+static bool contains_nocase(const char *s, const char *key)
+{
+	size_t n = strlen(key);
+	if (!n)
+		return false;
+	for (; *s; s++)
+	{
+		if (strncasecmp(s, key, n) == 0)
+			return true;
+	}
+	return false;
+}
+
+/** Parse the events of an iCal file
+ * Events whose summary contains the keyword suppress watering on the days they cover.
+ * Recurring events (RRULE) are only considered on their first occurrence.
+ */
+static void getcalendar_callback(char *buffer)
+{
+	peel_http_header(buffer);
+	if (strstr(buffer, "BEGIN:VCALENDAR") == NULL)
+	{
+		cal_errCode = HTTP_RQT_EMPTY_RETURN;
+		return;
+	}
+
+	char keyword[MAX_SOPTS_SIZE + 1];
+	keyword[0] = 0;
+	os.sopt_load(SOPT_CALENDAR_KEYWORD, keyword);
+	if (!keyword[0])
+		strcpy(keyword, CALENDAR_DEFAULT_KEYWORD);
+
+	cal_ndays = 0;
+	bool in_event = false, match = false;
+	long start = -1, end = -1;
+	char *line = buffer;
+	while (line && *line)
+	{
+		char *next = strchr(line, '\n');
+		if (next)
+		{
+			*next = 0;
+			next++;
+		}
+		char *cr = strchr(line, '\r');
+		if (cr)
+			*cr = 0;
+
+		if (strcmp(line, "BEGIN:VEVENT") == 0)
+		{
+			in_event = true;
+			match = false;
+			start = end = -1;
+		}
+		else if (in_event && strcmp(line, "END:VEVENT") == 0)
+		{
+			in_event = false;
+			if (match && start >= 0)
+				calendar_add_days(start, (end > start) ? end : start + 1);
+		}
+		else if (in_event)
+		{
+			// property values follow the first ':', parameters (e.g. ;VALUE=DATE) come before it
+			char *v = strchr(line, ':');
+			if (v)
+			{
+				v++;
+				if (strncmp(line, "SUMMARY", 7) == 0 && (line[7] == ':' || line[7] == ';'))
+					match = contains_nocase(v, keyword);
+				else if (strncmp(line, "DTSTART", 7) == 0 && (line[7] == ':' || line[7] == ';'))
+					start = parse_ical_day(v, false);
+				else if (strncmp(line, "DTEND", 5) == 0 && (line[5] == ':' || line[5] == ';'))
+					end = parse_ical_day(v, true);
+			}
+		}
+		line = next;
+	}
+	cal_errCode = 0;
+}
+
+/** Apply the result of a calendar request */
+static void apply_calendar(int8_t ret, char *response)
+{
+	cal_errCode = HTTP_RQT_NOT_RECEIVED;
+	if (ret != HTTP_RQT_SUCCESS)
+	{
+		cal_errCode = ret;
+		return;
+	}
+	getcalendar_callback(response);
+	if (cal_errCode == 0)
+		cal_success_lasttime = os.now_tz();
+}
+
+/** Start fetching the calendar given by the calendar url option (http only) on the weather worker
+ * Returns false if a request is already in progress or the worker cannot be started, true if it
+ * has been started or no calendar is set
+ */
+bool GetCalendar(ulong curr_time)
+{
+	char *url = tmp_buffer;
+	url[0] = 0;
+	os.sopt_load(SOPT_CALENDAR_URL, url);
+	if (!url[0])
+	{
+		cal_ndays = 0;
+		cal_errCode = HTTP_RQT_NOT_RECEIVED;
+		return true;
+	}
+	if (strncmp(url, "http://", 7) == 0)
+		url += 7;
+
+	// split host[:port] and path
+	char *path = strchr(url, '/');
+	strcpy(ether_buffer, "GET ");
+	strcat(ether_buffer, path ? path : "/");
+	if (path)
+		*path = 0;
+	os.http_request_head(ether_buffer, ETHER_BUFFER_SIZE, url, false);
+	strcat(ether_buffer, "\r\n");
+
+	if (!weather_worker_start(url, ether_buffer, 3000, apply_calendar))
+		return false;
+	cal_today = curr_time / 86400L;
+	return true;
+}
+
+/** Check if watering is suppressed by the calendar on the day of curr_time */
+bool calendar_skip_day(ulong curr_time)
+{
+	uint16_t day = curr_time / 86400L;
+	for (byte i = 0; i < cal_ndays; i++)
+	{
+		if (cal_days[i] == day)
+			return true;
+	}
+	return false;
+}
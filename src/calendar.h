@@ -0,0 +1,39 @@
+/* OpenSprinkler Unified (RPI/LINUX) Firmware
+ * Copyright (C) 2015 by Ray Wang (ray@opensprinkler.com)
+ *
+ * Calendar functions header file
+ * Feb 2015 @ OpenSprinkler.com
+ *
+ * This file is part of the OpenSprinkler library
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see
+ * <http://www.gnu.org/licenses/>.
+ */
+
+#ifndef _CALENDAR_H
+#define _CALENDAR_H
+
+#define CALENDAR_MAX_DAYS 32			  // number of upcoming days kept from the calendar
+#define CALENDAR_CHECK_INTERVAL 86400L	  // calendar fetch interval (in seconds)
+#define CALENDAR_RETRY_INTERVAL 3600L	  // calendar fetch interval after a failure (in seconds)
+#define CALENDAR_DEFAULT_KEYWORD "no watering" // event summary that suppresses watering
+
+bool GetCalendar(ulong curr_time);
+bool calendar_skip_day(ulong curr_time);
+
+extern ulong cal_lasttime;
+extern ulong cal_success_lasttime;
+extern int cal_errCode;
+extern byte cal_ndays;
+#endif // _CALENDAR_H
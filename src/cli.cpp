@@ -0,0 +1,721 @@
+/* OpenSprinkler Unified (RPI/LINUX) Firmware
+ * Copyright (C) 2015 by Ray Wang (ray@opensprinkler.com)
+ *
+ * Command line interface
+ * Feb 2015 @ OpenSprinkler.com
+ *
+ * This file is part of the OpenSprinkler library
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see
+ * <http://www.gnu.org/licenses/>.
+ */
+
+#include <sys/socket.h>
+#include <sys/un.h>
+#include <sys/stat.h>
+#include <pwd.h>
+#include <grp.h>
+#include <dirent.h>
+#include <ctype.h>
+
+#include "OpenSprinkler.h"
+#include "program.h"
+#include "opensprinkler_server.h"
+#include "cli.h"
+#include "main.h"
+
+/* Configuration keys use the same names as the json output of /jo and /jc:
+ *
+ *	 wl, sdt, ...					integer options
+ *	 loc, jsp, wsp, ifkey, ...		string options
+ *	 wto, mqtt, mqtls				object options (flat json objects)
+ *	 mqtt.host, wto.baseETo, ...	a single field of an object option
+ *	 stations.N.name				station name (N is station index, starting from 0)
+ *	 stations.N.dis, ...			station attribute bits (mas, igs, mas2, igs2, igrd, dis, seq)
+ *	 programs.N.name				program name (N is program index, starting from 0)
+ *	 programs.N.en, programs.N.uwt	program flag bits
+ *
+ * Passwords and WiFi credentials are deliberately not accessible.
+ */
+
+/** String options exposed through the command line */
+static const byte cli_sopt_ids[] = {SOPT_LOCATION, SOPT_JAVASCRIPTURL, SOPT_WEATHERURL, SOPT_IFTTT_KEY, SOPT_CORS_ORIGINS, SOPT_MQTT_TOPIC, SOPT_MQTT_EVENTS, SOPT_DEVICE_NAME, SOPT_WEATHER_OPTS, SOPT_MQTT_OPTS, SOPT_MQTT_TLS};
+static const char *cli_sopt_names[] = {"loc", "jsp", "wsp", "ifkey", "cors", "mqtpc", "mqev", "dname", "wto", "mqtt", "mqtls"};
+#define CLI_NUM_SOPTS sizeof(cli_sopt_ids)
+#define CLI_SOPT_FIRST_OBJECT 8 // string options from this index on are stored as flat json objects
+
+/** Station attribute names and the attribute bytes they map to */
+static const char *cli_attrib_names[] = {"mas", "igs", "mas2", "igs2", "igrd", "dis", "seq"};
+static byte *const cli_attrib_bytes[] = {os.attrib_mas, os.attrib_igs, os.attrib_mas2, os.attrib_igs2, os.attrib_igrd, os.attrib_dis, os.attrib_seq};
+#define CLI_NUM_ATTRIBS (sizeof(cli_attrib_bytes) / sizeof(byte *))
+
+typedef void (*CLIEmitter)(const char *key, const char *value);
+
+static const char *cli_filter = NULL; // key prefix for --list, exact key for --get
+static bool cli_found = false;
+
+static void usage(const char *prog)
+{
+	printf("Usage: %s --list [prefix]\n", prog);
+	printf("       %s --get <key>\n", prog);
+	printf("       %s --set <key> <value>\n", prog);
+	printf("       %s run-station <sid> --duration <seconds>\n", prog);
+	printf("       %s run-program <pid> [--use-weather]\n", prog);
+	printf("       %s stop-all\n", prog);
+	printf("       %s status\n", prog);
+	printf("       %s --install-service [user]\n", prog);
+}
+
+/** Check if an integer option can only be read */
+static bool iopt_readonly(byte oid)
+{
+	return (oid == IOPT_FW_VERSION || oid == IOPT_HW_VERSION || oid == IOPT_SEQUENTIAL_RETIRED ||
+			oid == IOPT_FW_MINOR || oid == IOPT_URS_RETIRED || oid == IOPT_RSO_RETIRED ||
+			oid == IOPT_WIFI_MODE || oid == IOPT_RESET || oid == IOPT_SETUP);
+}
+
+/** Check if an integer option stores a signed time value */
+static bool iopt_signed(byte oid)
+{
+	return (oid == IOPT_MASTER_OFF_ADJ || oid == IOPT_MASTER_OFF_ADJ_2 ||
+			oid == IOPT_MASTER_ON_ADJ || oid == IOPT_MASTER_ON_ADJ_2 ||
+			oid == IOPT_STATION_DELAY_TIME);
+}
+
+/** Check if an integer option stores a master adjusted time in minutes */
+static bool iopt_minutes(byte oid)
+{
+	return (oid >= IOPT_MASTER_ON_ADJ_MIN && oid <= IOPT_MASTER_OFF_ADJ_MIN_2);
+}
+
+/** Find integer option index by json name, returns NUM_IOPTS if not found */
+static byte iopt_find(const char *name)
+{
+	char tbuf[6];
+	for (byte oid = 0; oid < NUM_IOPTS; oid++)
+	{
+		strncpy_P0(tbuf, iopt_json_names + oid * 5, 5);
+		if (strcmp(tbuf, name) == 0)
+			return oid;
+	}
+	return NUM_IOPTS;
+}
+
+/** Check if a string is a (signed, optionally decimal) number */
+static bool is_number(const char *s)
+{
+	if (*s == '-')
+		s++;
+	if (!*s)
+		return false;
+	bool dot = false;
+	for (; *s; s++)
+	{
+		if (*s == '.' && !dot)
+			dot = true;
+		else if (*s < '0' || *s > '9')
+			return false;
+	}
+	return true;
+}
+
+/** Set the value of a field in a flat json object, adding the field if necessary */
+static bool json_field_set(char *obj, const char *field, const char *value)
+{
+	char out[MAX_SOPTS_SIZE * 2];
+	const char *start, *end;
+	bool quote;
+	if (json_field_find(obj, field, &start, &end))
+	{
+		quote = (*start == '"'); // keep the existing value type
+		snprintf(out, sizeof(out), "%.*s%s%s%s%s", (int)(start - obj), obj,
+				 quote ? "\"" : "", value, quote ? "\"" : "", end);
+	}
+	else
+	{
+		quote = !is_number(value);
+		snprintf(out, sizeof(out), "%s%s\"%s\":%s%s%s", obj, (*obj) ? "," : "", field,
+				 quote ? "\"" : "", value, quote ? "\"" : "");
+	}
+	if (strlen(out) >= MAX_SOPTS_SIZE)
+		return false;
+	strcpy(obj, out);
+	return true;
+}
+
+/** Emit one key/value pair if it matches the current filter */
+static void cli_emit(CLIEmitter emit, const char *key, const char *value)
+{
+	if (cli_filter && strncmp(key, cli_filter, strlen(cli_filter)) != 0)
+		return;
+	emit(key, value);
+}
+
+/** Go through every accessible configuration key */
+static void cli_visit(CLIEmitter emit)
+{
+	char key[64];
+	char value[MAX_SOPTS_SIZE + 1];
+
+	// integer options
+	for (byte oid = 0; oid < NUM_IOPTS; oid++)
+	{
+		int v = os.iopts[oid];
+		if (iopt_signed(oid))
+			v = water_time_decode_signed(v);
+		if (iopt_minutes(oid))
+			v -= MASTER_ADJ_MIN_ZERO;
+		if (oid == IOPT_BOOST_TIME)
+			v <<= 2;
+		strncpy_P0(key, iopt_json_names + oid * 5, 5);
+		snprintf(value, sizeof(value), "%d", v);
+		cli_emit(emit, key, value);
+	}
+
+	// string options and the fields of object options
+	for (byte i = 0; i < CLI_NUM_SOPTS; i++)
+	{
+		os.sopt_load(cli_sopt_ids[i], value);
+		cli_emit(emit, cli_sopt_names[i], value);
+		if (i < CLI_SOPT_FIRST_OBJECT)
+			continue;
+
+		char obj[MAX_SOPTS_SIZE + 1];
+		strcpy(obj, value);
+		const char *p = obj;
+		while ((p = strchr(p, '"')) != NULL)
+		{
+			const char *q = strchr(p + 1, '"');
+			if (!q || *(q + 1) != ':')
+				break;
+			const char *start, *end;
+			snprintf(key, sizeof(key), "%s.%.*s", cli_sopt_names[i], (int)(q - p - 1), p + 1);
+			snprintf(value, sizeof(value), "%.*s", (int)(q - p - 1), p + 1);
+			if (!json_field_find(obj, value, &start, &end))
+				break;
+			json_value_copy(value, start, end);
+			cli_emit(emit, key, value);
+			p = end;
+		}
+	}
+
+	// stations
+	for (byte sid = 0; sid < os.nstations; sid++)
+	{
+		byte bid = sid >> 3;
+		byte s = sid & 0x07;
+		snprintf(key, sizeof(key), "stations.%d.name", sid);
+		os.get_station_name(sid, value);
+		cli_emit(emit, key, value);
+		snprintf(key, sizeof(key), "stations.%d.type", sid);
+		snprintf(value, sizeof(value), "%d", os.get_station_type(sid));
+		cli_emit(emit, key, value);
+		for (byte a = 0; a < CLI_NUM_ATTRIBS; a++)
+		{
+			snprintf(key, sizeof(key), "stations.%d.%s", sid, cli_attrib_names[a]);
+			snprintf(value, sizeof(value), "%d", (cli_attrib_bytes[a][bid] >> s) & 1);
+			cli_emit(emit, key, value);
+		}
+	}
+
+	// programs
+	ProgramStruct prog;
+	for (byte pid = 0; pid < pd.nprograms; pid++)
+	{
+		pd.read(pid, &prog);
+		snprintf(key, sizeof(key), "programs.%d.name", pid);
+		snprintf(value, sizeof(value), "%.*s", PROGRAM_NAME_SIZE, prog.name);
+		cli_emit(emit, key, value);
+		snprintf(key, sizeof(key), "programs.%d.en", pid);
+		snprintf(value, sizeof(value), "%d", prog.enabled);
+		cli_emit(emit, key, value);
+		snprintf(key, sizeof(key), "programs.%d.uwt", pid);
+		snprintf(value, sizeof(value), "%d", prog.use_weather);
+		cli_emit(emit, key, value);
+	}
+}
+
+static void print_keyval(const char *key, const char *value)
+{
+	printf("%s=%s\n", key, value);
+}
+
+static void print_exact(const char *key, const char *value)
+{
+	if (strcmp(key, cli_filter) != 0)
+		return;
+	printf("%s\n", value);
+	cli_found = true;
+}
+
+/** Parse "<group>.<index>.<field>" keys, returns the field name or NULL */
+static const char *parse_indexed_key(const char *key, const char *group, int *index)
+{
+	size_t glen = strlen(group);
+	if (strncmp(key, group, glen) != 0 || key[glen] != '.')
+		return NULL;
+	char *p;
+	*index = strtol(key + glen + 1, &p, 10);
+	if (p == key + glen + 1 || *p != '.')
+		return NULL;
+	return p + 1;
+}
+
+/** Parse a 0/1 flag value */
+static int parse_flag(const char *value)
+{
+	if (strcmp(value, "0") == 0)
+		return 0;
+	if (strcmp(value, "1") == 0)
+		return 1;
+	return -1;
+}
+
+static int cli_set_station(int sid, const char *field, const char *value)
+{
+	if (sid < 0 || sid >= os.nstations)
+		return CLI_ERR_KEY;
+	if (strcmp(field, "name") == 0)
+	{
+		// same rules as /cs
+		if (strlen(value) > STATION_NAME_SIZE || !utf8_valid(value) || strpbrk(value, "\"\\"))
+			return CLI_ERR_VALUE;
+		char name[STATION_NAME_SIZE + 1];
+		strncpy(name, value, STATION_NAME_SIZE);
+		name[STATION_NAME_SIZE] = 0;
+		os.set_station_name(sid, name);
+		return CLI_OK;
+	}
+	for (byte a = 0; a < CLI_NUM_ATTRIBS; a++)
+	{
+		if (strcmp(field, cli_attrib_names[a]) == 0)
+		{
+			int v = parse_flag(value);
+			if (v < 0)
+				return CLI_ERR_VALUE;
+			byte mask = 1 << (sid & 0x07);
+			if (v)
+				cli_attrib_bytes[a][sid >> 3] |= mask;
+			else
+				cli_attrib_bytes[a][sid >> 3] &= ~mask;
+			os.attribs_save();
+			return CLI_OK;
+		}
+	}
+	return CLI_ERR_KEY;
+}
+
+static int cli_set_program(int pid, const char *field, const char *value)
+{
+	if (pid < 0 || pid >= pd.nprograms)
+		return CLI_ERR_KEY;
+	if (strcmp(field, "name") == 0)
+	{
+		ProgramStruct prog;
+		pd.read(pid, &prog);
+		memset(prog.name, 0, PROGRAM_NAME_SIZE);
+		strncpy(prog.name, value, PROGRAM_NAME_SIZE);
+		pd.modify(pid, &prog);
+		return CLI_OK;
+	}
+	byte bid;
+	if (strcmp(field, "en") == 0)
+		bid = PROGRAMSTRUCT_EN_BIT;
+	else if (strcmp(field, "uwt") == 0)
+		bid = PROGRAMSTRUCT_UWT_BIT;
+	else
+		return CLI_ERR_KEY;
+	int v = parse_flag(value);
+	if (v < 0)
+		return CLI_ERR_VALUE;
+	pd.set_flagbit(pid, bid, v);
+	return CLI_OK;
+}
+
+static int cli_set(const char *key, const char *value)
+{
+	int index;
+	const char *field;
+
+	if ((field = parse_indexed_key(key, "stations", &index)) != NULL)
+		return cli_set_station(index, field, value);
+	if ((field = parse_indexed_key(key, "programs", &index)) != NULL)
+		return cli_set_program(index, field, value);
+
+	// string and object options
+	for (byte i = 0; i < CLI_NUM_SOPTS; i++)
+	{
+		size_t nlen = strlen(cli_sopt_names[i]);
+		if (strncmp(key, cli_sopt_names[i], nlen) != 0)
+			continue;
+
+		char buf[MAX_SOPTS_SIZE * 2];
+		if (key[nlen] == 0)
+		{
+			size_t vlen = strlen(value);
+			// object options also accept json input, i.e. {"host":"x","port":1883}
+			if (i >= CLI_SOPT_FIRST_OBJECT && vlen >= 2 && value[0] == '{' && value[vlen - 1] == '}')
+			{
+				value++;
+				vlen -= 2;
+			}
+			if (vlen >= MAX_SOPTS_SIZE)
+				return CLI_ERR_VALUE;
+			strncpy(buf, value, vlen);
+			buf[vlen] = 0;
+		}
+		else if (key[nlen] == '.' && i >= CLI_SOPT_FIRST_OBJECT)
+		{
+			os.sopt_load(cli_sopt_ids[i], buf);
+			if (!json_field_set(buf, key + nlen + 1, value))
+				return CLI_ERR_VALUE;
+		}
+		else
+		{
+			continue;
+		}
+		os.sopt_save(cli_sopt_ids[i], buf);
+		return CLI_OK;
+	}
+
+	// integer options
+	byte oid = iopt_find(key);
+	if (oid == NUM_IOPTS || iopt_readonly(oid))
+		return CLI_ERR_KEY;
+	if (!is_number(value))
+		return CLI_ERR_VALUE;
+	int32_t v = atol(value);
+	if (iopt_signed(oid))
+		v = water_time_encode_signed(v);
+	if (iopt_minutes(oid))
+		v += MASTER_ADJ_MIN_ZERO;
+	if (oid == IOPT_BOOST_TIME)
+		v >>= 2;
+	if (v < 0 || v > pgm_read_byte(iopt_max + oid))
+		return CLI_ERR_VALUE;
+	os.iopts[oid] = v;
+	os.iopts_save();
+	return CLI_OK;
+}
+
+/** Send a JSON-RPC request to the control socket of the running controller
+ * Returns the length of the response, -1 if the controller is not running.
+ */
+static int cli_socket_request(const char *req, char *resp, int maxlen)
+{
+	struct sockaddr_un addr;
+	memset(&addr, 0, sizeof(addr));
+	addr.sun_family = AF_UNIX;
+	strncpy(addr.sun_path, get_filename_fullpath(CONTROL_SOCKET_FILENAME), sizeof(addr.sun_path) - 1);
+
+	int sock = socket(AF_UNIX, SOCK_STREAM, 0);
+	if (sock < 0)
+		return -1;
+	int len = -1, n;
+	if (connect(sock, (struct sockaddr *)&addr, sizeof(addr)) == 0)
+	{
+		len = 0;
+		if (write(sock, req, strlen(req)) > 0)
+		{
+			while (len < maxlen - 1 && (n = read(sock, resp + len, maxlen - 1 - len)) > 0)
+				len += n;
+		}
+		resp[len] = 0;
+	}
+	close(sock);
+	return len;
+}
+
+/** Send a command to the controller running on this machine
+ * The control socket needs no password, so this also works with a hashed password.
+ * params are web API parameters (&key1=val1&key2=val2...)
+ */
+static int cli_request(const char *cmd, const char *params)
+{
+	char req[TMP_BUFFER_SIZE];
+	int n = snprintf(req, sizeof(req), "{\"jsonrpc\":\"2.0\",\"id\":1,\"method\":\"%s\",\"params\":{", cmd);
+	// convert the parameters to a json object of strings
+	char buf[64];
+	strncpy(buf, params, sizeof(buf) - 1);
+	buf[sizeof(buf) - 1] = 0;
+	bool first = true;
+	for (char *kv = strtok(buf, "&"); kv; kv = strtok(NULL, "&"))
+	{
+		char *v = strchr(kv, '=');
+		if (!v)
+			continue;
+		*v++ = 0;
+		n += snprintf(req + n, sizeof(req) - n, "%s\"%s\":\"%s\"", first ? "" : ",", kv, v);
+		first = false;
+	}
+	snprintf(req + n, sizeof(req) - n, "}}\n");
+
+	static char resp[ETHER_BUFFER_SIZE];
+	if (cli_socket_request(req, resp, sizeof(resp)) < 0)
+	{
+		fprintf(stderr, "Cannot reach the controller through %s\n", get_filename_fullpath(CONTROL_SOCKET_FILENAME));
+		return CLI_ERR_REQUEST;
+	}
+	// print the handler output, i.e. the result member of the response
+	char *r = strstr(resp, "\"result\":");
+	if (!r)
+	{
+		fprintf(stderr, "%s", resp);
+		return CLI_ERR_REQUEST;
+	}
+	r += 9;
+	char *e = strrchr(r, '}');
+	if (e)
+		*e = 0;
+	printf("%s\n", r);
+	// json outputs (e.g. /jc) do not carry a result field
+	r = strstr(r, "\"result\":");
+	int result = r ? atoi(r + 9) : HTML_SUCCESS;
+	return (result == HTML_SUCCESS) ? CLI_OK : CLI_ERR_REQUEST;
+}
+
+/** Handle commands for the running controller, returns -1 if argv is not such a command */
+static int cli_command(int argc, char *argv[])
+{
+	const char *cmd = argv[1];
+	char params[64];
+
+	if (strcmp(cmd, "run-station") == 0 && argc == 5 && strcmp(argv[3], "--duration") == 0)
+	{
+		snprintf(params, sizeof(params), "&sid=%d&en=1&t=%ld", atoi(argv[2]), atol(argv[4]));
+		return cli_request("cm", params);
+	}
+	if (strcmp(cmd, "run-program") == 0 && (argc == 3 || (argc == 4 && strcmp(argv[3], "--use-weather") == 0)))
+	{
+		snprintf(params, sizeof(params), "&pid=%d&uwt=%d", atoi(argv[2]), (argc == 4) ? 1 : 0);
+		return cli_request("mp", params);
+	}
+	if (strcmp(cmd, "stop-all") == 0 && argc == 2)
+	{
+		return cli_request("cv", "&rsn=1");
+	}
+	if (strcmp(cmd, "status") == 0 && argc == 2)
+	{
+		return cli_request("jc", "");
+	}
+	return -1;
+}
+
+/** Ask a running controller to reload its configuration through the control socket
+ * Nothing is done if the controller is not running.
+ */
+static void cli_notify_reload()
+{
+	const char *req = "{\"jsonrpc\":\"2.0\",\"id\":1,\"method\":\"rl\"}\n";
+	char resp[128];
+	if (cli_socket_request(req, resp, sizeof(resp)) > 0 && strstr(resp, "\"restart\":1"))
+		printf("Restart the controller to apply this change\n");
+}
+
+/** Give a folder and the files in it to the service user, creating the folder if needed */
+static void cli_chown_folder(const char *path, uid_t uid, gid_t gid)
+{
+	mkdir(path, 0755);
+	chown(path, uid, gid);
+	DIR *dir = opendir(path);
+	if (!dir)
+		return;
+	struct dirent *entry;
+	char name[PATH_MAX];
+	while ((entry = readdir(dir)) != NULL)
+	{
+		if (entry->d_name[0] == '.')
+			continue;
+		snprintf(name, sizeof(name), "%s%s", path, entry->d_name);
+		chown(name, uid, gid);
+	}
+	closedir(dir);
+}
+
+/** Check that a user name is a plain system user name, [a-z_][a-z0-9_-]* of at most 32 characters
+ * The name is passed to useradd through the shell and written to the unit file.
+ */
+static bool cli_valid_user(const char *user)
+{
+	size_t n = strlen(user);
+	if (n == 0 || n > 32)
+		return false;
+	if (!(islower(user[0]) || user[0] == '_'))
+		return false;
+	for (size_t i = 1; i < n; i++)
+	{
+		char c = user[i];
+		if (!(islower(c) || isdigit(c) || c == '_' || c == '-'))
+			return false;
+	}
+	return true;
+}
+
+/** Install a systemd service running the controller from the folder of this executable
+ * The service runs as an unprivileged user, created if needed, with only the capabilities the
+ * controller uses: rebooting from the web UI, real-time priority of the RF transmitter and,
+ * if the web server port is below 1024, binding it. The data and log folders are given to the user.
+ */
+static int cli_install_service(const char *user)
+{
+	if (geteuid() != 0)
+	{
+		fprintf(stderr, "Installing the service needs root\n");
+		return CLI_ERR_SYSTEM;
+	}
+	if (!cli_valid_user(user))
+	{
+		fprintf(stderr, "Invalid user name: %s\n", user);
+		return CLI_ERR_VALUE;
+	}
+	if (!getpwnam(user))
+	{
+		char cmd[128];
+		snprintf(cmd, sizeof(cmd), "useradd --system --no-create-home --shell /usr/sbin/nologin %s", user);
+		if (system(cmd) != 0)
+		{
+			fprintf(stderr, "Cannot create the user %s\n", user);
+			return CLI_ERR_SYSTEM;
+		}
+	}
+	struct passwd *pw = getpwnam(user);
+	if (!pw)
+	{
+		fprintf(stderr, "Cannot find the user %s\n", user);
+		return CLI_ERR_SYSTEM;
+	}
+
+	const char *path = get_runtime_path();
+	if (!path)
+	{
+		fprintf(stderr, "Cannot find the folder of the executable\n");
+		return CLI_ERR_SYSTEM;
+	}
+	chown(path, pw->pw_uid, pw->pw_gid);
+	const char *folders[] = {"data/", LOG_PREFIX + 2, AUDIT_PREFIX + 2, WEATHERLOG_PREFIX + 2};
+	char folder[PATH_MAX];
+	for (byte i = 0; i < sizeof(folders) / sizeof(folders[0]); i++)
+	{
+		snprintf(folder, sizeof(folder), "%s%s", path, folders[i]);
+		cli_chown_folder(folder, pw->pw_uid, pw->pw_gid);
+	}
+
+	if (file_exists(IOPTS_FILENAME))
+		os.iopts_load();
+	bool low_port = os.get_http_port() < 1024;
+	bool gpio_group = getgrnam("gpio") != NULL;
+
+	FILE *fp = fopen(SERVICE_UNIT_FILE, "w");
+	if (!fp)
+	{
+		fprintf(stderr, "Cannot write %s\n", SERVICE_UNIT_FILE);
+		return CLI_ERR_SYSTEM;
+	}
+	fprintf(fp, "[Unit]\nDescription=OpenSprinkler Controller\nWants=network-online.target\nAfter=network-online.target\n\n");
+	fprintf(fp, "[Service]\nType=notify\nUser=%s\n", user);
+	if (gpio_group)
+		fprintf(fp, "SupplementaryGroups=gpio\n");
+	char exe[PATH_MAX];
+	if (!realpath("/proc/self/exe", exe))
+		snprintf(exe, sizeof(exe), "%sOpenSprinkler", path);
+	fprintf(fp, "ExecStart=/usr/bin/stdbuf -oL %s\nWorkingDirectory=%s\n", exe, path);
+	fprintf(fp, "Restart=always\nRestartSec=10\nWatchdogSec=%d\n", SERVICE_WATCHDOG_SEC);
+	fprintf(fp, "# reboot from the web UI, real-time priority of the RF transmitter%s\n", low_port ? ", web server port below 1024" : "");
+	const char *caps = low_port ? "CAP_SYS_BOOT CAP_SYS_NICE CAP_NET_BIND_SERVICE" : "CAP_SYS_BOOT CAP_SYS_NICE";
+	fprintf(fp, "AmbientCapabilities=%s\nCapabilityBoundingSet=%s\n", caps, caps);
+	fprintf(fp, "NoNewPrivileges=yes\nProtectSystem=strict\nReadWritePaths=%s\nProtectHome=yes\nPrivateTmp=yes\n", path);
+	fprintf(fp, "ProtectKernelModules=yes\nProtectControlGroups=yes\nRestrictAddressFamilies=AF_UNIX AF_INET AF_INET6 AF_NETLINK\n");
+	fprintf(fp, "# on boards without sysfs gpio, or to use another gpiochip, uncomment and adjust\n");
+	fprintf(fp, "#Environment=OS_GPIO_BACKEND=chardev OS_GPIO_CHIP=/dev/gpiochip0\n\n");
+	fprintf(fp, "[Install]\nWantedBy=multi-user.target\n");
+	fclose(fp);
+
+	printf("Installed %s, running %s as %s\n", SERVICE_UNIT_FILE, exe, user);
+	if (system("systemctl daemon-reload") != 0)
+		fprintf(stderr, "Run systemctl daemon-reload to load the service\n");
+	printf("Start it with: systemctl enable --now opensprinkler\n");
+	return CLI_OK;
+}
+
+/** Command line entry
+ * Configuration is read from and written to the data files directly,
+ * hardware pins are not touched. Run and status commands are sent
+ * to the controller running on this machine through its control socket.
+ */
+int cli_main(int argc, char *argv[])
+{
+	const char *cmd = argv[1];
+	bool command = (strcmp(cmd, "run-station") == 0 || strcmp(cmd, "run-program") == 0 ||
+					strcmp(cmd, "stop-all") == 0 || strcmp(cmd, "status") == 0);
+	bool list = (strcmp(cmd, "--list") == 0 && argc <= 3);
+	bool get = (strcmp(cmd, "--get") == 0 && argc == 3);
+	bool set = (strcmp(cmd, "--set") == 0 && argc == 4);
+	if (strcmp(cmd, "--install-service") == 0 && argc <= 3)
+		return cli_install_service((argc == 3) ? argv[2] : SERVICE_USER);
+	if (!command && !list && !get && !set)
+	{
+		usage(argv[0]);
+		return CLI_ERR_USAGE;
+	}
+
+	setup_data_path(); // the data files of the controller may not be next to the executable
+	if (!file_exists(DONE_FILENAME))
+	{
+		fprintf(stderr, "No configuration found in %s\n", get_filename_fullpath("data/"));
+		return CLI_ERR_USAGE;
+	}
+	os.iopts_load();
+	os.attribs_load();
+	pd.init();
+
+	if (command)
+	{
+		initialiseEpoch(); // request timeouts rely on millis()
+		int ret = cli_command(argc, argv);
+		if (ret < 0)
+		{
+			usage(argv[0]);
+			return CLI_ERR_USAGE;
+		}
+		return ret;
+	}
+
+	if (list)
+	{
+		cli_filter = (argc == 3) ? argv[2] : NULL;
+		cli_visit(print_keyval);
+		return CLI_OK;
+	}
+
+	if (get)
+	{
+		cli_filter = argv[2];
+		cli_visit(print_exact);
+		if (!cli_found)
+		{
+			fprintf(stderr, "Unknown key: %s\n", argv[2]);
+			return CLI_ERR_KEY;
+		}
+		return CLI_OK;
+	}
+
+	int ret = cli_set(argv[2], argv[3]);
+	if (ret == CLI_OK)
+		cli_notify_reload();
+	else if (ret == CLI_ERR_KEY)
+		fprintf(stderr, "Unknown or read-only key: %s\n", argv[2]);
+	else if (ret == CLI_ERR_VALUE)
+		fprintf(stderr, "Invalid value for %s: %s\n", argv[2], argv[3]);
+	return ret;
+}
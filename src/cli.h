@@ -0,0 +1,40 @@
+/* OpenSprinkler Unified (RPI/LINUX) Firmware
+ * Copyright (C) 2015 by Ray Wang (ray@opensprinkler.com)
+ *
+ * Command line interface header file
+ * Feb 2015 @ OpenSprinkler.com
+ *
+ * This file is part of the OpenSprinkler library
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see
+ * <http://www.gnu.org/licenses/>.
+ */
+
+#ifndef _CLI_H
+#define _CLI_H
+
+#define CLI_OK 0
+#define CLI_ERR_USAGE 1
+#define CLI_ERR_KEY 2
+#define CLI_ERR_VALUE 3
+#define CLI_ERR_REQUEST 4
+#define CLI_ERR_SYSTEM 5
+
+#define SERVICE_UNIT_FILE "/etc/systemd/system/opensprinkler.service"
+#define SERVICE_USER "opensprinkler" // user the service runs as, unless another one is given
+#define SERVICE_WATCHDOG_SEC 60		 // the service is restarted if the main loop stalls this long
+
+int cli_main(int argc, char *argv[]); // run a command line request and return the process exit code
+
+#endif // _CLI_H
@@ -0,0 +1,209 @@
+/* OpenSprinkler Unified (RPI/LINUX) Firmware
+ * Copyright (C) 2015 by Ray Wang (ray@opensprinkler.com)
+ *
+ * Local control socket
+ * Feb 2015 @ OpenSprinkler.com
+ *
+ * This file is part of the OpenSprinkler library
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see
+ * <http://www.gnu.org/licenses/>.
+ */
+
+#include <sys/socket.h>
+#include <sys/stat.h>
+#include <sys/un.h>
+#include <unistd.h>
+
+#include "OpenSprinkler.h"
+#include "opensprinkler_server.h"
+#include "control.h"
+
+int OSControl::_sock = -1;
+
+
+/** Find the value of a key in a json object, returns NULL if not found */
+static char *json_find(char *json, const char *key)
+{
+	size_t klen = strlen(key);
+	for (char *p = json; (p = strchr(p, '"')) != NULL; p++)
+	{
+		if (strncmp(p + 1, key, klen) == 0 && p[klen + 1] == '"')
+		{
+			p += klen + 2;
+			while (*p == ' ' || *p == ':')
+				p++;
+			return p;
+		}
+	}
+	return NULL;
+}
+
+/** Copy a json value (string quotes removed) into buf, returns pointer right after the value */
+static char *json_copy(char *p, char *buf, size_t maxlen)
+{
+	size_t i = 0;
+	if (*p == '"')
+	{
+		for (p++; *p && *p != '"'; p++)
+		{
+			if (*p == '\\' && *(p + 1))
+				p++;
+			if (i < maxlen - 1)
+				buf[i++] = *p;
+		}
+		if (*p)
+			p++;
+	}
+	else
+	{
+		for (; *p && *p != ',' && *p != '}' && *p != ' '; p++)
+		{
+			if (i < maxlen - 1)
+				buf[i++] = *p;
+		}
+	}
+	buf[i] = 0;
+	return p;
+}
+
+/** Convert a flat json object into web API parameters (key1=val1&key2=val2...) */
+static void json_to_params(char *p, char *buf, size_t maxlen)
+{
+	size_t len = 0;
+	buf[0] = 0;
+	if (*p != '{')
+		return;
+	p++;
+	while (*p && *p != '}')
+	{
+		if (*p != '"')
+		{
+			p++;
+			continue;
+		}
+		char key[32], value[CONTROL_REQUEST_SIZE];
+		p = json_copy(p, key, sizeof(key));
+		while (*p == ' ' || *p == ':')
+			p++;
+		p = json_copy(p, value, sizeof(value));
+		len += snprintf(buf + len, (len < maxlen) ? maxlen - len : 0, "%s%s=%s", len ? "&" : "", key, value);
+	}
+}
+
+/** Create the control socket in the data folder */
+void OSControl::begin(void)
+{
+	const char *path = get_filename_fullpath(CONTROL_SOCKET_FILENAME);
+	struct sockaddr_un addr;
+	memset(&addr, 0, sizeof(addr));
+	addr.sun_family = AF_UNIX;
+	strncpy(addr.sun_path, path, sizeof(addr.sun_path) - 1);
+
+	if ((_sock = socket(AF_UNIX, SOCK_STREAM, 0)) < 0)
+	{
+		DEBUG_PRINTLN("can't create control socket");
+		return;
+	}
+	unlink(path); // remove the socket left over from a previous run
+	if (bind(_sock, (struct sockaddr *)&addr, sizeof(addr)) < 0 || listen(_sock, 2) < 0)
+	{
+		DEBUG_PRINTLN("control socket bind error");
+		close(_sock);
+		_sock = -1;
+		return;
+	}
+	// only the owner and group may issue commands
+	chmod(path, 0660);
+}
+
+/** Serve one pending request, if any */
+void OSControl::loop(void)
+{
+	if (_sock < 0)
+		return;
+
+	fd_set sock_set;
+	FD_ZERO(&sock_set);
+	FD_SET(_sock, &sock_set);
+	struct timeval timeout = {0, 0};
+
+	if (select(_sock + 1, &sock_set, NULL, NULL, &timeout) > 0 && FD_ISSET(_sock, &sock_set))
+	{
+		int client_sock = accept(_sock, NULL, NULL);
+		if (client_sock > 0)
+			_handle(client_sock);
+	}
+}
+
+void OSControl::_handle(int sock)
+{
+	EthernetClient client(sock);
+	static char request[CONTROL_REQUEST_SIZE];
+	static char params[CONTROL_REQUEST_SIZE];
+	char method[4], id[24];
+
+	// read until the end of the request line
+	int len = 0;
+	while (len < CONTROL_REQUEST_SIZE - 1)
+	{
+		int n = client.read((uint8_t *)request + len, CONTROL_REQUEST_SIZE - 1 - len);
+		if (n <= 0)
+			break;
+		len += n;
+		request[len] = 0;
+		if (strchr(request, '\n'))
+			break;
+	}
+	request[len] = 0;
+
+	// the id is echoed back as is, strings keep their quotes
+	strcpy(id, "null");
+	char *p = json_find(request, "id");
+	if (p && *p == '"')
+	{
+		char *e = strchr(p + 1, '"');
+		if (e && (size_t)(e - p + 1) < sizeof(id))
+		{
+			strncpy(id, p, e - p + 1);
+			id[e - p + 1] = 0;
+		}
+	}
+	else if (p && *p >= '0' && *p <= '9')
+	{
+		size_t n = strspn(p, "0123456789");
+		if (n < sizeof(id))
+		{
+			strncpy(id, p, n);
+			id[n] = 0;
+		}
+	}
+
+	p = json_find(request, "method");
+	if (!p || *p != '"')
+	{
+		const char *err = "{\"jsonrpc\":\"2.0\",\"id\":null,\"error\":{\"code\":-32700,\"message\":\"Parse error\"}}\n";
+		client.write((const uint8_t *)err, strlen(err));
+		return;
+	}
+	json_copy(p, method, sizeof(method));
+
+	p = json_find(request, "params");
+	if (p)
+		json_to_params(p, params, sizeof(params));
+	else
+		params[0] = 0;
+
+	handle_local_request(&client, method, params, id);
+}
@@ -0,0 +1,47 @@
+/* OpenSprinkler Unified (RPI/LINUX) Firmware
+ * Copyright (C) 2015 by Ray Wang (ray@opensprinkler.com)
+ *
+ * Local control socket header file
+ * Feb 2015 @ OpenSprinkler.com
+ *
+ * This file is part of the OpenSprinkler library
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see
+ * <http://www.gnu.org/licenses/>.
+ */
+
+#ifndef _CONTROL_H
+#define _CONTROL_H
+
+#define CONTROL_REQUEST_SIZE 1024 // maximum size of a control socket request
+
+/** Local control socket
+ * Accepts JSON-RPC requests over a unix domain socket, e.g.
+ *   {"jsonrpc":"2.0","id":1,"method":"cm","params":{"sid":0,"en":1,"t":60}}
+ * Method names and parameters are the same as the web API commands.
+ * No password is needed, access is controlled by the socket file mode.
+ */
+class OSControl
+{
+private:
+	static int _sock;
+
+	static void _handle(int sock);
+
+public:
+	static void begin(void);
+	static void loop(void);
+};
+
+#endif // _CONTROL_H
@@ -0,0 +1,203 @@
+/* OpenSprinkler Unified (RPI/LINUX) Firmware
+ * Copyright (C) 2015 by Ray Wang (ray@opensprinkler.com)
+ *
+ * Debug log: debug output filtered by the level of the module (source file) it comes from,
+ * with the recent lines kept in memory so they can be read through the web API
+ *
+ * This file is part of the OpenSprinkler library
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see
+ * <http://www.gnu.org/licenses/>.
+ */
+
+#include <stdarg.h>
+#include <pthread.h>
+#include "OpenSprinkler.h"
+#include "debuglog.h"
+
+/** Modules are the source files that print debug output */
+static const char *debug_modules[] = {
+	"main",
+	"OpenSprinkler",
+	"opensprinkler_server",
+	"weather",
+	"mqtt",
+	"etherport",
+	"gpio",
+	"control",
+	"display",
+	"rf"};
+#define NUM_DEBUG_MODULES (sizeof(debug_modules) / sizeof(debug_modules[0]))
+
+// all output is shown until the levels are changed, they are not saved
+static byte debug_levels[NUM_DEBUG_MODULES] = {
+	DEBUG_LEVEL_TRACE, DEBUG_LEVEL_TRACE, DEBUG_LEVEL_TRACE, DEBUG_LEVEL_TRACE, DEBUG_LEVEL_TRACE,
+	DEBUG_LEVEL_TRACE, DEBUG_LEVEL_TRACE, DEBUG_LEVEL_TRACE, DEBUG_LEVEL_TRACE, DEBUG_LEVEL_TRACE};
+
+static DebugLine debug_lines[DEBUG_LOG_LINES];
+static byte debug_lines_head = 0; // index of the oldest line
+static byte debug_lines_len = 0;
+static char debug_pending[NUM_DEBUG_MODULES][DEBUG_LINE_SIZE]; // line being printed by each module
+static pthread_mutex_t debug_mutex = PTHREAD_MUTEX_INITIALIZER;
+static __thread ulong debug_request = 0; // web request being handled by this thread, 0 for none
+
+byte debug_num_modules()
+{
+	return NUM_DEBUG_MODULES;
+}
+
+const char *debug_module_name(byte mid)
+{
+	return (mid < NUM_DEBUG_MODULES) ? debug_modules[mid] : "";
+}
+
+/** Find a module by name, returns -1 if there is no such module */
+int debug_find_module(const char *name)
+{
+	for (byte i = 0; i < NUM_DEBUG_MODULES; i++)
+	{
+		if (!strcmp(name, debug_modules[i]))
+			return i;
+	}
+	return -1;
+}
+
+/** Find the module of a source file, files without a module of their own print as main */
+static byte debug_file_module(const char *file)
+{
+	const char *base = strrchr(file, '/');
+	base = base ? base + 1 : file;
+	char name[DEBUG_MODULE_SIZE];
+	strncpy(name, base, sizeof(name) - 1);
+	name[sizeof(name) - 1] = 0;
+	char *ext = strrchr(name, '.');
+	if (ext)
+		*ext = 0;
+	int mid = debug_find_module(name);
+	return (mid < 0) ? 0 : mid;
+}
+
+byte debug_get_level(byte mid)
+{
+	return (mid < NUM_DEBUG_MODULES) ? debug_levels[mid] : DEBUG_LEVEL_OFF;
+}
+
+void debug_set_level(byte mid, byte level)
+{
+	if (mid < NUM_DEBUG_MODULES)
+		debug_levels[mid] = (level > DEBUG_LEVEL_TRACE) ? DEBUG_LEVEL_TRACE : level;
+}
+
+bool debug_enabled(const char *file, int level)
+{
+	return debug_levels[debug_file_module(file)] >= level;
+}
+
+/** Keep a completed line, quotes and backslashes are replaced so it can be sent as a json string */
+static void debug_keep_line(byte mid, byte level, char *text)
+{
+	for (char *c = text; *c; c++)
+	{
+		if (*c == '"')
+			*c = '\'';
+		else if (*c == '\\')
+			*c = '/';
+		else if ((unsigned char)*c < ' ')
+			*c = ' ';
+	}
+	DebugLine *l;
+	if (debug_lines_len < DEBUG_LOG_LINES)
+		l = &debug_lines[(debug_lines_head + debug_lines_len++) % DEBUG_LOG_LINES];
+	else
+	{ // drop the oldest line
+		l = &debug_lines[debug_lines_head];
+		debug_lines_head = (debug_lines_head + 1) % DEBUG_LOG_LINES;
+	}
+	l->time = OpenSprinkler::now_tz();
+	l->module = mid;
+	l->level = level;
+	strncpy(l->text, text, DEBUG_LINE_SIZE - 1);
+	l->text[DEBUG_LINE_SIZE - 1] = 0;
+}
+
+/** Print debug output, unless the level of its module is lower
+ * Output is kept in memory one line at a time, carriage returns are left out.
+ */
+void debug_write(const char *file, int level, const char *s)
+{
+	byte mid = debug_file_module(file);
+	if (debug_levels[mid] < level)
+		return;
+	printf("%s", s);
+
+	pthread_mutex_lock(&debug_mutex);
+	char *line = debug_pending[mid];
+	size_t len = strlen(line);
+	if (len == 0 && debug_request)
+	{ // lines printed while handling a request are tagged with its id
+		len = snprintf(line, DEBUG_LINE_SIZE, "[r%lu] ", debug_request);
+	}
+	for (; *s; s++)
+	{
+		if (*s == '\r')
+			continue;
+		if (*s == '\n')
+		{
+			debug_keep_line(mid, level, line);
+			line[0] = 0;
+			len = 0;
+		}
+		else if (len < DEBUG_LINE_SIZE - 1)
+		{
+			line[len++] = *s;
+			line[len] = 0;
+		}
+	}
+	pthread_mutex_unlock(&debug_mutex);
+}
+
+/** Tag the following output of this thread with a web request id, 0 ends the request */
+void debug_set_request(ulong id)
+{
+	debug_request = id;
+}
+
+void debug_write(const char *file, int level, long x)
+{
+	char s[24];
+	snprintf(s, sizeof(s), "%ld", x);
+	debug_write(file, level, s);
+}
+
+void debug_printf(const char *file, int level, const char *fmt, ...)
+{
+	char s[TMP_BUFFER_SIZE];
+	va_list args;
+	va_start(args, fmt);
+	vsnprintf(s, sizeof(s), fmt, args);
+	va_end(args);
+	debug_write(file, level, s);
+}
+
+/** Copy the recent lines, oldest first, returns the number of lines copied */
+byte debug_get_lines(DebugLine *lines, byte max)
+{
+	pthread_mutex_lock(&debug_mutex);
+	byte n = (debug_lines_len < max) ? debug_lines_len : max;
+	byte skip = debug_lines_len - n; // the newest lines are copied
+	for (byte i = 0; i < n; i++)
+		lines[i] = debug_lines[(debug_lines_head + skip + i) % DEBUG_LOG_LINES];
+	pthread_mutex_unlock(&debug_mutex);
+	return n;
+}
@@ -0,0 +1,47 @@
+/* OpenSprinkler Unified (RPI/LINUX) Firmware
+ * Copyright (C) 2015 by Ray Wang (ray@opensprinkler.com)
+ *
+ * Debug log header file
+ *
+ * This file is part of the OpenSprinkler library
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see
+ * <http://www.gnu.org/licenses/>.
+ */
+
+#ifndef _DEBUGLOG_H
+#define _DEBUGLOG_H
+
+#define DEBUG_LOG_LINES 64	  // number of recent lines kept in memory
+#define DEBUG_LINE_SIZE 120	  // longest line kept, longer lines are cut
+#define DEBUG_MODULE_SIZE 24 // longest module name
+
+/** Recent debug output line */
+struct DebugLine
+{
+	ulong time;
+	byte module;
+	byte level;
+	char text[DEBUG_LINE_SIZE];
+};
+
+byte debug_num_modules();
+const char *debug_module_name(byte mid);
+int debug_find_module(const char *name);
+byte debug_get_level(byte mid);
+void debug_set_level(byte mid, byte level);
+byte debug_get_lines(DebugLine *lines, byte max);
+void debug_set_request(ulong id);
+
+#endif // _DEBUGLOG_H
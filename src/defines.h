@@ -26,6 +26,11 @@
 
 #define ENABLE_DEBUG // enable serial debug
 
+// Optional features can be left out of minimal builds by passing these to the compiler (see build.sh):
+// DISABLE_MQTT     no MQTT client, the firmware does not need libmosquitto
+// DISABLE_IFTTT    no IFTTT notifications
+// DISABLE_WEATHER  no weather service requests, the water level only changes manually
+
 typedef unsigned char byte;
 typedef unsigned long ulong;
 
@@ -41,6 +46,8 @@ typedef unsigned long ulong;
 /** Hardware version base numbers */
 #define OS_HW_VERSION_BASE 0x00
 #define OSPI_HW_VERSION_BASE 0x40
+#define HW_MODEL_SIZE 63 // maximum length of the board model name
+#define DEVICE_UUID_SIZE 36 // length of the device UUID, e.g. 0e5c9d4a-3f1b-4c2e-9a7d-5b8e6f1a2c3d
 #define SIM_HW_VERSION_BASE 0xC0
 
 /** Hardware type macro defines */
@@ -55,7 +62,16 @@ typedef unsigned long ulong;
 #define STATIONS_FILENAME "data/stns.dat" // stations data file
 #define NVCON_FILENAME "data/nvcon.dat"	  // non-volatile controller data file, see OpenSprinkler.h --> struct NVConData
 #define PROG_FILENAME "data/prog.dat"	  // program data file
+#define PROGEXT_FILENAME "data/progext.dat" // program cycle, soak and watering window data file
+#define GROUPS_FILENAME "data/groups.dat" // station group names file
+#define RDWIN_FILENAME "data/rdwin.dat"	  // scheduled rain delay windows file
+#define TASKS_FILENAME "data/tasks.dat"	  // scheduled tasks file, see timer.h
+#define LASTRUN_FILENAME "data/lastrun.dat" // most recent station run, see ProgramData::lastrun
+#define BUDGET_FILENAME "data/budget.dat"	// station moisture budgets, see budget.h
+#define STNMETA_FILENAME "data/stnmeta.dat" // station notes, emitter type, rated flow and photo, see OpenSprinkler.h --> struct StationMeta
+#define RUNONCE_FILENAME "data/runonce.dat" // station durations of the last run-once program, see /cr
 #define DONE_FILENAME "data/done.dat"	  // used to indicate the completion of all files
+#define CONTROL_SOCKET_FILENAME "control.sock" // local control socket
 
 /** Station macro defines */
 #define STN_TYPE_STANDARD 0x00
@@ -65,6 +81,8 @@ typedef unsigned long ulong;
 #define STN_TYPE_HTTP 0x04	 // HTTP station
 #define STN_TYPE_OTHER 0xFF
 
+#define REMOTE_PROTO_JSON '3' // remote station data suffix: authenticate with a header and check the json result
+
 /** Notification macro defines */
 #define NOTIFY_PROGRAM_SCHED 0x0001
 #define NOTIFY_SENSOR1 0x0002
@@ -75,13 +93,50 @@ typedef unsigned long ulong;
 #define NOTIFY_SENSOR2 0x0040
 #define NOTIFY_RAINDELAY 0x0080
 #define NOTIFY_STATION_ON 0x0100
+#define NOTIFY_ELECTRICAL_FAULT 0x0200
+#define NOTIFY_WEATHER_FAIL 0x0400
+#define NOTIFY_QUEUE_OVERFLOW 0x0800
+#define NOTIFY_NO_FLOW 0x1000
+#define NOTIFY_FROST 0x2000
+
+/** Run-time queue overflow policies */
+#define QUEUE_OVERFLOW_REJECT 0 // reject new runs (default)
+#define QUEUE_OVERFLOW_DROP 1	// drop the waiting run with the lowest priority
+#define QUEUE_OVERFLOW_EXTEND 2 // extend the queue up to RUNTIME_QUEUE_MAX elements
+#define QUEUE_OVERFLOW_MERGE 3	// merge new runs into a waiting run of the same station
+#define QUEUE_OVERFLOW_NOTIFY_INTERVAL 60 // at most one queue overflow notification in this time (in seconds)
+
+/** Handling of overlapping runs of the same station */
+#define STATION_OVERLAP_STACK 0	 // start the new run after the earlier one (default)
+#define STATION_OVERLAP_EXTEND 1 // add the water time to the earlier run
+#define STATION_OVERLAP_IGNORE 2 // drop the new run
+
+/** Languages of notifications and the built-in web UI */
+#define LANGUAGE_EN 0 // English (default)
+#define LANGUAGE_DE 1 // German
+#define LANGUAGE_FR 2 // French
+#define LANGUAGE_ES 3 // Spanish
+#define NUM_LANGUAGES 4
+
+/** Unit systems of flow volumes and rates
+ * Flow is measured in liters and shown in the volume unit of the unit system,
+ * which is also the unit of the flow sensor pulse rate.
+ */
+#define UNIT_SYSTEM_IMPERIAL 0 // US gallons (default)
+#define UNIT_SYSTEM_METRIC 1   // liters
+#define LITERS_PER_GALLON 3.78541f
+
+/** HTTP request results, API result codes and weather service errors */
+#include "errors.h"
 
-/** HTTP request macro defines */
-#define HTTP_RQT_SUCCESS 0
-#define HTTP_RQT_NOT_RECEIVED -1
-#define HTTP_RQT_CONNECT_ERR -2
-#define HTTP_RQT_TIMEOUT -3
-#define HTTP_RQT_EMPTY_RETURN -4
+#define HTTP_QUEUE_SIZE 8 // number of outgoing requests that can wait for the http worker
+#define HTTP_USER_AGENT "OpenSprinkler" // product name in the User-Agent of outgoing requests
+
+/** Special station command status */
+#define SPE_STATUS_NONE 0	 // no command since startup
+#define SPE_STATUS_PENDING 1 // command waiting to be sent
+#define SPE_STATUS_SENT 2	 // RF code transmitted, GPIO switched or HTTP response received
+#define SPE_STATUS_FAILED 3	 // command could not be sent
 
 /** Sensor macro defines */
 #define SENSOR_TYPE_NONE 0x00
@@ -92,6 +147,37 @@ typedef unsigned long ulong;
 #define SENSOR_TYPE_OTHER 0xFF
 
 #define FLOWCOUNT_RT_WINDOW 30 // flow count window (for computing real-time flow rate), 30 seconds
+#define NO_FLOW_MIN_DURATION 60	 // runs shorter than this (in seconds) are not checked for missing flow
+#define NO_FLOW_RATED_PERCENT 25 // runs of stations with a rated flow must count this much of it
+
+/** Virtual program switches */
+#define MAX_NUM_VSWITCHES 4	  // number of virtual program switches
+#define VSWITCH_MIN_INTERVAL 4 // minimum time between two triggers of the same switch (in seconds)
+#define MAX_SENSOR_OVERRIDE 1440 // longest sensor override (in minutes)
+#define PSWITCH_RUNONCE 255	  // program switch mapped to the run-once template instead of a program
+
+/** Weekday bits of weekly programs and rain delay windows: bit n is set for weekday n */
+#define WEEKDAY_MONDAY 0
+#define WEEKDAY_TUESDAY 1
+#define WEEKDAY_WEDNESDAY 2
+#define WEEKDAY_THURSDAY 3
+#define WEEKDAY_FRIDAY 4
+#define WEEKDAY_SATURDAY 5
+#define WEEKDAY_SUNDAY 6
+#define WEEKDAY_EPOCH WEEKDAY_THURSDAY // weekday of 1970-01-01
+
+/** Station state checks, see check_station_state() */
+#define STATE_MISMATCH_NO_RUN 1 // station was on without a running queue element, it was turned off
+#define STATE_MISMATCH_NOT_ON 2 // queue element was running while its station was off
+
+/** Scheduled rain delay windows */
+#define MAX_RD_WINDOWS 8 // maximum number of rain delay windows
+
+/** Valve current sensing */
+#define CURRENT_SENSE_PATH "/sys/bus/iio/devices/iio:device0/" // IIO ADC providing in_voltage?_raw and in_voltage?_scale
+#define CURRENT_CHECK_DELAY 2								   // time (in seconds) after a station turns on before its current is checked
+#define CURRENT_FAULT_OPEN 1								   // no current drawn (broken wire)
+#define CURRENT_FAULT_SHORT 2								   // overcurrent (short circuit)
 
 /** Reboot cause */
 #define REBOOT_CAUSE_NONE 0
@@ -123,13 +209,38 @@ typedef unsigned long ulong;
 /** Storage / zone expander defines */
 #define MAX_EXT_BOARDS 24 // allow more zones for linux-based firmwares
 
+// outputs of a shift register, which are the stations of a board
+// station bits and attributes are kept a byte per board and indexed with sid>>3 and sid&0x07, so this cannot change
+#define SHIFT_REGISTER_LINES 8
+static_assert(SHIFT_REGISTER_LINES == 8, "station bits and attributes are a byte per board");
+#define MAX_EXP_ZONES 16	   // zones of the largest expander, driven as chained shift registers
+
 #define MAX_NUM_BOARDS (1 + MAX_EXT_BOARDS)	  // maximum number of 8-zone boards including expanders
-#define MAX_NUM_STATIONS (MAX_NUM_BOARDS * 8) // maximum number of stations
+#define MAX_NUM_STATIONS (MAX_NUM_BOARDS * SHIFT_REGISTER_LINES) // maximum number of stations
 #define STATION_NAME_SIZE 32				  // maximum number of characters in each station name
+#define MAX_NUM_GROUPS 15					  // maximum number of station groups (group id 0 means no group)
+#define GROUP_NAME_SIZE 32					  // maximum number of characters in each group name
+#define STATION_NOTES_SIZE 96				  // size of each station's notes, including the terminating zero
+#define STATION_EMITTER_SIZE 16				  // size of each station's emitter type, including the terminating zero
+#define STATION_PHOTO_SIZE 64				  // size of each station's photo path, including the terminating zero
+#define MAX_QUEUE_PRIORITY 15				  // maximum station and program priority
+#define MAX_WATER_TIME 64800L				  // longest station run (in seconds, 18 hours)
 #define MAX_SOPTS_SIZE 160					  // maximum string option size
 
 #define STATION_SPECIAL_DATA_SIZE (TMP_BUFFER_SIZE - STATION_NAME_SIZE - 12)
 
+/** Master adjusted times in minutes, stored as minutes + MASTER_ADJ_MIN_ZERO */
+#define MASTER_ADJ_MIN_ZERO 120 // zero minutes: the adjusted time in seconds applies instead
+#define MASTER_ADJ_MIN_MAX 240	// +120 minutes
+
+/** First-boot setup, the bits of IOPT_SETUP */
+#define SETUP_STEP_PASSWORD 0x01
+#define SETUP_STEP_LOCATION 0x02
+#define SETUP_STEP_TIMEZONE 0x04
+#define SETUP_STEP_BOARDS 0x08
+#define SETUP_STEP_WEATHER 0x10
+#define SETUP_COMPLETE 0x80 // until then other commands are locked
+
 /** Default string option values */
 #define DEFAULT_PASSWORD "a6d82bced638de3def1e9bbb4983225c" // md5 of 'opendoor'
 #define DEFAULT_LOCATION "0,0"								// Boston,MA
@@ -137,6 +248,15 @@ typedef unsigned long ulong;
 #define DEFAULT_WEATHER_URL "weather.opensprinkler.com"
 #define DEFAULT_IFTTT_URL "maker.ifttt.com"
 #define DEFAULT_EMPTY_STRING ""
+#define DEFAULT_DEVICE_NAME "OpenSprinkler" // device name used when none is set
+
+/** Stored password */
+#define PASSWORD_HASH_PREFIX "$argon2id$" // a stored password starting with this is a hash of the key
+#define PASSWORD_HASH_TIME 2			 // argon2id passes
+#define PASSWORD_HASH_MEMORY 19456		 // argon2id memory (in KiB)
+#define PASSWORD_HASH_SALT_SIZE 16
+#define PASSWORD_HASH_SIZE 32
+#define PASSWORD_KEY_SIZE 32 // clients send the md5 of the password, in hex
 
 /** Macro define of each option
  * Refer to OpenSprinkler.cpp for details on each option
@@ -207,7 +327,47 @@ enum
 	IOPT_SUBNET_MASK4,
 	IOPT_WIFI_MODE, // ro
 	IOPT_RESET,		// ro
-	NUM_IOPTS		// total number of integer options
+	IOPT_DISPLAY_TYPE,
+	IOPT_DISPLAY_ADDR,
+	IOPT_VSWITCH1_PROG,
+	IOPT_VSWITCH2_PROG,
+	IOPT_VSWITCH3_PROG,
+	IOPT_VSWITCH4_PROG,
+	IOPT_PSWITCH1_PROG,
+	IOPT_PSWITCH2_PROG,
+	IOPT_CURRENT_CHANNEL,
+	IOPT_CURRENT_GAIN,
+	IOPT_CURRENT_MIN,
+	IOPT_CURRENT_MAX,
+	IOPT_WEATHER_TIMEOUT,
+	IOPT_WEATHER_RETRIES,
+	IOPT_WEATHER_FALLBACK,
+	IOPT_WATER_WINDOW_BEGIN,
+	IOPT_WATER_WINDOW_END,
+	IOPT_QUEUE_OVERFLOW,
+	IOPT_STATION_OVERLAP,
+	IOPT_LANGUAGE,
+	IOPT_UNIT_SYSTEM,
+	IOPT_MQTT_STATION_NAMES,
+	IOPT_NO_FLOW_PULSES,
+	IOPT_SPE_REFRESH_INTERVAL,
+	IOPT_RF_REPEAT,
+	IOPT_FORECAST_HOURS,
+	IOPT_EXP_ZONES,
+	IOPT_MASTER_ON_ADJ_MIN,
+	IOPT_MASTER_OFF_ADJ_MIN,
+	IOPT_MASTER_ON_ADJ_MIN_2,
+	IOPT_MASTER_OFF_ADJ_MIN_2,
+	IOPT_SETUP,
+	IOPT_PASSWORD_HASH,
+	IOPT_PASSWORD_CHECK,
+	IOPT_GUEST,
+	IOPT_DRY_RUN,
+	IOPT_FROST_TEMP,
+	IOPT_FROST_PROG,
+	IOPT_REPORTED_FW_VERSION,
+	IOPT_IFTTT_ENABLE2,
+	NUM_IOPTS // total number of integer options
 };
 
 enum
@@ -221,6 +381,17 @@ enum
 	SOPT_STA_SSID,
 	SOPT_STA_PASS,
 	SOPT_MQTT_OPTS,
+	SOPT_PSWITCH_RUNONCE,
+	SOPT_CALENDAR_URL,
+	SOPT_CALENDAR_KEYWORD,
+	SOPT_CORS_ORIGINS,
+	SOPT_MQTT_TOPIC,
+	SOPT_MQTT_EVENTS,
+	SOPT_MQTT_TLS,
+	SOPT_DEVICE_NAME,
+	SOPT_DEVICE_UUID,
+	SOPT_REMOTE_KEY,
+	SOPT_GUEST_KEY,
 	// SOPT_WEATHER_KEY,
 	// SOPT_AP_PASS,
 	NUM_SOPTS // total number of string options
@@ -233,6 +404,11 @@ enum
 #define LOGDATA_WATERLEVEL 0x03
 #define LOGDATA_FLOWSENSE 0x04
 #define LOGDATA_SENSOR2 0x05
+#define LOGDATA_WATERWINDOW 0x07
+#define LOGDATA_NOFLOW 0x08
+#define LOGDATA_FORECAST 0x09
+#define LOGDATA_FROST 0x0A
+#define LOGDATA_MISMATCH 0x0B // station state did not match the run-time queue, see STATE_MISMATCH_*
 #define LOGDATA_CURRENT 0x80
 
 #undef OS_HW_VERSION
@@ -262,33 +438,40 @@ enum
 #else
 #define OS_HW_VERSION SIM_HW_VERSION_BASE
 #endif
-#define PIN_SR_LATCH 0
-#define PIN_SR_DATA 0
-#define PIN_SR_CLOCK 0
-#define PIN_SR_OE 0
-#define PIN_SENSOR1 0
-#define PIN_SENSOR2 0
-#define PIN_RFTX 0
+// virtual pins of the simulated hardware, see sim.cpp
+#define PIN_SR_LATCH 1
+#define PIN_SR_DATA 2
+#define PIN_SR_CLOCK 3
+#define PIN_SR_OE 4
+#define PIN_SENSOR1 5
+#define PIN_SENSOR2 6
+#define PIN_RFTX 7
 #define PIN_FREE_LIST \
 	{                 \
 	}
 #define ETHER_BUFFER_SIZE 16384
 #endif
 
+/** Debug output levels, set for each module (see debuglog.cpp) */
+#define DEBUG_LEVEL_OFF 0
+#define DEBUG_LEVEL_INFO 1	// messages
+#define DEBUG_LEVEL_TRACE 2 // detailed tracing, e.g. of MQTT
+#define SLOW_REQUEST_MS 1000 // web requests taking longer are printed as messages
+
 #if defined(ENABLE_DEBUG) /** Serial debug functions */
 #include <stdio.h>
+void debug_write(const char *file, int level, const char *s);
+void debug_write(const char *file, int level, long x);
+void debug_printf(const char *file, int level, const char *fmt, ...);
+bool debug_enabled(const char *file, int level);
 #define DEBUG_BEGIN(x) \
 	{                  \
 	} /** Serial debug functions */
-inline void DEBUG_PRINT(int x)
-{
-	printf("%d", x);
-}
-inline void DEBUG_PRINT(const char *s) { printf("%s", s); }
-#define DEBUG_PRINTLN(x) \
-	{                    \
-		DEBUG_PRINT(x);  \
-		printf("\n");    \
+#define DEBUG_PRINT(x) debug_write(__FILE__, DEBUG_LEVEL_INFO, x)
+#define DEBUG_PRINTLN(x)   \
+	{                      \
+		DEBUG_PRINT(x);    \
+		DEBUG_PRINT("\n"); \
 	}
 #else
 
@@ -0,0 +1,251 @@
+/* OpenSprinkler Unified (RPI/LINUX) Firmware
+ * Copyright (C) 2015 by Ray Wang (ray@opensprinkler.com)
+ *
+ * Front panel display
+ * Feb 2015 @ OpenSprinkler.com
+ *
+ * This file is part of the OpenSprinkler library
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see
+ * <http://www.gnu.org/licenses/>.
+ */
+
+#include <fcntl.h>
+#include <unistd.h>
+#include <sys/ioctl.h>
+#include <linux/i2c-dev.h>
+#include <arpa/inet.h>
+#include <ifaddrs.h>
+#include <net/if.h>
+
+#include "OpenSprinkler.h"
+#include "program.h"
+#include "display.h"
+#include "main.h"
+
+int OSDisplay::_fd = -1;
+byte OSDisplay::_type = DISPLAY_TYPE_NONE;
+char OSDisplay::_lines[DISPLAY_LINES][DISPLAY_LINE_SIZE + 1];
+
+/** PCF8574 to HD44780 pin mapping */
+#define LCD_RS 0x01
+#define LCD_EN 0x04
+#define LCD_BACKLIGHT 0x08
+
+/** 5x7 font for ASCII 0x20 to 0x7E, one byte per column */
+static const byte oled_font[] PROGMEM = {
+	0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x5F, 0x00, 0x00, 0x00, 0x07, 0x00, 0x07, 0x00, 0x14, 0x7F, 0x14, 0x7F, 0x14,
+	0x24, 0x2A, 0x7F, 0x2A, 0x12, 0x23, 0x13, 0x08, 0x64, 0x62, 0x36, 0x49, 0x55, 0x22, 0x50, 0x00, 0x05, 0x03, 0x00, 0x00,
+	0x00, 0x1C, 0x22, 0x41, 0x00, 0x00, 0x41, 0x22, 0x1C, 0x00, 0x14, 0x08, 0x3E, 0x08, 0x14, 0x08, 0x08, 0x3E, 0x08, 0x08,
+	0x00, 0x50, 0x30, 0x00, 0x00, 0x08, 0x08, 0x08, 0x08, 0x08, 0x00, 0x60, 0x60, 0x00, 0x00, 0x20, 0x10, 0x08, 0x04, 0x02,
+	0x3E, 0x51, 0x49, 0x45, 0x3E, 0x00, 0x42, 0x7F, 0x40, 0x00, 0x42, 0x61, 0x51, 0x49, 0x46, 0x21, 0x41, 0x45, 0x4B, 0x31,
+	0x18, 0x14, 0x12, 0x7F, 0x10, 0x27, 0x45, 0x45, 0x45, 0x39, 0x3C, 0x4A, 0x49, 0x49, 0x30, 0x01, 0x71, 0x09, 0x05, 0x03,
+	0x36, 0x49, 0x49, 0x49, 0x36, 0x06, 0x49, 0x49, 0x29, 0x1E, 0x00, 0x36, 0x36, 0x00, 0x00, 0x00, 0x56, 0x36, 0x00, 0x00,
+	0x08, 0x14, 0x22, 0x41, 0x00, 0x14, 0x14, 0x14, 0x14, 0x14, 0x00, 0x41, 0x22, 0x14, 0x08, 0x02, 0x01, 0x51, 0x09, 0x06,
+	0x32, 0x49, 0x79, 0x41, 0x3E, 0x7E, 0x11, 0x11, 0x11, 0x7E, 0x7F, 0x49, 0x49, 0x49, 0x36, 0x3E, 0x41, 0x41, 0x41, 0x22,
+	0x7F, 0x41, 0x41, 0x22, 0x1C, 0x7F, 0x49, 0x49, 0x49, 0x41, 0x7F, 0x09, 0x09, 0x09, 0x01, 0x3E, 0x41, 0x49, 0x49, 0x7A,
+	0x7F, 0x08, 0x08, 0x08, 0x7F, 0x00, 0x41, 0x7F, 0x41, 0x00, 0x20, 0x40, 0x41, 0x3F, 0x01, 0x7F, 0x08, 0x14, 0x22, 0x41,
+	0x7F, 0x40, 0x40, 0x40, 0x40, 0x7F, 0x02, 0x0C, 0x02, 0x7F, 0x7F, 0x04, 0x08, 0x10, 0x7F, 0x3E, 0x41, 0x41, 0x41, 0x3E,
+	0x7F, 0x09, 0x09, 0x09, 0x06, 0x3E, 0x41, 0x51, 0x21, 0x5E, 0x7F, 0x09, 0x19, 0x29, 0x46, 0x46, 0x49, 0x49, 0x49, 0x31,
+	0x01, 0x01, 0x7F, 0x01, 0x01, 0x3F, 0x40, 0x40, 0x40, 0x3F, 0x1F, 0x20, 0x40, 0x20, 0x1F, 0x3F, 0x40, 0x38, 0x40, 0x3F,
+	0x63, 0x14, 0x08, 0x14, 0x63, 0x07, 0x08, 0x70, 0x08, 0x07, 0x61, 0x51, 0x49, 0x45, 0x43, 0x00, 0x7F, 0x41, 0x41, 0x00,
+	0x02, 0x04, 0x08, 0x10, 0x20, 0x00, 0x41, 0x41, 0x7F, 0x00, 0x04, 0x02, 0x01, 0x02, 0x04, 0x40, 0x40, 0x40, 0x40, 0x40,
+	0x00, 0x01, 0x02, 0x04, 0x00, 0x20, 0x54, 0x54, 0x54, 0x78, 0x7F, 0x48, 0x44, 0x44, 0x38, 0x38, 0x44, 0x44, 0x44, 0x20,
+	0x38, 0x44, 0x44, 0x48, 0x7F, 0x38, 0x54, 0x54, 0x54, 0x18, 0x08, 0x7E, 0x09, 0x01, 0x02, 0x0C, 0x52, 0x52, 0x52, 0x3E,
+	0x7F, 0x08, 0x04, 0x04, 0x78, 0x00, 0x44, 0x7D, 0x40, 0x00, 0x20, 0x40, 0x44, 0x3D, 0x00, 0x7F, 0x10, 0x28, 0x44, 0x00,
+	0x00, 0x41, 0x7F, 0x40, 0x00, 0x7C, 0x04, 0x18, 0x04, 0x78, 0x7C, 0x08, 0x04, 0x04, 0x78, 0x38, 0x44, 0x44, 0x44, 0x38,
+	0x7C, 0x14, 0x14, 0x14, 0x08, 0x08, 0x14, 0x14, 0x18, 0x7C, 0x7C, 0x08, 0x04, 0x04, 0x08, 0x48, 0x54, 0x54, 0x54, 0x20,
+	0x04, 0x3F, 0x44, 0x40, 0x20, 0x3C, 0x40, 0x40, 0x20, 0x7C, 0x1C, 0x20, 0x40, 0x20, 0x1C, 0x3C, 0x40, 0x30, 0x40, 0x3C,
+	0x44, 0x28, 0x10, 0x28, 0x44, 0x0C, 0x50, 0x50, 0x50, 0x3C, 0x44, 0x64, 0x54, 0x4C, 0x44, 0x00, 0x08, 0x36, 0x41, 0x00,
+	0x00, 0x00, 0x7F, 0x00, 0x00, 0x00, 0x41, 0x36, 0x08, 0x00, 0x08, 0x04, 0x08, 0x10, 0x08};
+
+/** Open the I2C bus and initialize the display selected in the options */
+void OSDisplay::begin(void)
+{
+	if (_fd >= 0)
+	{
+		close(_fd);
+		_fd = -1;
+	}
+	_type = os.iopts[IOPT_DISPLAY_TYPE];
+	if (_type == DISPLAY_TYPE_NONE)
+		return;
+
+	if ((_fd = open(DISPLAY_I2C_DEVICE, O_RDWR)) < 0 || ioctl(_fd, I2C_SLAVE, os.iopts[IOPT_DISPLAY_ADDR]) < 0)
+	{
+		DEBUG_PRINTLN("can't open display");
+		if (_fd >= 0)
+			close(_fd);
+		_fd = -1;
+		return;
+	}
+
+	if (_type == DISPLAY_TYPE_LCD)
+	{
+		// switch the controller into 4-bit mode
+		delay(50);
+		_lcd_write4(0x30, 0);
+		delay(5);
+		_lcd_write4(0x30, 0);
+		delay(1);
+		_lcd_write4(0x30, 0);
+		_lcd_write4(0x20, 0);
+		_lcd_send(0x28, 0); // 2 lines, 5x8 font
+		_lcd_send(0x0C, 0); // display on, no cursor
+		_lcd_send(0x01, 0); // clear
+		delay(2);
+		_lcd_send(0x06, 0); // entry mode: left to right
+	}
+	else
+	{
+		static const byte init[] = {0xAE, 0xD5, 0x80, 0xA8, 0x3F, 0xD3, 0x00, 0x40, 0x8D, 0x14, 0x20, 0x00,
+									0xA1, 0xC8, 0xDA, 0x12, 0x81, 0xCF, 0xD9, 0xF1, 0xDB, 0x40, 0xA4, 0xA6, 0xAF};
+		for (byte i = 0; i < sizeof(init); i++)
+			_oled_command(init[i]);
+		for (byte page = 0; page < 8; page++)
+			_oled_print(page, "");
+	}
+}
+
+void OSDisplay::_write(const byte *data, int len)
+{
+	if (::write(_fd, data, len) != len)
+		DEBUG_PRINTLN("display write error");
+}
+
+void OSDisplay::_lcd_write4(byte nibble, byte mode)
+{
+	byte data[3];
+	data[0] = (nibble & 0xF0) | mode | LCD_BACKLIGHT;
+	data[1] = data[0] | LCD_EN; // data is latched on the falling edge of EN
+	data[2] = data[0];
+	_write(data, 3);
+	delayMicroseconds(50);
+}
+
+void OSDisplay::_lcd_send(byte value, byte mode)
+{
+	_lcd_write4(value & 0xF0, mode);
+	_lcd_write4((value << 4) & 0xF0, mode);
+}
+
+void OSDisplay::_lcd_print(byte row, const char *s)
+{
+	_lcd_send(0x80 | (row ? 0x40 : 0x00), 0); // set ddram address
+	for (byte i = 0; i < DISPLAY_LINE_SIZE; i++)
+		_lcd_send(*s ? *s++ : ' ', LCD_RS);
+}
+
+void OSDisplay::_oled_command(byte c)
+{
+	byte data[2] = {0x00, c};
+	_write(data, 2);
+}
+
+void OSDisplay::_oled_print(byte page, const char *s)
+{
+	byte data[129];
+	memset(data, 0, sizeof(data));
+	data[0] = 0x40; // the rest are display data
+	for (byte i = 0; i < 21 && *s; i++, s++)
+	{
+		byte c = (*s < 0x20 || *s > 0x7E) ? '?' : *s;
+		for (byte j = 0; j < 5; j++)
+			data[1 + i * 6 + j] = pgm_read_byte(oled_font + (c - 0x20) * 5 + j);
+	}
+	_oled_command(0x21); // column range
+	_oled_command(0);
+	_oled_command(127);
+	_oled_command(0x22); // page range
+	_oled_command(page);
+	_oled_command(page);
+	_write(data, sizeof(data));
+}
+
+/** Get the IPv4 address of the first network interface that is up */
+static bool get_ip_address(char *buf, size_t len)
+{
+	struct ifaddrs *ifaddr, *ifa;
+	bool found = false;
+	if (getifaddrs(&ifaddr) < 0)
+		return false;
+	for (ifa = ifaddr; ifa; ifa = ifa->ifa_next)
+	{
+		if (!ifa->ifa_addr || ifa->ifa_addr->sa_family != AF_INET || (ifa->ifa_flags & IFF_LOOPBACK) || !(ifa->ifa_flags & IFF_UP))
+			continue;
+		inet_ntop(AF_INET, &((struct sockaddr_in *)ifa->ifa_addr)->sin_addr, buf, len);
+		found = true;
+		break;
+	}
+	freeifaddrs(ifaddr);
+	return found;
+}
+
+/** Fill in the status lines */
+void OSDisplay::_render(time_t curr_time)
+{
+	char *line;
+
+	// time and water level
+	line = _lines[0];
+	struct tm *ti = gmtime(&curr_time);
+	snprintf(line, DISPLAY_LINE_SIZE + 1, "%02d:%02d:%02d  WL%3d%%", ti->tm_hour, ti->tm_min, ti->tm_sec, os.iopts[IOPT_WATER_PERCENTAGE]);
+
+	// stations of the current board, the number of each running station is shown
+	line = _lines[1];
+	byte bid = os.status.display_board;
+	if (bid >= os.nboards)
+		bid = 0;
+	int n = snprintf(line, DISPLAY_LINE_SIZE + 1, "%c%d ", bid ? 'E' : 'M', bid);
+	for (byte s = 0; s < SHIFT_REGISTER_LINES; s++)
+		line[n++] = ((os.station_bits[bid] >> s) & 1) ? '1' + s : '-';
+	line[n] = 0;
+	if (!os.status.enabled)
+		strcat(line, " OFF");
+	else if (os.status.rain_delayed)
+		strcat(line, " RD");
+	else if (pd.nqueue)
+		strcat(line, " RUN");
+
+	// sensors
+	line = _lines[2];
+	snprintf(line, DISPLAY_LINE_SIZE + 1, "Sn1 %s Sn2 %s",
+			 os.iopts[IOPT_SENSOR1_TYPE] ? (os.status.sensor1_active ? "on " : "off") : "-- ",
+			 os.iopts[IOPT_SENSOR2_TYPE] ? (os.status.sensor2_active ? "on" : "off") : "--");
+
+	// network address
+	line = _lines[3];
+	if (!get_ip_address(line, DISPLAY_LINE_SIZE + 1))
+		strcpy(line, "No network");
+}
+
+/** Refresh the display, called once per second */
+void OSDisplay::update(time_t curr_time)
+{
+	if (_fd < 0)
+		return;
+
+	_render(curr_time);
+	if (_type == DISPLAY_TYPE_LCD)
+	{
+		// the second row cycles through the remaining status lines
+		_lcd_print(0, _lines[0]);
+		_lcd_print(1, _lines[1 + (curr_time / DISPLAY_ROTATE_TIME) % (DISPLAY_LINES - 1)]);
+	}
+	else
+	{
+		for (byte i = 0; i < DISPLAY_LINES; i++)
+			_oled_print(i * 2, _lines[i]);
+	}
+}
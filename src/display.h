@@ -0,0 +1,57 @@
+/* OpenSprinkler Unified (RPI/LINUX) Firmware
+ * Copyright (C) 2015 by Ray Wang (ray@opensprinkler.com)
+ *
+ * Front panel display header file
+ * Feb 2015 @ OpenSprinkler.com
+ *
+ * This file is part of the OpenSprinkler library
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see
+ * <http://www.gnu.org/licenses/>.
+ */
+
+#ifndef _DISPLAY_H
+#define _DISPLAY_H
+
+/** Display types */
+#define DISPLAY_TYPE_NONE 0x00 // no display
+#define DISPLAY_TYPE_LCD 0x01  // 16x2 character LCD on a PCF8574 I2C backpack
+#define DISPLAY_TYPE_OLED 0x02 // 128x64 SSD1306 OLED
+
+#define DISPLAY_I2C_DEVICE "/dev/i2c-1"
+#define DISPLAY_LINES 4		  // number of status lines
+#define DISPLAY_LINE_SIZE 16  // characters per status line
+#define DISPLAY_ROTATE_TIME 4 // seconds each status line is shown on the second LCD row
+
+class OSDisplay
+{
+private:
+	static int _fd;
+	static byte _type;
+	static char _lines[DISPLAY_LINES][DISPLAY_LINE_SIZE + 1];
+
+	static void _write(const byte *data, int len);
+	static void _lcd_write4(byte nibble, byte mode);
+	static void _lcd_send(byte value, byte mode);
+	static void _lcd_print(byte row, const char *s);
+	static void _oled_command(byte c);
+	static void _oled_print(byte page, const char *s);
+	static void _render(time_t curr_time);
+
+public:
+	static void begin(void);
+	static void update(time_t curr_time);
+};
+
+#endif // _DISPLAY_H
@@ -0,0 +1,97 @@
+/* OpenSprinkler Unified (RPI/LINUX) Firmware
+ * Copyright (C) 2015 by Ray Wang (ray@opensprinkler.com)
+ *
+ * Error codes: descriptions of web API results, outgoing request errors
+ * and weather service errors, as sent along with the numeric codes
+ *
+ * This file is part of the OpenSprinkler library
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see
+ * <http://www.gnu.org/licenses/>.
+ */
+
+#include "errors.h"
+
+struct ErrorMessage
+{
+	int code;
+	const char *message;
+};
+
+static const ErrorMessage result_messages[] = {
+	{HTML_OK, "ok"},
+	{HTML_SUCCESS, "success"},
+	{HTML_UNAUTHORIZED, "unauthorized"},
+	{HTML_MISMATCH, "mismatch"},
+	{HTML_DATA_MISSING, "data missing"},
+	{HTML_DATA_OUTOFBOUND, "out of bound"},
+	{HTML_DATA_FORMATERROR, "format error"},
+	{HTML_RFCODE_ERROR, "rf code error"},
+	{HTML_PAGE_NOT_FOUND, "page not found"},
+	{HTML_NOT_PERMITTED, "not permitted"},
+	{HTML_UPLOAD_FAILED, "upload failed"}};
+
+static const ErrorMessage request_error_messages[] = {
+	{HTTP_RQT_SUCCESS, "success"},
+	{HTTP_RQT_NOT_RECEIVED, "no response yet"},
+	{HTTP_RQT_CONNECT_ERR, "cannot connect"},
+	{HTTP_RQT_TIMEOUT, "timeout"},
+	{HTTP_RQT_EMPTY_RETURN, "empty or invalid response"},
+	{HTTP_RQT_BAD_STATUS, "bad http status"},
+	{HTTP_RQT_QUEUE_FULL, "request queue full"},
+	{WEATHER_ERR_BAD_DATA, "bad weather data"},
+	{WEATHER_ERR_INSUFFICIENT_DATA, "insufficient weather data"},
+	{WEATHER_ERR_MISSING_FIELD, "missing weather field"},
+	{WEATHER_ERR_API, "weather provider error"},
+	{WEATHER_ERR_LOCATION, "location error"},
+	{WEATHER_ERR_LOCATION_API, "location service error"},
+	{WEATHER_ERR_NO_LOCATION, "location not found"},
+	{WEATHER_ERR_LOCATION_FORMAT, "invalid location format"},
+	{WEATHER_ERR_PWS, "pws error"},
+	{WEATHER_ERR_PWS_ID, "invalid pws id"},
+	{WEATHER_ERR_PWS_KEY, "invalid pws api key"},
+	{WEATHER_ERR_PWS_AUTH, "pws authentication error"},
+	{WEATHER_ERR_PWS_UNSUPPORTED, "pws not supported"},
+	{WEATHER_ERR_NO_PWS, "no pws provided"},
+	{WEATHER_ERR_METHOD, "adjustment method error"},
+	{WEATHER_ERR_METHOD_UNSUPPORTED, "unsupported adjustment method"},
+	{WEATHER_ERR_METHOD_INVALID, "invalid adjustment method"},
+	{WEATHER_ERR_OPTIONS, "adjustment options error"},
+	{WEATHER_ERR_OPTIONS_MALFORMED, "malformed adjustment options"},
+	{WEATHER_ERR_OPTION_MISSING, "missing adjustment option"},
+	{WEATHER_ERR_UNEXPECTED, "unexpected weather service error"}};
+
+static const char *find_message(const ErrorMessage *messages, int n, int code)
+{
+	for (int i = 0; i < n; i++)
+	{
+		if (messages[i].code == code)
+			return messages[i].message;
+	}
+	return "unknown error";
+}
+
+/** Describe a web API result code */
+const char *result_message(int code)
+{
+	return find_message(result_messages, sizeof(result_messages) / sizeof(ErrorMessage), code);
+}
+
+/** Describe the error of a request sent by the controller
+ * Negative codes are HTTP_RQT errors, positive ones the errCode of the weather service.
+ */
+const char *request_error_message(int code)
+{
+	return find_message(request_error_messages, sizeof(request_error_messages) / sizeof(ErrorMessage), code);
+}
@@ -0,0 +1,78 @@
+/* OpenSprinkler Unified (RPI/LINUX) Firmware
+ * Copyright (C) 2015 by Ray Wang (ray@opensprinkler.com)
+ *
+ * Error codes header file: web API results, outgoing requests and the weather service
+ *
+ * This file is part of the OpenSprinkler library
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see
+ * <http://www.gnu.org/licenses/>.
+ */
+
+#ifndef _ERRORS_H
+#define _ERRORS_H
+
+/** Result codes of web API commands ("result") */
+#define HTML_OK 0x00
+#define HTML_SUCCESS 0x01
+#define HTML_UNAUTHORIZED 0x02
+#define HTML_MISMATCH 0x03
+#define HTML_DATA_MISSING 0x10
+#define HTML_DATA_OUTOFBOUND 0x11
+#define HTML_DATA_FORMATERROR 0x12
+#define HTML_RFCODE_ERROR 0x13
+#define HTML_PAGE_NOT_FOUND 0x20
+#define HTML_NOT_PERMITTED 0x30
+#define HTML_UPLOAD_FAILED 0x40
+#define HTML_REDIRECT_HOME 0xFF
+
+/** Results of HTTP requests sent by the controller
+ * They are negative, so they can share a variable with the weather service error codes
+ * (e.g. "wterr" is one of these if the weather service could not be reached).
+ */
+#define HTTP_RQT_SUCCESS 0
+#define HTTP_RQT_NOT_RECEIVED -1
+#define HTTP_RQT_CONNECT_ERR -2
+#define HTTP_RQT_TIMEOUT -3
+#define HTTP_RQT_EMPTY_RETURN -4
+#define HTTP_RQT_BAD_STATUS -5
+#define HTTP_RQT_QUEUE_FULL -6
+
+/** Error codes reported by the weather service (errCode) */
+#define WEATHER_ERR_BAD_DATA 1
+#define WEATHER_ERR_INSUFFICIENT_DATA 10
+#define WEATHER_ERR_MISSING_FIELD 11
+#define WEATHER_ERR_API 12
+#define WEATHER_ERR_LOCATION 2
+#define WEATHER_ERR_LOCATION_API 20
+#define WEATHER_ERR_NO_LOCATION 21
+#define WEATHER_ERR_LOCATION_FORMAT 22
+#define WEATHER_ERR_PWS 3
+#define WEATHER_ERR_PWS_ID 30
+#define WEATHER_ERR_PWS_KEY 31
+#define WEATHER_ERR_PWS_AUTH 32
+#define WEATHER_ERR_PWS_UNSUPPORTED 33
+#define WEATHER_ERR_NO_PWS 34
+#define WEATHER_ERR_METHOD 4
+#define WEATHER_ERR_METHOD_UNSUPPORTED 40
+#define WEATHER_ERR_METHOD_INVALID 41
+#define WEATHER_ERR_OPTIONS 5
+#define WEATHER_ERR_OPTIONS_MALFORMED 50
+#define WEATHER_ERR_OPTION_MISSING 51
+#define WEATHER_ERR_UNEXPECTED 99
+
+const char *result_message(int code);
+const char *request_error_message(int code);
+
+#endif // _ERRORS_H
@@ -87,16 +87,16 @@ bool EthernetServer::begin()
 }
 
 //	This function blocks until we get a client connected.
-//	 It will timeout after 50ms and return a blank client.
+//	 It will timeout after timeout_ms and return a blank client.
 //	 If it succeeds it will return an EthernetClient.
-EthernetClient EthernetServer::available()
+EthernetClient EthernetServer::available(uint16_t timeout_ms)
 {
 	fd_set sock_set;
 	FD_ZERO(&sock_set);
 	FD_SET(m_sock, &sock_set);
 	struct timeval timeout;
-	timeout.tv_sec = 0;
-	timeout.tv_usec = 50 * 1000; // 50ms
+	timeout.tv_sec = timeout_ms / 1000;
+	timeout.tv_usec = (timeout_ms % 1000) * 1000L;
 
 	select(m_sock + 1, &sock_set, NULL, NULL, &timeout);
 	if (FD_ISSET(m_sock, &sock_set))
@@ -65,7 +65,7 @@ public:
 	~EthernetServer();
 
 	bool begin();
-	EthernetClient available();
+	EthernetClient available(uint16_t timeout_ms = 50);
 
 private:
 	uint16_t m_port;
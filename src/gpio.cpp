@@ -22,6 +22,9 @@
  */
 
 #include "gpio.h"
+#include "utils.h"
+#include <pthread.h>
+#include <string.h>
 
 #if defined(OSPI)
 
@@ -34,6 +37,7 @@
 #include <string.h>
 #include <poll.h>
 #include <pthread.h>
+#include <linux/gpio.h>
 
 #define BUFFER_MAX 64
 #define GPIO_MAX 64
@@ -106,6 +110,73 @@ static int sysFds[GPIO_MAX] = {
 	-1,
 };
 
+// gpiochip line handles, owned by the character device backend
+static int lineFds[GPIO_MAX];
+static byte lineModes[GPIO_MAX];
+static int chipFd = -1;
+static int gpioBackend = -1;
+
+/** Select the GPIO backend on first use
+ * OS_GPIO_BACKEND may be set to "sysfs" or "chardev", otherwise
+ * sysfs is used where the kernel still provides it.
+ * OS_GPIO_CHIP selects the gpiochip device of the character device backend.
+ */
+static int gpio_backend()
+{
+	if (gpioBackend >= 0)
+		return gpioBackend;
+
+	const char *env = getenv("OS_GPIO_BACKEND");
+	struct stat st;
+	if (env && strcmp(env, "chardev") == 0)
+		gpioBackend = GPIO_BACKEND_CHARDEV;
+	else if (env && strcmp(env, "sysfs") == 0)
+		gpioBackend = GPIO_BACKEND_SYSFS;
+	else
+		gpioBackend = stat("/sys/class/gpio/export", &st) ? GPIO_BACKEND_CHARDEV : GPIO_BACKEND_SYSFS;
+
+	if (gpioBackend == GPIO_BACKEND_CHARDEV)
+	{
+		const char *chip = getenv("OS_GPIO_CHIP");
+		if ((chipFd = open(chip ? chip : GPIO_DEFAULT_CHIP, O_RDWR)) < 0)
+			DEBUG_PRINTLN("failed to open gpiochip");
+		for (int i = 0; i < GPIO_MAX; i++)
+			lineFds[i] = -1;
+	}
+	return gpioBackend;
+}
+
+/** Request a gpiochip line in the given mode, returns the line handle */
+static int line_request(int pin, byte mode)
+{
+	if (pin < 0 || pin >= GPIO_MAX || chipFd < 0)
+		return -1;
+	if (lineFds[pin] >= 0)
+	{
+		if (lineModes[pin] == mode)
+			return lineFds[pin];
+		close(lineFds[pin]);
+		lineFds[pin] = -1;
+	}
+
+	struct gpiohandle_request req;
+	memset(&req, 0, sizeof(req));
+	req.lineoffsets[0] = pin;
+	req.lines = 1;
+	req.flags = (mode == OUTPUT) ? GPIOHANDLE_REQUEST_OUTPUT : GPIOHANDLE_REQUEST_INPUT;
+	if (mode == INPUT_PULLUP)
+		req.flags |= GPIOHANDLE_REQUEST_BIAS_PULL_UP;
+	strcpy(req.consumer_label, "opensprinkler");
+	if (ioctl(chipFd, GPIO_GET_LINEHANDLE_IOCTL, &req) < 0)
+	{
+		DEBUG_PRINTLN("failed to request gpio line");
+		return -1;
+	}
+	lineFds[pin] = req.fd;
+	lineModes[pin] = mode;
+	return req.fd;
+}
+
 // Interrupt service routine functions
 static void (*isrFunctions[GPIO_MAX])(void);
 
@@ -174,6 +245,12 @@ void pinMode(int pin, byte mode)
 {
 	static const char dir_str[] = "in\0out";
 
+	if (gpio_backend() == GPIO_BACKEND_CHARDEV)
+	{
+		line_request(pin, mode);
+		return;
+	}
+
 	char path[BUFFER_MAX];
 	int fd;
 
@@ -217,6 +294,9 @@ int gpio_fd_open(int pin, int mode)
 	char path[BUFFER_MAX];
 	int fd;
 
+	if (gpio_backend() == GPIO_BACKEND_CHARDEV)
+		return line_request(pin, (mode == O_RDONLY) ? INPUT : OUTPUT);
+
 	snprintf(path, BUFFER_MAX, "/sys/class/gpio/gpio%d/value", pin);
 	fd = open(path, mode);
 	if (fd < 0)
@@ -230,6 +310,9 @@ int gpio_fd_open(int pin, int mode)
 /** Close file */
 void gpio_fd_close(int fd)
 {
+	// line handles stay open until the pin mode changes
+	if (gpio_backend() == GPIO_BACKEND_CHARDEV)
+		return;
 	close(fd);
 }
 
@@ -238,6 +321,18 @@ byte digitalRead(int pin)
 {
 	char value_str[3];
 
+	if (gpio_backend() == GPIO_BACKEND_CHARDEV)
+	{
+		if (pin < 0 || pin >= GPIO_MAX)
+			return 0;
+		// keep the mode set by pinMode if the line is already an input
+		int fd = (lineFds[pin] >= 0 && lineModes[pin] != OUTPUT) ? lineFds[pin] : line_request(pin, INPUT);
+		struct gpiohandle_data data;
+		if (fd < 0 || ioctl(fd, GPIOHANDLE_GET_LINE_VALUES_IOCTL, &data) < 0)
+			return 0;
+		return data.values[0];
+	}
+
 	int fd = gpio_fd_open(pin, O_RDONLY);
 	if (fd < 0)
 	{
@@ -254,15 +349,29 @@ byte digitalRead(int pin)
 	return atoi(value_str);
 }
 
-/** Write digital value given file descriptor */
-void gpio_write(int fd, byte value)
+/** Write digital value given file descriptor, without reporting failures
+ * This only makes a system call, so it can be used in a signal handler once the backend is selected.
+ * Returns false if the value could not be written.
+ */
+bool gpio_write_quiet(int fd, byte value)
 {
 	static const char value_str[] = "01";
 
-	if (1 != write(fd, &value_str[LOW == value ? 0 : 1], 1))
+	if (gpioBackend == GPIO_BACKEND_CHARDEV)
 	{
-		DEBUG_PRINT("failed to write value on pin ");
+		struct gpiohandle_data data = {};
+		data.values[0] = (LOW == value) ? 0 : 1;
+		return ioctl(fd, GPIOHANDLE_SET_LINE_VALUES_IOCTL, &data) >= 0;
 	}
+	return write(fd, &value_str[LOW == value ? 0 : 1], 1) == 1;
+}
+
+/** Write digital value given file descriptor */
+void gpio_write(int fd, byte value)
+{
+	gpio_backend();
+	if (!gpio_write_quiet(fd, value))
+		DEBUG_PRINTLN("failed to write value");
 }
 
 /** Write digital value */
@@ -274,7 +383,7 @@ void digitalWrite(int pin, byte value)
 		return;
 	}
 	gpio_write(fd, value);
-	close(fd);
+	gpio_fd_close(fd);
 }
 
 static int HiPri(const int pri)
@@ -339,6 +448,11 @@ void attachInterrupt(int pin, const char *mode, void (*isr)(void))
 		DEBUG_PRINTLN("pin out of range");
 		return;
 	}
+	if (gpio_backend() != GPIO_BACKEND_SYSFS)
+	{
+		DEBUG_PRINTLN("interrupts require the sysfs gpio backend");
+		return;
+	}
 
 	// set pin to INPUT mode and set interrupt edge mode
 	pinMode(pin, INPUT);
@@ -378,12 +492,131 @@ void attachInterrupt(int pin, const char *mode, void (*isr)(void))
 }
 #else
 
+#include "sim.h"
+
 void pinMode(int pin, byte mode) {}
-void digitalWrite(int pin, byte value) {}
-byte digitalRead(int pin) { return 0; }
+void digitalWrite(int pin, byte value) { sim_pin_write(pin, value); }
+byte digitalRead(int pin) { return sim_pin_read(pin); }
 void attachInterrupt(int pin, const char *mode, void (*isr)(void)) {}
 int gpio_fd_open(int pin, int mode) { return 0; }
 void gpio_fd_close(int fd) {}
 void gpio_write(int fd, byte value) {}
+bool gpio_write_quiet(int fd, byte value) { return true; }
 
 #endif
+
+// pins used by the controller itself (shift register, sensors and RF transmitter)
+static const byte reserved[] = {PIN_SR_LATCH, PIN_SR_DATA, PIN_SR_CLOCK, PIN_SR_OE, PIN_SENSOR1, PIN_SENSOR2, PIN_RFTX};
+
+/** Check if a pin is used by the controller itself */
+bool gpio_pin_reserved(int pin)
+{
+	for (byte i = 0; i < sizeof(reserved); i++)
+	{
+		if (reserved[i] == pin)
+			return true;
+	}
+	return false;
+}
+
+/** List the pins used by the controller itself
+ * pins must hold at least MAX_GPIO_PINS entries.
+ */
+byte gpio_reserved_pins(byte *pins)
+{
+	memcpy(pins, reserved, sizeof(reserved));
+	return sizeof(reserved);
+}
+
+/** List the pins GPIO stations can use
+ * These are the free pins of the hardware, minus the reserved ones.
+ * pins must hold at least MAX_GPIO_PINS entries.
+ */
+byte gpio_free_pins(byte *pins)
+{
+	byte list[] = PIN_FREE_LIST;
+	byte n = 0;
+	for (byte i = 0; i < sizeof(list); i++)
+	{
+		if (!gpio_pin_reserved(list[i]))
+			pins[n++] = list[i];
+	}
+	return n;
+}
+
+// momentary outputs: gpio_pulse() sets a pin active and the pulse thread sets it back when the pulse ends
+struct GPIOPulse
+{
+	int pin;
+	byte active;
+	ulong end; // millis() at the end of the pulse
+};
+static GPIOPulse pulses[MAX_GPIO_PULSES];
+static byte npulses = 0;
+static bool pulse_thread_started = false;
+static pthread_mutex_t pulse_mutex = PTHREAD_MUTEX_INITIALIZER;
+static pthread_cond_t pulse_cond = PTHREAD_COND_INITIALIZER;
+
+static void *pulse_thread(void *)
+{
+	pthread_mutex_lock(&pulse_mutex);
+	for (;;)
+	{
+		while (npulses == 0)
+			pthread_cond_wait(&pulse_cond, &pulse_mutex);
+		ulong curr = millis();
+		for (byte i = 0; i < npulses;)
+		{
+			if ((long)(curr - pulses[i].end) >= 0)
+			{
+				digitalWrite(pulses[i].pin, 1 - pulses[i].active);
+				pulses[i] = pulses[--npulses];
+			}
+			else
+				i++;
+		}
+		pthread_mutex_unlock(&pulse_mutex);
+		delay(1);
+		pthread_mutex_lock(&pulse_mutex);
+	}
+	return NULL;
+}
+
+/** Emit a pulse on an output pin
+ * The pin is set to active now and back to inactive after ms milliseconds, without blocking.
+ * A new pulse on a pin that is still pulsing extends the pulse.
+ * Returns false if the pulse cannot be timed, the pin is then left inactive.
+ */
+bool gpio_pulse(int pin, byte active, uint16_t ms)
+{
+	pthread_mutex_lock(&pulse_mutex);
+	if (!pulse_thread_started)
+	{
+		pthread_t thread;
+		if (pthread_create(&thread, NULL, pulse_thread, NULL) == 0)
+		{
+			pthread_detach(thread);
+			pulse_thread_started = true;
+		}
+	}
+	byte i;
+	for (i = 0; i < npulses; i++)
+	{
+		if (pulses[i].pin == pin)
+			break;
+	}
+	bool timed = pulse_thread_started && i < MAX_GPIO_PULSES;
+	if (timed)
+	{
+		pinMode(pin, OUTPUT);
+		digitalWrite(pin, active);
+		pulses[i].pin = pin;
+		pulses[i].active = active;
+		pulses[i].end = millis() + ms;
+		if (i == npulses)
+			npulses++;
+		pthread_cond_signal(&pulse_cond);
+	}
+	pthread_mutex_unlock(&pulse_mutex);
+	return timed;
+}
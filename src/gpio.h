@@ -41,13 +41,25 @@
 #define HIGH 1
 #define LOW 0
 
+/** GPIO backends, selected at runtime (see gpio.cpp) */
+#define GPIO_BACKEND_SYSFS 0   // /sys/class/gpio, pins are kernel gpio numbers
+#define GPIO_BACKEND_CHARDEV 1 // gpiochip character device, pins are line offsets
+#define GPIO_DEFAULT_CHIP "/dev/gpiochip0"
+#define MAX_GPIO_PINS 64 // upper bound of the number of pins, see gpio_free_pins
+#define MAX_GPIO_PULSES 8 // pins that can be pulsing at the same time, see gpio_pulse
+
 void pinMode(int pin, byte mode);
 void digitalWrite(int pin, byte value);
 int gpio_fd_open(int pin, int mode = O_WRONLY);
 void gpio_fd_close(int fd);
 void gpio_write(int fd, byte value);
+bool gpio_write_quiet(int fd, byte value); // for signal handlers, see gpio.cpp
 byte digitalRead(int pin);
 // mode can be any of 'rising', 'falling', 'both'
 void attachInterrupt(int pin, const char *mode, void (*isr)(void));
+bool gpio_pin_reserved(int pin); // pin used by the controller itself
+byte gpio_reserved_pins(byte *pins); // pins used by the controller itself, returns the count
+byte gpio_free_pins(byte *pins); // pins available to GPIO stations, returns the count
+bool gpio_pulse(int pin, byte active, uint16_t ms); // momentary output, returns without waiting for the pulse to end
 
 #endif // GPIO_H
@@ -0,0 +1,156 @@
+/* OpenSprinkler Unified (RPI/LINUX) Firmware
+ * Copyright (C) 2015 by Ray Wang (ray@opensprinkler.com)
+ *
+ * Localized strings for notifications and the built-in web UI
+ * Strings must not contain double quotes or backslashes, they are sent inside JSON strings.
+ *
+ * This file is part of the OpenSprinkler library
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see
+ * <http://www.gnu.org/licenses/>.
+ */
+
+#include "OpenSprinkler.h"
+#include "lang.h"
+#include "main.h"
+
+/** Notification strings, in the order of the LANG_ defines
+ * Columns: English, German, French, Spanish
+ */
+static const LangString lang_strings[NUM_LANG_STRINGS] = {
+	{NULL, {"Station %s closed. It ran for %d minutes %d seconds.",
+			"Station %s geschlossen. Laufzeit %d Minuten %d Sekunden.",
+			"Station %s fermée. Elle a fonctionné %d minutes %d secondes.",
+			"Estación %s cerrada. Funcionó %d minutos %d segundos."}},
+	{NULL, {" Flow rate: %d.%02d %s/min", " Durchfluss: %d.%02d %s/min", " Débit : %d.%02d %s/min", " Caudal: %d.%02d %s/min"}},
+	{NULL, {" Program: %s.", " Programm: %s.", " Programme : %s.", " Programa: %s."}},
+	{NULL, {"Manually scheduled Program %s with %d%% water level.",
+			"Programm %s manuell gestartet mit %d%% Wasserstand.",
+			"Programme %s lancé manuellement avec un niveau d'arrosage de %d%%.",
+			"Programa %s iniciado manualmente con nivel de riego del %d%%."}},
+	{NULL, {"Automatically scheduled Program %s with %d%% water level.",
+			"Programm %s automatisch gestartet mit %d%% Wasserstand.",
+			"Programme %s lancé automatiquement avec un niveau d'arrosage de %d%%.",
+			"Programa %s iniciado automáticamente con nivel de riego del %d%%."}},
+	{NULL, {"Sensor %d activated.", "Sensor %d aktiviert.", "Capteur %d activé.", "Sensor %d activado."}},
+	{NULL, {"Sensor %d de-activated.", "Sensor %d deaktiviert.", "Capteur %d désactivé.", "Sensor %d desactivado."}},
+	{NULL, {"Rain delay activated.", "Regenpause aktiviert.", "Report pluie activé.", "Retraso por lluvia activado."}},
+	{NULL, {"Rain delay de-activated.", "Regenpause deaktiviert.", "Report pluie désactivé.", "Retraso por lluvia desactivado."}},
+	{NULL, {"Flow count: %lu, volume: %d.%02d %s",
+			"Impulse: %lu, Volumen: %d.%02d %s",
+			"Impulsions : %lu, volume : %d.%02d %s",
+			"Pulsos: %lu, volumen: %d.%02d %s"}},
+	{NULL, {"External IP updated: ", "Externe IP aktualisiert: ", "IP externe mise à jour : ", "IP externa actualizada: "}},
+	{NULL, {"Water level updated: %d%%.",
+			"Wasserstand aktualisiert: %d%%.",
+			"Niveau d'arrosage mis à jour : %d%%.",
+			"Nivel de riego actualizado: %d%%."}},
+	{NULL, {"Station %s turned off: electrical fault (%s), current %d mA.",
+			"Station %s abgeschaltet: elektrischer Fehler (%s), Strom %d mA.",
+			"Station %s arrêtée : défaut électrique (%s), courant %d mA.",
+			"Estación %s apagada: fallo eléctrico (%s), corriente %d mA."}},
+	{NULL, {"Weather service failed %d times in a row (error %d).",
+			"Wetterdienst %d-mal in Folge fehlgeschlagen (Fehler %d).",
+			"Le service météo a échoué %d fois de suite (erreur %d).",
+			"El servicio meteorológico falló %d veces seguidas (error %d)."}},
+	{NULL, {"Queue full: run of station %s rejected.",
+			"Warteschlange voll: Lauf von Station %s abgelehnt.",
+			"File d'attente pleine : arrosage de la station %s refusé.",
+			"Cola llena: riego de la estación %s rechazado."}},
+	{NULL, {"Queue full: run of station %s dropped.",
+			"Warteschlange voll: Lauf von Station %s verworfen.",
+			"File d'attente pleine : arrosage de la station %s abandonné.",
+			"Cola llena: riego de la estación %s descartado."}},
+	{NULL, {"Process stopped.", "Prozess beendet.", "Processus arrêté.", "Proceso detenido."}},
+	{NULL, {"Process restarted.", "Prozess neu gestartet.", "Processus redémarré.", "Proceso reiniciado."}},
+	{NULL, {"Station %s ran for %d minutes %d seconds without flow. Check the valve and the water supply.",
+			"Station %s lief %d Minuten %d Sekunden ohne Durchfluss. Ventil und Wasserversorgung prüfen.",
+			"La station %s a fonctionné %d minutes %d secondes sans débit. Vérifiez la vanne et l'alimentation en eau.",
+			"La estación %s funcionó %d minutos %d segundos sin caudal. Revise la válvula y el suministro de agua."}},
+	{NULL, {"Frost protection activated at %d F, programs will not start.",
+			"Frostschutz bei %d F aktiviert, Programme starten nicht.",
+			"Protection antigel activée à %d F, les programmes ne démarrent pas.",
+			"Protección antiheladas activada a %d F, los programas no se inician."}},
+	{NULL, {"Frost protection de-activated.", "Frostschutz deaktiviert.", "Protection antigel désactivée.", "Protección antiheladas desactivada."}},
+};
+
+/** Strings of the built-in web UI, see webui.cpp */
+static const LangString lang_ui_strings[] = {
+	{"status", {"Status", "Status", "État", "Estado"}},
+	{"run", {"Run", "Starten", "Lancer", "Regar"}},
+	{"programs", {"Programs", "Programme", "Programmes", "Programas"}},
+	{"program", {"Program", "Programm", "Programme", "Programa"}},
+	{"password", {"Password", "Passwort", "Mot de passe", "Contraseña"}},
+	{"login", {"Log in", "Anmelden", "Connexion", "Iniciar sesión"}},
+	{"logout", {"Log out", "Abmelden", "Déconnexion", "Cerrar sesión"}},
+	{"wrong_pw", {"Wrong password", "Falsches Passwort", "Mot de passe incorrect", "Contraseña incorrecta"}},
+	{"enabled", {"Enabled", "Aktiviert", "Activé", "Activado"}},
+	{"disabled", {"Disabled", "Deaktiviert", "Désactivé", "Desactivado"}},
+	{"water_level", {"water level", "Wasserstand", "niveau d'arrosage", "nivel de riego"}},
+	{"rain_delay", {"rain delay", "Regenpause", "report pluie", "retraso por lluvia"}},
+	{"stop_all", {"Stop all stations", "Alle Stationen stoppen", "Arrêter toutes les stations", "Detener todas las estaciones"}},
+	{"station", {"Station", "Station", "Station", "Estación"}},
+	{"minutes", {"Minutes", "Minuten", "Minutes", "Minutos"}},
+	{"start", {"Start", "Starten", "Démarrer", "Iniciar"}},
+	{"running", {"running", "läuft", "en cours", "regando"}},
+	{"waiting", {"waiting", "wartet", "en attente", "en espera"}},
+	{"stop", {"Stop", "Stopp", "Arrêter", "Detener"}},
+	{"edit", {"Edit", "Bearbeiten", "Modifier", "Editar"}},
+	{"delete", {"Delete", "Löschen", "Supprimer", "Eliminar"}},
+	{"delete_confirm", {"Delete this program?", "Dieses Programm löschen?", "Supprimer ce programme ?", "¿Eliminar este programa?"}},
+	{"new_program", {"New program", "Neues Programm", "Nouveau programme", "Nuevo programa"}},
+	{"name", {"Name", "Name", "Nom", "Nombre"}},
+	{"use_weather", {"Use weather adjustment", "Wetteranpassung verwenden", "Utiliser l'ajustement météo", "Usar ajuste meteorológico"}},
+	{"repeat", {"Repeat", "Wiederholen", "Répétition", "Repetir"}},
+	{"weekdays", {"Days of the week", "Wochentage", "Jours de la semaine", "Días de la semana"}},
+	{"interval", {"Every n days", "Alle n Tage", "Tous les n jours", "Cada n días"}},
+	{"every", {"Every", "Alle", "Tous les", "Cada"}},
+	{"days_starting", {"days, starting in", "Tage, beginnend in", "jours, premier dans", "días, empezando en"}},
+	{"days", {"days", "Tagen", "jours", "días"}},
+	{"start_times", {"Start times", "Startzeiten", "Heures de début", "Horas de inicio"}},
+	{"durations", {"Minutes per station", "Minuten pro Station", "Minutes par station", "Minutos por estación"}},
+	{"save", {"Save", "Speichern", "Enregistrer", "Guardar"}},
+	{"cancel", {"Cancel", "Abbrechen", "Annuler", "Cancelar"}},
+	{"error", {"Error", "Fehler", "Erreur", "Error"}},
+	{"sunrise", {"sunrise", "Sonnenaufgang", "lever du soleil", "amanecer"}},
+	{"sunset", {"sunset", "Sonnenuntergang", "coucher du soleil", "atardecer"}},
+	{"mon", {"Mon", "Mo", "Lun", "Lun"}},
+	{"tue", {"Tue", "Di", "Mar", "Mar"}},
+	{"wed", {"Wed", "Mi", "Mer", "Mié"}},
+	{"thu", {"Thu", "Do", "Jeu", "Jue"}},
+	{"fri", {"Fri", "Fr", "Ven", "Vie"}},
+	{"sat", {"Sat", "Sa", "Sam", "Sáb"}},
+	{"sun", {"Sun", "So", "Dim", "Dom"}},
+};
+
+/** Text of a string in the configured language */
+const char *lang_text(const LangString *s)
+{
+	byte lang = os.iopts[IOPT_LANGUAGE];
+	if (lang >= NUM_LANGUAGES || !s->text[lang] || !s->text[lang][0])
+		lang = LANGUAGE_EN;
+	return s->text[lang];
+}
+
+/** Notification string in the configured language */
+const char *lang_string(byte id)
+{
+	return lang_text(lang_strings + id);
+}
+
+/** UI string by index, NULL past the last one */
+const LangString *lang_ui_string(byte i)
+{
+	return (i < sizeof(lang_ui_strings) / sizeof(LangString)) ? lang_ui_strings + i : NULL;
+}
@@ -0,0 +1,68 @@
+/* OpenSprinkler Unified (RPI/LINUX) Firmware
+ * Copyright (C) 2015 by Ray Wang (ray@opensprinkler.com)
+ *
+ * Localized strings header file
+ *
+ * This file is part of the OpenSprinkler library
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see
+ * <http://www.gnu.org/licenses/>.
+ */
+
+#ifndef _LANG_H
+#define _LANG_H
+
+#include "defines.h"
+
+/** Notification strings, in the language set by IOPT_LANGUAGE
+ * These are printf formats, their arguments are listed next to them.
+ */
+enum
+{
+	LANG_STATION_RAN,	   // station name, minutes, seconds
+	LANG_FLOW_RATE,		   // flow rate integer part, fraction, volume unit
+	LANG_STATION_PROGRAM,  // program name
+	LANG_PROGRAM_MANUAL,   // program name, water level
+	LANG_PROGRAM_AUTO,	   // program name, water level
+	LANG_SENSOR_ON,		   // sensor number
+	LANG_SENSOR_OFF,	   // sensor number
+	LANG_RAINDELAY_ON,
+	LANG_RAINDELAY_OFF,
+	LANG_FLOW_COUNT,	   // pulse count, volume integer part, fraction, volume unit
+	LANG_EXTERNAL_IP,	   // followed by the address
+	LANG_WATER_LEVEL,	   // water level
+	LANG_ELECTRICAL_FAULT, // station name, fault, current
+	LANG_WEATHER_FAIL,	   // failure count, error code
+	LANG_QUEUE_REJECTED,   // station name
+	LANG_QUEUE_DROPPED,	   // station name
+	LANG_PROCESS_STOPPED,
+	LANG_PROCESS_RESTARTED,
+	LANG_NO_FLOW,		   // station name, minutes, seconds
+	LANG_FROST_ON,		   // temperature in F
+	LANG_FROST_OFF,
+	NUM_LANG_STRINGS
+};
+
+/** String translated to each language; an empty translation falls back to English */
+struct LangString
+{
+	const char *key; // name used by the built-in web UI
+	const char *text[NUM_LANGUAGES];
+};
+
+const char *lang_string(byte id);
+const LangString *lang_ui_string(byte i);
+const char *lang_text(const LangString *s);
+
+#endif // _LANG_H
@@ -22,22 +22,30 @@
  */
 
 #include <limits.h>
+#include <signal.h>
+#include <stddef.h>
+#include <sys/socket.h>
+#include <sys/un.h>
 
 #include "OpenSprinkler.h"
+#include "rf.h"
 #include "program.h"
 #include "weather.h"
+#include "calendar.h"
+#include "budget.h"
+#include "lang.h"
+#include "timer.h"
 #include "opensprinkler_server.h"
 #include "mqtt.h"
+#include "cli.h"
+#include "main.h"
 
 // header and defs for RPI
 EthernetServer *m_server = 0;
 EthernetClient *m_client = 0;
 
-void reset_all_stations();
-void reset_all_stations_immediate();
-void push_message(int type, uint32_t lval = 0, float fval = 0.f, const char *sval = NULL);
-void manual_start_program(byte, byte);
-void remote_http_callback(char *);
+void start_switch_program(byte);
+boolean enqueue_runonce(char *);
 
 // Small variations have been added to the timing values below
 // to minimize conflicting events
@@ -45,11 +53,13 @@ void remote_http_callback(char *);
 #define CHECK_NETWORK_INTERVAL 601			 // Network checking timeout (in seconds)
 #define CHECK_WEATHER_TIMEOUT 21613L		 // Weather check interval (in seconds)
 #define CHECK_WEATHER_SUCCESS_TIMEOUT 86400L // Weather check success interval (in seconds)
+#define CHECK_WEATHER_RETRY_INTERVAL 61L	 // Weather retry interval after the first failure (in seconds)
 #define LCD_BACKLIGHT_TIMEOUT 15			 // LCD backlight timeout (in seconds))
 #define PING_TIMEOUT 200					 // Ping test timeout (in ms)
 #define UI_STATE_MACHINE_INTERVAL 50		 // how often does ui_state_machine run (in ms)
 #define CLIENT_READ_TIMEOUT 5				 // client read timeout (in seconds)
 #define DHCP_CHECKLEASE_INTERVAL 3600L		 // DHCP check lease interval (in seconds)
+#define LOOP_IDLE_TIMEOUT 50				 // maximum time the main loop waits for a web request (in ms)
 // Define buffers: need them to be sufficiently large to cover string option reading
 char ether_buffer[ETHER_BUFFER_SIZE * 2]; // ethernet buffer, make it twice as large to allow overflow
 char tmp_buffer[TMP_BUFFER_SIZE * 2];	  // scratch buffer, make it twice as large to allow overflow
@@ -62,14 +72,73 @@ ProgramData pd;	  // ProgramdData object
  * flow_begin - time when valve turns on
  * flow_start - time when flow starts being measured (i.e. 2 mins after flow_begin approx
  * flow_stop - time when valve turns off (last rising edge pulse detected before off)
- * flow_gallons - total # of gallons+1 from flow_start to flow_stop
- * flow_last_gpm - last flow rate measured (averaged over flow_gallons) from last valve stopped (used to write to log file). */
-ulong flow_begin, flow_start, flow_stop, flow_gallons;
+ * flow_pulses - total # of pulses+1 from flow_start to flow_stop
+ * flow_last_lpm - last flow rate measured in liters per minute (averaged over flow_pulses) from last valve stopped (used to write to log file). */
+ulong flow_begin, flow_start, flow_stop, flow_pulses;
 ulong flow_count = 0;
+ulong flow_last_pulse = 0;	   // time (in ms) of the most recent flow pulse
+ulong flow_pulse_interval = 0; // time (in ms) between the two most recent flow pulses
 byte prev_flow_state = HIGH;
-float flow_last_gpm = 0;
+float flow_last_lpm = 0;
+
+// valve current checks of the stations that opened recently
+ulong current_check_time[MAX_NUM_STATIONS];	  // time when the check of each station is due (0 means none)
+uint16_t current_before_on[MAX_NUM_STATIONS]; // current (in mA) before each station opened
+
+// flow count when each station opened, and the most recent run without flow, for the log
+ulong flow_count_on[MAX_NUM_STATIONS];
+byte noflow_sid = 0;
+ulong noflow_pulses = 0;
+
+// program name and run id of the most recent station run, for the log
+char lastrun_pname[PROGRAM_NAME_SIZE + 1] = "";
+ulong lastrun_rid = 0;
+
+// most recent program run cut short by its watering window, for the log
+byte waterwindow_sid = 0;
+ulong waterwindow_cut = 0; // time (in seconds) that was cut
+
+// most recent program run skipped or reduced by the rain forecast, for the log
+byte forecast_pid = 0;
+byte forecast_wl = 0; // water time kept (in percent, 0 if skipped)
+
+// most recent station state that did not match the run-time queue, for the log
+byte mismatch_sid = 0;
+byte mismatch_kind = 0; // STATE_MISMATCH_*
+// frost protection, see check_frost()
+int frost_temp = 0;			 // latest temperature (in F) frost protection is based on
+bool frost_temp_known = false; // whether a recent temperature is known
+ulong frost_on_lasttime = 0;	 // time frost protection started
+
+// zone test started from the web server
+byte zonetest_total = 0; // number of stations in the zone test
+ulong zonetest_end = 0;	 // time when the last station of the zone test stops
 
 uint32_t reboot_timer = 0;
+byte reboot_cause = REBOOT_CAUSE_TIMER; // reboot cause recorded when reboot_timer expires
+
+/** Convert a volume (or rate) in liters to the volume unit of the unit system */
+float flow_from_liters(float liters)
+{
+	return (os.iopts[IOPT_UNIT_SYSTEM] == UNIT_SYSTEM_METRIC) ? liters : liters / LITERS_PER_GALLON;
+}
+
+/** Name of the volume unit of the unit system */
+const char *flow_unit()
+{
+	return (os.iopts[IOPT_UNIT_SYSTEM] == UNIT_SYSTEM_METRIC) ? "L" : "gal";
+}
+
+/** Volume of one flow sensor pulse in liters
+ * The pulse rate is set in 1/100 of the volume unit of the unit system.
+ */
+float flow_pulse_liters()
+{
+	ulong rate = os.iopts[IOPT_PULSE_RATE_1];
+	rate = (rate << 8) + os.iopts[IOPT_PULSE_RATE_0];
+	float volume = rate / 100.f;
+	return (os.iopts[IOPT_UNIT_SYSTEM] == UNIT_SYSTEM_METRIC) ? volume : volume * LITERS_PER_GALLON;
+}
 
 void flow_poll()
 {
@@ -82,33 +151,90 @@ void flow_poll()
 	prev_flow_state = curr_flow_state;
 	ulong curr = millis();
 	flow_count++;
+	flow_pulse_interval = flow_last_pulse ? curr - flow_last_pulse : 0;
+	flow_last_pulse = curr;
 
 	/* RAH implementation of flow sensor */
 	if (flow_start == 0)
 	{
-		flow_gallons = 0;
+		flow_pulses = 0;
 		flow_start = curr;
 	} // if first pulse, record time
 	if ((curr - flow_start) < 90000)
 	{
-		flow_gallons = 0;
+		flow_pulses = 0;
 	} // wait 90 seconds before recording flow_begin
 	else
 	{
-		if (flow_gallons == 1)
+		if (flow_pulses == 1)
 		{
 			flow_begin = curr;
 		}
 	}
 	flow_stop = curr; // get time in ms for stop
-	flow_gallons++;	  // increment gallon count for each poll
+	flow_pulses++;	  // increment pulse count for each poll
 					  /* End of RAH implementation of flow sensor */
 }
 
+static volatile sig_atomic_t shutdown_requested = 0;
+
+/** Request a clean shutdown on SIGTERM or SIGINT */
+static void shutdown_signal_handler(int sig)
+{
+	shutdown_requested = 1;
+}
+
+/** Returns true once SIGTERM or SIGINT has been received */
+bool shutdown_pending()
+{
+	return shutdown_requested;
+}
+
+/** Close the valves before the process dies from a fatal signal
+ * Only the shift register valves are closed, special stations stay as they are (see close_valves_on_crash).
+ */
+static void fatal_signal_handler(int sig)
+{
+	os.close_valves_on_crash();
+	// restore the default action and raise the signal again, so that the process terminates as usual
+	signal(sig, SIG_DFL);
+	raise(sig);
+}
+
+/** Send a state to systemd (sd_notify protocol), if the controller runs as a notify service */
+static void service_notify(const char *state)
+{
+	const char *path = getenv("NOTIFY_SOCKET");
+	if (!path || (path[0] != '/' && path[0] != '@') || strlen(path) >= sizeof(((struct sockaddr_un *)0)->sun_path))
+		return;
+	struct sockaddr_un addr;
+	memset(&addr, 0, sizeof(addr));
+	addr.sun_family = AF_UNIX;
+	strcpy(addr.sun_path, path);
+	if (addr.sun_path[0] == '@')
+		addr.sun_path[0] = 0; // abstract socket
+	int sock = socket(AF_UNIX, SOCK_DGRAM | SOCK_CLOEXEC, 0);
+	if (sock < 0)
+		return;
+	sendto(sock, state, strlen(state), 0, (struct sockaddr *)&addr, offsetof(struct sockaddr_un, sun_path) + strlen(path));
+	close(sock);
+}
+
+static ulong watchdog_interval = 0; // how often the systemd watchdog is told the loop runs (in ms), 0 if it is not used
+
 void do_setup()
 {
 	initialiseEpoch();	// initialize time reference for millis() and micros()
+	setup_data_path();	// check the data files can be written, or find another place for them
 	os.begin();			// OpenSprinkler init
+
+	signal(SIGSEGV, fatal_signal_handler);
+	signal(SIGBUS, fatal_signal_handler);
+	signal(SIGFPE, fatal_signal_handler);
+	signal(SIGILL, fatal_signal_handler);
+	signal(SIGABRT, fatal_signal_handler);
+	signal(SIGTERM, shutdown_signal_handler);
+	signal(SIGINT, shutdown_signal_handler);
 	os.options_setup(); // Setup options
 
 	pd.init(); // ProgramData init
@@ -127,23 +253,165 @@ void do_setup()
 
 	os.mqtt.init();
 	os.status.req_mqtt_restart = true;
+
+	os.control.begin();
+	os.display.begin();
+
+	// the watchdog is notified at half its timeout
+	const char *usec = getenv("WATCHDOG_USEC");
+	if (usec)
+		watchdog_interval = strtoul(usec, NULL, 10) / 2000;
+	service_notify("READY=1");
 }
 
-void write_log(byte type, ulong curr_time);
-void schedule_all_stations(ulong curr_time);
+void set_program_busy(ulong curr_time);
+ulong water_window_start(RuntimeQueueStruct *q, ulong t);
 void turn_on_station(byte sid);
-void turn_off_station(byte sid, ulong curr_time);
+void check_current_fault(ulong curr_time);
+void check_station_state(ulong curr_time);
 void process_dynamic_events(ulong curr_time);
 void check_network();
 void check_weather();
+void check_calendar();
+void check_forecast();
+void check_frost(ulong curr_time);
+bool forecast_scale(ProgramExtra *px, ulong curr_time, byte *scale);
+bool program_running(ulong curr_time);
+void cut_program_runs(ulong curr_time);
 bool process_special_program_command(const char *, uint32_t curr_time);
 void perform_ntp_sync();
-void delete_log(char *name);
 void handle_web_request(char *p);
 
-/** Main Loop */
+static ulong master_hold[2]; // time until which each master stays on after its stations stopped
+
+/** Adjusted on or off time of a master in seconds
+ * The minute option extends the range, a zero minute value falls back to the seconds option.
+ */
+static long master_adjust(byte sec_oid, byte min_oid)
+{
+	byte m = os.iopts[min_oid];
+	if (m != MASTER_ADJ_MIN_ZERO)
+		return ((long)m - MASTER_ADJ_MIN_ZERO) * 60;
+	return water_time_decode_signed(os.iopts[sec_oid]);
+}
+
+/** Whether a master station should be on
+ * A master is on while any station set to use it is on, within the adjusted on and off times of its run.
+ * Stations of any type count. If the station bit of a station is on but its station_qid is not
+ * a queue element, the master is kept on rather than reading past the queue.
+ * A negative on time turns the master on before a waiting run starts, a positive off time
+ * keeps it on after its stations stopped. The master delay of a station (see master_delay)
+ * turns the master on that much earlier again.
+ */
+static byte master_bit(byte mi, byte mas, const byte *attrib, long on_adj, long off_adj, ulong curr_time)
+{
+	byte on = 0;
+	for (byte sid = 0; sid < os.nstations; sid++)
+	{
+		// skip if this is the master station
+		if (mas == sid + 1)
+			continue;
+		byte bid = sid >> 3;
+		byte s = sid & 0x07;
+		// if this station is running and is set to activate master
+		if (!(os.station_bits[bid] & (1 << s)) || !(attrib[bid] & (1 << s)))
+			continue;
+		if (off_adj > 0 && curr_time + off_adj > master_hold[mi])
+			master_hold[mi] = curr_time + off_adj;
+		byte qid = pd.station_qid[sid];
+		if (qid >= pd.nqueue)
+		{
+			on = 1;
+			continue;
+		}
+		RuntimeQueueStruct *q = pd.queue + qid;
+		// check if timing is within the acceptable range
+		if ((long)curr_time >= (long)q->st + on_adj - os.attrib_mdel[sid] && (long)curr_time <= (long)(q->st + q->dur) + off_adj)
+			on = 1;
+	}
+	// waiting runs that start within the on time
+	for (RuntimeQueueStruct *q = pd.queue; q < pd.queue + pd.nqueue; q++)
+	{
+		if (q->sid >= os.nstations || mas == q->sid + 1 || !q->dur || q->st <= curr_time)
+			continue;
+		if ((attrib[q->sid >> 3] & (1 << (q->sid & 0x07))) && (long)curr_time >= (long)q->st + on_adj - os.attrib_mdel[q->sid])
+			on = 1;
+	}
+	if (curr_time < master_hold[mi])
+		on = 1;
+	return on;
+}
+
+/** Whether a run of another station keeps a master on at time t, see master_bit */
+static bool master_kept_on(RuntimeQueueStruct *q, byte mas, const byte *attrib, long off_adj, ulong t)
+{
+	for (RuntimeQueueStruct *r = pd.queue; r < pd.queue + pd.nqueue; r++)
+	{
+		if (r == q || !r->st || !r->dur || r->sid >= os.nstations || mas == r->sid + 1)
+			continue;
+		if (!(attrib[r->sid >> 3] & (1 << (r->sid & 0x07))))
+			continue;
+		if (r->st <= t && t <= r->st + r->dur + (off_adj > 0 ? off_adj : 0))
+			return true;
+	}
+	return false;
+}
+
+/** Delay (in seconds) before a run opens its station, so that its master can build up pressure first
+ * This is the master delay of the station, unless none of its masters has to turn on for the run
+ * because a run of another station keeps it on at start time st.
+ */
+static ulong master_delay(RuntimeQueueStruct *q, ulong st)
+{
+	byte sid = q->sid;
+	byte bid = sid >> 3;
+	byte s = 1 << (sid & 0x07);
+	if (!os.attrib_mdel[sid])
+		return 0;
+	bool m1 = os.status.mas > 0 && os.status.mas != sid + 1 && (os.attrib_mas[bid] & s);
+	bool m2 = os.status.mas2 > 0 && os.status.mas2 != sid + 1 && (os.attrib_mas2[bid] & s);
+	if (m1 && !master_kept_on(q, os.status.mas, os.attrib_mas, master_adjust(IOPT_MASTER_OFF_ADJ, IOPT_MASTER_OFF_ADJ_MIN), st))
+		return os.attrib_mdel[sid];
+	if (m2 && !master_kept_on(q, os.status.mas2, os.attrib_mas2, master_adjust(IOPT_MASTER_OFF_ADJ_2, IOPT_MASTER_OFF_ADJ_MIN_2), st))
+		return os.attrib_mdel[sid];
+	return 0;
+}
+
+/** Set the bits of the master stations */
+static void handle_masters(ulong curr_time)
+{
+	if (os.status.mas > 0)
+		os.set_station_bit(os.status.mas - 1, master_bit(0, os.status.mas, os.attrib_mas,
+			master_adjust(IOPT_MASTER_ON_ADJ, IOPT_MASTER_ON_ADJ_MIN), master_adjust(IOPT_MASTER_OFF_ADJ, IOPT_MASTER_OFF_ADJ_MIN), curr_time));
+	if (os.status.mas2 > 0)
+		os.set_station_bit(os.status.mas2 - 1, master_bit(1, os.status.mas2, os.attrib_mas2,
+			master_adjust(IOPT_MASTER_ON_ADJ_2, IOPT_MASTER_ON_ADJ_MIN_2), master_adjust(IOPT_MASTER_OFF_ADJ_2, IOPT_MASTER_OFF_ADJ_MIN_2), curr_time));
+}
+
+/** Main Loop
+ * All controller state is owned by this loop: web requests, MQTT messages and
+ * local control requests are handled here in turn, so no locking is needed.
+ * The other threads only touch their own state, which they share with this loop under a mutex:
+ * - the weather worker fills the wt_ request and response of weather, forecast and calendar requests,
+ *   handed back through weather_worker_result()
+ * - the HTTP worker (job_worker() in OpenSprinkler.cpp) sends the requests posted by
+ *   post_http_request(), it touches the job queue and spe_status under job_mutex
+ * - the RF transmit thread (rf_worker() in rf.cpp) sends the codes queued by rf_transmit() on PIN_RFTX,
+ *   it touches the RF queue under rf_mutex and sets spe_status through set_spe_status()
+ * - the pulse thread (pulse_thread() in gpio.cpp) ends the pulses started by gpio_pulse(),
+ *   it touches the pulse list under pulse_mutex and writes only the pins that are pulsing
+ * - the GPIO interrupt threads on OSPI
+ * Any thread may write the debug log, which has a mutex of its own.
+ */
 void do_loop()
 {
+	static ulong watchdog_lastnotify = 0;
+	if (watchdog_interval && millis() - watchdog_lastnotify >= watchdog_interval)
+	{
+		service_notify("WATCHDOG=1");
+		watchdog_lastnotify = millis();
+	}
+
 	// handle flow sensor using polling every 1ms (maximum freq 1/(2*1ms)=500Hz)
 	static ulong flowpoll_timeout = 0;
 	if (os.iopts[IOPT_SENSOR1_TYPE] == SENSOR_TYPE_FLOW)
@@ -167,7 +435,10 @@ void do_loop()
 	time_t curr_time = os.now_tz();
 
 	// ====== Process Ethernet packets ======
-	EthernetClient client = m_server->available();
+	// the loop sleeps here until a web request arrives: for 1 ms while the flow sensor
+	// is polled, otherwise for up to LOOP_IDLE_TIMEOUT
+	bool flowsense = (os.iopts[IOPT_SENSOR1_TYPE] == SENSOR_TYPE_FLOW);
+	EthernetClient client = m_server->available(flowsense ? 1 : LOOP_IDLE_TIMEOUT);
 	if (client)
 	{
 		while (true)
@@ -204,6 +475,9 @@ void do_loop()
 	}
 	os.mqtt.loop();
 
+	// ====== Process local control requests ======
+	os.control.loop();
+
 	// The main control loop runs once every second
 	if (curr_time != last_time)
 	{
@@ -212,6 +486,20 @@ void do_loop()
 		if (os.button_timeout)
 			os.button_timeout--;
 
+		// ====== Check scheduled rain delay windows ======
+		// each window occurrence is applied once, so a rain delay stopped manually stays off
+		static ulong rdwindow_applied = 0;
+		ulong rdw_end = os.rdwindow_end(curr_time);
+		if (rdw_end && rdw_end != rdwindow_applied)
+		{
+			rdwindow_applied = rdw_end;
+			if (os.nvdata.rd_stop_time < rdw_end)
+			{
+				os.nvdata.rd_stop_time = rdw_end;
+				os.nvdata_save();
+			}
+		}
+
 		// ====== Check raindelay status ======
 		if (os.status.rain_delayed)
 		{
@@ -228,6 +516,10 @@ void do_loop()
 			}
 		}
 
+		// ====== Check water scale override ======
+		if (os.nvdata.wl_stop_time && curr_time >= os.nvdata.wl_stop_time)
+			os.wl_override_stop();
+
 		// ====== Check controller status changes and write log ======
 		if (os.old_status.rain_delayed != os.status.rain_delayed)
 		{
@@ -246,6 +538,9 @@ void do_loop()
 			os.old_status.rain_delayed = os.status.rain_delayed;
 		}
 
+		// ====== Check frost protection ======
+		check_frost(curr_time);
+
 		// ====== Check binary (i.e. rain or soil) sensor status ======
 		os.detect_binarysensor_status(curr_time);
 
@@ -289,13 +584,22 @@ void do_loop()
 		}
 		if (pswitch & 0x01)
 		{
-			if (pd.nprograms > 0)
-				manual_start_program(1, 0);
+			start_switch_program(os.iopts[IOPT_PSWITCH1_PROG]);
 		}
 		if (pswitch & 0x02)
 		{
-			if (pd.nprograms > 1)
-				manual_start_program(2, 0);
+			start_switch_program(os.iopts[IOPT_PSWITCH2_PROG]);
+		}
+
+		// ===== Check virtual program switch status =====
+		byte vswitch = os.detect_vswitch_status();
+		for (byte i = 0; i < MAX_NUM_VSWITCHES; i++)
+		{
+			if (vswitch & (1 << i))
+			{
+				reset_all_stations_immediate(); // as with physical switches
+				start_switch_program(os.iopts[IOPT_VSWITCH1_PROG + i]);
+			}
 		}
 
 		// ====== Schedule program data ======
@@ -311,57 +615,117 @@ void do_loop()
 			for (pid = 0; pid < pd.nprograms; pid++)
 			{
 				pd.read(pid, &prog); // TODO future: reduce load time
-				if (prog.check_match(curr_time))
+				ProgramExtra px;
+				pd.read_extra(pid, &px);
+				if (prog.check_match(curr_time, px.ivanchor))
 				{
 					// program match found
 					// check and process special program command
 					if (process_special_program_command(prog.name, curr_time))
+					{
+						dry_run_log("program %d (%s) is a special command", pid + 1, prog.name);
 						continue;
+					}
 
-					// process all selected stations
-					for (sid = 0; sid < os.nstations; sid++)
+					// programs do not start while frost protection is on
+					if (os.status.frost)
+					{
+						dry_run_log("program %d (%s) skipped: frost", pid + 1, prog.name);
+						continue;
+					}
+
+					// skip the program if the calendar suppresses watering today
+					if (prog.use_calendar && calendar_skip_day(curr_time))
+					{
+						dry_run_log("program %d (%s) skipped: calendar", pid + 1, prog.name);
+						continue;
+					}
+
+					// if a program is still running, skip this start or cut the running program short,
+					// otherwise the runs are queued after it
+					if (px.collide != PROGRAM_COLLIDE_STACK && program_running(curr_time))
 					{
-						bid = sid >> 3;
-						s = sid & 0x07;
-						// skip if the station is a master station (because master cannot be scheduled independently
-						if ((os.status.mas == sid + 1) || (os.status.mas2 == sid + 1))
+						if (px.collide == PROGRAM_COLLIDE_SKIP)
+						{
+							dry_run_log("program %d (%s) skipped: another program is running", pid + 1, prog.name);
 							continue;
+						}
+						dry_run_log("program %d (%s) cuts the running program short", pid + 1, prog.name);
+						cut_program_runs(curr_time);
+					}
 
-						// if station has non-zero water time and the station is not disabled
-						if (prog.durations[sid] && !(os.attrib_dis[bid] & (1 << s)))
+					// skip or reduce the program if rain is forecast
+					byte fc_wl = 100;
+					if (forecast_scale(&px, curr_time, &fc_wl))
+					{
+						forecast_pid = pid + 1;
+						forecast_wl = fc_wl;
+						write_log(LOGDATA_FORECAST, curr_time);
+						if (!fc_wl)
 						{
-							// water time is scaled by watering percentage
-							ulong water_time = water_time_resolve(prog.durations[sid]);
-							// if the program is set to use weather scaling
-							if (prog.use_weather)
-							{
-								byte wl = os.iopts[IOPT_WATER_PERCENTAGE];
-								water_time = water_time * wl / 100;
-								if (wl < 20 && water_time < 10) // if water_percentage is less than 20% and water_time is less than 10 seconds
-																// do not water
-									water_time = 0;
-							}
-
-							if (water_time)
+							dry_run_log("program %d (%s) skipped: rain forecast", pid + 1, prog.name);
+							continue;
+						}
+						dry_run_log("program %d (%s) reduced to %d%%: rain forecast", pid + 1, prog.name, fc_wl);
+					}
+
+					// with cycle and soak, each station's water time is split into several cycles,
+					// queued round by round so that the stations take turns
+					byte ncycles = (px.cycles > 1) ? px.cycles : 1;
+
+					// process all selected stations
+					for (byte c = 0; c < ncycles; c++)
+					{
+						for (sid = 0; sid < os.nstations; sid++)
+						{
+							bid = sid >> 3;
+							s = sid & 0x07;
+							// skip if the station is a master station (because master cannot be scheduled independently
+							if ((os.status.mas == sid + 1) || (os.status.mas2 == sid + 1))
+								continue;
+
+							// if station has non-zero water time and the station is not disabled
+							if (prog.durations[sid] && !(os.attrib_dis[bid] & (1 << s)))
 							{
-								// check if water time is still valid
-								// because it may end up being zero after scaling
-								q = pd.enqueue();
-								if (q)
+								// water time is scaled by watering percentage
+								ulong water_time = water_time_resolve(prog.durations[sid]);
+								// if the program is set to use weather scaling,
+								// stations with a moisture budget water what their budget needs instead
+								if (prog.use_weather && !budget_water_time(sid, &water_time))
 								{
-									q->st = 0;
-									q->dur = water_time;
-									q->sid = sid;
-									q->pid = pid + 1;
-									match_found = true;
+									byte wl = os.iopts[IOPT_WATER_PERCENTAGE];
+									water_time = water_time * wl / 100;
+									if (wl < 20 && water_time < 10) // if water_percentage is less than 20% and water_time is less than 10 seconds
+																	// do not water
+										water_time = 0;
 								}
-								else
+								water_time = water_time * fc_wl / 100;
+								// the first cycle also takes the remainder
+								water_time = water_time / ncycles + ((c == 0) ? water_time % ncycles : 0);
+
+								if (water_time)
+								{
+									// check if water time is still valid
+									// because it may end up being zero after scaling
+									// the first cycle follows the previous run of the station without a soak
+									q = pd.enqueue(sid, pid + 1, water_time, prog.name, c ? (uint16_t)px.soak * 60 : 0);
+									if (q)
+									{
+										match_found = true;
+										dry_run_log("program %d (%s) queues station %d for %lu s", pid + 1, prog.name, sid + 1, water_time);
+									}
+									else
+									{
+										dry_run_log("program %d (%s) skipped station %d: queue full", pid + 1, prog.name, sid + 1);
+									}
+								} // if water_time
+								else if (c == 0)
 								{
-									// queue is full
+									dry_run_log("program %d (%s) skipped station %d: water time scaled to 0", pid + 1, prog.name, sid + 1);
 								}
-							} // if water_time
-						}	  // if prog.durations[sid]
-					}		  // for sid
+							}	  // if prog.durations[sid]
+						}		  // for sid
+					}			  // for c
 					if (match_found)
 					{
 						push_message(NOTIFY_PROGRAM_SCHED, pid, prog.use_weather ? os.iopts[IOPT_WATER_PERCENTAGE] : 100);
@@ -394,6 +758,19 @@ void do_loop()
 		// If so, do station run-time keeping
 		if (os.status.program_busy)
 		{
+			// cut program runs short when their watering window closes
+			for (q = pd.queue; q < pd.queue + pd.nqueue; q++)
+			{
+				if (!q->dur || q->st > (ulong)curr_time || (ulong)curr_time >= q->st + q->dur)
+					continue; // only runs that are active now, runs that have not started wait for the window
+				if (water_window_start(q, curr_time) == (ulong)curr_time)
+					continue;
+				waterwindow_sid = q->sid;
+				waterwindow_cut = q->st + q->dur - curr_time;
+				q->dur = curr_time - q->st; // the station is turned off below
+				write_log(LOGDATA_WATERWINDOW, curr_time);
+			}
+
 			// first, go through run time queue to assign queue elements to stations
 			q = pd.queue;
 			qid = 0;
@@ -412,9 +789,9 @@ void do_loop()
 			for (bid = 0; bid < os.nboards; bid++)
 			{
 				bitvalue = os.station_bits[bid];
-				for (s = 0; s < 8; s++)
+				for (s = 0; s < SHIFT_REGISTER_LINES; s++)
 				{
-					byte sid = bid * 8 + s;
+					byte sid = bid * SHIFT_REGISTER_LINES + s;
 
 					// skip master station
 					if (os.status.mas == sid + 1)
@@ -489,8 +866,9 @@ void do_loop()
 			// reset all stations
 			if (!pd.nqueue)
 			{
-				// turn off all stations
+				// turn off all stations, masters may stay on for their off time
 				os.clear_all_station_bits();
+				handle_masters(curr_time);
 				os.apply_all_station_bits();
 				// reset runtime
 				pd.reset_runtime();
@@ -500,7 +878,7 @@ void do_loop()
 				if (os.iopts[IOPT_SENSOR1_TYPE] == SENSOR_TYPE_FLOW)
 				{
 					write_log(LOGDATA_FLOWSENSE, curr_time);
-					push_message(NOTIFY_FLOWSENSOR, (flow_count > os.flowcount_log_start) ? (flow_count - os.flowcount_log_start) : 0);
+					push_message(NOTIFY_FLOWSENSOR, get_flow_log_count());
 				}
 
 				// in case some options have changed while executing the program
@@ -509,63 +887,8 @@ void do_loop()
 			}
 		} // if_some_program_is_running
 
-		// handle master
-		if (os.status.mas > 0)
-		{
-			int16_t mas_on_adj = water_time_decode_signed(os.iopts[IOPT_MASTER_ON_ADJ]);
-			int16_t mas_off_adj = water_time_decode_signed(os.iopts[IOPT_MASTER_OFF_ADJ]);
-			byte masbit = 0;
-
-			for (sid = 0; sid < os.nstations; sid++)
-			{
-				// skip if this is the master station
-				if (os.status.mas == sid + 1)
-					continue;
-				bid = sid >> 3;
-				s = sid & 0x07;
-				// if this station is running and is set to activate master
-				if ((os.station_bits[bid] & (1 << s)) && (os.attrib_mas[bid] & (1 << s)))
-				{
-					q = pd.queue + pd.station_qid[sid];
-					// check if timing is within the acceptable range
-					if (curr_time >= q->st + mas_on_adj &&
-						curr_time <= q->st + q->dur + mas_off_adj)
-					{
-						masbit = 1;
-						break;
-					}
-				}
-			}
-			os.set_station_bit(os.status.mas - 1, masbit);
-		}
-		// handle master2
-		if (os.status.mas2 > 0)
-		{
-			int16_t mas_on_adj_2 = water_time_decode_signed(os.iopts[IOPT_MASTER_ON_ADJ_2]);
-			int16_t mas_off_adj_2 = water_time_decode_signed(os.iopts[IOPT_MASTER_OFF_ADJ_2]);
-			byte masbit2 = 0;
-			for (sid = 0; sid < os.nstations; sid++)
-			{
-				// skip if this is the master station
-				if (os.status.mas2 == sid + 1)
-					continue;
-				bid = sid >> 3;
-				s = sid & 0x07;
-				// if this station is running and is set to activate master
-				if ((os.station_bits[bid] & (1 << s)) && (os.attrib_mas2[bid] & (1 << s)))
-				{
-					q = pd.queue + pd.station_qid[sid];
-					// check if timing is within the acceptable range
-					if (curr_time >= q->st + mas_on_adj_2 &&
-						curr_time <= q->st + q->dur + mas_off_adj_2)
-					{
-						masbit2 = 1;
-						break;
-					}
-				}
-			}
-			os.set_station_bit(os.status.mas2 - 1, masbit2);
-		}
+		// handle masters
+		handle_masters(curr_time);
 
 		// process dynamic events
 		process_dynamic_events(curr_time);
@@ -573,6 +896,12 @@ void do_loop()
 		// activate/deactivate valves
 		os.apply_all_station_bits();
 
+		// check valve current for electrical faults
+		check_current_fault(curr_time);
+
+		// check that the station bits agree with the run-time queue
+		check_station_state(curr_time);
+
 		// handle reboot request
 		// check safe_reboot condition
 		if (os.status.safe_reboot && (curr_time > reboot_timer))
@@ -585,7 +914,9 @@ void do_loop()
 				for (pid = 0; pid < pd.nprograms; pid++)
 				{
 					pd.read(pid, &prog);
-					if (prog.check_match(curr_time + 60))
+					ProgramExtra px;
+					pd.read_extra(pid, &px);
+					if (prog.check_match(curr_time + 60, px.ivanchor))
 					{
 						willrun = true;
 						break;
@@ -594,12 +925,15 @@ void do_loop()
 				if (!willrun)
 				{
 					os.reboot_dev(os.nvdata.reboot_cause);
+					os.status.safe_reboot = 0; // only reached if the reboot did not happen (demo)
+					reboot_timer = 0;
 				}
 			}
 		}
 		else if (reboot_timer && (curr_time > reboot_timer))
 		{
-			os.reboot_dev(REBOOT_CAUSE_TIMER);
+			os.reboot_dev(reboot_cause);
+			reboot_timer = 0;
 		}
 
 		// real-time flow count
@@ -625,9 +959,21 @@ void do_loop()
 			os.status.req_network = 1;
 		check_network();
 
+		// apply the result of a finished weather, forecast or calendar request
+		weather_worker_result();
+
 		// check weather
 		check_weather();
 
+		// check calendar
+		check_calendar();
+
+		// check rain forecast
+		check_forecast();
+
+		// run scheduled tasks
+		timer_check(curr_time);
+
 		byte wuf = os.weather_update_flag;
 		if (wuf)
 		{
@@ -646,9 +992,10 @@ void do_loop()
 			reboot_notification = 0;
 			push_message(NOTIFY_REBOOT);
 		}
-	}
 
-	delay(1); // For OSPI/LINUX, sleep 1 ms to minimize CPU usage
+		// refresh front panel display
+		os.display.update(curr_time);
+	}
 }
 
 /** Check and process special program command */
@@ -658,6 +1005,7 @@ bool process_special_program_command(const char *pname, uint32_t curr_time)
 	{ // special command start with :
 		if (strncmp(pname, ":>reboot_now", 12) == 0)
 		{
+			reboot_cause = REBOOT_CAUSE_TIMER;
 			os.status.safe_reboot = 0;	   // reboot regardless of program status
 			reboot_timer = curr_time + 65; // set a timer to reboot in 65 seconds
 			// this is to avoid the same command being executed again right after reboot
@@ -674,9 +1022,25 @@ bool process_special_program_command(const char *pname, uint32_t curr_time)
 	return false;
 }
 
+/** Interval until the next weather check
+ * After a failed call the retry interval doubles with each consecutive failure,
+ * up to the regular check interval
+ */
+static ulong weather_check_interval()
+{
+	if (!wt_nfails)
+		return CHECK_WEATHER_TIMEOUT;
+	byte shift = (wt_nfails > 9) ? 9 : (wt_nfails - 1);
+	ulong interval = CHECK_WEATHER_RETRY_INTERVAL << shift;
+	return (interval < CHECK_WEATHER_TIMEOUT) ? interval : CHECK_WEATHER_TIMEOUT;
+}
+
 /** Make weather query */
 void check_weather()
 {
+#if defined(DISABLE_WEATHER)
+	return;
+#endif
 	// do not check weather if
 	// - network check has failed, or
 	// - the controller is in remote extension mode
@@ -694,20 +1058,113 @@ void check_weather()
 		// TODO: the firmware currently needs to be explicitly aware of which adjustment methods
 		// use manual watering percentage (namely methods 0 and 2), this is not ideal
 		os.checkwt_success_lasttime = 0;
-		if (!(os.iopts[IOPT_USE_WEATHER] == 0 || os.iopts[IOPT_USE_WEATHER] == 2))
+		if (!(os.iopts[IOPT_USE_WEATHER] == 0 || os.iopts[IOPT_USE_WEATHER] == 2) && !os.iopts[IOPT_WEATHER_FALLBACK])
 		{
-			os.iopts[IOPT_WATER_PERCENTAGE] = 100; // reset watering percentage to 100%
+			os.set_water_scale(100); // reset watering percentage to 100%
 			wt_rawData[0] = 0;					   // reset wt_rawData and errCode
 			wt_errCode = HTTP_RQT_NOT_RECEIVED;
 		}
 	}
-	else if (!os.checkwt_lasttime || (ntz > os.checkwt_lasttime + CHECK_WEATHER_TIMEOUT))
+	else if (!os.checkwt_lasttime || (ntz > os.checkwt_lasttime + weather_check_interval()))
+	{
+		if (GetWeather())
+			os.checkwt_lasttime = ntz;
+	}
+}
+
+/** Fetch the calendar once a day, or sooner after a failure */
+void check_calendar()
+{
+	if (os.status.network_fails > 0 || os.iopts[IOPT_REMOTE_EXT_MODE])
+		return;
+	if (os.status.program_busy)
+		return;
+
+	ulong ntz = os.now_tz();
+	ulong interval = (cal_errCode == 0) ? CALENDAR_CHECK_INTERVAL : CALENDAR_RETRY_INTERVAL;
+	if (!cal_lasttime || (ntz > cal_lasttime + interval))
+	{
+		if (GetCalendar(ntz))
+			cal_lasttime = ntz;
+	}
+}
+
+/** Fetch the rain forecast every hour, if programs can check it */
+void check_forecast()
+{
+	if (!os.iopts[IOPT_FORECAST_HOURS] || !os.iopts[IOPT_USE_WEATHER])
+		return;
+	if (os.status.network_fails > 0 || os.iopts[IOPT_REMOTE_EXT_MODE])
+		return;
+	if (os.status.program_busy)
+		return;
+
+	ulong ntz = os.now_tz();
+	if (!fc_lasttime || (ntz > fc_lasttime + FORECAST_CHECK_INTERVAL))
+	{
+		if (GetForecast())
+			fc_lasttime = ntz;
+	}
+}
+
+/** Frost protection
+ * Below the frost temperature (IOPT_FROST_TEMP, in F) programs do not start,
+ * until the temperature is FROST_HYSTERESIS above it or no recent temperature is known.
+ * The frost program, if set, runs when frost starts and then every FROST_CYCLE_INTERVAL,
+ * unless other stations are running.
+ */
+void check_frost(ulong curr_time)
+{
+	static ulong frost_cycle_time = 0;
+	byte threshold = os.iopts[IOPT_FROST_TEMP];
+	frost_temp_known = frost_temperature(curr_time, &frost_temp);
+	bool known = threshold && frost_temp_known;
+	if (!os.status.frost)
+	{
+		if (known && frost_temp < threshold)
+		{
+			os.status.frost = 1;
+			frost_on_lasttime = curr_time;
+			frost_cycle_time = 0;
+			dry_run_log("frost protection on at %d F", frost_temp);
+			push_message(NOTIFY_FROST, 1, frost_temp);
+		}
+	}
+	else if (!known || frost_temp >= threshold + FROST_HYSTERESIS)
+	{
+		os.status.frost = 0;
+		dry_run_log("frost protection off");
+		write_log(LOGDATA_FROST, curr_time);
+		push_message(NOTIFY_FROST, 0, frost_temp);
+	}
+
+	byte pid = os.iopts[IOPT_FROST_PROG];
+	if (os.status.frost && pid && !os.status.program_busy &&
+		(!frost_cycle_time || curr_time >= frost_cycle_time + FROST_CYCLE_INTERVAL))
 	{
-		os.checkwt_lasttime = ntz;
-		GetWeather();
+		frost_cycle_time = curr_time;
+		dry_run_log("frost program %d starts", pid);
+		start_switch_program(pid);
 	}
 }
 
+/** Check a program's rain forecast thresholds
+ * Returns true if the forecast exceeds one of them, with scale set to the percentage
+ * of the water time to keep: 0 to skip the run, or what the precipitation probability leaves.
+ * A program is never skipped on a missing or outdated forecast.
+ */
+bool forecast_scale(ProgramExtra *px, ulong curr_time, byte *scale)
+{
+	if (!px->fcpop && !px->fcqpf)
+		return false;
+	if (!forecast_valid(curr_time))
+		return false;
+	if (!(px->fcpop && fc_pop >= px->fcpop) && !(px->fcqpf && fc_qpf >= px->fcqpf))
+		return false;
+	*scale = (px->fcmode == FORECAST_MODE_REDUCE) ? 100 - fc_pop : 0;
+	return true;
+}
+
 /** Turn on a station
  * This function turns on a scheduled station
  */
@@ -715,11 +1172,104 @@ void turn_on_station(byte sid)
 {
 	// RAH implementation of flow sensor
 	flow_start = 0;
+	flow_count_on[sid] = flow_count;
 
 	if (os.set_station_bit(sid, 1))
 	{
-		push_message(NOTIFY_STATION_ON, sid);
+		const char *pname = (pd.station_qid[sid] < pd.nqueue) ? pd.queue[pd.station_qid[sid]].pname : "";
+		dry_run_log("station %d would open for %s", sid + 1, pname);
+		push_message(NOTIFY_STATION_ON, sid, 0, pname);
+
+		// valve bits are applied later, so this is the current before the station opens
+		if (os.iopts[IOPT_CURRENT_CHANNEL])
+		{
+			current_before_on[sid] = os.read_current();
+			current_check_time[sid] = os.now_tz() + CURRENT_CHECK_DELAY;
+		}
+	}
+}
+
+/** Check valve current for electrical faults
+ * An open station that draws no current has a broken wire,
+ * a total current above the maximum indicates a short circuit.
+ * Each station that opened is checked once, CURRENT_CHECK_DELAY after it opened.
+ * A short is put down to the stations still waiting for their check, or to all open stations if none is.
+ * Faulty stations are turned off.
+ */
+void check_current_fault(ulong curr_time)
+{
+	if (!os.iopts[IOPT_CURRENT_CHANNEL])
+		return;
+	uint16_t curr = os.read_current();
+	if (!os.status.has_curr_sense)
+		return;
+
+	bool shorted = os.iopts[IOPT_CURRENT_MAX] && curr > (uint16_t)os.iopts[IOPT_CURRENT_MAX] * 10;
+	bool turned_off = false;
+	byte sid;
+	for (sid = 0; sid < os.nstations; sid++)
+	{
+		if (!current_check_time[sid])
+			continue;
+		if (!((os.station_bits[sid >> 3] >> (sid & 0x07)) & 1))
+		{
+			current_check_time[sid] = 0;
+			continue;
+		}
+		byte fault = 0;
+		if (shorted)
+		{
+			fault = CURRENT_FAULT_SHORT;
+		}
+		else if (curr_time >= current_check_time[sid])
+		{
+			if (os.iopts[IOPT_CURRENT_MIN] && curr < current_before_on[sid] + (uint16_t)os.iopts[IOPT_CURRENT_MIN] * 10)
+				fault = CURRENT_FAULT_OPEN;
+			current_check_time[sid] = 0;
+		}
+		if (!fault)
+			continue;
+		push_message(NOTIFY_ELECTRICAL_FAULT, sid, curr, (fault == CURRENT_FAULT_SHORT) ? "short" : "open");
+		turn_off_station(sid, curr_time);
+		current_check_time[sid] = 0;
+		turned_off = true;
+	}
+
+	if (shorted && !turned_off)
+	{
+		// the station that caused the short is unknown, so turn off all open stations
+		for (sid = 0; sid < os.nstations; sid++)
+		{
+			if ((os.station_bits[sid >> 3] >> (sid & 0x07)) & 1)
+			{
+				push_message(NOTIFY_ELECTRICAL_FAULT, sid, curr, "short");
+				turn_off_station(sid, curr_time);
+				turned_off = true;
+			}
+		}
+	}
+	if (turned_off)
+		os.apply_all_station_bits();
+}
+
+/** Least number of flow pulses a run must count, 0 if runs are not checked for missing flow
+ * Stations with a rated flow (see StationMeta) must also count NO_FLOW_RATED_PERCENT
+ * of the pulses their rating gives for the run.
+ */
+static ulong no_flow_min_pulses(byte sid, ulong duration)
+{
+	ulong min_pulses = os.iopts[IOPT_NO_FLOW_PULSES];
+	ulong pulse_rate = ((ulong)os.iopts[IOPT_PULSE_RATE_1] << 8) + os.iopts[IOPT_PULSE_RATE_0];
+	StationMeta meta;
+	os.get_station_meta(sid, &meta);
+	if (meta.flow && pulse_rate)
+	{
+		// both are in 1/100 of the volume unit, the rating is per minute
+		ulong rated = (ulong)meta.flow * duration / 60 / pulse_rate * NO_FLOW_RATED_PERCENT / 100;
+		if (rated > min_pulses)
+			min_pulses = rated;
 	}
+	return min_pulses;
 }
 
 /** Turn off a station
@@ -736,17 +1286,17 @@ void turn_off_station(byte sid, ulong curr_time)
 		return;
 
 	// RAH implementation of flow sensor
-	if (flow_gallons > 1)
+	if (flow_pulses > 1)
 	{
 		if (flow_stop <= flow_begin)
-			flow_last_gpm = 0;
+			flow_last_lpm = 0;
 		else
-			flow_last_gpm = (float)60000 / (float)((flow_stop - flow_begin) / (flow_gallons - 1));
-	} // RAH calculate GPM, 1 pulse per gallon
+			flow_last_lpm = (float)60000 / (float)((flow_stop - flow_begin) / (flow_pulses - 1)) * flow_pulse_liters();
+	} // RAH calculate pulses per minute, then the rate in liters
 	else
 	{
-		flow_last_gpm = 0;
-	} // RAH if not one gallon (two pulses) measured then record 0 gpm
+		flow_last_lpm = 0;
+	} // RAH if not two pulses measured then record 0
 
 	RuntimeQueueStruct *q = pd.queue + qid;
 
@@ -761,10 +1311,27 @@ void turn_off_station(byte sid, ulong curr_time)
 			pd.lastrun.program = q->pid;
 			pd.lastrun.duration = curr_time - q->st;
 			pd.lastrun.endtime = curr_time;
+			pd.save_lastrun();
+			strcpy(lastrun_pname, q->pname);
+			lastrun_rid = q->rid;
+			budget_irrigated(sid, pd.lastrun.duration);
 
 			// log station run
+			dry_run_log("station %d would close after %lu s", sid + 1, (ulong)pd.lastrun.duration);
 			write_log(LOGDATA_STATION, curr_time);
-			push_message(NOTIFY_STATION_OFF, sid, pd.lastrun.duration);
+			push_message(NOTIFY_STATION_OFF, sid, pd.lastrun.duration, q->pname);
+
+			// a run without flow points at a failed valve or a closed supply.
+			// stations running at the same time share the flow sensor, so their flow adds up
+			ulong min_pulses = no_flow_min_pulses(sid, pd.lastrun.duration);
+			if (os.iopts[IOPT_SENSOR1_TYPE] == SENSOR_TYPE_FLOW && min_pulses &&
+				pd.lastrun.duration >= NO_FLOW_MIN_DURATION && flow_count - flow_count_on[sid] < min_pulses)
+			{
+				noflow_sid = sid;
+				noflow_pulses = flow_count - flow_count_on[sid];
+				write_log(LOGDATA_NOFLOW, curr_time);
+				push_message(NOTIFY_NO_FLOW, sid, pd.lastrun.duration);
+			}
 		}
 	}
 
@@ -773,6 +1340,52 @@ void turn_off_station(byte sid, ulong curr_time)
 	pd.station_qid[sid] = 0xFF;
 }
 
+/** Check once a minute that the station bits agree with the run-time queue
+ * A station that is on without a running queue element is not turned off by the run-time
+ * loop, so it is turned off here. A running queue element whose station is off is only
+ * logged. Master stations follow their stations and are not checked.
+ */
+void check_station_state(ulong curr_time)
+{
+	static ulong last_minute = 0;
+	if (curr_time / 60 == last_minute)
+		return;
+	last_minute = curr_time / 60;
+
+	bool changed = false;
+	for (byte sid = 0; sid < os.nstations; sid++)
+	{
+		if (os.status.mas == sid + 1 || os.status.mas2 == sid + 1)
+			continue;
+		bool on = (os.station_bits[sid >> 3] >> (sid & 0x07)) & 1;
+		bool running = false;
+		for (RuntimeQueueStruct *q = pd.queue; q < pd.queue + pd.nqueue; q++)
+		{
+			if (q->sid == sid && q->dur && q->st && q->st <= curr_time && curr_time < q->st + q->dur)
+				running = true;
+		}
+		if (on == running)
+			continue;
+
+		mismatch_sid = sid;
+		mismatch_kind = on ? STATE_MISMATCH_NO_RUN : STATE_MISMATCH_NOT_ON;
+		DEBUG_PRINT("station state mismatch: ");
+		DEBUG_PRINT(sid + 1);
+		DEBUG_PRINTLN(on ? " on without a run, turned off" : " off during its run");
+		write_log(LOGDATA_MISMATCH, curr_time);
+		if (on)
+		{
+			os.set_station_bit(sid, 0);
+			changed = true;
+		}
+	}
+	if (changed)
+	{
+		handle_masters(curr_time);
+		os.apply_all_station_bits();
+	}
+}
+
 /** Process dynamic events
  * such as rain delay, rain sensing
  * and turn off stations accordingly
@@ -798,9 +1411,9 @@ void process_dynamic_events(ulong curr_time)
 		igs2 = os.attrib_igs2[bid];
 		igrd = os.attrib_igrd[bid];
 
-		for (s = 0; s < 8; s++)
+		for (s = 0; s < SHIFT_REGISTER_LINES; s++)
 		{
-			sid = bid * 8 + s;
+			sid = bid * SHIFT_REGISTER_LINES + s;
 
 			// ignore master stations because they are handled separately
 			if (os.status.mas == sid + 1)
@@ -818,16 +1431,112 @@ void process_dynamic_events(ulong curr_time)
 
 			if (q->pid >= 99)
 				continue; // if this is a manually started program, proceed
+			const char *reason = NULL;
 			if (!en)
-				turn_off_station(sid, curr_time); // if system is disabled, turn off zone
-			if (rd && !(igrd & (1 << s)))
-				turn_off_station(sid, curr_time); // if rain delay is on and zone does not ignore rain delay, turn it off
-			if (sn1 && !(igs & (1 << s)))
-				turn_off_station(sid, curr_time); // if sensor1 is on and zone does not ignore sensor1, turn it off
-			if (sn2 && !(igs2 & (1 << s)))
-				turn_off_station(sid, curr_time); // if sensor2 is on and zone does not ignore sensor2, turn it off
+				reason = "controller disabled"; // if system is disabled, turn off zone
+			else if (rd && !(igrd & (1 << s)))
+				reason = "rain delay"; // if rain delay is on and zone does not ignore rain delay, turn it off
+			else if (sn1 && !(igs & (1 << s)))
+				reason = "sensor 1"; // if sensor1 is on and zone does not ignore sensor1, turn it off
+			else if (sn2 && !(igs2 & (1 << s)))
+				reason = "sensor 2"; // if sensor2 is on and zone does not ignore sensor2, turn it off
+			if (reason)
+			{
+				dry_run_log("station %d skipped: %s", sid + 1, reason);
+				turn_off_station(sid, curr_time);
+			}
+		}
+	}
+}
+
+/** Start of the watering window for a queue element at or after the given time
+ * Returns the given time if it is inside the window, or if the element is not restricted.
+ * Only program runs are restricted; manual, run-once and test runs are not.
+ */
+ulong water_window_start(RuntimeQueueStruct *q, ulong t)
+{
+	if (q->pid == 0 || q->pid > MAX_NUM_PROGRAMS)
+		return t;
+	byte b, e;
+	pd.water_window(q->pid - 1, &b, &e);
+	uint16_t wbeg = b, wend = e;
+	if (wbeg == wend)
+	{
+		// the program has no window of its own, use the global one
+		wbeg = os.iopts[IOPT_WATER_WINDOW_BEGIN];
+		wend = os.iopts[IOPT_WATER_WINDOW_END];
+	}
+	if (wbeg == wend)
+		return t;
+	wbeg *= 15;
+	wend *= 15;
+	uint16_t m = (t % 86400L) / 60;
+	bool inside = (wbeg < wend) ? (m >= wbeg && m < wend) : (m >= wbeg || m < wend); // the window may span midnight
+	if (inside)
+		return t;
+	ulong open = t - t % 86400L + (ulong)wbeg * 60;
+	if (open <= t)
+		open += 86400L;
+	return open;
+}
+
+/** Earliest start time of a queue element that has to soak after the previous run of its station
+ * Only runs of the same station that start before the given time count
+ */
+static ulong soak_start_time(RuntimeQueueStruct *q, ulong start)
+{
+	if (!q->soak)
+		return start;
+	ulong t = start;
+	for (RuntimeQueueStruct *e = pd.queue; e < pd.queue + pd.nqueue; e++)
+	{
+		if (e == q || e->sid != q->sid || !e->st || !e->dur || e->st >= start)
+			continue;
+		if (e->st + e->dur + q->soak > t)
+			t = e->st + e->dur + q->soak;
+	}
+	return t;
+}
+
+/** Earliest start time of a queue element that overlaps with a scheduled run of the same station
+ * Depending on the station overlap option, the element starts after the other run,
+ * or it is merged into / dropped in favor of the other run. In the latter cases 0 is returned
+ * and the element is marked for removal.
+ * The cycles of a cycle and soak program are not overlaps, soak_start_time() keeps them apart.
+ */
+static ulong overlap_start_time(RuntimeQueueStruct *q, ulong start)
+{
+	ulong t = start;
+	for (RuntimeQueueStruct *e = pd.queue; e < pd.queue + pd.nqueue; e++)
+	{
+		if (e == q || e->sid != q->sid || !e->st || !e->dur || e->st + e->dur <= start)
+			continue;
+		if (q->soak && e->pid == q->pid)
+			continue; // an earlier cycle of the same program
+		switch (os.iopts[IOPT_STATION_OVERLAP])
+		{
+		case STATION_OVERLAP_EXTEND:
+		{
+			ulong end = e->st + e->dur;
+			e->dur = ((ulong)e->dur + q->dur > 0xFFFF) ? 0xFFFF : e->dur + q->dur;
+			// later runs of the station, e.g. the next cycle, move back so they keep their soak
+			for (RuntimeQueueStruct *f = pd.queue; f < pd.queue + pd.nqueue; f++)
+			{
+				if (f != e && f->sid == q->sid && f->st >= end)
+					f->st += e->st + e->dur - end;
+			}
+			q->dur = 0;
+			return 0;
+		}
+		case STATION_OVERLAP_IGNORE:
+			q->dur = 0;
+			return 0;
+		default:
+			if (e->st + e->dur > t)
+				t = e->st + e->dur;
 		}
 	}
+	return t;
 }
 
 /** Scheduler
@@ -849,6 +1558,7 @@ void schedule_all_stations(ulong curr_time)
 
 	RuntimeQueueStruct *q = pd.queue;
 	byte re = os.iopts[IOPT_REMOTE_EXT_MODE];
+	bool merged = false;
 	// go through runtime queue and calculate start time of each station
 	for (; q < pd.queue + pd.nqueue; q++)
 	{
@@ -865,14 +1575,25 @@ void schedule_all_stations(ulong curr_time)
 		if (os.attrib_seq[bid] & (1 << s) && !re)
 		{
 			// sequential scheduling
-			q->st = seq_start_time;
-			seq_start_time += q->dur;
+			ulong st = overlap_start_time(q, seq_start_time);
+			if (!st)
+			{
+				merged = true;
+				continue;
+			}
+			q->st = water_window_start(q, soak_start_time(q, st));
+			q->st += master_delay(q, q->st);
+			seq_start_time = q->st + q->dur;
 			seq_start_time += station_delay; // add station delay time
 		}
 		else
 		{
 			// otherwise, concurrent scheduling
-			q->st = con_start_time;
+			ulong st = overlap_start_time(q, con_start_time);
+			if (!st)
+				continue;
+			q->st = water_window_start(q, soak_start_time(q, st));
+			q->st += master_delay(q, q->st);
 			// stagger concurrent stations by 1 second
 			con_start_time++;
 		}
@@ -884,17 +1605,123 @@ void schedule_all_stations(ulong curr_time)
 		DEBUG_PRINT(q->dur);
 		DEBUG_PRINT("]");
 		DEBUG_PRINTLN(pd.nqueue);*/
-		if (!os.status.program_busy)
+		set_program_busy(curr_time);
+	}
+
+	// a sequential run that was extended may now overlap the runs queued after it
+	if (merged)
+		reschedule_waiting_stations(curr_time, 0xFF);
+}
+
+/** Set the program busy bit and start the flow count if a program is not running yet */
+void set_program_busy(ulong curr_time)
+{
+	if (!os.status.program_busy)
+	{
+		os.status.program_busy = 1; // set program busy bit
+		// start flow count
+		if (os.iopts[IOPT_SENSOR1_TYPE] == SENSOR_TYPE_FLOW)
+		{ // if flow sensor is connected
+			os.flowcount_log_start = flow_count;
+			os.sensor1_active_lasttime = curr_time;
+		}
+	}
+}
+
+/** Check if the runs of a program started earlier are still in the queue
+ * Manual, run-once and test runs do not count, nor runs queued in the current minute.
+ */
+bool program_running(ulong curr_time)
+{
+	for (RuntimeQueueStruct *q = pd.queue; q < pd.queue + pd.nqueue; q++)
+	{
+		if (q->st && q->dur && q->pid && q->pid < 99 && q->st + q->dur > curr_time)
+			return true;
+	}
+	return false;
+}
+
+/** Cut the runs of programs started earlier short
+ * Running stations are turned off in the next run time check, waiting runs are removed.
+ */
+void cut_program_runs(ulong curr_time)
+{
+	RuntimeQueueStruct *q;
+	for (q = pd.queue; q < pd.queue + pd.nqueue; q++)
+	{
+		if (!q->st || !q->dur || !q->pid || q->pid >= 99)
+			continue;
+		q->dur = (q->st < curr_time) ? curr_time - q->st : 0;
+	}
+	reschedule_waiting_stations(curr_time, 0xFF);
+
+	// runs queued next start after the sequential runs that are left
+	pd.last_seq_stop_time = 0;
+	if (os.iopts[IOPT_REMOTE_EXT_MODE])
+		return;
+	for (q = pd.queue; q < pd.queue + pd.nqueue; q++)
+	{
+		if (!q->st || !q->dur || !(os.attrib_seq[q->sid >> 3] & (1 << (q->sid & 0x07))))
+			continue;
+		if (q->st + q->dur > pd.last_seq_stop_time)
+			pd.last_seq_stop_time = q->st + q->dur;
+	}
+}
+
+/** Re-pack the start times of sequential stations that are waiting to run
+ * Waiting elements keep their order, unless first is a valid queue index,
+ * in which case that element is moved to the front.
+ */
+void reschedule_waiting_stations(ulong curr_time, byte first)
+{
+	byte order[RUNTIME_QUEUE_MAX];
+	byte n = 0, i, j;
+	ulong start = 0;
+	int16_t station_delay = water_time_decode_signed(os.iopts[IOPT_STATION_DELAY_TIME]);
+
+	if (os.iopts[IOPT_REMOTE_EXT_MODE])
+		return; // no sequential scheduling in remote extension mode
+
+	// waiting stations start once the running sequential stations are done
+	start = curr_time + 1;
+	for (byte qid = 0; qid < pd.nqueue; qid++)
+	{
+		RuntimeQueueStruct *q = pd.queue + qid;
+		if (!q->dur)
+			continue; // skip elements marked for removal
+		if (!(os.attrib_seq[q->sid >> 3] & (1 << (q->sid & 0x07))))
+			continue;
+		if (q->st <= curr_time)
 		{
-			os.status.program_busy = 1; // set program busy bit
-			// start flow count
-			if (os.iopts[IOPT_SENSOR1_TYPE] == SENSOR_TYPE_FLOW)
-			{ // if flow sensor is connected
-				os.flowcount_log_start = flow_count;
-				os.sensor1_active_lasttime = curr_time;
-			}
+			if (q->st + q->dur + station_delay > start)
+				start = q->st + q->dur + station_delay;
+			continue;
+		}
+		// insert by start time
+		for (i = n; i > 0 && pd.queue[order[i - 1]].st > q->st; i--)
+			order[i] = order[i - 1];
+		order[i] = qid;
+		n++;
+	}
+
+	for (i = 0; i < n; i++)
+	{
+		if (order[i] == first)
+		{
+			for (j = i; j > 0; j--)
+				order[j] = order[j - 1];
+			order[0] = first;
+			break;
 		}
 	}
+
+	for (i = 0; i < n; i++)
+	{
+		RuntimeQueueStruct *q = pd.queue + order[i];
+		q->st = water_window_start(q, soak_start_time(q, start));
+		q->st += master_delay(q, q->st);
+		start = q->st + q->dur + station_delay;
+	}
 }
 
 /** Immediately reset all stations
@@ -902,6 +1729,7 @@ void schedule_all_stations(ulong curr_time)
  */
 void reset_all_stations_immediate()
 {
+	master_hold[0] = master_hold[1] = 0;
 	os.clear_all_station_bits();
 	os.apply_all_station_bits();
 	pd.reset_runtime();
@@ -915,6 +1743,7 @@ void reset_all_stations_immediate()
  */
 void reset_all_stations()
 {
+	master_hold[0] = master_hold[1] = 0;
 	RuntimeQueueStruct *q = pd.queue;
 	// go through runtime queue and assign water time to 0
 	for (; q < pd.queue + pd.nqueue; q++)
@@ -923,12 +1752,31 @@ void reset_all_stations()
 	}
 }
 
+/** Start the program mapped to a program switch
+ * pid: program number (starting from 1), or PSWITCH_RUNONCE for the run-once template
+ */
+void start_switch_program(byte pid)
+{
+	if (pid == PSWITCH_RUNONCE)
+	{
+		// the list parser may read past the end of the template, so keep zeros there
+		char tpl[MAX_SOPTS_SIZE + MAX_NUM_STATIONS + 1];
+		memset(tpl, 0, sizeof(tpl));
+		os.sopt_load(SOPT_PSWITCH_RUNONCE, tpl);
+		if (enqueue_runonce(tpl))
+			schedule_all_stations(os.now_tz());
+	}
+	else if (pid > 0 && pid <= pd.nprograms)
+	{
+		manual_start_program(pid, 100);
+	}
+}
+
 /** Manually start a program
- * If pid==0, this is a test program (1 minute per station)
- * If pid==255, this is a short test program (2 second per station)
- * If pid > 0. run program pid-1
+ * pid: program number (starting from 1), 0 runs each station for 60 seconds, 255 for 2 seconds
+ * wl:	watering percentage applied to the durations
  */
-void manual_start_program(byte pid, byte uwt)
+void manual_start_program(byte pid, uint16_t wl)
 {
 	boolean match_found = false;
 	reset_all_stations_immediate();
@@ -938,7 +1786,7 @@ void manual_start_program(byte pid, byte uwt)
 	if ((pid > 0) && (pid < 255))
 	{
 		pd.read(pid - 1, &prog);
-		push_message(NOTIFY_PROGRAM_SCHED, pid - 1, uwt ? os.iopts[IOPT_WATER_PERCENTAGE] : 100, "");
+		push_message(NOTIFY_PROGRAM_SCHED, pid - 1, wl, "");
 	}
 	for (sid = 0; sid < os.nstations; sid++)
 	{
@@ -952,21 +1800,14 @@ void manual_start_program(byte pid, byte uwt)
 			dur = 2;
 		else if (pid > 0)
 			dur = water_time_resolve(prog.durations[sid]);
-		if (uwt)
+		if (wl != 100)
 		{
-			dur = dur * os.iopts[IOPT_WATER_PERCENTAGE] / 100;
+			dur = dur * wl / 100;
 		}
 		if (dur > 0 && !(os.attrib_dis[bid] & (1 << s)))
 		{
-			RuntimeQueueStruct *q = pd.enqueue();
-			if (q)
-			{
-				q->st = 0;
-				q->dur = dur;
-				q->sid = sid;
-				q->pid = 254;
+			if (pd.enqueue(sid, 254, dur, (pid > 0 && pid < 255) ? prog.name : NULL))
 				match_found = true;
-			}
 		}
 	}
 	if (match_found)
@@ -975,6 +1816,33 @@ void manual_start_program(byte pid, byte uwt)
 	}
 }
 
+/** Start a zone test
+ * The stations run one after another for dur seconds each, pausing soak seconds in between.
+ * Returns the number of stations queued.
+ */
+byte start_zone_test(const byte *sids, byte n, uint16_t dur, uint16_t soak)
+{
+	reset_all_stations_immediate();
+	ulong curr_time = os.now_tz();
+	ulong st = curr_time + 1;
+	zonetest_total = 0;
+	for (byte i = 0; i < n; i++)
+	{
+		bool merged;
+		RuntimeQueueStruct *q = pd.enqueue(sids[i], ZONETEST_PID, dur, NULL, 0, &merged);
+		if (!q)
+			break;
+		if (!merged)
+			q->st = st;
+		zonetest_total++;
+		zonetest_end = st + dur;
+		st += (ulong)dur + soak;
+	}
+	if (zonetest_total)
+		set_program_busy(curr_time);
+	return zonetest_total;
+}
+
 // ==========================================
 // ====== PUSH NOTIFICATION FUNCTIONS =======
 // ==========================================
@@ -983,14 +1851,52 @@ void ip2string(char *str, byte ip[4])
 	sprintf_P(str + strlen(str), PSTR("%d.%d.%d.%d"), ip[0], ip[1], ip[2], ip[3]);
 }
 
+/** Append s to buf as a JSON string body, escaping quotes and backslashes */
+static void append_json_escaped(char *buf, const char *s, int maxlen)
+{
+	int n = strlen(buf);
+	for (const char *p = s; *p && n < maxlen - 3; p++)
+	{
+		if (*p == '"' || *p == '\\')
+			buf[n++] = '\\';
+		buf[n++] = *p;
+	}
+	buf[n] = 0;
+}
+
+/** Run id of the queue element of a station, 0 if it has none
+ * Station events are sent while the element of the run is still queued.
+ */
+static ulong station_run_id(byte sid)
+{
+	byte qid = pd.station_qid[sid];
+	return (qid < pd.nqueue) ? pd.queue[qid].rid : 0;
+}
+
+/** Append the device name to buf as a JSON string body */
+static void append_device_name(char *buf, int maxlen)
+{
+	char name[MAX_SOPTS_SIZE + 1];
+	os.get_device_name(name);
+	append_json_escaped(buf, name, maxlen);
+}
+
 void push_message(int type, uint32_t lval, float fval, const char *sval)
 {
 	static char topic[TMP_BUFFER_SIZE];
 	static char payload[TMP_BUFFER_SIZE];
 	char *postval = tmp_buffer;
+	char key[STATION_NAME_SIZE + 1];
+	char pname[2 * PROGRAM_NAME_SIZE + 1] = ""; // program name of station events, escaped
+	ulong rid = 0;								// run id of station events
 	uint32_t volume;
 
-	bool ifttt_enabled = os.iopts[IOPT_IFTTT_ENABLE] & type;
+#if defined(DISABLE_IFTTT)
+	bool ifttt_enabled = false;
+#else
+	// ife holds the enable bits of the first eight notification types, ife2 those of the others
+	bool ifttt_enabled = (os.iopts[IOPT_IFTTT_ENABLE] & type) || (os.iopts[IOPT_IFTTT_ENABLE2] & (type >> 8));
+#endif
 
 	// check if this type of event is enabled for push notification
 	if (!ifttt_enabled && !os.mqtt.enabled())
@@ -1014,34 +1920,42 @@ void push_message(int type, uint32_t lval, float fval, const char *sval)
 		// TODO: add IFTTT support for this event as well
 		if (os.mqtt.enabled())
 		{
-			sprintf_P(topic, PSTR("opensprinkler/station/%d"), lval);
-			strcpy_P(payload, PSTR("{\"state\":1}"));
+			append_json_escaped(pname, sval ? sval : "", sizeof(pname));
+			rid = station_run_id(lval);
+			os.mqtt.topic(topic, "station/%s", os.mqtt.station_key(lval, key));
+			sprintf_P(payload, PSTR("{\"state\":1,\"program\":\"%s\",\"run\":%lu}"), pname, rid);
 		}
 		break;
 
 	case NOTIFY_STATION_OFF:
 
+		append_json_escaped(pname, sval ? sval : "", sizeof(pname));
 		if (os.mqtt.enabled())
 		{
-			sprintf_P(topic, PSTR("opensprinkler/station/%d"), lval);
+			rid = station_run_id(lval);
+			os.mqtt.topic(topic, "station/%s", os.mqtt.station_key(lval, key));
 			if (os.iopts[IOPT_SENSOR1_TYPE] == SENSOR_TYPE_FLOW)
 			{
-				sprintf_P(payload, PSTR("{\"state\":0,\"duration\":%d,\"flow\":%d.%02d}"), (int)fval, (int)flow_last_gpm, (int)(flow_last_gpm * 100) % 100);
+				float rate = flow_from_liters(flow_last_lpm);
+				sprintf_P(payload, PSTR("{\"state\":0,\"duration\":%d,\"program\":\"%s\",\"run\":%lu,\"flow\":%d.%02d,\"unit\":\"%s/min\"}"), (int)fval, pname, rid, (int)rate, (int)(rate * 100) % 100, flow_unit());
 			}
 			else
 			{
-				sprintf_P(payload, PSTR("{\"state\":0,\"duration\":%d}"), (int)fval);
+				sprintf_P(payload, PSTR("{\"state\":0,\"duration\":%d,\"program\":\"%s\",\"run\":%lu}"), (int)fval, pname, rid);
 			}
 		}
 		if (ifttt_enabled)
 		{
 			char name[STATION_NAME_SIZE];
 			os.get_station_name(lval, name);
-			sprintf_P(postval + strlen(postval), PSTR("Station %s closed. It ran for %d minutes %d seconds."), name, (int)fval / 60, (int)fval % 60);
+			sprintf(postval + strlen(postval), lang_string(LANG_STATION_RAN), name, (int)fval / 60, (int)fval % 60);
+			if (pname[0])
+				sprintf(postval + strlen(postval), lang_string(LANG_STATION_PROGRAM), pname);
 
 			if (os.iopts[IOPT_SENSOR1_TYPE] == SENSOR_TYPE_FLOW)
 			{
-				sprintf_P(postval + strlen(postval), PSTR(" Flow rate: %d.%02d"), (int)flow_last_gpm, (int)(flow_last_gpm * 100) % 100);
+				float rate = flow_from_liters(flow_last_lpm);
+				sprintf(postval + strlen(postval), lang_string(LANG_FLOW_RATE), (int)rate, (int)(rate * 100) % 100, flow_unit());
 			}
 		}
 		break;
@@ -1050,18 +1964,15 @@ void push_message(int type, uint32_t lval, float fval, const char *sval)
 
 		if (ifttt_enabled)
 		{
-			if (sval)
-				strcat_P(postval, PSTR("Manually scheduled "));
-			else
-				strcat_P(postval, PSTR("Automatically scheduled "));
-			strcat_P(postval, PSTR("Program "));
+			char name[PROGRAM_NAME_SIZE + 1] = "";
+			if (lval < pd.nprograms)
 			{
 				ProgramStruct prog;
 				pd.read(lval, &prog);
-				if (lval < pd.nprograms)
-					strcat(postval, prog.name);
+				strncpy(name, prog.name, PROGRAM_NAME_SIZE);
+				name[PROGRAM_NAME_SIZE] = 0;
 			}
-			sprintf_P(postval + strlen(postval), PSTR(" with %d%% water level."), (int)fval);
+			sprintf(postval + strlen(postval), lang_string(sval ? LANG_PROGRAM_MANUAL : LANG_PROGRAM_AUTO), name, (int)fval);
 		}
 		break;
 
@@ -1069,13 +1980,12 @@ void push_message(int type, uint32_t lval, float fval, const char *sval)
 
 		if (os.mqtt.enabled())
 		{
-			strcpy_P(topic, PSTR("opensprinkler/sensor1"));
+			os.mqtt.topic(topic, "sensor1");
 			sprintf_P(payload, PSTR("{\"state\":%d}"), (int)fval);
 		}
 		if (ifttt_enabled)
 		{
-			strcat_P(postval, PSTR("Sensor 1 "));
-			strcat_P(postval, ((int)fval) ? PSTR("activated.") : PSTR("de-activated."));
+			sprintf(postval + strlen(postval), lang_string(((int)fval) ? LANG_SENSOR_ON : LANG_SENSOR_OFF), 1);
 		}
 		break;
 
@@ -1083,13 +1993,12 @@ void push_message(int type, uint32_t lval, float fval, const char *sval)
 
 		if (os.mqtt.enabled())
 		{
-			strcpy_P(topic, PSTR("opensprinkler/sensor2"));
+			os.mqtt.topic(topic, "sensor2");
 			sprintf_P(payload, PSTR("{\"state\":%d}"), (int)fval);
 		}
 		if (ifttt_enabled)
 		{
-			strcat_P(postval, PSTR("Sensor 2 "));
-			strcat_P(postval, ((int)fval) ? PSTR("activated.") : PSTR("de-activated."));
+			sprintf(postval + strlen(postval), lang_string(((int)fval) ? LANG_SENSOR_ON : LANG_SENSOR_OFF), 2);
 		}
 		break;
 
@@ -1097,29 +2006,27 @@ void push_message(int type, uint32_t lval, float fval, const char *sval)
 
 		if (os.mqtt.enabled())
 		{
-			strcpy_P(topic, PSTR("opensprinkler/raindelay"));
+			os.mqtt.topic(topic, "raindelay");
 			sprintf_P(payload, PSTR("{\"state\":%d}"), (int)fval);
 		}
 		if (ifttt_enabled)
 		{
-			strcat_P(postval, PSTR("Rain delay "));
-			strcat_P(postval, ((int)fval) ? PSTR("activated.") : PSTR("de-activated."));
+			strcat(postval, lang_string(((int)fval) ? LANG_RAINDELAY_ON : LANG_RAINDELAY_OFF));
 		}
 		break;
 
 	case NOTIFY_FLOWSENSOR:
 
-		volume = os.iopts[IOPT_PULSE_RATE_1];
-		volume = (volume << 8) + os.iopts[IOPT_PULSE_RATE_0];
-		volume = lval * volume;
+		// volume in 1/100 units
+		volume = (uint32_t)(flow_from_liters(lval * flow_pulse_liters()) * 100 + 0.5f);
 		if (os.mqtt.enabled())
 		{
-			strcpy_P(topic, PSTR("opensprinkler/sensor/flow"));
-			sprintf_P(payload, PSTR("{\"count\":%lu,\"volume\":%d.%02d}"), lval, (int)volume / 100, (int)volume % 100);
+			os.mqtt.topic(topic, "sensor/flow");
+			sprintf_P(payload, PSTR("{\"count\":%lu,\"volume\":%d.%02d,\"unit\":\"%s\"}"), lval, (int)volume / 100, (int)volume % 100, flow_unit());
 		}
 		if (ifttt_enabled)
 		{
-			sprintf_P(postval + strlen(postval), PSTR("Flow count: %lu, volume: %d.%02d"), lval, (int)volume / 100, (int)volume % 100);
+			sprintf(postval + strlen(postval), lang_string(LANG_FLOW_COUNT), lval, (int)volume / 100, (int)volume % 100, flow_unit());
 		}
 		break;
 
@@ -1129,7 +2036,7 @@ void push_message(int type, uint32_t lval, float fval, const char *sval)
 		{
 			if (lval > 0)
 			{
-				strcat_P(postval, PSTR("External IP updated: "));
+				strcat(postval, lang_string(LANG_EXTERNAL_IP));
 				byte ip[4] = {(byte)((lval >> 24) & 0xFF),
 							  (byte)((lval >> 16) & 0xFF),
 							  (byte)((lval >> 8) & 0xFF),
@@ -1138,42 +2045,131 @@ void push_message(int type, uint32_t lval, float fval, const char *sval)
 			}
 			if (fval >= 0)
 			{
-				sprintf_P(postval + strlen(postval), PSTR("Water level updated: %d%%."), (int)fval);
+				sprintf(postval + strlen(postval), lang_string(LANG_WATER_LEVEL), (int)fval);
 			}
 		}
 		break;
 
+	case NOTIFY_ELECTRICAL_FAULT:
+
+		if (os.mqtt.enabled())
+		{
+			os.mqtt.topic(topic, "station/%s/fault", os.mqtt.station_key(lval, key));
+			sprintf_P(payload, PSTR("{\"fault\":\"%s\",\"current\":%d}"), sval, (int)fval);
+		}
+		if (ifttt_enabled)
+		{
+			char name[STATION_NAME_SIZE + 1];
+			os.get_station_name(lval, name);
+			sprintf(postval + strlen(postval), lang_string(LANG_ELECTRICAL_FAULT), name, sval, (int)fval);
+		}
+		break;
+
+	case NOTIFY_WEATHER_FAIL:
+
+		if (os.mqtt.enabled())
+		{
+			os.mqtt.topic(topic, "weather");
+			sprintf_P(payload, PSTR("{\"state\":\"failed\",\"fails\":%d,\"err\":%d}"), (int)lval, wt_errCode);
+		}
+		if (ifttt_enabled)
+		{
+			sprintf(postval + strlen(postval), lang_string(LANG_WEATHER_FAIL), (int)lval, wt_errCode);
+		}
+		break;
+
+	case NOTIFY_QUEUE_OVERFLOW:
+
+		// sval is "rejected" for a new run that did not fit, or "dropped" for a waiting run it replaced
+		if (os.mqtt.enabled())
+		{
+			os.mqtt.topic(topic, "queue");
+			sprintf_P(payload, PSTR("{\"state\":\"%s\",\"station\":%d,\"program\":%d}"), sval, (int)lval, (int)fval);
+		}
+		if (ifttt_enabled)
+		{
+			char name[STATION_NAME_SIZE + 1];
+			os.get_station_name(lval, name);
+			sprintf(postval + strlen(postval), lang_string(strcmp(sval, "dropped") ? LANG_QUEUE_REJECTED : LANG_QUEUE_DROPPED), name);
+		}
+		break;
+
+	case NOTIFY_NO_FLOW:
+
+		// lval is the station index, fval the run duration
+		if (os.mqtt.enabled())
+		{
+			os.mqtt.topic(topic, "station/%s/noflow", os.mqtt.station_key(lval, key));
+			sprintf_P(payload, PSTR("{\"duration\":%d,\"pulses\":%lu}"), (int)fval, noflow_pulses);
+		}
+		if (ifttt_enabled)
+		{
+			char name[STATION_NAME_SIZE + 1];
+			os.get_station_name(lval, name);
+			sprintf(postval + strlen(postval), lang_string(LANG_NO_FLOW), name, (int)fval / 60, (int)fval % 60);
+		}
+		break;
+
+	case NOTIFY_FROST:
+
+		// lval is 1 when frost protection starts and 0 when it ends, fval the temperature in F
+		if (os.mqtt.enabled())
+		{
+			os.mqtt.topic(topic, "frost");
+			sprintf_P(payload, PSTR("{\"state\":%d,\"temp\":%d}"), (int)lval, (int)fval);
+		}
+		if (ifttt_enabled)
+		{
+			if (lval)
+				sprintf(postval + strlen(postval), lang_string(LANG_FROST_ON), (int)fval);
+			else
+				strcat(postval, lang_string(LANG_FROST_OFF));
+		}
+		break;
+
 	case NOTIFY_REBOOT:
 
+		// lval is 0 when the process has started, and 1 when it is shutting down
 		if (os.mqtt.enabled())
 		{
-			strcpy_P(topic, PSTR("opensprinkler/system"));
-			strcpy_P(payload, PSTR("{\"state\":\"started\"}"));
+			os.mqtt.topic(topic, "system");
+			strcpy_P(payload, lval ? PSTR("{\"state\":\"stopped\"}") : PSTR("{\"state\":\"started\"}"));
 		}
 		if (ifttt_enabled)
 		{
-			strcat_P(postval, PSTR("Process restarted."));
+			strcat(postval, lang_string(lval ? LANG_PROCESS_STOPPED : LANG_PROCESS_RESTARTED));
 		}
 		break;
 	}
 
 	if (os.mqtt.enabled() && strlen(topic) && strlen(payload))
-		os.mqtt.publish(topic, payload);
+	{
+		// every event names the controller it comes from
+		char event[TMP_BUFFER_SIZE];
+		strcpy_P(event, PSTR("{\"device\":{\"name\":\""));
+		append_device_name(event, TMP_BUFFER_SIZE / 2);
+		snprintf(event + strlen(event), sizeof(event) - strlen(event), "\",\"uuid\":\"%s\"}%s%s",
+				 os.device_uuid, (payload[1] == '}') ? "" : ",", payload + 1);
+		os.mqtt.publish(topic, event, type);
+	}
 
 	if (ifttt_enabled)
 	{
+		strcat_P(postval, PSTR("\",\"value2\":\""));
+		append_device_name(postval, TMP_BUFFER_SIZE);
 		strcat_P(postval, PSTR("\"}"));
 
 		// char postBuffer[1500];
 		BufferFiller bf = ether_buffer;
-		bf.emit_p(PSTR("POST /trigger/sprinkler/with/key/$O HTTP/1.0\r\n"
-					   "Host: $S\r\n"
-					   "Accept: */*\r\n"
+		bf.emit_p(PSTR("POST /trigger/sprinkler/with/key/$O"), SOPT_IFTTT_KEY);
+		os.http_request_head(ether_buffer, ETHER_BUFFER_SIZE, DEFAULT_IFTTT_URL, false);
+		BufferFiller hf = ether_buffer + strlen(ether_buffer);
+		hf.emit_p(PSTR("Accept: */*\r\n"
 					   "Content-Length: $D\r\n"
 					   "Content-Type: application/json\r\n\r\n$S"),
-				  SOPT_IFTTT_KEY, DEFAULT_IFTTT_URL, strlen(postval), postval);
+				  strlen(postval), postval);
 
-		os.send_http_request(DEFAULT_IFTTT_URL, 80, ether_buffer, remote_http_callback);
+		os.post_http_request(DEFAULT_IFTTT_URL, 80, ether_buffer);
 	}
 }
 
@@ -1205,7 +2201,37 @@ static const char log_type_names[] PROGMEM =
 	"wl\0"
 	"fl\0"
 	"s2\0"
-	"cu\0";
+	"cu\0"
+	"ww\0"
+	"nf\0"
+	"fc\0"
+	"fr\0"
+	"sm\0";
+
+/** Append a record to the file of the day in a log folder, creating the folder if needed
+ * Files are named xxxxx.txt, where xxxxx is the day in epoch time.
+ */
+static void write_log_message(const char *prefix, mode_t mode, const char *record, ulong curr_time)
+{
+	struct stat st;
+	if (stat(get_filename_fullpath(prefix), &st) && mkdir(get_filename_fullpath(prefix), mode))
+		return;
+
+	char name[32];
+	snprintf(name, sizeof(name), "%s%lu.txt", prefix, curr_time / 86400);
+	data_write_warning(name);
+	FILE *file = fopen(get_filename_fullpath(name), "ab");
+	if (!file)
+		return;
+	fwrite(record, 1, strlen(record), file);
+	fclose(file);
+}
+
+/** Flow sensor pulses counted since the current log period started */
+ulong get_flow_log_count()
+{
+	return (flow_count > os.flowcount_log_start) ? (flow_count - os.flowcount_log_start) : 0;
+}
 
 /** write run record to log on SD card */
 void write_log(byte type, ulong curr_time)
@@ -1214,32 +2240,7 @@ void write_log(byte type, ulong curr_time)
 	if (!os.iopts[IOPT_ENABLE_LOGGING])
 		return;
 
-	// file name will be logs/xxxxx.tx where xxxxx is the day in epoch time
-	ultoa(curr_time / 86400, tmp_buffer, 10);
-	make_logfile_name(tmp_buffer);
-
-	// Step 1: open file if exists, or create new otherwise,
-	// and move file pointer to the end
-	// prepare log folder for RPI
-	struct stat st;
-	if (stat(get_filename_fullpath(LOG_PREFIX), &st))
-	{
-		if (mkdir(get_filename_fullpath(LOG_PREFIX), S_IRUSR | S_IWUSR | S_IXUSR | S_IRGRP | S_IWGRP | S_IXGRP | S_IROTH | S_IWOTH | S_IXOTH))
-		{
-			return;
-		}
-	}
-	FILE *file;
-	file = fopen(get_filename_fullpath(tmp_buffer), "rb+");
-	if (!file)
-	{
-		file = fopen(get_filename_fullpath(tmp_buffer), "wb");
-		if (!file)
-			return;
-	}
-	fseek(file, 0, SEEK_END);
-
-	// Step 2: prepare data buffer
+	// prepare data buffer
 	strcpy_P(tmp_buffer, PSTR("["));
 
 	if (type == LOGDATA_STATION)
@@ -1256,7 +2257,23 @@ void write_log(byte type, ulong curr_time)
 		ulong lvalue = 0;
 		if (type == LOGDATA_FLOWSENSE)
 		{
-			lvalue = (flow_count > os.flowcount_log_start) ? (flow_count - os.flowcount_log_start) : 0;
+			lvalue = get_flow_log_count();
+		}
+		else if (type == LOGDATA_WATERWINDOW)
+		{
+			lvalue = waterwindow_sid;
+		}
+		else if (type == LOGDATA_NOFLOW)
+		{
+			lvalue = noflow_sid;
+		}
+		else if (type == LOGDATA_FORECAST)
+		{
+			lvalue = forecast_pid;
+		}
+		else if (type == LOGDATA_MISMATCH)
+		{
+			lvalue = mismatch_sid;
 		}
 		ultoa(lvalue, tmp_buffer + strlen(tmp_buffer), 10);
 		strcat_P(tmp_buffer, PSTR(",\""));
@@ -1277,9 +2294,24 @@ void write_log(byte type, ulong curr_time)
 		case LOGDATA_RAINDELAY:
 			lvalue = (curr_time > os.raindelay_on_lasttime) ? (curr_time - os.raindelay_on_lasttime) : 0;
 			break;
+		case LOGDATA_FROST:
+			lvalue = (curr_time > frost_on_lasttime) ? (curr_time - frost_on_lasttime) : 0;
+			break;
 		case LOGDATA_WATERLEVEL:
 			lvalue = os.iopts[IOPT_WATER_PERCENTAGE];
 			break;
+		case LOGDATA_WATERWINDOW:
+			lvalue = waterwindow_cut;
+			break;
+		case LOGDATA_NOFLOW:
+			lvalue = pd.lastrun.duration;
+			break;
+		case LOGDATA_FORECAST:
+			lvalue = forecast_wl;
+			break;
+		case LOGDATA_MISMATCH:
+			lvalue = mismatch_kind;
+			break;
 		}
 		ultoa(lvalue, tmp_buffer + strlen(tmp_buffer), 10);
 	}
@@ -1289,12 +2321,28 @@ void write_log(byte type, ulong curr_time)
 	{
 		// RAH implementation of flow sensor
 		strcat_P(tmp_buffer, PSTR(","));
-		sprintf(tmp_buffer + strlen(tmp_buffer), "%5.2f", flow_last_gpm);
+		sprintf(tmp_buffer + strlen(tmp_buffer), "%5.2f", flow_from_liters(flow_last_lpm));
+	}
+	if (type == LOGDATA_STATION)
+	{
+		// the name of the program, manually started programs run with the run-once program id,
+		// and the run id of the station events
+		strcat_P(tmp_buffer, PSTR(",\""));
+		append_json_escaped(tmp_buffer, lastrun_pname, TMP_BUFFER_SIZE);
+		strcat_P(tmp_buffer, PSTR("\","));
+		ultoa(lastrun_rid, tmp_buffer + strlen(tmp_buffer), 10);
+	}
+	if (type == LOGDATA_FORECAST)
+	{
+		// the forecast the decision was based on: probability and amount
+		strcat_P(tmp_buffer, PSTR(","));
+		itoa(fc_pop, tmp_buffer + strlen(tmp_buffer), 10);
+		strcat_P(tmp_buffer, PSTR(","));
+		ultoa(fc_qpf, tmp_buffer + strlen(tmp_buffer), 10);
 	}
 	strcat_P(tmp_buffer, PSTR("]\r\n"));
 
-	fwrite(tmp_buffer, 1, strlen(tmp_buffer), file);
-	fclose(file);
+	write_log_message(LOG_PREFIX, S_IRWXU | S_IRWXG | S_IRWXO, tmp_buffer, curr_time);
 }
 
 /** Delete log file
@@ -1318,6 +2366,74 @@ void delete_log(char *name)
 	}
 }
 
+char AUDIT_PREFIX[] = "./audit/";
+
+/** Generate audit log file name
+ * Audit log files will be named /audit/xxxxx.txt
+ */
+void make_auditfile_name(char *name)
+{
+	strcpy(tmp_buffer + TMP_BUFFER_SIZE - 10, name);
+	strcpy(tmp_buffer, AUDIT_PREFIX);
+	strcat(tmp_buffer, tmp_buffer + TMP_BUFFER_SIZE - 10);
+	strcat_P(tmp_buffer, PSTR(".txt"));
+}
+
+/** Write a state-changing API request to the audit log
+ * Records are in the form of [time,"client","command","parameters",result]
+ */
+void write_audit_log(const char *client, const char *cmd, const char *params, byte result, ulong curr_time)
+{
+	if (!os.iopts[IOPT_ENABLE_LOGGING])
+		return;
+
+	char record[2 * TMP_BUFFER_SIZE + 128];
+	int n = snprintf(record, sizeof(record), "[%lu,\"%s\",\"%.2s\",\"", curr_time, client, cmd);
+	// parameters are url-encoded, only quotes and backslashes need escaping
+	for (const char *p = params; *p && n < (int)sizeof(record) - 16; p++)
+	{
+		if (*p == '"' || *p == '\\')
+			record[n++] = '\\';
+		record[n++] = *p;
+	}
+	snprintf(record + n, sizeof(record) - n, "\",%d]\r\n", result);
+	write_log_message(AUDIT_PREFIX, S_IRWXU | S_IRWXG, record, curr_time);
+}
+
+char WEATHERLOG_PREFIX[] = "./wlog/";
+
+/** Generate weather log file name
+ * Weather log files will be named /wlog/xxxxx.txt
+ */
+void make_weatherlog_name(char *name)
+{
+	strcpy(tmp_buffer + TMP_BUFFER_SIZE - 10, name);
+	strcpy(tmp_buffer, WEATHERLOG_PREFIX);
+	strcat(tmp_buffer, tmp_buffer + TMP_BUFFER_SIZE - 10);
+	strcat_P(tmp_buffer, PSTR(".txt"));
+}
+
+/** Write a weather call result to the weather log
+ * Records are in the form of [time,error code,water level,raw data], with the raw data
+ * object as the weather service returned it ({} if there is none).
+ */
+void write_weather_log(int errCode, byte wl, const char *raw, ulong curr_time)
+{
+	if (!os.iopts[IOPT_ENABLE_LOGGING])
+		return;
+
+	char record[TMP_BUFFER_SIZE + 64];
+	int n = snprintf(record, sizeof(record), "[%lu,%d,%d,", curr_time, errCode, wl);
+	// the record must stay on one line
+	for (const char *p = (raw && raw[0] == '{') ? raw : "{}"; *p && n < (int)sizeof(record) - 4; p++)
+	{
+		if (*p != '\r' && *p != '\n')
+			record[n++] = *p;
+	}
+	strcpy(record + n, "]\r\n");
+	write_log_message(WEATHERLOG_PREFIX, S_IRWXU | S_IRWXG | S_IRWXO, record, curr_time);
+}
+
 /** Perform network check
  * This function pings the router
  * to check if it's still online.
@@ -1335,14 +2451,45 @@ void perform_ntp_sync()
 	// Linux will do this for you
 }
 
+/** Close all valves and save the controller state before the process exits */
+void do_shutdown()
+{
+	service_notify("STOPPING=1");
+	ulong curr_time = os.now_tz();
+	// turn off running stations one by one, so that their runs are logged
+	for (byte sid = 0; sid < os.nstations; sid++)
+	{
+		if ((os.station_bits[sid >> 3] >> (sid & 0x07)) & 1)
+			turn_off_station(sid, curr_time);
+	}
+	reset_all_stations_immediate();
+	os.nvdata_save();
+
+	push_message(NOTIFY_REBOOT, 1);
+	// remote and HTTP stations are turned off by the worker thread, which dies with the process
+	if (!os.drain_http_requests())
+		DEBUG_PRINTLN(F("shutdown: some http requests were not sent"));
+	// and RF stations by the transmit thread
+	if (!rf_drain(RF_DRAIN_TIMEOUT))
+		DEBUG_PRINTLN(F("shutdown: some RF codes were not sent"));
+	os.mqtt.end();
+}
+
 // main function for RPI
+#if !defined(OS_LIBRARY)
 int main(int argc, char *argv[])
 {
+	// command line requests are handled without starting the controller
+	if (argc > 1)
+		return cli_main(argc, argv);
+
 	do_setup();
 
-	while (true)
+	while (!shutdown_requested)
 	{
 		do_loop();
 	}
+	do_shutdown();
 	return 0;
 }
+#endif // OS_LIBRARY
@@ -0,0 +1,100 @@
+/* OpenSprinkler Unified (RPI/LINUX) Firmware
+ * Copyright (C) 2015 by Ray Wang (ray@opensprinkler.com)
+ *
+ * Controller functions header file
+ * Feb 2015 @ OpenSprinkler.com
+ *
+ * This file is part of the OpenSprinkler library
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see
+ * <http://www.gnu.org/licenses/>.
+ */
+
+#ifndef _MAIN_H
+#define _MAIN_H
+
+#include "program.h"
+
+/** The controller objects and buffers shared by all modules (defined in main.cpp) */
+extern OpenSprinkler os;
+extern ProgramData pd;
+extern char ether_buffer[];
+extern char tmp_buffer[];
+
+extern ulong flow_count;
+extern ulong flow_last_pulse;
+extern ulong flow_pulse_interval;
+extern byte zonetest_total;
+extern ulong zonetest_end;
+extern uint32_t reboot_timer;
+extern byte reboot_cause;
+extern char LOG_PREFIX[];
+extern int frost_temp;
+extern bool frost_temp_known;
+extern char AUDIT_PREFIX[];
+extern char WEATHERLOG_PREFIX[];
+
+// Controller life cycle, see main() for how they are called.
+// Building with OS_LIBRARY leaves out main(), so the controller can be embedded in another program.
+void do_setup();
+void do_loop();
+void do_shutdown();
+bool shutdown_pending();
+
+// Station scheduling
+void schedule_all_stations(ulong curr_time);
+void reschedule_waiting_stations(ulong curr_time, byte first);
+void turn_off_station(byte sid, ulong curr_time);
+void reset_all_stations();
+void reset_all_stations_immediate();
+void manual_start_program(byte, uint16_t);
+byte start_zone_test(const byte *sids, byte n, uint16_t dur, uint16_t soak);
+
+// Notifications
+void push_message(int type, uint32_t lval = 0, float fval = 0.f, const char *sval = NULL);
+
+// Logs
+void write_log(byte type, ulong curr_time);
+void delete_log(char *name);
+void make_logfile_name(char *name);
+void make_auditfile_name(char *name);
+void write_audit_log(const char *client, const char *cmd, const char *params, byte result, ulong curr_time);
+void make_weatherlog_name(char *name);
+void write_weather_log(int errCode, byte wl, const char *raw, ulong curr_time);
+ulong get_flow_log_count();
+
+/** Log a scheduling decision in dry run mode
+ * The lines go to the debug output (see /jx) of the calling module, so new programs can be
+ * checked on a live system: what would run, for how long, and why runs are skipped.
+ */
+#if defined(ENABLE_DEBUG)
+#define dry_run_log(fmt, ...)                                                              \
+	do                                                                                     \
+	{                                                                                      \
+		if (os.iopts[IOPT_DRY_RUN])                                                        \
+			debug_printf(__FILE__, DEBUG_LEVEL_INFO, "dry run: " fmt "\n", ##__VA_ARGS__); \
+	} while (0)
+#else
+#define dry_run_log(fmt, ...) \
+	do                        \
+	{                         \
+	} while (0)
+#endif
+
+// Flow units
+float flow_from_liters(float liters);
+float flow_pulse_liters();
+const char *flow_unit();
+
+#endif // _MAIN_H
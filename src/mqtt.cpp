@@ -23,20 +23,29 @@
 
 #include <time.h>
 #include <stdio.h>
+#include <stdarg.h>
+#include <ctype.h>
+#include <sys/stat.h>
+#if defined(DISABLE_MQTT)
+static void *mqtt_client = NULL; // never created, so the public interface does nothing
+#else
 #include <mosquitto.h>
 
 struct mosquitto *mqtt_client = NULL;
+#endif
 
 #include "OpenSprinkler.h"
 #include "mqtt.h"
+#include "opensprinkler_server.h"
+#include "main.h"
 
 // Debug routines to help identify any blocking of the event loop for an extended period
 
 #if defined(ENABLE_DEBUG)
 #include <sys/time.h>
-#define DEBUG_PRINTF(msg, ...)      \
-	{                               \
-		printf(msg, ##__VA_ARGS__); \
+#define DEBUG_PRINTF(msg, ...)                                         \
+	{                                                                  \
+		debug_printf(__FILE__, DEBUG_LEVEL_TRACE, msg, ##__VA_ARGS__); \
 	}
 #define DEBUG_TIMESTAMP()                               \
 	{                                                   \
@@ -46,10 +55,13 @@ struct mosquitto *mqtt_client = NULL;
 		strftime(tstr, 21, "%y-%m-%d %H:%M:%S - ", tm); \
 		printf("%s", tstr);                             \
 	}
-#define DEBUG_LOGF(msg, ...)              \
-	{                                     \
-		DEBUG_TIMESTAMP();                \
-		DEBUG_PRINTF(msg, ##__VA_ARGS__); \
+#define DEBUG_LOGF(msg, ...)                            \
+	{                                                   \
+		if (debug_enabled(__FILE__, DEBUG_LEVEL_TRACE)) \
+		{                                               \
+			DEBUG_TIMESTAMP();                          \
+			DEBUG_PRINTF(msg, ##__VA_ARGS__);           \
+		}                                               \
 	}
 
 static unsigned long _lastMillis = 0; // Holds the timestamp associated with the last call to DEBUG_DURATION()
@@ -74,21 +86,52 @@ inline unsigned long DEBUG_DURATION()
 #define str(s) #s
 #define xstr(s) str(s)
 
-extern OpenSprinkler os;
-extern char tmp_buffer[];
 
 #define MQTT_KEEPALIVE 60
 #define MQTT_DEFAULT_PORT 1883	 // Default port for MQTT. Can be overwritten through App config
+#define MQTT_DEFAULT_TLS_PORT 8883 // Default port for MQTT over TLS
+#define MQTT_DEFAULT_CA_PATH "/etc/ssl/certs" // CA certificates used when SOPT_MQTT_TLS does not name any
 #define MQTT_MAX_HOST_LEN 50	 // Note: App is set to max 50 chars for broker name
 #define MQTT_MAX_USERNAME_LEN 32 // Note: App is set to max 32 chars for username
 #define MQTT_MAX_PASSWORD_LEN 32 // Note: App is set to max 32 chars for password
-#define MQTT_MAX_ID_LEN 16		 // MQTT Client Id to uniquely reference this unit
+#define MQTT_MAX_ID_LEN 23		 // MQTT Client Id to uniquely reference this unit (23 is the limit of MQTT 3.1)
 #define MQTT_RECONNECT_DELAY 120 // Minumum of 60 seconds between reconnect attempts
 
-#define MQTT_ROOT_TOPIC "opensprinkler"
-#define MQTT_AVAILABILITY_TOPIC MQTT_ROOT_TOPIC "/availability"
+#define MQTT_ROOT_TOPIC "opensprinkler" // default root topic, see SOPT_MQTT_TOPIC
+#define MQTT_NAMED_ROOT_TOPIC "opensprinkler/{name}" // default root topic once the device is named
+#define MQTT_MAX_ROOT_LEN 64
+#define MQTT_MAX_TOPIC_LEN 128
+#define MQTT_AVAILABILITY_TOPIC "availability"
 #define MQTT_ONLINE_PAYLOAD "online"
 #define MQTT_OFFLINE_PAYLOAD "offline"
+#define MQTT_GROUP_TOPIC "group/"	// followed by the group id, payload holds the command parameters
+#define MQTT_SWITCH_TOPIC "switch/" // followed by the virtual switch index, any payload triggers it
+#define MQTT_RUNONCE_TOPIC "runonce" // any payload runs the last run-once program again
+#define MQTT_MAX_COMMAND_LEN 64		// maximum length of a command payload
+#define MQTT_STATE_TOPIC "state"	// retained snapshot of the controller state
+#define MQTT_MAX_STATE_LEN 320		// maximum length of the state payload
+#define MQTT_STATE_INTERVAL 1000	// how often the state is checked for changes (in ms)
+#define MQTT_NUM_EVENTS 16			// number of notification type bits
+#define MQTT_EVENT_RETAIN 0x04		// retain flag in the event options, the lower bits are the QoS
+
+/** Notification types by the name of their topic, used to set QoS and retain flags in SOPT_MQTT_EVENTS */
+static const struct
+{
+	int type;
+	const char *name;
+} mqtt_events[] = {
+	{NOTIFY_STATION_ON | NOTIFY_STATION_OFF, "station"},
+	{NOTIFY_SENSOR1, "sensor1"},
+	{NOTIFY_SENSOR2, "sensor2"},
+	{NOTIFY_RAINDELAY, "raindelay"},
+	{NOTIFY_FLOWSENSOR, "flow"},
+	{NOTIFY_NO_FLOW, "noflow"},
+	{NOTIFY_ELECTRICAL_FAULT, "fault"},
+	{NOTIFY_WEATHER_UPDATE | NOTIFY_WEATHER_FAIL, "weather"},
+	{NOTIFY_PROGRAM_SCHED, "program"},
+	{NOTIFY_QUEUE_OVERFLOW, "queue"},
+	{NOTIFY_REBOOT, "system"},
+};
 
 #define MQTT_SUCCESS 0 // Returned when function operated successfully
 #define MQTT_ERROR 1   // Returned whan function failed
@@ -99,12 +142,28 @@ char OSMqtt::_username[MQTT_MAX_USERNAME_LEN + 1] = {0}; // username to connect
 char OSMqtt::_password[MQTT_MAX_PASSWORD_LEN + 1] = {0}; // password to connect to the broker
 int OSMqtt::_port = MQTT_DEFAULT_PORT;					 // Port of the broker (default 1883)
 bool OSMqtt::_enabled = false;							 // Flag indicating whether MQTT is enabled
+char OSMqtt::_root[MQTT_MAX_ROOT_LEN + 1] = MQTT_ROOT_TOPIC;	 // Root topic of all topics
+byte OSMqtt::_event_opts[MQTT_NUM_EVENTS] = {0};			 // QoS and retain flag of each notification type
+bool OSMqtt::_tls = false;									 // Flag indicating whether to connect with TLS
+bool OSMqtt::_tls_verify = true;							 // Flag indicating whether to verify the broker host name
+char OSMqtt::_tls_ca[MAX_SOPTS_SIZE + 1] = {0};				 // CA certificate file or directory
+char OSMqtt::_tls_cert[MAX_SOPTS_SIZE + 1] = {0};			 // Client certificate file
+char OSMqtt::_tls_key[MAX_SOPTS_SIZE + 1] = {0};			 // Client key file
+char OSMqtt::_state[MQTT_MAX_STATE_LEN] = {0};				 // Last published state snapshot
 
 // Initialise the client libraries and event handlers.
 void OSMqtt::init(void)
 {
 	DEBUG_LOGF("MQTT Init\r\n");
-	char id[MQTT_MAX_ID_LEN + 1] = {0};
+	// the client id is made from the device UUID, so that several controllers can share a broker
+	char id[MQTT_MAX_ID_LEN + 1] = "OS-";
+	byte n = 3;
+	for (const char *p = os.device_uuid; *p && n < MQTT_MAX_ID_LEN; p++)
+	{
+		if (*p != '-')
+			id[n++] = *p;
+	}
+	id[n] = 0;
 	init(id);
 };
 
@@ -139,9 +198,165 @@ void OSMqtt::begin(void)
 			&enabled, host, &port, username, password);
 	}
 
+	_load_topics();
+	_load_tls(host);
+	// a host given as mqtts://name or ssl://name selects TLS
+	char *scheme = strstr(host, "://");
+	if (scheme)
+		memmove(host, scheme + 3, strlen(scheme + 3) + 1);
+	if (_tls && port == MQTT_DEFAULT_PORT)
+		port = MQTT_DEFAULT_TLS_PORT;
 	begin(host, port, username, password, (bool)enabled);
 }
 
+// Load the TLS settings in the form of "en":0|1,"ca":"file or directory","cert":"file","key":"file","vfy":0|1
+// TLS is also used if the host starts with mqtts:// or ssl://
+void OSMqtt::_load_tls(const char *host)
+{
+	char *config = tmp_buffer;
+	char value[MAX_SOPTS_SIZE + 1];
+	const char *start, *end;
+	os.sopt_load(SOPT_MQTT_TLS, config);
+
+	_tls = (strncmp(host, "mqtts://", 8) == 0 || strncmp(host, "ssl://", 6) == 0);
+	if (json_field_find(config, "en", &start, &end))
+	{
+		json_value_copy(value, start, end);
+		_tls = _tls || atoi(value);
+	}
+	_tls_verify = true;
+	if (json_field_find(config, "vfy", &start, &end))
+	{
+		json_value_copy(value, start, end);
+		_tls_verify = atoi(value);
+	}
+
+	struct
+	{
+		const char *field;
+		char *dst;
+	} paths[] = {{"ca", _tls_ca}, {"cert", _tls_cert}, {"key", _tls_key}};
+	for (byte i = 0; i < sizeof(paths) / sizeof(paths[0]); i++)
+	{
+		paths[i].dst[0] = 0;
+		if (json_field_find(config, paths[i].field, &start, &end))
+			json_value_copy(paths[i].dst, start, end);
+	}
+	if (!_tls_ca[0])
+		strcpy(_tls_ca, MQTT_DEFAULT_CA_PATH);
+}
+
+// Write the slug of a name to buf: lower case letters and digits, other characters become single dashes.
+// Returns the length of the slug, 0 if nothing is left of the name.
+static byte slugify(const char *name, char *buf)
+{
+	byte n = 0;
+	for (const char *p = name; *p; p++)
+	{
+		if (isalnum(*p))
+			buf[n++] = tolower(*p);
+		else if (n > 0 && buf[n - 1] != '-')
+			buf[n++] = '-';
+	}
+	while (n > 0 && buf[n - 1] == '-')
+		n--;
+	buf[n] = 0;
+	return n;
+}
+
+// Load the root topic template and the QoS and retain flags of each event.
+// The template may hold {id}, the device id, {name}, the slug of the device name, and {uuid}, the device UUID.
+// Event options are in the form of station=1r,sensor1=0,... where the digit is the QoS and r retains the message.
+void OSMqtt::_load_topics(void)
+{
+	char name[MAX_SOPTS_SIZE + 1];
+	os.sopt_load(SOPT_DEVICE_NAME, name);
+	bool named = name[0];
+	if (!slugify(name, name))
+		slugify(DEFAULT_DEVICE_NAME, name);
+
+	char *tpl = tmp_buffer;
+	os.sopt_load(SOPT_MQTT_TOPIC, tpl);
+	if (!tpl[0])
+		strcpy(tpl, named ? MQTT_NAMED_ROOT_TOPIC : MQTT_ROOT_TOPIC);
+	byte n = 0;
+	for (const char *p = tpl; *p && n < MQTT_MAX_ROOT_LEN; p++)
+	{
+		if (strncmp(p, "{id}", 4) == 0)
+		{
+			n += snprintf(_root + n, MQTT_MAX_ROOT_LEN + 1 - n, "%d", os.iopts[IOPT_DEVICE_ID]);
+			p += 3;
+		}
+		else if (strncmp(p, "{name}", 6) == 0)
+		{
+			n += snprintf(_root + n, MQTT_MAX_ROOT_LEN + 1 - n, "%s", name);
+			p += 5;
+		}
+		else if (strncmp(p, "{uuid}", 6) == 0)
+		{
+			n += snprintf(_root + n, MQTT_MAX_ROOT_LEN + 1 - n, "%s", os.device_uuid);
+			p += 5;
+		}
+		else
+		{
+			_root[n++] = *p;
+		}
+	}
+	if (n > MQTT_MAX_ROOT_LEN)
+		n = MQTT_MAX_ROOT_LEN;
+	while (n > 0 && _root[n - 1] == '/')
+		n--;
+	_root[n] = 0;
+
+	memset(_event_opts, 0, sizeof(_event_opts));
+	char *opts = tmp_buffer;
+	os.sopt_load(SOPT_MQTT_EVENTS, opts);
+	for (char *item = strtok(opts, ","); item; item = strtok(NULL, ","))
+	{
+		char *v = strchr(item, '=');
+		if (!v || v[1] < '0' || v[1] > '2')
+			continue;
+		*v++ = 0;
+		byte opt = (v[0] - '0') | ((v[1] == 'r') ? MQTT_EVENT_RETAIN : 0);
+		for (byte i = 0; i < sizeof(mqtt_events) / sizeof(mqtt_events[0]); i++)
+		{
+			if (strcmp(item, mqtt_events[i].name))
+				continue;
+			for (byte b = 0; b < MQTT_NUM_EVENTS; b++)
+			{
+				if (mqtt_events[i].type & (1 << b))
+					_event_opts[b] = opt;
+			}
+		}
+	}
+}
+
+// Write the full topic of a sub topic, e.g. station/1 becomes opensprinkler/station/1
+char *OSMqtt::topic(char *buf, const char *fmt, ...)
+{
+	int n = snprintf(buf, MQTT_MAX_TOPIC_LEN, "%s/", _root);
+	va_list ap;
+	va_start(ap, fmt);
+	vsnprintf(buf + n, MQTT_MAX_TOPIC_LEN - n, fmt, ap);
+	va_end(ap);
+	return buf;
+}
+
+// Write the topic segment of a station: its index, or the slug of its name if IOPT_MQTT_STATION_NAMES is set
+char *OSMqtt::station_key(byte sid, char *buf)
+{
+	sprintf(buf, "%d", sid);
+	if (!os.iopts[IOPT_MQTT_STATION_NAMES])
+		return buf;
+
+	char name[STATION_NAME_SIZE + 1];
+	os.get_station_name(sid, name);
+	name[STATION_NAME_SIZE] = 0;
+	if (!slugify(name, buf))
+		sprintf(buf, "%d", sid); // nothing left of the name
+	return buf;
+}
+
 // Start the MQTT service and connect to the MQTT broker.
 void OSMqtt::begin(const char *host, int port, const char *username, const char *password, bool enabled)
 {
@@ -170,8 +385,8 @@ void OSMqtt::begin(const char *host, int port, const char *username, const char
 	}
 }
 
-// Publish an MQTT message to a specific topic
-void OSMqtt::publish(const char *topic, const char *payload)
+// Publish an MQTT message to a specific topic, with the QoS and retain flag of the notification type
+void OSMqtt::publish(const char *topic, const char *payload, int type)
 {
 	DEBUG_LOGF("MQTT Publish: %s %s\r\n", topic, payload);
 
@@ -184,7 +399,49 @@ void OSMqtt::publish(const char *topic, const char *payload)
 		return;
 	}
 
-	_publish(topic, payload);
+	byte opt = 0;
+	for (byte b = 0; b < MQTT_NUM_EVENTS; b++)
+	{
+		if (type & (1 << b))
+			opt = _event_opts[b];
+	}
+	_publish(topic, payload, opt & 0x03, opt & MQTT_EVENT_RETAIN);
+}
+
+// Publish a retained snapshot of the controller state, so that subscribers know it without waiting for events.
+// Nothing is sent unless the state has changed since the last snapshot, or force is set (e.g. after connecting).
+void OSMqtt::publish_state(bool force)
+{
+	if (mqtt_client == NULL || !_enabled || os.status.network_fails > 0 || !_connected())
+		return;
+
+	char payload[MQTT_MAX_STATE_LEN];
+	BufferFiller bf = payload;
+	bf.emit_p(PSTR("{\"en\":$D,\"wl\":$D,\"rd\":$D,\"rdst\":$L,\"sn1\":$D,\"sn2\":$D,\"busy\":$D,\"nbrd\":$D,\"sbits\":"),
+			  os.status.enabled, os.iopts[IOPT_WATER_PERCENTAGE], os.status.rain_delayed, os.nvdata.rd_stop_time,
+			  os.status.sensor1_active, os.status.sensor2_active, os.status.program_busy, os.nboards);
+	emit_station_bits(bf, false);
+	bf.emit_p(PSTR("}"));
+
+	if (!force && strcmp(payload, _state) == 0)
+		return;
+	strcpy(_state, payload);
+
+	char topic[MQTT_MAX_TOPIC_LEN];
+	DEBUG_LOGF("MQTT Publish: %s %s\r\n", OSMqtt::topic(topic, MQTT_STATE_TOPIC), payload);
+	_publish(topic, payload, 1, true);
+}
+
+// Disconnect from the MQTT broker before the process exits.
+void OSMqtt::end(void)
+{
+	DEBUG_LOGF("MQTT End\r\n");
+
+	if (mqtt_client == NULL || !_connected())
+		return;
+
+	_disconnect();
+	_loop(); // send the pending messages
 }
 
 // Regularly call the loop function to ensure "keep alive" messages are sent to the broker and to reconnect if needed.
@@ -205,6 +462,13 @@ void OSMqtt::loop(void)
 
 	int state = _loop();
 
+	static unsigned long last_state_check = 0;
+	if (millis() - last_state_check >= MQTT_STATE_INTERVAL)
+	{
+		publish_state();
+		last_state_check = millis();
+	}
+
 #if defined(ENABLE_DEBUG)
 	// Print a diagnostic message whenever the MQTT state changes
 	bool network = os.network_connected(), mqtt = _connected();
@@ -224,9 +488,27 @@ void OSMqtt::loop(void)
 #endif
 }
 
+#if defined(DISABLE_MQTT)
+/************************** DISABLED ****************************************/
+
+int OSMqtt::_init(void)
+{
+	DEBUG_LOGF("MQTT Init: Disabled at compile time\r\n");
+	return MQTT_ERROR;
+}
+
+int OSMqtt::_connect(void) { return MQTT_ERROR; }
+int OSMqtt::_disconnect(void) { return MQTT_SUCCESS; }
+bool OSMqtt::_connected(void) { return false; }
+int OSMqtt::_publish(const char *topic, const char *payload, int qos, bool retain) { return MQTT_ERROR; }
+int OSMqtt::_loop(void) { return MQTT_ERROR; }
+const char *OSMqtt::_state_string(int error) { return "disabled"; }
+
+#else
 /************************** RASPBERRY PI / DEMO ****************************************/
 
 static bool _connected = false;
+static bool _tls_set = false; // TLS has been set on the client, which can only be undone by a new client
 
 static void _mqtt_connection_cb(struct mosquitto *mqtt_client, void *obj, int reason)
 {
@@ -236,11 +518,70 @@ static void _mqtt_connection_cb(struct mosquitto *mqtt_client, void *obj, int re
 
 	if (reason == 0)
 	{
-		int rc = mosquitto_publish(mqtt_client, NULL, MQTT_AVAILABILITY_TOPIC, strlen(MQTT_ONLINE_PAYLOAD), MQTT_ONLINE_PAYLOAD, 0, true);
+		char topic[MQTT_MAX_TOPIC_LEN];
+		OSMqtt::topic(topic, MQTT_AVAILABILITY_TOPIC);
+		int rc = mosquitto_publish(mqtt_client, NULL, topic, strlen(MQTT_ONLINE_PAYLOAD), MQTT_ONLINE_PAYLOAD, 0, true);
 		if (rc != MOSQ_ERR_SUCCESS)
 		{
 			DEBUG_LOGF("MQTT Publish: Failed (%s)\r\n", mosquitto_strerror(rc));
 		}
+		const char *subscriptions[] = {MQTT_GROUP_TOPIC "+", MQTT_SWITCH_TOPIC "+", MQTT_RUNONCE_TOPIC};
+		for (byte i = 0; i < sizeof(subscriptions) / sizeof(subscriptions[0]); i++)
+		{
+			rc = mosquitto_subscribe(mqtt_client, NULL, OSMqtt::topic(topic, subscriptions[i]), 0);
+			if (rc != MOSQ_ERR_SUCCESS)
+			{
+				DEBUG_LOGF("MQTT Subscribe: Failed (%s)\r\n", mosquitto_strerror(rc));
+			}
+		}
+		// the broker may have lost the retained state, e.g. after a restart
+		OSMqtt::publish_state(true);
+	}
+}
+
+static void _mqtt_message_cb(struct mosquitto *mqtt_client, void *obj, const struct mosquitto_message *msg)
+{
+	DEBUG_LOGF("MQTT Message Callback: %s (%d bytes)\r\n", msg->topic, msg->payloadlen);
+
+	char group_topic[MQTT_MAX_TOPIC_LEN], switch_topic[MQTT_MAX_TOPIC_LEN], runonce_topic[MQTT_MAX_TOPIC_LEN];
+	OSMqtt::topic(group_topic, MQTT_GROUP_TOPIC);
+	OSMqtt::topic(switch_topic, MQTT_SWITCH_TOPIC);
+	OSMqtt::topic(runonce_topic, MQTT_RUNONCE_TOPIC);
+
+	// group command, e.g. opensprinkler/group/1 with payload t=600
+	if (strncmp(msg->topic, group_topic, strlen(group_topic)) == 0)
+	{
+		if (msg->payloadlen <= 0 || msg->payloadlen > MQTT_MAX_COMMAND_LEN)
+			return;
+		char command[MQTT_MAX_COMMAND_LEN + 1];
+		memcpy(command, msg->payload, msg->payloadlen);
+		command[msg->payloadlen] = 0;
+		int gid = atoi(msg->topic + strlen(group_topic));
+		if (gid <= 0 || gid > MAX_NUM_GROUPS)
+			return;
+		if (apply_group_command(gid, command) != HTML_SUCCESS)
+		{
+			DEBUG_LOGF("MQTT Group Command: Failed (%d %s)\r\n", gid, command);
+		}
+	}
+	// virtual program switch, e.g. opensprinkler/switch/1
+	else if (strncmp(msg->topic, switch_topic, strlen(switch_topic)) == 0)
+	{
+		int sw = atoi(msg->topic + strlen(switch_topic));
+		if (sw <= 0 || sw > MAX_NUM_VSWITCHES)
+			return;
+		if (trigger_virtual_switch(sw) != HTML_SUCCESS)
+		{
+			DEBUG_LOGF("MQTT Switch: Ignored (%d)\r\n", sw);
+		}
+	}
+	// last run-once program, e.g. opensprinkler/runonce
+	else if (strcmp(msg->topic, runonce_topic) == 0)
+	{
+		if (rerun_last_runonce() != HTML_SUCCESS)
+		{
+			DEBUG_LOGF("MQTT Run-once: Ignored\r\n");
+		}
 	}
 }
 
@@ -271,7 +612,7 @@ int OSMqtt::_init(void)
 		mqtt_client = NULL;
 	};
 
-	mqtt_client = mosquitto_new("OS", true, NULL);
+	mqtt_client = mosquitto_new(_id[0] ? _id : "OS", true, NULL);
 	if (mqtt_client == NULL)
 	{
 		DEBUG_PRINTF("MQTT Init: Failed to initialise client\r\n");
@@ -281,14 +622,43 @@ int OSMqtt::_init(void)
 	mosquitto_connect_callback_set(mqtt_client, _mqtt_connection_cb);
 	mosquitto_disconnect_callback_set(mqtt_client, _mqtt_disconnection_cb);
 	mosquitto_log_callback_set(mqtt_client, _mqtt_log_cb);
-	mosquitto_will_set(mqtt_client, MQTT_AVAILABILITY_TOPIC, strlen(MQTT_OFFLINE_PAYLOAD), MQTT_OFFLINE_PAYLOAD, 0, true);
+	mosquitto_message_callback_set(mqtt_client, _mqtt_message_cb);
 
 	return MQTT_SUCCESS;
 }
 
 int OSMqtt::_connect(void)
 {
-	int rc;
+	if (_tls_set && !_tls)
+	{
+		_init();
+		_tls_set = false;
+	}
+	if (_tls)
+	{
+		// the CA setting may name a single file or a directory of certificates
+		struct stat st;
+		bool ca_dir = stat(_tls_ca, &st) == 0 && S_ISDIR(st.st_mode);
+		int rc = mosquitto_tls_set(mqtt_client, ca_dir ? NULL : _tls_ca, ca_dir ? _tls_ca : NULL,
+								   _tls_cert[0] ? _tls_cert : NULL, _tls_key[0] ? _tls_key : NULL, NULL);
+		if (rc == MOSQ_ERR_SUCCESS)
+			rc = mosquitto_tls_insecure_set(mqtt_client, !_tls_verify);
+		if (rc != MOSQ_ERR_SUCCESS)
+		{
+			DEBUG_LOGF("MQTT Connect: TLS Failed (%s)\r\n", mosquitto_strerror(rc));
+			return MQTT_ERROR;
+		}
+		_tls_set = true;
+	}
+
+	// the root topic may have changed since the last connection
+	char topic[MQTT_MAX_TOPIC_LEN];
+	OSMqtt::topic(topic, MQTT_AVAILABILITY_TOPIC);
+	int rc = mosquitto_will_set(mqtt_client, topic, strlen(MQTT_OFFLINE_PAYLOAD), MQTT_OFFLINE_PAYLOAD, 0, true);
+	if (rc != MOSQ_ERR_SUCCESS)
+	{
+		DEBUG_LOGF("MQTT Connect: Will Failed (%s)\r\n", mosquitto_strerror(rc));
+	}
 	if (_username[0])
 	{
 		rc = mosquitto_username_pw_set(mqtt_client, _username, _password);
@@ -314,15 +684,24 @@ int OSMqtt::_connect(void)
 
 int OSMqtt::_disconnect(void)
 {
-	int rc = mosquitto_disconnect(mqtt_client);
+	// a clean disconnect does not trigger the will message, so announce it here
+	char topic[MQTT_MAX_TOPIC_LEN];
+	OSMqtt::topic(topic, MQTT_AVAILABILITY_TOPIC);
+	int rc = mosquitto_publish(mqtt_client, NULL, topic, strlen(MQTT_OFFLINE_PAYLOAD), MQTT_OFFLINE_PAYLOAD, 0, true);
+	if (rc != MOSQ_ERR_SUCCESS)
+	{
+		DEBUG_LOGF("MQTT Publish: Failed (%s)\r\n", mosquitto_strerror(rc));
+	}
+	mosquitto_loop(mqtt_client, 0, 1);
+	rc = mosquitto_disconnect(mqtt_client);
 	return rc == MOSQ_ERR_SUCCESS ? MQTT_SUCCESS : MQTT_ERROR;
 }
 
 bool OSMqtt::_connected(void) { return ::_connected; }
 
-int OSMqtt::_publish(const char *topic, const char *payload)
+int OSMqtt::_publish(const char *topic, const char *payload, int qos, bool retain)
 {
-	int rc = mosquitto_publish(mqtt_client, NULL, topic, strlen(payload), payload, 0, false);
+	int rc = mosquitto_publish(mqtt_client, NULL, topic, strlen(payload), payload, qos, retain);
 	if (rc != MOSQ_ERR_SUCCESS)
 	{
 		DEBUG_LOGF("MQTT Publish: Failed (%s)\r\n", mosquitto_strerror(rc));
@@ -340,3 +719,4 @@ const char *OSMqtt::_state_string(int error)
 {
 	return mosquitto_strerror(error);
 }
+#endif // DISABLE_MQTT
@@ -33,13 +33,24 @@ private:
     static char _username[];
     static char _password[];
     static bool _enabled;
+    static char _root[];       // root topic, with the placeholders of the template filled in
+    static byte _event_opts[]; // QoS (bits 0-1) and retain flag (bit 2) of each notification type
+    static bool _tls;          // connect with TLS
+    static bool _tls_verify;   // verify the host name in the broker certificate
+    static char _tls_ca[];     // CA certificate file or directory
+    static char _tls_cert[];   // client certificate file, optional
+    static char _tls_key[];    // client key file, optional
+    static char _state[];      // last published state snapshot
+
+    static void _load_topics(void);
+    static void _load_tls(const char *host);
 
     // Following routines are platform specific versions of the public interface
     static int _init(void);
     static int _connect(void);
     static int _disconnect(void);
     static bool _connected(void);
-    static int _publish(const char *topic, const char *payload);
+    static int _publish(const char *topic, const char *payload, int qos, bool retain);
     static int _loop(void);
     static const char *_state_string(int state);
 
@@ -49,8 +60,12 @@ public:
     static void begin(void);
     static void begin(const char *host, int port, const char *username, const char *password, bool enable);
     static bool enabled(void) { return _enabled; };
-    static void publish(const char *topic, const char *payload);
+    static void publish(const char *topic, const char *payload, int type = 0);
+    static void publish_state(bool force = false);
+    static char *topic(char *buf, const char *fmt, ...);
+    static char *station_key(byte sid, char *buf);
     static void loop(void);
+    static void end(void);
 };
 
 #endif // _MQTT_H
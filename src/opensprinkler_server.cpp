@@ -25,11 +25,25 @@
 #include "program.h"
 #include "opensprinkler_server.h"
 #include "weather.h"
+#include "calendar.h"
 #include "mqtt.h"
+#include "sim.h"
+#include "webui.h"
+#include "lang.h"
+#include "main.h"
+#include "timer.h"
+#include "budget.h"
+#include "debuglog.h"
 
 // External variables defined in main ion file
 #include <stdarg.h>
 #include <stdlib.h>
+#include <arpa/inet.h>
+#include <sys/stat.h>
+#include <sys/statvfs.h>
+#include <ifaddrs.h>
+#include <net/if.h>
+#include <zlib.h>
 #include "etherport.h"
 
 extern EthernetClient *m_client;
@@ -39,49 +53,34 @@ extern EthernetClient *m_client;
 		return;          \
 	}
 
-extern char ether_buffer[];
-extern char tmp_buffer[];
-extern OpenSprinkler os;
-extern ProgramData pd;
-extern ulong flow_count;
+// return a result code along with the parameter (and list element, or -1) that failed validation
+#define handle_return_item(x, item, index) \
+	{                                      \
+		return_code = x;                   \
+		return_item = item;                \
+		return_index = index;              \
+		return;                            \
+	}
 
 static byte return_code;
+static const char *return_item = NULL;
+static int return_index = -1;
+static bool guest_request = false; // the request was let in by guest access
+static bool bearer_request = false; // the request has the password in an Authorization header
+static bool local_request = false; // request from the local control socket, sent without http headers
+static ulong request_id = 0; // counts the requests, debug output printed while handling one is tagged with it
+static const char *request_principal = NULL; // who the request was handled for
+static int request_result = HTML_OK;
 static char *get_buffer = NULL;
 
 BufferFiller bfill;
 
-void schedule_all_stations(ulong curr_time);
-void turn_off_station(byte sid, ulong curr_time);
-void process_dynamic_events(ulong curr_time);
-void check_network(time_t curr_time);
-void check_weather(time_t curr_time);
-void perform_ntp_sync(time_t curr_time);
-void log_statistics(time_t curr_time);
-void delete_log(char *name);
-void reset_all_stations_immediate();
-void reset_all_stations();
-void make_logfile_name(char *name);
-
 /* Check available space (number of bytes) in the Ethernet buffer */
 int available_ether_buffer()
 {
 	return ETHER_BUFFER_SIZE - (int)bfill.position();
 }
 
-// Define return error code
-#define HTML_OK 0x00
-#define HTML_SUCCESS 0x01
-#define HTML_UNAUTHORIZED 0x02
-#define HTML_MISMATCH 0x03
-#define HTML_DATA_MISSING 0x10
-#define HTML_DATA_OUTOFBOUND 0x11
-#define HTML_DATA_FORMATERROR 0x12
-#define HTML_RFCODE_ERROR 0x13
-#define HTML_PAGE_NOT_FOUND 0x20
-#define HTML_NOT_PERMITTED 0x30
-#define HTML_UPLOAD_FAILED 0x40
-#define HTML_REDIRECT_HOME 0xFF
-
 static const char html200OK[] PROGMEM =
 	"HTTP/1.1 200 OK\r\n";
 
@@ -91,11 +90,21 @@ static const char htmlCacheCtrl[] PROGMEM =
 static const char htmlNoCache[] PROGMEM =
 	"Cache-Control: max-age=0, no-cache, no-store, must-revalidate\r\n";
 
+// cache headers of configuration outputs, which are revalidated with their ETag
+static char htmlETag[64];
+static ulong config_version = 0; // incremented whenever a command changes the controller
+
 static const char htmlContentHTML[] PROGMEM =
 	"Content-Type: text/html\r\n";
 
-static const char htmlAccessControl[] PROGMEM =
-	"Access-Control-Allow-Origin: *\r\n";
+// CORS headers of the current response, see check_request_origin
+static char htmlAccessControl[MAX_SOPTS_SIZE + 64];
+
+static const char htmlCorsPreflight[] PROGMEM =
+	"HTTP/1.1 204 No Content\r\n"
+	"Access-Control-Allow-Methods: GET, OPTIONS\r\n"
+	"Access-Control-Allow-Headers: Content-Type\r\n"
+	"Access-Control-Max-Age: 86400\r\n";
 
 static const char htmlContentJSON[] PROGMEM =
 	"Content-Type: application/json\r\n"
@@ -109,7 +118,8 @@ static const char htmlReturnHome[] PROGMEM =
 
 void print_html_standard_header()
 {
-	bfill.emit_p(PSTR("$F$F$F$F\r\n"), html200OK, htmlContentHTML, htmlNoCache, htmlAccessControl);
+	if (!local_request)
+		bfill.emit_p(PSTR("$F$F$F$S\r\n"), html200OK, htmlContentHTML, htmlNoCache, htmlAccessControl);
 	// TODO: streamline this part as well
 	/*m_client->write((const uint8_t *)html200OK, strlen(html200OK));
 	m_client->write((const uint8_t *)htmlContentHTML, strlen(htmlContentHTML));
@@ -120,7 +130,13 @@ void print_html_standard_header()
 
 void print_json_header(bool bracket = true)
 {
-	bfill.emit_p(PSTR("$F$F$F$F\r\n"), html200OK, htmlContentJSON, htmlAccessControl, htmlNoCache);
+	if (!local_request)
+	{
+		if (htmlETag[0])
+			bfill.emit_p(PSTR("$F$F$S$S\r\n"), html200OK, htmlContentJSON, htmlAccessControl, htmlETag);
+		else
+			bfill.emit_p(PSTR("$F$F$S$F\r\n"), html200OK, htmlContentJSON, htmlAccessControl, htmlNoCache);
+	}
 	if (bracket)
 		bfill.emit_p(PSTR("{"));
 	// TODO: streamline
@@ -132,7 +148,7 @@ void print_json_header(bool bracket = true)
 	else m_client->write((const uint8_t *)"\r\n", 2);*/
 }
 
-byte findKeyVal(const char *str, char *strbuf, uint16_t maxlen, const char *key, bool key_in_pgm = false, uint8_t *keyfound = NULL)
+byte findKeyVal(const char *str, char *strbuf, uint16_t maxlen, const char *key, bool key_in_pgm, uint8_t *keyfound)
 {
 	uint8_t found = 0;
 	// case 2: otherwise, assume the key-val is stored in str
@@ -196,17 +212,84 @@ void rewind_ether_buffer()
 	ether_buffer[0] = 0;
 }
 
+// gzip compression of the response, if the client accepts it
+static bool gzip_response = false;
+static bool gzip_started = false;
+static z_stream gzip_stream;
+
+/** Compress the buffered response and send it
+ * The headers of the first packet are sent as they are, with a Content-Encoding header added.
+ */
+static void send_packet_gzip(bool final)
+{
+	static byte out[4096];
+	char *body = ether_buffer;
+	if (!gzip_started)
+	{
+		char *end = strstr(ether_buffer, "\r\n\r\n");
+		if (!end || deflateInit2(&gzip_stream, Z_DEFAULT_COMPRESSION, Z_DEFLATED, 15 + 16, 8, Z_DEFAULT_STRATEGY) != Z_OK)
+		{
+			// not a response with headers, send it uncompressed
+			gzip_response = false;
+			m_client->write((const uint8_t *)ether_buffer, strlen(ether_buffer));
+			return;
+		}
+		gzip_started = true;
+		m_client->write((const uint8_t *)ether_buffer, end + 2 - ether_buffer);
+		m_client->write((const uint8_t *)"Content-Encoding: gzip\r\n\r\n", 26);
+		body = end + 4;
+	}
+	gzip_stream.next_in = (Bytef *)body;
+	gzip_stream.avail_in = strlen(body);
+	int ret;
+	do
+	{
+		gzip_stream.next_out = out;
+		gzip_stream.avail_out = sizeof(out);
+		ret = deflate(&gzip_stream, final ? Z_FINISH : Z_NO_FLUSH);
+		if (sizeof(out) - gzip_stream.avail_out)
+			m_client->write(out, sizeof(out) - gzip_stream.avail_out);
+	} while (ret == Z_OK && (gzip_stream.avail_in || gzip_stream.avail_out == 0));
+	if (final)
+	{
+		deflateEnd(&gzip_stream);
+		gzip_started = false;
+	}
+}
+
 void send_packet(bool final = false)
 {
-	m_client->write((const uint8_t *)ether_buffer, strlen(ether_buffer));
+	if (gzip_response)
+		send_packet_gzip(final);
+	else
+		m_client->write((const uint8_t *)ether_buffer, strlen(ether_buffer));
 	if (final)
 	{
 		m_client->stop();
+		gzip_response = false;
 	}
 	rewind_ether_buffer();
 	return;
 }
 
+/** Emit a result code and close the json object
+ * Errors are described in error. The parameter that failed validation,
+ * if the handler reported one, is added as item and index.
+ */
+static void emit_result(int ret)
+{
+	bfill.emit_p(PSTR("\"result\":$D"), ret);
+	if (ret != HTML_SUCCESS)
+		bfill.emit_p(PSTR(",\"error\":\"$S\""), result_message(ret));
+	if (return_item)
+	{
+		bfill.emit_p(PSTR(",\"item\":\"$S\""), return_item);
+		if (return_index >= 0)
+			bfill.emit_p(PSTR(",\"index\":$D"), return_index);
+	}
+	bfill.emit_p(PSTR("}"));
+}
+
 char dec2hexchar(byte dec)
 {
 	if (dec < 10)
@@ -215,6 +298,24 @@ char dec2hexchar(byte dec)
 		return 'A' + (dec - 10);
 }
 
+/** Check whether a request has the password, or sends it as a bearer token, even if the password is ignored */
+static bool check_password_key(char *p)
+{
+	if (bearer_request)
+		return true;
+	if (m_client && !p)
+	{
+		p = get_buffer;
+	}
+	if (findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("pw"), true))
+	{
+		urlDecode(tmp_buffer);
+		if (os.password_verify(tmp_buffer))
+			return true;
+	}
+	return false;
+}
+
 /** Check and verify password */
 boolean check_password(char *p)
 {
@@ -223,19 +324,27 @@ boolean check_password(char *p)
 #endif
 	if (os.iopts[IOPT_IGNORE_PASSWORD])
 		return true;
-	if (m_client && !p)
-	{
-		p = get_buffer;
-	}
+	return check_password_key(p);
+}
+
+/** Check whether a request has the guest key */
+static bool check_guest_key(char *p)
+{
 	if (findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("pw"), true))
 	{
 		urlDecode(tmp_buffer);
-		if (os.password_verify(tmp_buffer))
+		if (os.guest_verify(tmp_buffer))
 			return true;
 	}
 	return false;
 }
 
+/** Check guest access, which lets a request read the json outputs only */
+static bool check_guest(char *p)
+{
+	return os.guest_open() || check_guest_key(p);
+}
+
 void server_json_stations_attrib(const char *name, byte *attrib)
 {
 	bfill.emit_p(PSTR("\"$F\":["), name);
@@ -264,6 +373,7 @@ void server_json_stations_main()
 	for (sid = 0; sid < os.nstations; sid++)
 	{
 		os.get_station_name(sid, tmp_buffer);
+		utf8_truncate(tmp_buffer, STATION_NAME_SIZE);
 		bfill.emit_p(PSTR("\"$S\""), tmp_buffer);
 		if (sid != os.nstations - 1)
 			bfill.emit_p(PSTR(","));
@@ -272,9 +382,51 @@ void server_json_stations_main()
 			send_packet();
 		}
 	}
+	bfill.emit_p(PSTR("],\"stn_grp\":["));
+	for (sid = 0; sid < os.nstations; sid++)
+	{
+		bfill.emit_p(PSTR("$D"), os.attrib_grp[sid]);
+		if (sid != os.nstations - 1)
+			bfill.emit_p(PSTR(","));
+	}
+	bfill.emit_p(PSTR("],\"stn_pri\":["));
+	for (sid = 0; sid < os.nstations; sid++)
+	{
+		bfill.emit_p(PSTR("$D"), os.attrib_pri[sid]);
+		if (sid != os.nstations - 1)
+			bfill.emit_p(PSTR(","));
+	}
+	bfill.emit_p(PSTR("],\"stn_mdel\":["));
+	for (sid = 0; sid < os.nstations; sid++)
+	{
+		bfill.emit_p(PSTR("$D"), os.attrib_mdel[sid]);
+		if (sid != os.nstations - 1)
+			bfill.emit_p(PSTR(","));
+	}
+	bfill.emit_p(PSTR("],\"gnames\":["));
+	for (byte gid = 1; gid <= MAX_NUM_GROUPS; gid++)
+	{
+		os.get_group_name(gid, tmp_buffer);
+		bfill.emit_p(PSTR("\"$S\""), tmp_buffer);
+		if (gid != MAX_NUM_GROUPS)
+			bfill.emit_p(PSTR(","));
+	}
 	bfill.emit_p(PSTR("],\"maxlen\":$D}"), STATION_NAME_SIZE);
 }
 
+/** Output the station bits as a json array with one byte per board, e.g. [5,0]
+ * Bit n of a byte is station n of the board (station 1 is bit 0 of the first byte).
+ * This is the sbits layout of the original firmware, which pads the array of /jc
+ * with a trailing 0 that apps may count on, so pad adds it.
+ */
+void emit_station_bits(BufferFiller &bf, bool pad)
+{
+	bf.emit_p(PSTR("["));
+	for (byte bid = 0; bid < os.nboards; bid++)
+		bf.emit_p(bid ? PSTR(",$D") : PSTR("$D"), os.station_bits[bid]);
+	bf.emit_p(pad ? PSTR(",0]") : PSTR("]"));
+}
+
 /** Output stations data */
 void server_json_stations()
 {
@@ -283,7 +435,52 @@ void server_json_stations()
 	handle_return(HTML_OK);
 }
 
-/** Output station special attribute */
+/**
+ * Find stations by name
+ * Command: /jf?pw=xxx&search=xxx
+ *
+ * pw:		password
+ * search:	text the station names contain (case insensitive for ASCII letters, empty lists all stations)
+ * Output: {"stations":[{"sid":x,"name":"xxx"},...]}
+ */
+void server_json_find_stations()
+{
+	char *p = get_buffer;
+
+	char search[STATION_NAME_SIZE + 1];
+	search[0] = 0;
+	if (findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("search"), true))
+	{
+		urlDecode(tmp_buffer);
+		if (strlen(tmp_buffer) > STATION_NAME_SIZE)
+			handle_return_item(HTML_DATA_OUTOFBOUND, "search", -1);
+		strcpy(search, tmp_buffer);
+	}
+
+	print_json_header();
+	bfill.emit_p(PSTR("\"stations\":["));
+	bool comma = false;
+	for (byte sid = 0; sid < os.nstations; sid++)
+	{
+		os.get_station_name(sid, tmp_buffer);
+		utf8_truncate(tmp_buffer, STATION_NAME_SIZE);
+		if (search[0] && !strcasestr(tmp_buffer, search))
+			continue;
+		if (comma)
+			bfill.emit_p(PSTR(","));
+		comma = true;
+		bfill.emit_p(PSTR("{\"sid\":$D,\"name\":\"$S\"}"), sid, tmp_buffer);
+		if (available_ether_buffer() <= 0)
+			send_packet();
+	}
+	bfill.emit_p(PSTR("]}"));
+	handle_return(HTML_OK);
+}
+
+/** Output station special attribute
+ * ss is the status of the latest command sent to the station (0: none, 1: pending, 2: sent, 3: failed),
+ * sv the value of that command.
+ */
 void server_json_station_special()
 {
 	byte sid;
@@ -301,7 +498,8 @@ void server_json_station_special()
 			{
 				comma = 1;
 			}
-			bfill.emit_p(PSTR("\"$D\":{\"st\":$D,\"sd\":\"$S\"}"), sid, data->type, data->sped);
+			bfill.emit_p(PSTR("\"$D\":{\"st\":$D,\"sd\":\"$S\",\"ss\":$D,\"sv\":$D}"), sid, data->type, data->sped,
+						 os.get_spe_status(sid), os.spe_value[sid]);
 		}
 		if (available_ether_buffer() <= 0)
 		{
@@ -327,17 +525,137 @@ void server_change_stations_attrib(char *p, char header, byte *attrib)
 	}
 }
 
+/** Check if the first len characters of a string are hex digits */
+static bool is_hex_string(const char *s, byte len)
+{
+	for (byte i = 0; i < len; i++)
+	{
+		char c = s[i];
+		if (!((c >= '0' && c <= '9') || (c >= 'A' && c <= 'F') || (c >= 'a' && c <= 'f')))
+			return false;
+	}
+	return true;
+}
+
+/** GPIO pin of a station, or 0xFF if it is not a GPIO station */
+static byte gpio_station_pin(byte sid)
+{
+	if (os.get_station_type(sid) != STN_TYPE_GPIO)
+		return 0xFF;
+	GPIOStationData data;
+	file_read_block(STATIONS_FILENAME, &data, (uint32_t)sid * sizeof(StationData) + offsetof(StationData, sped), sizeof(GPIOStationData));
+	return (data.pin[0] - '0') * 10 + data.pin[1] - '0';
+}
+
+/** Check if a GPIO station can use a pin
+ * The pin must be free on the hardware, and not used by a GPIO station other than sid.
+ */
+static bool gpio_pin_available(byte pin, byte sid)
+{
+	byte pins[MAX_GPIO_PINS];
+	byte n = gpio_free_pins(pins);
+	bool found = false;
+	for (byte i = 0; i < n && !found; i++)
+	{
+		if (pins[i] == pin)
+			found = true;
+	}
+	if (!found)
+		return false;
+	for (byte i = 0; i < os.nstations; i++)
+	{
+		if (i != sid && gpio_station_pin(i) == pin)
+			return false;
+	}
+	return true;
+}
+
+/** Validate the special data of a station, in the same string format /je outputs
+ * RF:		16 hex digits (on code, off code, timing)
+ * Remote:	14 hex digits (ip, port, station index), followed by REMOTE_PROTO_JSON for remotes running this firmware
+ * GPIO:	2 digit pin number followed by the active state (0 or 1)
+ * HTTP:	url encoded string, decoded in place
+ * Returns an HTML result code
+ */
+static byte verify_station_special(byte sid, byte type, char *sd)
+{
+	switch (type)
+	{
+	case STN_TYPE_STANDARD:
+		sd[0] = 0;
+		return HTML_SUCCESS;
+
+	case STN_TYPE_RF:
+	{
+		if (strlen(sd) != sizeof(RFStationData) || !is_hex_string(sd, sizeof(RFStationData)))
+			return HTML_DATA_FORMATERROR;
+		if (!os.parse_rfstation_code((RFStationData *)sd, NULL, NULL))
+			return HTML_DATA_OUTOFBOUND;
+		return HTML_SUCCESS;
+	}
+
+	case STN_TYPE_REMOTE:
+	{
+		// ip address, port and station index in hex, optionally followed by the protocol
+		RemoteStationData *data = (RemoteStationData *)sd;
+		byte len = strlen(sd);
+		if ((len != offsetof(RemoteStationData, proto) && len != sizeof(RemoteStationData)) ||
+			!is_hex_string(sd, offsetof(RemoteStationData, proto)))
+			return HTML_DATA_FORMATERROR;
+		if (len == sizeof(RemoteStationData) && data->proto != REMOTE_PROTO_JSON)
+			return HTML_DATA_OUTOFBOUND;
+		// the ip address and port must not be zero
+		if (!strncmp(sd, "00000000", sizeof(data->ip)) || !strncmp((char *)data->port, "0000", sizeof(data->port)))
+			return HTML_DATA_OUTOFBOUND;
+		return HTML_SUCCESS;
+	}
+
+	case STN_TYPE_GPIO:
+	{
+		// pin and active state, optionally followed by the pulse length
+		byte len = strlen(sd);
+		if (len != sizeof(GPIOStationData) && len != offsetof(GPIOStationData, pulse))
+			return HTML_DATA_FORMATERROR;
+		for (byte i = 0; i < len; i++)
+		{
+			if (sd[i] < '0' || sd[i] > '9')
+				return HTML_DATA_FORMATERROR;
+		}
+		// check that pin does not clash with OSPi pins or other GPIO stations
+		byte gpio = (sd[0] - '0') * 10 + sd[1] - '0';
+		byte activeState = sd[2] - '0';
+		if (!gpio_pin_available(gpio, sid) || activeState > 1)
+			return HTML_DATA_OUTOFBOUND;
+		return HTML_SUCCESS;
+	}
+
+	case STN_TYPE_HTTP:
+		urlDecode(sd);
+		// server, port, on command and off command
+		if (!strlen(sd) || !strchr(sd, ','))
+			return HTML_DATA_FORMATERROR;
+		if (strlen(sd) >= sizeof(HTTPStationData))
+			return HTML_DATA_OUTOFBOUND;
+		return HTML_SUCCESS;
+	}
+	return HTML_DATA_OUTOFBOUND;
+}
+
 /**Change Station Name and Attributes
  * Command: /cs?pw=xxx&s?=x&m?=x&i?=x&n?=x&d?=x
  *
  * pw: password
- * s?: station name (? is station index, starting from 0)
+ * s?: station name (? is station index, starting from 0; up to STATION_NAME_SIZE bytes of UTF-8, no control characters, quotes or backslashes)
  * m?: master operation bit field (? is board index, starting from 0)
  * i?: ignore rain bit field
  * n?: master2 operation bit field
  * d?: disable sation bit field
  * q?: station sequeitnal bit field
  * p?: station special flag bit field
+ * g?: station group id (? is station index, 0 means no group)
+ * r?: station priority (? is station index, 0 to 15, higher runs are kept when the queue overflows)
+ * w?: station master delay (? is station index, 0 to 255 seconds the station opens after its master turned on)
+ * sid, st, sd: index, type and special data of a special station (see verify_station_special)
  */
 void server_change_stations()
 {
@@ -345,6 +663,20 @@ void server_change_stations()
 
 	byte sid;
 	char tbuf2[5] = {'s', 0, 0, 0, 0};
+	// check all station names before changing any
+	for (sid = 0; sid < os.nstations; sid++)
+	{
+		itoa(sid, tbuf2 + 1, 10);
+		if (findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, tbuf2))
+		{
+			urlDecode(tmp_buffer);
+			// names are stored in STATION_NAME_SIZE bytes and output to json as they are
+			if (strlen(tmp_buffer) > STATION_NAME_SIZE)
+				handle_return_item(HTML_DATA_OUTOFBOUND, "s", sid);
+			if (!utf8_valid(tmp_buffer) || strpbrk(tmp_buffer, "\"\\"))
+				handle_return_item(HTML_DATA_FORMATERROR, "s", sid);
+		}
+	}
 	// process station names
 	for (sid = 0; sid < os.nstations; sid++)
 	{
@@ -356,6 +688,48 @@ void server_change_stations()
 		}
 	}
 
+	// process station groups
+	tbuf2[0] = 'g';
+	for (sid = 0; sid < os.nstations; sid++)
+	{
+		itoa(sid, tbuf2 + 1, 10);
+		if (findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, tbuf2))
+		{
+			byte gid = atoi(tmp_buffer);
+			if (gid > MAX_NUM_GROUPS)
+				handle_return(HTML_DATA_OUTOFBOUND);
+			os.attrib_grp[sid] = gid;
+		}
+	}
+
+	// process station priorities
+	tbuf2[0] = 'r';
+	for (sid = 0; sid < os.nstations; sid++)
+	{
+		itoa(sid, tbuf2 + 1, 10);
+		if (findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, tbuf2))
+		{
+			byte pri = atoi(tmp_buffer);
+			if (pri > MAX_QUEUE_PRIORITY)
+				handle_return(HTML_DATA_OUTOFBOUND);
+			os.attrib_pri[sid] = pri;
+		}
+	}
+
+	// process station master delays
+	tbuf2[0] = 'w';
+	for (sid = 0; sid < os.nstations; sid++)
+	{
+		itoa(sid, tbuf2 + 1, 10);
+		if (findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, tbuf2))
+		{
+			int v = atoi(tmp_buffer);
+			if (v < 0 || v > 255)
+				handle_return(HTML_DATA_OUTOFBOUND);
+			os.attrib_mdel[sid] = v;
+		}
+	}
+
 	server_change_stations_attrib(p, 'm', os.attrib_mas);  // master1
 	server_change_stations_attrib(p, 'i', os.attrib_igrd); // ignore rain delay
 	server_change_stations_attrib(p, 'j', os.attrib_igs);  // ignore sensor1
@@ -369,7 +743,7 @@ void server_change_stations()
 	if (findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("sid"), true))
 	{
 		sid = atoi(tmp_buffer);
-		if (sid < 0 || sid > os.nstations)
+		if (sid >= os.nstations)
 			handle_return(HTML_DATA_OUTOFBOUND);
 		if (findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("st"), true) &&
 			findKeyVal(p, tmp_buffer + 1, TMP_BUFFER_SIZE - 1, PSTR("sd"), true))
@@ -378,31 +752,9 @@ void server_change_stations()
 			tmp_buffer[0] -= '0';
 			tmp_buffer[STATION_SPECIAL_DATA_SIZE] = 0;
 
-			// process GPIO and HTTP stations
-			if (tmp_buffer[0] == STN_TYPE_GPIO)
-			{
-				// check that pin does not clash with OSPi pins
-				byte gpio = (tmp_buffer[1] - '0') * 10 + tmp_buffer[2] - '0';
-				byte activeState = tmp_buffer[3] - '0';
-
-				byte gpioList[] = PIN_FREE_LIST;
-				bool found = false;
-				for (byte i = 0; i < sizeof(gpioList) && found == false; i++)
-				{
-					if (gpioList[i] == gpio)
-						found = true;
-				}
-				if (!found || activeState > 1)
-					handle_return(HTML_DATA_OUTOFBOUND);
-			}
-			else if (tmp_buffer[0] == STN_TYPE_HTTP)
-			{
-				urlDecode(tmp_buffer + 1);
-				if (strlen(tmp_buffer + 1) > sizeof(HTTPStationData))
-				{
-					handle_return(HTML_DATA_OUTOFBOUND);
-				}
-			}
+			byte ret = verify_station_special(sid, tmp_buffer[0], tmp_buffer + 1);
+			if (ret != HTML_SUCCESS)
+				handle_return(ret);
 			// write spe data
 			file_write_block(STATIONS_FILENAME, tmp_buffer,
 							 (uint32_t)sid * sizeof(StationData) + offsetof(StationData, type), STATION_SPECIAL_DATA_SIZE + 1);
@@ -420,7 +772,7 @@ void server_change_stations()
 }
 
 /** Parse one number from a comma separate list */
-uint16_t parse_listdata(char **p)
+static long parse_listvalue(char **p)
 {
 	char *pv;
 	int i = 0;
@@ -435,16 +787,101 @@ uint16_t parse_listdata(char **p)
 	}
 	tmp_buffer[i] = 0;
 	*p = pv + 1;
-	return (uint16_t)atol(tmp_buffer);
+	return atol(tmp_buffer);
+}
+
+uint16_t parse_listdata(char **p)
+{
+	return (uint16_t)parse_listvalue(p);
+}
+
+/** Check the station durations of a comma separated list
+ * Returns the index of the first invalid duration, -1 if they are all valid
+ */
+static int check_listdurations(char *pv, byte n)
+{
+	for (byte i = 0; i < n; i++)
+	{
+		if (!water_time_valid(parse_listvalue(&pv)))
+			return i;
+	}
+	return -1;
+}
+
+/** Enqueue a station of a run-once program
+ * Returns true if the station was enqueued
+ */
+static boolean enqueue_runonce_station(byte sid, uint16_t dur)
+{
+	// if non-zero duration is given
+	// and if the station has not been disabled
+	if (dur > 0 && !(os.attrib_dis[sid >> 3] & (1 << (sid & 0x07))))
+		return pd.enqueue(sid, 254, water_time_resolve(dur)) != NULL;
+	return false;
+}
+
+/** Enqueue a run-once program from a comma separated list of station durations
+ * Also used by program switches mapped to the run-once template
+ * Returns true if any station was enqueued; call schedule_all_stations afterwards
+ */
+boolean enqueue_runonce(char *pv)
+{
+	boolean match_found = false;
+	for (byte sid = 0; sid < os.nstations; sid++)
+	{
+		long v = parse_listvalue(&pv);
+		if (enqueue_runonce_station(sid, water_time_valid(v) ? v : 0))
+			match_found = true;
+	}
+	return match_found;
+}
+
+/** Save the station durations of a run-once program, so that it can be run again
+ * pv is a checked comma separated list of durations, stations beyond it are saved as 0
+ */
+static void save_last_runonce(char *pv)
+{
+	uint16_t durs[MAX_NUM_STATIONS];
+	memset(durs, 0, sizeof(durs));
+	for (byte sid = 0; sid < os.nstations; sid++)
+		durs[sid] = parse_listvalue(&pv);
+	file_write_block(RUNONCE_FILENAME, durs, 0, sizeof(durs));
+}
+
+/**
+ * Run the last run-once program again, with the durations saved by /cr
+ * Used by /cr?last=1 and by MQTT runonce messages
+ * Returns an HTML result code
+ */
+byte rerun_last_runonce()
+{
+	if (!file_exists(RUNONCE_FILENAME))
+		return HTML_DATA_MISSING;
+	uint16_t durs[MAX_NUM_STATIONS];
+	file_read_block(RUNONCE_FILENAME, durs, 0, sizeof(durs));
+
+	// reset all stations and prepare to run one-time program
+	reset_all_stations_immediate();
+
+	boolean match_found = false;
+	for (byte sid = 0; sid < os.nstations; sid++)
+	{
+		if (enqueue_runonce_station(sid, water_time_valid(durs[sid]) ? durs[sid] : 0))
+			match_found = true;
+	}
+	if (!match_found)
+		return HTML_DATA_MISSING;
+	schedule_all_stations(os.now_tz());
+	return HTML_SUCCESS;
 }
 
-void manual_start_program(byte, byte);
 /** Manual start program
- * Command: /mp?pw=xxx&pid=xxx&uwt=xxx
+ * Command: /mp?pw=xxx&pid=xxx&uwt=xxx&wl=xxx
  *
  * pw:	password
  * pid: program index (0 refers to the first program)
  * uwt: use weather (i.e. watering percentage)
+ * wl:	watering percentage override (0 to 250, takes precedence over uwt)
  */
 void server_manual_program()
 {
@@ -459,17 +896,24 @@ void server_manual_program()
 		handle_return(HTML_DATA_OUTOFBOUND);
 	}
 
-	byte uwt = 0;
+	uint16_t wl = 100;
 	if (findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("uwt"), true))
 	{
 		if (tmp_buffer[0] == '1')
-			uwt = 1;
+			wl = os.iopts[IOPT_WATER_PERCENTAGE];
+	}
+	if (findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("wl"), true))
+	{
+		int v = atoi(tmp_buffer);
+		if (v < 0 || v > 250)
+			handle_return(HTML_DATA_OUTOFBOUND);
+		wl = v;
 	}
 
 	// reset all stations and prepare to run one-time program
 	reset_all_stations_immediate();
 
-	manual_start_program(pid + 1, uwt);
+	manual_start_program(pid + 1, wl);
 
 	handle_return(HTML_SUCCESS);
 }
@@ -477,14 +921,20 @@ void server_manual_program()
 /**
  * Change run-once program
  * Command: /cr?pw=xxx&t=[x,x,x...]
+ *			/cr?pw=xxx&last=1
  *
- * pw: password
- * t:  station water time
+ * pw:	 password
+ * t:	 station water time (0 to MAX_WATER_TIME seconds, or 65534/65535 for sunrise/sunset)
+ * last: 1 runs the last run-once program again instead
+ * The durations are saved for last=1 and the MQTT runonce topic.
  */
 void server_change_runonce()
 {
 	char *p = get_buffer;
 
+	if (findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("last"), true) && tmp_buffer[0] == '1')
+		handle_return(rerun_last_runonce());
+
 	// decode url first
 	if (p)
 		urlDecode(p);
@@ -503,34 +953,16 @@ void server_change_runonce()
 		handle_return(HTML_DATA_MISSING);
 	pv += 3;
 
+	int bad = check_listdurations(pv, os.nstations);
+	if (bad >= 0)
+		handle_return_item(HTML_DATA_OUTOFBOUND, "t", bad);
+
 	// reset all stations and prepare to run one-time program
 	reset_all_stations_immediate();
 
-	byte sid, bid, s;
-	uint16_t dur;
-	boolean match_found = false;
-	for (sid = 0; sid < os.nstations; sid++)
-	{
-		dur = parse_listdata(&pv);
-		bid = sid >> 3;
-		s = sid & 0x07;
-		// if non-zero duration is given
-		// and if the station has not been disabled
-		if (dur > 0 && !(os.attrib_dis[bid] & (1 << s)))
-		{
-			RuntimeQueueStruct *q = pd.enqueue();
-			if (q)
-			{
-				q->st = 0;
-				q->dur = water_time_resolve(dur);
-				q->pid = 254;
-				q->sid = sid;
-				match_found = true;
-			}
-		}
-	}
-	if (match_found)
+	if (enqueue_runonce(pv))
 	{
+		save_last_runonce(pv);
 		schedule_all_stations(os.now_tz());
 		handle_return(HTML_SUCCESS);
 	}
@@ -593,16 +1025,66 @@ void server_moveup_program()
 
 /**
  * Change a program
- * Command: /cp?pw=xxx&pid=x&v=[flag,days0,days1,[start0,start1,start2,start3],[dur0,dur1,dur2..]]&name=x
+ * Command: /cp?pw=xxx&pid=x&v=[flag,days0,days1,[start0,start1,start2,start3],[dur0,dur1,dur2..]]&name=x&cyc=x&soak=x
  *
  * pw:		password
  * pid:		program index
  * flag:	program flag
+ * en, uwt, ucal: set the enable, use weather or use calendar flag only
  * start?:up to 4 start times
- * dur?:	station water time
+ * dur?:	station water time (as for /cr)
  * name:	program name
+ * cyc:		number of cycles each station's water time is split into (optional)
+ * soak:	minimum soak time between cycles, in minutes (optional)
+ * wwb, wwe: watering window start and end, in 15 minute units (optional, equal values use the global window)
+ * pri:		priority when the run-time queue overflows, 0 to 15 (optional)
+ * fcp:		rain forecast probability, in percent, that skips or reduces the run (optional, 0: not checked)
+ * fcq:		rain forecast amount, in 1/100 inch, that skips or reduces the run (optional, 0: not checked)
+ * fcm:		0: skip the run, 1: reduce the water time by the forecast probability (optional)
+ * col:		while a program is still running, 0: queue after it, 1: skip this start, 2: cut it short (optional)
+ * anc:		interval programs: anchor day, in days since 1970, the runs count from (optional).
+ *			Without it, days0 is the number of days from today until the next run.
  */
 const char _str_program[] PROGMEM = "Program ";
+
+/** Check that a program's schedule fields are within bounds */
+static bool check_program_bounds(ProgramStruct *prog)
+{
+	if (prog->type == PROGRAM_TYPE_INTERVAL)
+	{
+		// days[1] is the interval, days[0] the (relative) remainder
+		if (prog->days[1] == 0 || prog->days[0] >= prog->days[1])
+			return false;
+	}
+	else if (prog->type == PROGRAM_TYPE_MONTHLY)
+	{
+		// day of the month, 32 means the last day
+		if ((prog->days[0] & 0x3F) > 32)
+			return false;
+	}
+
+	// standard start times are minutes of the day; disabled (bit 15),
+	// sunrise and sunset start times carry their own offset
+	byte n = prog->starttime_type ? MAX_NUM_STARTTIMES : 1;
+	for (byte i = 0; i < n; i++)
+	{
+		int16_t t = prog->starttimes[i];
+		if (t < 0 || (t >> STARTTIME_SUNRISE_BIT) & 1 || (t >> STARTTIME_SUNSET_BIT) & 1)
+			continue;
+		if (t >= 1440)
+			return false;
+	}
+	if (!prog->starttime_type)
+	{
+		// repeating start time: repeat count and interval (in minutes)
+		if (prog->starttimes[1] < 0 || prog->starttimes[2] < 0)
+			return false;
+		if (prog->starttimes[1] > 0 && prog->starttimes[2] == 0)
+			return false;
+	}
+	return true;
+}
+
 void server_change_program()
 {
 	char *p = get_buffer;
@@ -637,10 +1119,105 @@ void server_change_program()
 		handle_return(HTML_SUCCESS);
 	}
 
-	// parse program name
-	if (findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("name"), true))
+	// check if "ucal" parameter is present
+	if (findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("ucal"), true))
 	{
-		urlDecode(tmp_buffer);
+		if (pid < 0)
+			handle_return(HTML_DATA_OUTOFBOUND);
+		pd.set_flagbit(pid, PROGRAMSTRUCT_CAL_BIT, (tmp_buffer[0] == '0') ? 0 : 1);
+		handle_return(HTML_SUCCESS);
+	}
+
+	// parse cycle and soak, watering window, priority, rain forecast, collisions
+	ProgramExtra px;
+	pd.read_extra(pid < 0 ? pd.nprograms : pid, &px);
+	bool extra_change = false;
+	if (findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("cyc"), true))
+	{
+		int v = atoi(tmp_buffer);
+		if (v < 0 || v > MAX_PROGRAM_CYCLES)
+			handle_return(HTML_DATA_OUTOFBOUND);
+		px.cycles = v;
+		extra_change = true;
+	}
+	if (findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("soak"), true))
+	{
+		int v = atoi(tmp_buffer);
+		if (v < 0 || v > MAX_PROGRAM_SOAK)
+			handle_return(HTML_DATA_OUTOFBOUND);
+		px.soak = v;
+		extra_change = true;
+	}
+	if (findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("wwb"), true))
+	{
+		int v = atoi(tmp_buffer);
+		if (v < 0 || v > WATER_WINDOW_UNITS)
+			handle_return(HTML_DATA_OUTOFBOUND);
+		px.wwbeg = v;
+		extra_change = true;
+	}
+	if (findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("wwe"), true))
+	{
+		int v = atoi(tmp_buffer);
+		if (v < 0 || v > WATER_WINDOW_UNITS)
+			handle_return(HTML_DATA_OUTOFBOUND);
+		px.wwend = v;
+		extra_change = true;
+	}
+	if (findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("pri"), true))
+	{
+		int v = atoi(tmp_buffer);
+		if (v < 0 || v > MAX_QUEUE_PRIORITY)
+			handle_return(HTML_DATA_OUTOFBOUND);
+		px.pri = v;
+		extra_change = true;
+	}
+	if (findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("fcp"), true))
+	{
+		int v = atoi(tmp_buffer);
+		if (v < 0 || v > 100)
+			handle_return(HTML_DATA_OUTOFBOUND);
+		px.fcpop = v;
+		extra_change = true;
+	}
+	if (findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("fcq"), true))
+	{
+		int v = atoi(tmp_buffer);
+		if (v < 0 || v > 255)
+			handle_return(HTML_DATA_OUTOFBOUND);
+		px.fcqpf = v;
+		extra_change = true;
+	}
+	if (findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("fcm"), true))
+	{
+		int v = atoi(tmp_buffer);
+		if (v != FORECAST_MODE_SKIP && v != FORECAST_MODE_REDUCE)
+			handle_return(HTML_DATA_OUTOFBOUND);
+		px.fcmode = v;
+		extra_change = true;
+	}
+	if (findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("col"), true))
+	{
+		int v = atoi(tmp_buffer);
+		if (v < PROGRAM_COLLIDE_STACK || v > PROGRAM_COLLIDE_CUT)
+			handle_return(HTML_DATA_OUTOFBOUND);
+		px.collide = v;
+		extra_change = true;
+	}
+	bool anchor_given = false;
+	if (findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("anc"), true))
+	{
+		long v = atol(tmp_buffer);
+		if (v <= 0 || v > 65535)
+			handle_return(HTML_DATA_OUTOFBOUND);
+		px.ivanchor = v;
+		anchor_given = true;
+	}
+
+	// parse program name
+	if (findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("name"), true))
+	{
+		urlDecode(tmp_buffer);
 		strncpy(prog.name, tmp_buffer, PROGRAM_NAME_SIZE);
 	}
 	else
@@ -676,20 +1253,28 @@ void server_change_program()
 	prog.days[0] = parse_listdata(&pv);
 	prog.days[1] = parse_listdata(&pv);
 	// parse start times
-	pv++; // this should be a '['
+	if (*pv != '[')
+		handle_return(HTML_DATA_FORMATERROR);
+	pv++;
 	for (i = 0; i < MAX_NUM_STARTTIMES; i++)
 	{
 		prog.starttimes[i] = parse_listdata(&pv);
 	}
-	pv++; // this should be a ','
-	pv++; // this should be a '['
+	// the last start time is followed by "]," which parse_listdata skipped the first of
+	if (*(pv - 1) != ']' || *pv != ',')
+		handle_return(HTML_DATA_FORMATERROR);
+	pv++;
+	if (*pv != '[')
+		handle_return(HTML_DATA_FORMATERROR);
+	pv++;
+	int bad = check_listdurations(pv, os.nstations);
+	if (bad >= 0)
+		handle_return_item(HTML_DATA_OUTOFBOUND, "v", bad);
 	for (i = 0; i < os.nstations; i++)
 	{
 		uint16_t pre = parse_listdata(&pv);
 		prog.durations[i] = pre;
 	}
-	pv++; // this should be a ']'
-	pv++; // this should be a ']'
 	// parse program name
 
 	// i should be equal to os.nstations at this point
@@ -698,22 +1283,228 @@ void server_change_program()
 		prog.durations[i] = 0; // clear unused field
 	}
 
-	// process interval day remainder (relative-> absolute)
+	if (!check_program_bounds(&prog))
+		handle_return(HTML_DATA_OUTOFBOUND);
+
+	// interval programs run from their anchor day: the given one, or the next run day
+	// given as days from today by days[0]. The remainder is kept for older firmwares.
 	if (prog.type == PROGRAM_TYPE_INTERVAL && prog.days[1] >= 1)
 	{
-		pd.drem_to_absolute(prog.days);
+		if (!anchor_given)
+			px.ivanchor = pd.interval_anchor(prog.days[0]);
+		prog.days[0] = px.ivanchor % prog.days[1];
+		extra_change = true;
 	}
 
 	if (pid == -1)
 	{
 		if (!pd.add(&prog))
 			handle_return(HTML_DATA_OUTOFBOUND);
+		pid = pd.nprograms - 1;
 	}
 	else
 	{
 		if (!pd.modify(pid, &prog))
 			handle_return(HTML_DATA_OUTOFBOUND);
 	}
+	if (extra_change)
+		pd.write_extra(pid, &px);
+	handle_return(HTML_SUCCESS);
+}
+
+/** Check the extra data of an imported program */
+static bool check_extra_bounds(ProgramExtra *px)
+{
+	return px->cycles <= MAX_PROGRAM_CYCLES && px->soak <= MAX_PROGRAM_SOAK &&
+		   px->wwbeg <= WATER_WINDOW_UNITS && px->wwend <= WATER_WINDOW_UNITS &&
+		   px->pri <= MAX_QUEUE_PRIORITY && px->fcpop <= 100 &&
+		   (px->fcmode == FORECAST_MODE_SKIP || px->fcmode == FORECAST_MODE_REDUCE) &&
+		   px->collide <= PROGRAM_COLLIDE_CUT;
+}
+
+/** Parse a program in the form of the /jp output:
+ * [flag,days0,days1,[start0,...],[dur0,...],"name",[cyc,soak,...,anchor]]
+ * Exports of older firmwares end with the name, their extra data is left at the defaults.
+ * Durations beyond the stations of this controller are dropped.
+ * Returns an HTML result code, pv is moved past the program.
+ */
+static byte parse_program(char **ppv, ProgramStruct *prog, ProgramExtra *px)
+{
+	char *pv = *ppv;
+	byte i;
+	memset(prog, 0, sizeof(ProgramStruct));
+	memset(px, 0, sizeof(ProgramExtra));
+	if (*pv != '[')
+		return HTML_DATA_FORMATERROR;
+	pv++;
+	*(char *)prog = parse_listvalue(&pv);
+	prog->days[0] = parse_listvalue(&pv);
+	prog->days[1] = parse_listvalue(&pv);
+	if (*pv != '[')
+		return HTML_DATA_FORMATERROR;
+	pv++;
+	for (i = 0; i < MAX_NUM_STARTTIMES; i++)
+		prog->starttimes[i] = parse_listvalue(&pv);
+	if (*(pv - 1) != ']' || *pv != ',' || pv[1] != '[')
+		return HTML_DATA_FORMATERROR;
+	pv += 2;
+	for (i = 0;; i++)
+	{
+		long v = parse_listvalue(&pv);
+		if (!water_time_valid(v))
+			return HTML_DATA_OUTOFBOUND;
+		if (i < os.nstations)
+			prog->durations[i] = v;
+		if (*(pv - 1) == ']')
+			break;
+		if (*(pv - 1) != ',' || i >= MAX_NUM_STATIONS - 1)
+			return HTML_DATA_FORMATERROR;
+	}
+	if (*pv != ',' || pv[1] != '"')
+		return HTML_DATA_FORMATERROR;
+	pv += 2;
+	for (i = 0; *pv && *pv != '"'; i++, pv++)
+	{
+		if (i >= PROGRAM_NAME_SIZE)
+			return HTML_DATA_OUTOFBOUND;
+		prog->name[i] = *pv;
+	}
+	if (*pv != '"')
+		return HTML_DATA_FORMATERROR;
+	pv++;
+	if (*pv == ',' && pv[1] == '[')
+	{
+		pv += 2;
+		byte *e = (byte *)px;
+		for (i = 0;; i++)
+		{
+			long v = parse_listvalue(&pv);
+			if (v < 0 || v > ((i == PROGRAM_EXTRA_BYTES) ? 65535 : 255))
+				return HTML_DATA_OUTOFBOUND;
+			if (i < PROGRAM_EXTRA_BYTES)
+				e[i] = v;
+			else if (i == PROGRAM_EXTRA_BYTES)
+				px->ivanchor = v;
+			// fields added by later firmwares are ignored
+			if (*(pv - 1) == ']')
+				break;
+			if (*(pv - 1) != ',')
+				return HTML_DATA_FORMATERROR;
+		}
+	}
+	if (*pv != ']')
+		return HTML_DATA_FORMATERROR;
+	*ppv = pv + 1;
+	if (!check_program_bounds(prog) || !check_extra_bounds(px))
+		return HTML_DATA_OUTOFBOUND;
+	return HTML_SUCCESS;
+}
+
+/** Find a program by name, returns -1 if there is none */
+static int find_program(const char *name)
+{
+	ProgramStruct prog;
+	for (byte pid = 0; pid < pd.nprograms; pid++)
+	{
+		pd.read(pid, &prog);
+		if (strncmp(prog.name, name, PROGRAM_NAME_SIZE) == 0)
+			return pid;
+	}
+	return -1;
+}
+
+/**
+ * Import programs
+ * Command: /ip?pw=xxx&mode=x&pd=[[...],[...]]
+ *
+ * pw:	 password
+ * mode: 0 replaces all programs, 1 merges: programs with the name of an existing one replace it,
+ *		 the others are added
+ * pd:	 programs as in the pd array of /jp. The /jp output itself, or an app backup
+ *		 that holds it, is also accepted, its pd array is then imported.
+ * All programs are checked before any is changed; errors report the index of the failing program.
+ * The request must fit in the receive buffer (ETHER_BUFFER_SIZE).
+ */
+void server_import_programs()
+{
+	char *p = get_buffer;
+
+	byte mode = 0;
+	if (findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("mode"), true))
+	{
+		mode = atoi(tmp_buffer);
+		if (mode > 1)
+			handle_return_item(HTML_DATA_OUTOFBOUND, "mode", -1);
+	}
+
+	if (p)
+		urlDecode(p);
+	char *pv = strstr(p, "pd=");
+	if (!pv)
+		handle_return(HTML_DATA_MISSING);
+	pv += 3;
+	if (*pv == '{')
+	{
+		// convert a /jp output or an app backup to the program list
+		pv = strstr(pv, "\"pd\":[");
+		if (!pv)
+			handle_return(HTML_DATA_FORMATERROR);
+		pv += 5;
+	}
+	if (*pv != '[')
+		handle_return(HTML_DATA_FORMATERROR);
+	pv++;
+
+	ProgramStruct prog;
+	ProgramExtra px;
+	char *list = pv;
+	int n = 0, added = 0;
+	// first check all programs
+	while (*pv != ']')
+	{
+		if (n > 0)
+		{
+			if (*pv != ',')
+				handle_return_item(HTML_DATA_FORMATERROR, "pd", n);
+			pv++;
+		}
+		byte ret = parse_program(&pv, &prog, &px);
+		if (ret != HTML_SUCCESS)
+			handle_return_item(ret, "pd", n);
+		if (mode == 0 || find_program(prog.name) < 0)
+			added++;
+		n++;
+	}
+	if ((mode == 0 ? 0 : pd.nprograms) + added > MAX_NUM_PROGRAMS)
+		handle_return_item(HTML_DATA_OUTOFBOUND, "pd", -1);
+
+	if (mode == 0)
+		pd.eraseall();
+	pv = list;
+	for (int i = 0; i < n; i++)
+	{
+		if (i > 0)
+			pv++;
+		parse_program(&pv, &prog, &px);
+		if (prog.type == PROGRAM_TYPE_INTERVAL && prog.days[1] >= 1)
+		{
+			// exports of older firmwares have no anchor day, only the days until the next run
+			if (!px.ivanchor)
+				px.ivanchor = pd.interval_anchor(prog.days[0]);
+			prog.days[0] = px.ivanchor % prog.days[1];
+		}
+		int pid = (mode == 0) ? -1 : find_program(prog.name);
+		if (pid < 0)
+		{
+			pd.add(&prog);
+			pid = pd.nprograms - 1;
+		}
+		else
+		{
+			pd.modify(pid, &prog);
+		}
+		pd.write_extra(pid, &px);
+	}
 	handle_return(HTML_SUCCESS);
 }
 
@@ -743,6 +1534,10 @@ void server_json_options_main()
 		{
 			v = water_time_decode_signed(v);
 		}
+		if (oid >= IOPT_MASTER_ON_ADJ_MIN && oid <= IOPT_MASTER_OFF_ADJ_MIN_2)
+		{
+			v -= MASTER_ADJ_MIN_ZERO;
+		}
 
 		if (oid == IOPT_BOOST_TIME)
 			continue;
@@ -750,7 +1545,7 @@ void server_json_options_main()
 		if (oid == IOPT_SEQUENTIAL_RETIRED || oid == IOPT_URS_RETIRED || oid == IOPT_RSO_RETIRED)
 			continue;
 
-		// for Linux-based platforms, there is no LCD currently
+		// contrast, brightness and dimming only apply to the built-in LCD of other platforms
 		if (oid == IOPT_LCD_CONTRAST || oid == IOPT_LCD_BACKLIGHT || oid == IOPT_LCD_DIMMING)
 			continue;
 
@@ -761,7 +1556,11 @@ void server_json_options_main()
 			bfill.emit_p(PSTR(","));
 	}
 
-	bfill.emit_p(PSTR(",\"dexp\":$D,\"mexp\":$D,\"hwt\":$D}"), os.detect_exp(), MAX_EXT_BOARDS, os.hw_type);
+	bfill.emit_p(PSTR(",\"dexp\":$D,\"mexp\":$D,\"mwt\":$L,\"hwt\":$D,\"hwm\":\"$S\",\"cors\":\"$O\",\"mqtpc\":\"$O\",\"mqev\":\"$O\",\"wlost\":$L,\"wtdata\":$S,\"wterr\":$D}"),
+				 os.detect_exp(), MAX_EXT_BOARDS, MAX_WATER_TIME, os.hw_type, os.hw_model, SOPT_CORS_ORIGINS, SOPT_MQTT_TOPIC, SOPT_MQTT_EVENTS,
+				 os.nvdata.wl_stop_time,
+				 strlen(wt_rawData) == 0 ? "{}" : wt_rawData,
+				 wt_errCode);
 }
 
 /** Output Options */
@@ -782,9 +1581,11 @@ void server_json_programs_main()
 	for (pid = 0; pid < pd.nprograms; pid++)
 	{
 		pd.read(pid, &prog);
+		ProgramExtra px;
+		pd.read_extra(pid, &px);
 		if (prog.type == PROGRAM_TYPE_INTERVAL && prog.days[1] >= 1)
 		{
-			pd.drem_to_relative(prog.days);
+			prog.days[0] = pd.interval_days_ahead(prog.days, px.ivanchor);
 		}
 
 		byte bytedata = *(char *)(&prog);
@@ -805,13 +1606,12 @@ void server_json_programs_main()
 		strncpy(tmp_buffer, prog.name, PROGRAM_NAME_SIZE);
 		tmp_buffer[PROGRAM_NAME_SIZE] = 0; // make sure the string ends
 		bfill.emit_p(PSTR("$S"), tmp_buffer);
+		// cycle and soak, watering window, priority, rain forecast, collisions, interval anchor day
+		bfill.emit_p(PSTR("\",[$D,$D,$D,$D,$D,$D,$D,$D,$D,$L]]"), px.cycles, px.soak, px.wwbeg, px.wwend, px.pri, px.fcpop, px.fcqpf, px.fcmode, px.collide,
+					 (ulong)px.ivanchor);
 		if (pid != pd.nprograms - 1)
 		{
-			bfill.emit_p(PSTR("\"],"));
-		}
-		else
-		{
-			bfill.emit_p(PSTR("\"]"));
+			bfill.emit_p(PSTR(","));
 		}
 		// push out a packet if available
 		// buffer size is getting small
@@ -839,13 +1639,43 @@ void server_view_scripturl()
 	handle_return(HTML_OK);
 }
 
+/** Remaining time of a queue element */
+static ulong queue_remaining(RuntimeQueueStruct *q, ulong curr_time)
+{
+	ulong rem = (curr_time >= q->st) ? (q->st + q->dur - curr_time) : q->dur;
+	return (rem > 65535) ? 0 : rem;
+}
+
+/** Output program status of each station: [pid, remaining time, start time] */
+void server_json_ps(ulong curr_time)
+{
+	bfill.emit_p(PSTR("\"ps\":["));
+	for (byte sid = 0; sid < os.nstations; sid++)
+	{
+		// if available ether buffer is getting small
+		// send out a packet
+		if (available_ether_buffer() <= 0)
+		{
+			send_packet();
+		}
+		unsigned long rem = 0;
+		byte qid = pd.station_qid[sid];
+		RuntimeQueueStruct *q = pd.queue + qid;
+		if (qid < 255)
+		{
+			rem = queue_remaining(q, curr_time);
+		}
+		bfill.emit_p(PSTR("[$D,$L,$L]"), (qid < 255) ? q->pid : 0, rem, (qid < 255) ? q->st : 0);
+		bfill.emit_p((sid < os.nstations - 1) ? PSTR(",") : PSTR("]"));
+	}
+}
+
 void server_json_controller_main()
 {
-	byte bid, sid;
 	ulong curr_time = os.now_tz();
 	bfill.emit_p(PSTR("\"devt\":$L,\"nbrd\":$D,\"en\":$D,\"sn1\":$D,\"sn2\":$D,\"rd\":$D,\"rdst\":$L,"
 					  "\"sunrise\":$D,\"sunset\":$D,\"eip\":$L,\"lwc\":$L,\"lswc\":$L,"
-					  "\"lupt\":$L,\"lrbtc\":$D,\"lrun\":[$D,$D,$D,$L],"),
+					  "\"lupt\":$L,\"lrbtc\":$D,\"lrun\":[$D,$D,$D,$L],\"sovr\":[$L,$L],"),
 				 curr_time,
 				 os.nboards,
 				 os.status.enabled,
@@ -863,54 +1693,86 @@ void server_json_controller_main()
 				 pd.lastrun.station,
 				 pd.lastrun.program,
 				 pd.lastrun.duration,
-				 pd.lastrun.endtime);
+				 pd.lastrun.endtime,
+				 os.sensor_override_until[0],
+				 os.sensor_override_until[1]);
 
 	byte mac[6] = {0};
 	os.load_hardware_mac(mac, m_server != NULL);
 	bfill.emit_p(PSTR("\"mac\":\"$X:$X:$X:$X:$X:$X\","), mac[0], mac[1], mac[2], mac[3], mac[4], mac[5]);
+	bfill.emit_p(PSTR("\"dname\":\"$O\",\"duuid\":\"$S\","), SOPT_DEVICE_NAME, os.device_uuid);
 
-	bfill.emit_p(PSTR("\"loc\":\"$O\",\"jsp\":\"$O\",\"wsp\":\"$O\",\"wto\":{$O},\"ifkey\":\"$O\",\"mqtt\":{$O},\"wtdata\":$S,\"wterr\":$D,"),
+	bfill.emit_p(PSTR("\"loc\":\"$O\",\"jsp\":\"$O\",\"wsp\":\"$O\","),
 				 SOPT_LOCATION,
 				 SOPT_JAVASCRIPTURL,
-				 SOPT_WEATHERURL,
-				 SOPT_WEATHER_OPTS,
-				 SOPT_IFTTT_KEY,
-				 SOPT_MQTT_OPTS,
+				 SOPT_WEATHERURL);
+	// guests do not get the weather, IFTTT and MQTT credentials
+	if (guest_request)
+		bfill.emit_p(PSTR("\"wto\":{},\"ifkey\":\"\",\"mqtt\":{},\"mqtls\":{},"));
+	else
+		bfill.emit_p(PSTR("\"wto\":{$O},\"ifkey\":\"$O\",\"mqtt\":{$O},\"mqtls\":{$O},"),
+					 SOPT_WEATHER_OPTS,
+					 SOPT_IFTTT_KEY,
+					 SOPT_MQTT_OPTS,
+					 SOPT_MQTT_TLS);
+	bfill.emit_p(PSTR("\"wtdata\":$S,\"wterr\":$D,\"wtmsg\":\"$S\","),
 				 strlen(wt_rawData) == 0 ? "{}" : wt_rawData,
-				 wt_errCode);
+				 wt_errCode,
+				 request_error_message(wt_errCode));
+	// frost protection, with the temperature (in F) it is based on
+	if (frost_temp_known)
+		bfill.emit_p(PSTR("\"frost\":$D,\"frtmp\":$D,"), os.status.frost, frost_temp);
+	else
+		bfill.emit_p(PSTR("\"frost\":$D,\"frtmp\":null,"), os.status.frost);
 
 	if (os.iopts[IOPT_SENSOR1_TYPE] == SENSOR_TYPE_FLOW)
 	{
 		bfill.emit_p(PSTR("\"flcrt\":$L,\"flwrt\":$D,"), os.flowcount_rt, FLOWCOUNT_RT_WINDOW);
 	}
 
-	bfill.emit_p(PSTR("\"sbits\":["));
-	// print sbits
-	for (bid = 0; bid < os.nboards; bid++)
-		bfill.emit_p(PSTR("$D,"), os.station_bits[bid]);
-	bfill.emit_p(PSTR("0],\"ps\":["));
-	// print ps
-	for (sid = 0; sid < os.nstations; sid++)
+	// calendar: url, keyword, whether today is suppressed, number of suppressed days, last successful fetch and error
+	// the url of a private calendar holds its secret, so guests do not get it
+	if (guest_request)
+		bfill.emit_p(PSTR("\"cal\":\"\","));
+	else
+		bfill.emit_p(PSTR("\"cal\":\"$O\","), SOPT_CALENDAR_URL);
+	bfill.emit_p(PSTR("\"calkw\":\"$O\",\"calst\":{\"skip\":$D,\"ndays\":$D,\"lswc\":$L,\"err\":$D,\"msg\":\"$S\"},"),
+				 SOPT_CALENDAR_KEYWORD,
+				 calendar_skip_day(curr_time) ? 1 : 0,
+				 cal_ndays,
+				 cal_success_lasttime,
+				 cal_errCode,
+				 request_error_message(cal_errCode));
+
+	// rain forecast: highest probability, amount, last successful fetch and error
+	if (os.iopts[IOPT_FORECAST_HOURS])
 	{
-		// if available ether buffer is getting small
-		// send out a packet
-		if (available_ether_buffer() <= 0)
-		{
-			send_packet();
-		}
-		unsigned long rem = 0;
-		byte qid = pd.station_qid[sid];
-		RuntimeQueueStruct *q = pd.queue + qid;
-		if (qid < 255)
-		{
-			rem = (curr_time >= q->st) ? (q->st + q->dur - curr_time) : q->dur;
-			if (rem > 65535)
-				rem = 0;
-		}
-		bfill.emit_p(PSTR("[$D,$L,$L]"), (qid < 255) ? q->pid : 0, rem, (qid < 255) ? q->st : 0);
-		bfill.emit_p((sid < os.nstations - 1) ? PSTR(",") : PSTR("]"));
+		bfill.emit_p(PSTR("\"fcst\":{\"pop\":$D,\"qpf\":$D,\"lswc\":$L,\"err\":$D,\"msg\":\"$S\"},"),
+					 fc_pop,
+					 fc_qpf,
+					 fc_success_lasttime,
+					 fc_errCode,
+					 request_error_message(fc_errCode));
+	}
+
+	if (os.iopts[IOPT_CURRENT_CHANNEL])
+	{
+		uint16_t curr = os.read_current();
+		if (os.status.has_curr_sense)
+			bfill.emit_p(PSTR("\"curr\":$D,"), curr);
 	}
 
+	// programs started by program switch sensors (0 if the sensor is not a program switch)
+	bfill.emit_p(PSTR("\"pswitch\":[$D,$D],\"pst\":\"$O\","),
+				 os.iopts[IOPT_SENSOR1_TYPE] == SENSOR_TYPE_PSWITCH ? os.iopts[IOPT_PSWITCH1_PROG] : 0,
+				 os.iopts[IOPT_SENSOR2_TYPE] == SENSOR_TYPE_PSWITCH ? os.iopts[IOPT_PSWITCH2_PROG] : 0,
+				 SOPT_PSWITCH_RUNONCE);
+
+	bfill.emit_p(PSTR("\"sbits\":"));
+	emit_station_bits(bfill, true);
+	bfill.emit_p(PSTR(","));
+	server_json_ps(curr_time);
+
 	// bfill.emit_p(PSTR(",\"blynk\":\"$O\""), SOPT_BLYNK_TOKEN);
 	// bfill.emit_p(PSTR(",\"mqtt\":\"$O\""), SOPT_MQTT_IP);
 
@@ -942,13 +1804,15 @@ void server_home()
 
 /**
  * Change controller variables
- * Command: /cv?pw=xxx&rsn=x&rbt=x&en=x&rd=x&re=x&ap=x
+ * Command: /cv?pw=xxx&rsn=x&rbt=x&en=x&rd=x&wlo=x&wlh=x&re=x&ap=x
  *
  * pw:	password
  * rsn: reset all stations (0 or 1)
  * rbt: reboot controller (0 or 1)
  * en:	enable (0 or 1)
  * rd:	rain delay hours (0 turns off rain delay)
+ * wlo: water scale override in percent (0 to 250), supersedes the weather adjustment
+ * wlh: override hours (0 ends the override, default 24)
  * re:	remote extension mode
  * ap:	reset to ap (ESP8266 only)
  * update: launch update script (for OSPi/Linux only)
@@ -968,10 +1832,10 @@ void server_change_values()
 
 	if (findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("rbt"), true) && atoi(tmp_buffer) > 0)
 	{
-		print_html_standard_header();
-		// bfill.emit_p(PSTR("Rebooting..."));
-		send_packet(true);
-		os.reboot_dev(REBOOT_CAUSE_WEB);
+		// reboot from the main loop, after the response has been sent
+		os.status.safe_reboot = 0;
+		reboot_cause = REBOOT_CAUSE_WEB;
+		reboot_timer = os.now_tz();
 	}
 
 	if (findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("en"), true))
@@ -998,6 +1862,24 @@ void server_change_values()
 			handle_return(HTML_DATA_OUTOFBOUND);
 	}
 
+	if (findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("wlo"), true))
+	{
+		int wlo = atoi(tmp_buffer);
+		int wlh = 24;
+		if (findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("wlh"), true))
+			wlh = atoi(tmp_buffer);
+		if (wlo < 0 || wlo > 250 || wlh < 0 || wlh > 24 * 30)
+			handle_return(HTML_DATA_OUTOFBOUND);
+		if (wlh > 0)
+			os.wl_override_start(wlo, os.now_tz() + (ulong)wlh * 3600);
+		else
+			os.wl_override_stop();
+	}
+	else if (findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("wlh"), true) && atoi(tmp_buffer) == 0)
+	{
+		os.wl_override_stop();
+	}
+
 	if (findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("re"), true))
 	{
 		if (tmp_buffer[0] == '1' && !os.iopts[IOPT_REMOTE_EXT_MODE])
@@ -1058,10 +1940,39 @@ void server_change_scripturl()
 		tmp_buffer[TMP_BUFFER_SIZE] = 0;
 		string_remove_space(tmp_buffer);
 		os.sopt_save(SOPT_WEATHERURL, tmp_buffer);
+		fc_lasttime = 0;
 	}
 	handle_return(HTML_REDIRECT_HOME);
 }
 
+/** Apply changed integer options that take effect immediately */
+void apply_option_changes(bool time_change, bool weather_change, bool sensor_change, bool display_change)
+{
+	if (time_change)
+	{
+		os.status.req_ntpsync = 1;
+	}
+
+	if (weather_change)
+	{
+		os.set_water_scale(100); // reset watering percentage to 100%
+		wt_rawData[0] = 0;					   // reset wt_rawData and errCode
+		wt_errCode = HTTP_RQT_NOT_RECEIVED;
+		os.checkwt_lasttime = 0; // force weather update
+		fc_lasttime = 0;		 // and forecast update
+	}
+
+	if (sensor_change)
+	{
+		os.sensor_resetall();
+	}
+
+	if (display_change)
+	{
+		os.display.begin();
+	}
+}
+
 /**
  * Change options
  * Command: /co?pw=xxx&o?=x&loc=x&ttt=x
@@ -1069,6 +1980,12 @@ void server_change_scripturl()
  * pw:	password
  * o?:	option name (? is option index)
  * loc: location
+ * dname: device name, used in MQTT topics and events
+ * rkey: password key of remote stations (empty: the password of this controller, which cannot be sent once hashed)
+ * gkey: guest key, which can only read the json outputs if guest access is on (empty: guests need no key),
+ *		  without the credentials in /jc, the debug log (/jx) and the audit log (/jt)
+ * cors: comma separated origins of other sites allowed to use the API, * for all (empty: other sites can read
+ *		 the outputs, their commands need the password, also when the password is ignored)
  * ttt: manual time (applicable only if ntp=0)
  */
 void server_change_options()
@@ -1079,6 +1996,8 @@ void server_change_options()
 	bool time_change = false;
 	bool weather_change = false;
 	bool sensor_change = false;
+	bool display_change = false;
+	bool dry_run_change = false;
 
 	// !!! p and bfill share the same buffer, so don't write
 	// to bfill before you are done analyzing the buffer !!!
@@ -1086,13 +2005,15 @@ void server_change_options()
 	byte err = 0;
 	byte prev_value;
 	byte max_value;
+	byte prev_ext = os.iopts[IOPT_EXT_BOARDS], prev_zones = os.iopts[IOPT_EXP_ZONES];
 	for (byte oid = 0; oid < NUM_IOPTS; oid++)
 	{
 
 		// skip options that cannot be set through /co command
 		if (oid == IOPT_FW_VERSION || oid == IOPT_HW_VERSION || oid == IOPT_SEQUENTIAL_RETIRED ||
 			oid == IOPT_DEVICE_ENABLE || oid == IOPT_FW_MINOR || oid == IOPT_REMOTE_EXT_MODE ||
-			oid == IOPT_RESET || oid == IOPT_WIFI_MODE || oid == IOPT_URS_RETIRED || oid == IOPT_RSO_RETIRED)
+			oid == IOPT_RESET || oid == IOPT_WIFI_MODE || oid == IOPT_URS_RETIRED || oid == IOPT_RSO_RETIRED ||
+			oid == IOPT_SETUP)
 			continue;
 		prev_value = os.iopts[oid];
 		max_value = pgm_read_byte(iopt_max + oid);
@@ -1110,10 +2031,18 @@ void server_change_options()
 			{
 				v = water_time_encode_signed(v);
 			} // encode station delay time
+			if (oid >= IOPT_MASTER_ON_ADJ_MIN && oid <= IOPT_MASTER_OFF_ADJ_MIN_2)
+			{
+				v += MASTER_ADJ_MIN_ZERO;
+			} // master adjusted times in minutes are signed
 			if (oid == IOPT_BOOST_TIME)
 			{
 				v >>= 2;
 			}
+			if (oid == IOPT_EXP_ZONES && v % SHIFT_REGISTER_LINES)
+			{
+				v = -1; // expanders have whole shift registers
+			}
 			if (v >= 0 && v <= max_value)
 			{
 				os.iopts[oid] = v;
@@ -1130,13 +2059,25 @@ void server_change_options()
 				time_change = true;
 			if (oid >= IOPT_NTP_IP1 && oid <= IOPT_NTP_IP4)
 				time_change = true;
-			if (oid == IOPT_USE_WEATHER)
+			if (oid == IOPT_USE_WEATHER || oid == IOPT_FORECAST_HOURS)
 				weather_change = true;
 			if (oid >= IOPT_SENSOR1_TYPE && oid <= IOPT_SENSOR2_OFF_DELAY)
 				sensor_change = true;
+			if (oid == IOPT_DISPLAY_TYPE || oid == IOPT_DISPLAY_ADDR)
+				display_change = true;
+			if (oid == IOPT_DRY_RUN)
+				dry_run_change = true;
 		}
 	}
 
+	// the expanders must fit in the station bits
+	if (os.expander_boards(os.iopts[IOPT_EXT_BOARDS], os.iopts[IOPT_EXP_ZONES]) > MAX_EXT_BOARDS)
+	{
+		os.iopts[IOPT_EXT_BOARDS] = prev_ext;
+		os.iopts[IOPT_EXP_ZONES] = prev_zones;
+		err = 1;
+	}
+
 	if (findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("loc"), true))
 	{
 		urlDecode(tmp_buffer);
@@ -1168,6 +2109,30 @@ void server_change_options()
 		os.sopt_save(SOPT_IFTTT_KEY, tmp_buffer);
 	}
 
+	keyfound = 0;
+	if (findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("rkey"), true, &keyfound))
+	{
+		urlDecode(tmp_buffer);
+		os.sopt_save(SOPT_REMOTE_KEY, tmp_buffer);
+	}
+	else if (keyfound)
+	{
+		tmp_buffer[0] = 0;
+		os.sopt_save(SOPT_REMOTE_KEY, tmp_buffer);
+	}
+
+	keyfound = 0;
+	if (findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("gkey"), true, &keyfound))
+	{
+		urlDecode(tmp_buffer);
+		os.sopt_save(SOPT_GUEST_KEY, tmp_buffer);
+	}
+	else if (keyfound)
+	{
+		tmp_buffer[0] = 0;
+		os.sopt_save(SOPT_GUEST_KEY, tmp_buffer);
+	}
+
 	keyfound = 0;
 	if (findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("mqtt"), true, &keyfound))
 	{
@@ -1182,59 +2147,297 @@ void server_change_options()
 		os.status.req_mqtt_restart = true;
 	}
 
-	/*
-	// wtkey is retired
-	if (findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("wtkey"), true, &keyfound)) {
+	// mqtt tls settings, e.g. "en":1,"ca":"/etc/ssl/certs/ca.pem","cert":"","key":"","vfy":1
+	keyfound = 0;
+	if (findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("mqtls"), true, &keyfound))
+	{
 		urlDecode(tmp_buffer);
-		if (os.sopt_save(SOPT_WEATHER_KEY, tmp_buffer)) {  // if weather key has changed
-			weather_change = true;
-		}
-	} else if (keyfound) {
-		tmp_buffer[0]=0;
-		os.sopt_save(SOPT_WEATHER_KEY, tmp_buffer);
+		os.sopt_save(SOPT_MQTT_TLS, tmp_buffer);
+		os.status.req_mqtt_restart = true;
+	}
+	else if (keyfound)
+	{
+		tmp_buffer[0] = 0;
+		os.sopt_save(SOPT_MQTT_TLS, tmp_buffer);
+		os.status.req_mqtt_restart = true;
 	}
 
+	// mqtt root topic, {id} is replaced by the device id, empty uses opensprinkler
 	keyfound = 0;
-	if (findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("blynk"), true, &keyfound)) {
+	if (findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("mqtpc"), true, &keyfound))
+	{
 		urlDecode(tmp_buffer);
-		os.sopt_save(SOPT_BLYNK_TOKEN, tmp_buffer);
-	} else if (keyfound) {
-		tmp_buffer[0]=0;
-		os.sopt_save(SOPT_BLYNK_TOKEN, tmp_buffer);
+		os.sopt_save(SOPT_MQTT_TOPIC, tmp_buffer);
 	}
-	*/
-
-	// if not using NTP and manually setting time
-	if (!os.iopts[IOPT_USE_NTP] && findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("ttt"), true))
+	else if (keyfound)
 	{
-		unsigned long t;
-		t = strtoul(tmp_buffer, NULL, 0);
-		// before chaging time, reset all stations to avoid messing up with timing
-		reset_all_stations_immediate();
+		tmp_buffer[0] = 0;
+		os.sopt_save(SOPT_MQTT_TOPIC, tmp_buffer);
 	}
-	if (err)
-		handle_return(HTML_DATA_OUTOFBOUND);
-
-	os.iopts_save();
 
-	if (time_change)
+	// mqtt qos and retain flag of each event, e.g. station=1r,sensor1=0r
+	keyfound = 0;
+	if (findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("mqev"), true, &keyfound))
 	{
-		os.status.req_ntpsync = 1;
+		urlDecode(tmp_buffer);
+		os.sopt_save(SOPT_MQTT_EVENTS, tmp_buffer);
 	}
-
-	if (weather_change)
+	else if (keyfound)
 	{
-		os.iopts[IOPT_WATER_PERCENTAGE] = 100; // reset watering percentage to 100%
-		wt_rawData[0] = 0;					   // reset wt_rawData and errCode
-		wt_errCode = HTTP_RQT_NOT_RECEIVED;
-		os.checkwt_lasttime = 0; // force weather update
+		tmp_buffer[0] = 0;
+		os.sopt_save(SOPT_MQTT_EVENTS, tmp_buffer);
 	}
 
-	if (sensor_change)
+	// calendar url and the keyword of events that suppress watering
+	keyfound = 0;
+	if (findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("cal"), true, &keyfound))
 	{
-		os.sensor_resetall();
+		urlDecode(tmp_buffer);
+		if (os.sopt_save(SOPT_CALENDAR_URL, tmp_buffer))
+			cal_lasttime = 0; // fetch the new calendar
+	}
+	else if (keyfound)
+	{
+		tmp_buffer[0] = 0;
+		os.sopt_save(SOPT_CALENDAR_URL, tmp_buffer);
+		cal_lasttime = 0;
+	}
+
+	keyfound = 0;
+	if (findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("calkw"), true, &keyfound))
+	{
+		urlDecode(tmp_buffer);
+		if (os.sopt_save(SOPT_CALENDAR_KEYWORD, tmp_buffer))
+			cal_lasttime = 0;
+	}
+	else if (keyfound)
+	{
+		tmp_buffer[0] = 0;
+		os.sopt_save(SOPT_CALENDAR_KEYWORD, tmp_buffer);
+		cal_lasttime = 0;
+	}
+
+	// device name, used in MQTT topics and events
+	keyfound = 0;
+	if (findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("dname"), true, &keyfound))
+	{
+		urlDecode(tmp_buffer);
+		os.sopt_save(SOPT_DEVICE_NAME, tmp_buffer);
+	}
+	else if (keyfound)
+	{
+		tmp_buffer[0] = 0;
+		os.sopt_save(SOPT_DEVICE_NAME, tmp_buffer);
+	}
+
+	// allowed origins of cross-site requests, comma separated, see check_request_origin
+	keyfound = 0;
+	if (findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("cors"), true, &keyfound))
+	{
+		urlDecode(tmp_buffer);
+		os.sopt_save(SOPT_CORS_ORIGINS, tmp_buffer);
+	}
+	else if (keyfound)
+	{
+		tmp_buffer[0] = 0;
+		os.sopt_save(SOPT_CORS_ORIGINS, tmp_buffer);
+	}
+
+	// run-once template for program switches, e.g. 60,0,120
+	keyfound = 0;
+	if (findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("pst"), true, &keyfound))
+	{
+		urlDecode(tmp_buffer);
+		os.sopt_save(SOPT_PSWITCH_RUNONCE, tmp_buffer);
+	}
+	else if (keyfound)
+	{
+		tmp_buffer[0] = 0;
+		os.sopt_save(SOPT_PSWITCH_RUNONCE, tmp_buffer);
+	}
+
+	/*
+	// wtkey is retired
+	if (findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("wtkey"), true, &keyfound)) {
+		urlDecode(tmp_buffer);
+		if (os.sopt_save(SOPT_WEATHER_KEY, tmp_buffer)) {  // if weather key has changed
+			weather_change = true;
+		}
+	} else if (keyfound) {
+		tmp_buffer[0]=0;
+		os.sopt_save(SOPT_WEATHER_KEY, tmp_buffer);
+	}
+
+	keyfound = 0;
+	if (findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("blynk"), true, &keyfound)) {
+		urlDecode(tmp_buffer);
+		os.sopt_save(SOPT_BLYNK_TOKEN, tmp_buffer);
+	} else if (keyfound) {
+		tmp_buffer[0]=0;
+		os.sopt_save(SOPT_BLYNK_TOKEN, tmp_buffer);
+	}
+	*/
+
+	// if not using NTP and manually setting time
+	if (!os.iopts[IOPT_USE_NTP] && findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("ttt"), true))
+	{
+		unsigned long t;
+		t = strtoul(tmp_buffer, NULL, 0);
+		// before chaging time, reset all stations to avoid messing up with timing
+		reset_all_stations_immediate();
+	}
+	if (err)
+		handle_return(HTML_DATA_OUTOFBOUND);
+
+	// runs started in one mode do not carry over to the other,
+	// special stations that were really turned on are turned off
+	if (dry_run_change)
+		reset_all_stations_immediate();
+
+	os.iopts_save();
+
+	apply_option_changes(time_change, weather_change, sensor_change, display_change);
+
+	handle_return(HTML_SUCCESS);
+}
+
+/**
+ * Reload configuration
+ * Command: /rl?pw=xxx
+ *
+ * pw: password
+ * Re-reads the data files after they have been changed outside of the
+ * controller (e.g. by the command line tool) and applies the changes.
+ * restart is set if some changes only take effect after a restart.
+ */
+void server_reload_config()
+{
+	byte prev[NUM_IOPTS];
+	memcpy(prev, os.iopts, NUM_IOPTS);
+	os.iopts_load();
+	os.attribs_load();
+	pd.load_count();
+
+	bool time_change = false, weather_change = false, sensor_change = false, display_change = false;
+	byte restart = 0;
+	for (byte oid = 0; oid < NUM_IOPTS; oid++)
+	{
+		if (os.iopts[oid] == prev[oid])
+			continue;
+		if (oid == IOPT_TIMEZONE || oid == IOPT_USE_NTP || (oid >= IOPT_NTP_IP1 && oid <= IOPT_NTP_IP4))
+			time_change = true;
+		else if (oid == IOPT_USE_WEATHER)
+			weather_change = true;
+		else if (oid >= IOPT_SENSOR1_TYPE && oid <= IOPT_SENSOR2_OFF_DELAY)
+			sensor_change = true;
+		else if (oid == IOPT_DISPLAY_TYPE || oid == IOPT_DISPLAY_ADDR)
+			display_change = true;
+		else if ((oid >= IOPT_USE_DHCP && oid <= IOPT_HTTPPORT_1) || (oid >= IOPT_DNS_IP1 && oid <= IOPT_DNS_IP4) ||
+				 (oid >= IOPT_SUBNET_MASK1 && oid <= IOPT_SUBNET_MASK4))
+			restart = 1; // network settings are only applied when the network starts
+	}
+	apply_option_changes(time_change, weather_change, sensor_change, display_change);
+
+	// string options are read from file when used,
+	// but mqtt and weather need to pick up changes
+	os.status.req_mqtt_restart = true;
+	os.checkwt_lasttime = 0;
+	fc_lasttime = 0;
+
+	print_json_header();
+	bfill.emit_p(PSTR("\"result\":$D,\"restart\":$D}"), HTML_SUCCESS, restart);
+	handle_return(HTML_OK);
+}
+
+/** Whether the first-boot setup is still pending */
+static bool setup_pending()
+{
+#if defined(DEMO)
+	return false; // the password cannot be changed in demo mode
+#endif
+	return !(os.iopts[IOPT_SETUP] & SETUP_COMPLETE);
+}
+
+/**
+ * First-boot setup
+ * Command: /sw?step=x&...
+ *
+ * While a new controller is not set up, /sw needs no password and other commands
+ * (except /ui) return result 48 with "item":"setup". Steps can be taken in any order and repeated:
+ * step=pw&npw=x&cpw=x: new password, as for /sp; it must not be the default one
+ * step=loc&loc=x:	location
+ * step=tz&tz=x:	time zone (as in /jo)
+ * step=ext&ext=x:	number of expansion boards
+ * step=wm&uwt=x:	weather adjustment method (as in /jo)
+ * step=done:		complete the setup, the password must have been set
+ * Without step, or once the setup is complete, the output is {"setup":x,"steps":x}
+ * where setup is 1 while pending and steps has a SETUP_STEP_* bit for each step taken.
+ */
+void server_setup()
+{
+	char *p = get_buffer;
+
+	if (!setup_pending() || !findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("step"), true))
+	{
+		print_json_header();
+		bfill.emit_p(PSTR("\"setup\":$D,\"steps\":$D}"), setup_pending() ? 1 : 0, os.iopts[IOPT_SETUP] & ~SETUP_COMPLETE);
+		handle_return(HTML_OK);
+	}
+
+	byte step;
+	bool weather_change = false, time_change = false;
+	char tbuf2[TMP_BUFFER_SIZE];
+	strcpy(tbuf2, tmp_buffer);
+	if (!strcmp(tbuf2, "pw"))
+	{
+		if (!findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("npw"), true) ||
+			!findKeyVal(p, tbuf2, TMP_BUFFER_SIZE, PSTR("cpw"), true))
+			handle_return(HTML_DATA_MISSING);
+		if (strncmp(tmp_buffer, tbuf2, TMP_BUFFER_SIZE) != 0)
+			handle_return(HTML_MISMATCH);
+		urlDecode(tmp_buffer);
+		if (!os.password_acceptable(tmp_buffer) || !strcmp(tmp_buffer, DEFAULT_PASSWORD))
+			handle_return_item(HTML_DATA_OUTOFBOUND, "npw", -1);
+		os.password_save(tmp_buffer);
+		step = SETUP_STEP_PASSWORD;
+	}
+	else if (!strcmp(tbuf2, "loc"))
+	{
+		if (!findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("loc"), true))
+			handle_return(HTML_DATA_MISSING);
+		urlDecode(tmp_buffer);
+		weather_change = os.sopt_save(SOPT_LOCATION, tmp_buffer);
+		step = SETUP_STEP_LOCATION;
+	}
+	else if (!strcmp(tbuf2, "tz") || !strcmp(tbuf2, "ext") || !strcmp(tbuf2, "wm"))
+	{
+		byte oid = (tbuf2[0] == 't') ? IOPT_TIMEZONE : (tbuf2[0] == 'e') ? IOPT_EXT_BOARDS : IOPT_USE_WEATHER;
+		strncpy_P0(tbuf2, iopt_json_names + oid * 5, 5);
+		if (!findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, tbuf2))
+			handle_return(HTML_DATA_MISSING);
+		long v = atol(tmp_buffer);
+		if (v < 0 || v > pgm_read_byte(iopt_max + oid) ||
+			(oid == IOPT_EXT_BOARDS && os.expander_boards(v, os.iopts[IOPT_EXP_ZONES]) > MAX_EXT_BOARDS))
+			handle_return_item(HTML_DATA_OUTOFBOUND, (oid == IOPT_TIMEZONE) ? "tz" : (oid == IOPT_EXT_BOARDS) ? "ext" : "uwt", -1);
+		time_change = (oid == IOPT_TIMEZONE && os.iopts[oid] != v);
+		weather_change = (oid == IOPT_USE_WEATHER && os.iopts[oid] != v);
+		os.iopts[oid] = v;
+		step = (oid == IOPT_TIMEZONE) ? SETUP_STEP_TIMEZONE : (oid == IOPT_EXT_BOARDS) ? SETUP_STEP_BOARDS : SETUP_STEP_WEATHER;
+	}
+	else if (!strcmp(tbuf2, "done"))
+	{
+		if (!(os.iopts[IOPT_SETUP] & SETUP_STEP_PASSWORD))
+			handle_return_item(HTML_DATA_MISSING, "pw", -1);
+		step = SETUP_COMPLETE;
+	}
+	else
+	{
+		handle_return_item(HTML_DATA_OUTOFBOUND, "step", -1);
 	}
 
+	os.iopts[IOPT_SETUP] |= step;
+	os.iopts_save();
+	apply_option_changes(time_change, weather_change, false, false);
 	handle_return(HTML_SUCCESS);
 }
 
@@ -1260,7 +2463,9 @@ void server_change_password()
 		if (findKeyVal(p, tbuf2, TMP_BUFFER_SIZE, PSTR("cpw"), true) && strncmp(tmp_buffer, tbuf2, TMP_BUFFER_SIZE) == 0)
 		{
 			urlDecode(tmp_buffer);
-			os.sopt_save(SOPT_PASSWORD, tmp_buffer);
+			if (!os.password_acceptable(tmp_buffer))
+				handle_return_item(HTML_DATA_OUTOFBOUND, "npw", -1);
+			os.password_save(tmp_buffer);
 			handle_return(HTML_SUCCESS);
 		}
 		else
@@ -1282,7 +2487,12 @@ void server_json_status_main()
 		if (sid != os.nstations - 1)
 			bfill.emit_p(PSTR(","));
 	}
-	bfill.emit_p(PSTR("],\"nstations\":$D}"), os.nstations);
+	bfill.emit_p(PSTR("],\"sbits\":"));
+	emit_station_bits(bfill, true);
+	bfill.emit_p(PSTR(",\"nstations\":$D,\"lrun\":[$D,$D,$D,$L],"), os.nstations,
+				 pd.lastrun.station, pd.lastrun.program, pd.lastrun.duration, pd.lastrun.endtime);
+	server_json_ps(os.now_tz());
+	bfill.emit_p(PSTR("}"));
 }
 
 /** Output station status */
@@ -1293,6 +2503,110 @@ void server_json_status()
 	handle_return(HTML_OK);
 }
 
+/**
+ * Add a manual (test) run of a station to the queue
+ * Returns an HTML result code; call schedule_all_stations afterwards
+ */
+static byte enqueue_manual_station(byte sid, uint16_t timer)
+{
+	// skip if the station is a master station
+	// (because master cannot be scheduled independently)
+	if ((os.status.mas == sid + 1) || (os.status.mas2 == sid + 1))
+		return HTML_NOT_PERMITTED;
+
+	RuntimeQueueStruct *q = NULL;
+	byte sqi = pd.station_qid[sid];
+	// check if the station already has a schedule
+	if (sqi != 0xFF)
+	{ // if so, we will overwrite the schedule
+		q = pd.queue + sqi;
+		q->st = 0;
+		q->dur = timer;
+		q->pid = 99; // testing stations are assigned program index 99
+	}
+	else
+	{ // otherwise create a new queue element
+		q = pd.enqueue(sid, 99, timer);
+	}
+	// if the queue is full
+	if (!q)
+		return HTML_NOT_PERMITTED;
+	return HTML_SUCCESS;
+}
+
+/**
+ * Start a zone test
+ * Command: /zt?pw=xxx&t=x&sk=x&sids=x,x,x
+ *
+ * pw:	password
+ * t:	run time of each station (in seconds)
+ * sk:	soak time between stations (in seconds, optional)
+ * sids: comma separated station indices, in the order they run, each listed once (optional, all stations by default)
+ * Master and disabled stations are skipped. Progress is reported by /jr
+ */
+void server_zone_test()
+{
+	char *p = get_buffer;
+
+	if (!findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("t"), true))
+		handle_return(HTML_DATA_MISSING);
+	long dur = atol(tmp_buffer);
+	if (dur <= 0 || dur > MAX_WATER_TIME)
+		handle_return_item(HTML_DATA_OUTOFBOUND, "t", -1);
+
+	long soak = 0;
+	if (findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("sk"), true))
+	{
+		soak = atol(tmp_buffer);
+		if (soak < 0 || soak > MAX_WATER_TIME)
+			handle_return_item(HTML_DATA_OUTOFBOUND, "sk", -1);
+	}
+
+	byte sids[MAX_NUM_STATIONS];
+	byte n = 0;
+	if (findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("sids"), true))
+	{
+		char list[TMP_BUFFER_SIZE];
+		strcpy(list, tmp_buffer);
+		char *pv = list;
+		while (*pv && n < MAX_NUM_STATIONS)
+		{
+			uint16_t sid = parse_listdata(&pv);
+			if (sid >= os.nstations)
+				handle_return(HTML_DATA_OUTOFBOUND);
+			for (byte i = 0; i < n; i++)
+			{
+				if (sids[i] == sid)
+					handle_return_item(HTML_DATA_FORMATERROR, "sids", sid);
+			}
+			sids[n++] = sid;
+			if (*(pv - 1) == 0)
+				break; // reached the end of the list
+		}
+	}
+	else
+	{
+		for (byte sid = 0; sid < os.nstations; sid++)
+			sids[n++] = sid;
+	}
+
+	// leave out master and disabled stations
+	byte m = 0;
+	for (byte i = 0; i < n; i++)
+	{
+		byte sid = sids[i];
+		if ((os.status.mas == sid + 1) || (os.status.mas2 == sid + 1))
+			continue;
+		if (os.attrib_dis[sid >> 3] & (1 << (sid & 0x07)))
+			continue;
+		sids[m++] = sid;
+	}
+
+	if (!start_zone_test(sids, m, dur, soak))
+		handle_return(HTML_NOT_PERMITTED);
+	handle_return(HTML_SUCCESS);
+}
+
 /**
  * Test station (previously manual operation)
  * Command: /cm?pw=xxx&sid=x&en=x&t=x
@@ -1300,7 +2614,8 @@ void server_json_status()
  * pw: password
  * sid:station index (starting from 0)
  * en: enable (0 or 1)
- * t:  timer (required if en=1)
+ * t:  timer (required if en=1, 1 to MAX_WATER_TIME seconds)
+ * ssta: shift remaining sequential stations forward (optional, en=0 only)
  */
 void server_change_manual()
 {
@@ -1334,175 +2649,1427 @@ void server_change_manual()
 	{ // if turning on a station, must provide timer
 		if (findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("t"), true))
 		{
-			timer = (uint16_t)atol(tmp_buffer);
-			if (timer == 0 || timer > 64800)
-			{
-				handle_return(HTML_DATA_OUTOFBOUND);
-			}
-			// schedule manual station
-			// skip if the station is a master station
-			// (because master cannot be scheduled independently)
-			if ((os.status.mas == sid + 1) || (os.status.mas2 == sid + 1))
-				handle_return(HTML_NOT_PERMITTED);
-
-			RuntimeQueueStruct *q = NULL;
-			byte sqi = pd.station_qid[sid];
-			// check if the station already has a schedule
-			if (sqi != 0xFF)
-			{ // if so, we will overwrite the schedule
-				q = pd.queue + sqi;
-			}
-			else
-			{ // otherwise create a new queue element
-				q = pd.enqueue();
-			}
-			// if the queue is not full
-			if (q)
-			{
-				q->st = 0;
-				q->dur = timer;
-				q->sid = sid;
-				q->pid = 99; // testing stations are assigned program index 99
-				schedule_all_stations(curr_time);
-			}
-			else
+			long v = atol(tmp_buffer);
+			if (v <= 0 || v > MAX_WATER_TIME)
 			{
-				handle_return(HTML_NOT_PERMITTED);
+				handle_return_item(HTML_DATA_OUTOFBOUND, "t", -1);
 			}
+			timer = v;
+			byte ret = enqueue_manual_station(sid, timer);
+			if (ret != HTML_SUCCESS)
+				handle_return(ret);
+			schedule_all_stations(curr_time);
 		}
 		else
 		{
 			handle_return(HTML_DATA_MISSING);
 		}
 	}
-	else
-	{ // turn off station
-		turn_off_station(sid, curr_time);
-	}
-	handle_return(HTML_SUCCESS);
+	else
+	{ // turn off station
+		byte ssta = 0;
+		if (findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("ssta"), true))
+		{
+			ssta = atoi(tmp_buffer);
+		}
+		turn_off_station(sid, curr_time);
+		if (ssta)
+		{
+			reschedule_waiting_stations(curr_time, 0xFF);
+		}
+	}
+	handle_return(HTML_SUCCESS);
+}
+
+/**
+ * Change station group name
+ * Command: /cg?pw=xxx&gid=x&gn=xxx
+ *
+ * pw:	password
+ * gid: group id (1 to MAX_NUM_GROUPS)
+ * gn:	group name (empty to clear)
+ * Stations are assigned to groups with /cs (g? parameters)
+ */
+void server_change_group()
+{
+	char *p = get_buffer;
+
+	if (!findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("gid"), true))
+		handle_return(HTML_DATA_MISSING);
+	int gid = atoi(tmp_buffer);
+	if (gid <= 0 || gid > MAX_NUM_GROUPS)
+		handle_return(HTML_DATA_OUTOFBOUND);
+
+	uint8_t keyfound = 0;
+	findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("gn"), true, &keyfound);
+	if (!keyfound)
+		handle_return(HTML_DATA_MISSING);
+	urlDecode(tmp_buffer);
+	if (strlen(tmp_buffer) > GROUP_NAME_SIZE)
+		handle_return(HTML_DATA_OUTOFBOUND);
+	os.set_group_name(gid, tmp_buffer);
+	handle_return(HTML_SUCCESS);
+}
+
+/**
+ * Apply an operation to all stations of a group
+ * Used by /ag and by MQTT group commands
+ *
+ * t:	run each station for t seconds
+ * en:	0 turns off all stations of the group
+ * dis: disable (1) or enable (0) the stations
+ * igs: ignore (1) or obey (0) sensor 1 and sensor 2
+ * Returns an HTML result code
+ */
+byte apply_group_command(byte gid, char *p)
+{
+	if (gid == 0 || gid > MAX_NUM_GROUPS)
+		return HTML_DATA_OUTOFBOUND;
+
+	ulong curr_time = os.now_tz();
+	byte sid, bid, s;
+	byte found = 0;
+
+	if (findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("t"), true))
+	{
+		long timer = atol(tmp_buffer);
+		if (timer <= 0 || timer > MAX_WATER_TIME)
+			return HTML_DATA_OUTOFBOUND;
+		byte count = 0;
+		for (sid = 0; sid < os.nstations; sid++)
+		{
+			bid = sid >> 3;
+			s = sid & 0x07;
+			// disabled stations and masters are skipped
+			if (os.attrib_grp[sid] != gid || (os.attrib_dis[bid] & (1 << s)))
+				continue;
+			if (enqueue_manual_station(sid, timer) == HTML_SUCCESS)
+				count++;
+		}
+		if (!count)
+			return HTML_NOT_PERMITTED;
+		schedule_all_stations(curr_time);
+		found = 1;
+	}
+
+	if (findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("en"), true))
+	{
+		if (atoi(tmp_buffer) == 0)
+		{
+			for (sid = 0; sid < os.nstations; sid++)
+			{
+				if (os.attrib_grp[sid] == gid)
+					turn_off_station(sid, curr_time);
+			}
+			reschedule_waiting_stations(curr_time, 0xFF);
+		}
+		found = 1;
+	}
+
+	byte attrib_changed = 0;
+	if (findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("dis"), true))
+	{
+		byte dis = atoi(tmp_buffer) ? 1 : 0;
+		for (sid = 0; sid < os.nstations; sid++)
+		{
+			if (os.attrib_grp[sid] != gid)
+				continue;
+			bid = sid >> 3;
+			s = sid & 0x07;
+			os.attrib_dis[bid] = (os.attrib_dis[bid] & ~(1 << s)) | (dis << s);
+		}
+		attrib_changed = 1;
+	}
+
+	if (findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("igs"), true))
+	{
+		byte igs = atoi(tmp_buffer) ? 1 : 0;
+		for (sid = 0; sid < os.nstations; sid++)
+		{
+			if (os.attrib_grp[sid] != gid)
+				continue;
+			bid = sid >> 3;
+			s = sid & 0x07;
+			os.attrib_igs[bid] = (os.attrib_igs[bid] & ~(1 << s)) | (igs << s);
+			os.attrib_igs2[bid] = (os.attrib_igs2[bid] & ~(1 << s)) | (igs << s);
+		}
+		attrib_changed = 1;
+	}
+
+	if (attrib_changed)
+	{
+		os.attribs_save();
+		found = 1;
+	}
+
+	return found ? HTML_SUCCESS : HTML_DATA_MISSING;
+}
+
+/**
+ * Operate on a station group
+ * Command: /ag?pw=xxx&gid=x&t=x&en=x&dis=x&igs=x
+ *
+ * pw:	password
+ * gid: group id (1 to MAX_NUM_GROUPS)
+ * See apply_group_command for the remaining parameters
+ */
+void server_apply_group()
+{
+	char *p = get_buffer;
+
+	if (!findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("gid"), true))
+		handle_return(HTML_DATA_MISSING);
+	int gid = atoi(tmp_buffer);
+	if (gid <= 0 || gid > MAX_NUM_GROUPS)
+		handle_return(HTML_DATA_OUTOFBOUND);
+
+	handle_return(apply_group_command(gid, p));
+}
+
+/**
+ * Trigger a virtual program switch
+ * Used by /vs and by MQTT switch messages
+ *
+ * sw:	switch index (1 to MAX_NUM_VSWITCHES)
+ * Returns an HTML result code
+ */
+byte trigger_virtual_switch(byte sw)
+{
+	if (sw == 0 || sw > MAX_NUM_VSWITCHES)
+		return HTML_DATA_OUTOFBOUND;
+	byte pid = os.iopts[IOPT_VSWITCH1_PROG + sw - 1];
+	// the switch must be mapped to an existing program or the run-once template
+	if (pid == 0 || (pid > pd.nprograms && pid != PSWITCH_RUNONCE))
+		return HTML_NOT_PERMITTED;
+	if (!os.trigger_vswitch(sw - 1, os.now_tz()))
+		return HTML_NOT_PERMITTED;
+	return HTML_SUCCESS;
+}
+
+/**
+ * Trigger a virtual program switch
+ * Command: /vs?pw=xxx&sw=x
+ *
+ * pw:	password
+ * sw:	switch index (1 to MAX_NUM_VSWITCHES)
+ * The mapped program (option vsw?p) starts on the next main loop pass
+ */
+void server_virtual_switch()
+{
+	char *p = get_buffer;
+
+	if (!findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("sw"), true))
+		handle_return(HTML_DATA_MISSING);
+	int sw = atoi(tmp_buffer);
+	if (sw <= 0 || sw > MAX_NUM_VSWITCHES)
+		handle_return(HTML_DATA_OUTOFBOUND);
+
+	handle_return(trigger_virtual_switch(sw));
+}
+
+/**
+ * Override a sensor, to test notifications and skip logic without waiting for real rain
+ * Command: /so?pw=xxx&sn=x&st=x&dur=x
+ *
+ * pw:	password
+ * sn:	sensor index (1 or 2)
+ * st:	state a rain or soil sensor is held at (1: active, 0: inactive, -1: end the override)
+ * dur: override duration in minutes (1 to MAX_SENSOR_OVERRIDE, default 10)
+ * A program switch sensor is triggered once instead, st and dur are not used then.
+ * Overridden sensors show the end of the override in /jc (sovr) and /jr (ovr).
+ */
+void server_sensor_override()
+{
+	char *p = get_buffer;
+
+	if (!findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("sn"), true))
+		handle_return(HTML_DATA_MISSING);
+	int sn = atoi(tmp_buffer);
+	if (sn < 1 || sn > 2)
+		handle_return(HTML_DATA_OUTOFBOUND);
+	int st = 1;
+	if (findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("st"), true))
+		st = atoi(tmp_buffer);
+	long dur = 10;
+	if (findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("dur"), true))
+		dur = atol(tmp_buffer);
+	if (dur < 0 || dur > MAX_SENSOR_OVERRIDE)
+		handle_return(HTML_DATA_OUTOFBOUND);
+
+	handle_return(os.override_sensor(sn - 1, st < 0 ? -1 : st, dur, os.now_tz()));
+}
+
+/**
+ * Real-time flow rate in 1/100 volume units (of the unit system) per minute,
+ * from the interval between the two most recent pulses, 0 if no pulse came in FLOWCOUNT_RT_WINDOW seconds
+ */
+static ulong flow_rate_realtime()
+{
+	if (!flow_pulse_interval)
+		return 0;
+	// if the current pulse is overdue, the rate has dropped at least that much
+	ulong interval = millis() - flow_last_pulse;
+	if (interval > FLOWCOUNT_RT_WINDOW * 1000UL)
+		return 0; // the flow has stopped
+	if (interval < flow_pulse_interval)
+		interval = flow_pulse_interval;
+	return (ulong)(flow_from_liters(flow_pulse_liters()) * 100 * 60000UL / interval + 0.5f);
+}
+
+/** Output the state of one sensor */
+static void server_json_sensor(byte type, byte detected, byte active, ulong on_timer, ulong off_timer, ulong active_lasttime, ulong override_until)
+{
+	bfill.emit_p(PSTR("{\"type\":$D,\"det\":$D,\"act\":$D,\"ontm\":$L,\"oftm\":$L,\"last\":$L,\"ovr\":$L}"),
+				 type, detected, active, on_timer, off_timer, active_lasttime, override_until);
+}
+
+/**
+ * Output real-time flow and sensor states
+ * Command: /jr?pw=xxx
+ *
+ * pw:	password
+ * flow: pulse count, pulses in the last FLOWCOUNT_RT_WINDOW seconds,
+ *		 real-time rate (1/100 volume units per minute), last pulse interval (ms) and volume unit
+ * sensors: type, detected, active, on/off timers (time a pending change takes effect, 0 if none),
+ *			 last activation time and end of the override (see /so, 0 if none) of each sensor
+ * wl: water scale, wlost: end of the water scale override (0 if none, see /cv)
+ * valves: [sid, remaining time] of each open station
+ * ztest: [number of stations, stations not finished yet, end time] of the current zone test
+ */
+void server_json_realtime()
+{
+	ulong curr_time = os.now_tz();
+	print_json_header();
+	bfill.emit_p(PSTR("\"devt\":$L,\"flow\":{\"count\":$L,\"rt\":$L,\"rtw\":$D,\"rate\":$L,\"intv\":$L,\"unit\":\"$S\"},\"sensors\":["),
+				 curr_time,
+				 flow_count,
+				 os.flowcount_rt,
+				 FLOWCOUNT_RT_WINDOW,
+				 flow_rate_realtime(),
+				 flow_pulse_interval,
+				 flow_unit());
+	server_json_sensor(os.iopts[IOPT_SENSOR1_TYPE], os.status.sensor1, os.status.sensor1_active,
+					   os.sensor1_on_timer, os.sensor1_off_timer, os.sensor1_active_lasttime, os.sensor_override_until[0]);
+	bfill.emit_p(PSTR(","));
+	server_json_sensor(os.iopts[IOPT_SENSOR2_TYPE], os.status.sensor2, os.status.sensor2_active,
+					   os.sensor2_on_timer, os.sensor2_off_timer, os.sensor2_active_lasttime, os.sensor_override_until[1]);
+	bfill.emit_p(PSTR("],\"rd\":$D,\"rdst\":$L,\"wl\":$D,\"wlost\":$L,\"valves\":["),
+				 os.status.rain_delayed,
+				 os.nvdata.rd_stop_time,
+				 os.iopts[IOPT_WATER_PERCENTAGE],
+				 os.nvdata.wl_stop_time);
+	byte comma = 0;
+	for (byte sid = 0; sid < os.nstations; sid++)
+	{
+		if (!((os.station_bits[sid >> 3] >> (sid & 0x07)) & 1))
+			continue;
+		byte qid = pd.station_qid[sid];
+		ulong rem = (qid < pd.nqueue) ? queue_remaining(pd.queue + qid, curr_time) : 0;
+		bfill.emit_p(comma ? PSTR(",[$D,$L]") : PSTR("[$D,$L]"), sid, rem);
+		comma = 1;
+		if (available_ether_buffer() <= 0)
+			send_packet();
+	}
+	byte zleft = 0;
+	if (curr_time < zonetest_end)
+	{
+		for (byte qid = 0; qid < pd.nqueue; qid++)
+		{
+			if (pd.queue[qid].pid == ZONETEST_PID && pd.queue[qid].st + pd.queue[qid].dur > curr_time)
+				zleft++;
+		}
+	}
+	bfill.emit_p(PSTR("],\"ztest\":[$D,$D,$L]}"), zleft ? zonetest_total : 0, zleft, zleft ? zonetest_end : 0);
+	handle_return(HTML_OK);
+}
+
+/**
+ * Output GPIO pins for GPIO stations
+ * Command: /jg?pw=xxx
+ *
+ * pw:	password
+ * free: pins that a GPIO station can be assigned to
+ * rsvd: pins used by the controller itself (shift register, sensors and RF transmitter)
+ * used: [station index, pin] of each GPIO station
+ */
+void server_json_gpio()
+{
+	byte pins[MAX_GPIO_PINS];
+	byte n = gpio_free_pins(pins);
+	byte sid, i;
+	bool comma = false;
+	print_json_header();
+	bfill.emit_p(PSTR("\"free\":["));
+	for (i = 0; i < n; i++)
+	{
+		if (!gpio_pin_available(pins[i], 0xFF))
+			continue;
+		if (comma)
+			bfill.emit_p(PSTR(","));
+		comma = true;
+		bfill.emit_p(PSTR("$D"), pins[i]);
+	}
+	bfill.emit_p(PSTR("],\"rsvd\":["));
+	n = gpio_reserved_pins(pins);
+	for (i = 0; i < n; i++)
+		bfill.emit_p(i ? PSTR(",$D") : PSTR("$D"), pins[i]);
+	bfill.emit_p(PSTR("],\"used\":["));
+	comma = false;
+	for (sid = 0; sid < os.nstations; sid++)
+	{
+		byte pin = gpio_station_pin(sid);
+		if (pin == 0xFF)
+			continue;
+		if (comma)
+			bfill.emit_p(PSTR(","));
+		comma = true;
+		bfill.emit_p(PSTR("[$D,$D]"), sid, pin);
+	}
+	bfill.emit_p(PSTR("]}"));
+	handle_return(HTML_OK);
+}
+
+/** Read the first number of a line starting with key from a text file, or -1 if not found
+ * An empty key matches the first line.
+ */
+static long read_file_number(const char *fname, const char *key)
+{
+	FILE *fp = fopen(fname, "r");
+	if (!fp)
+		return -1;
+	long v = -1;
+	char line[128];
+	size_t len = strlen(key);
+	while (fgets(line, sizeof(line), fp))
+	{
+		if (strncmp(line, key, len))
+			continue;
+		v = atol(line + len);
+		break;
+	}
+	fclose(fp);
+	return v;
+}
+
+/**
+ * Output controller diagnostics
+ * Command: /jd?pw=xxx
+ *
+ * pw:	password
+ * uptime: seconds since the controller started, lupt and lrbtc are the start time and the reboot cause
+ * dfree, dtotal: free and total space of the file system the logs are stored on (in kB)
+ * cfsize, cftime: size and modification time of the options file
+ * dpath: folder of the data files and logs, dmem: whether it is in memory, so changes are lost on restart
+ * mem: resident memory of the process (in kB)
+ * temp: CPU temperature (in 0.1 degree C, -1 if unknown)
+ * ifs: [name, up, ip address] of each network interface
+ */
+void server_json_diagnostics()
+{
+	ulong curr_time = os.now_tz();
+	print_json_header();
+	bfill.emit_p(PSTR("\"uptime\":$L,\"lupt\":$L,\"lrbtc\":$D,"),
+				 curr_time - os.powerup_lasttime, os.powerup_lasttime, os.last_reboot_cause);
+
+	struct statvfs vfs;
+	ulong dfree = 0, dtotal = 0;
+	if (!statvfs(get_filename_fullpath(LOG_PREFIX), &vfs))
+	{
+		dfree = (ulong)((unsigned long long)vfs.f_bavail * vfs.f_frsize / 1024);
+		dtotal = (ulong)((unsigned long long)vfs.f_blocks * vfs.f_frsize / 1024);
+	}
+	bfill.emit_p(PSTR("\"dfree\":$L,\"dtotal\":$L,"), dfree, dtotal);
+
+	struct stat st;
+	if (stat(get_filename_fullpath(IOPTS_FILENAME), &st))
+		memset(&st, 0, sizeof(st));
+	bfill.emit_p(PSTR("\"cfsize\":$L,\"cftime\":$L,"), (ulong)st.st_size, (ulong)st.st_mtime);
+	bfill.emit_p(PSTR("\"dpath\":\"$S\",\"dmem\":$D,"), get_data_path(), data_path_in_memory() ? 1 : 0);
+
+	long temp = read_file_number("/sys/class/thermal/thermal_zone0/temp", "");
+	bfill.emit_p(PSTR("\"mem\":$D,\"temp\":$D,\"ifs\":["),
+				 (int)read_file_number("/proc/self/status", "VmRSS:"), (int)(temp < 0 ? -1 : temp / 100));
+
+	struct ifaddrs *ifaddr, *ifa;
+	if (!getifaddrs(&ifaddr))
+	{
+		bool comma = false;
+		for (ifa = ifaddr; ifa; ifa = ifa->ifa_next)
+		{
+			if (!ifa->ifa_addr || ifa->ifa_addr->sa_family != AF_INET || (ifa->ifa_flags & IFF_LOOPBACK))
+				continue;
+			char ip[INET_ADDRSTRLEN];
+			inet_ntop(AF_INET, &((struct sockaddr_in *)ifa->ifa_addr)->sin_addr, ip, sizeof(ip));
+			if (comma)
+				bfill.emit_p(PSTR(","));
+			comma = true;
+			bfill.emit_p(PSTR("[\"$S\",$D,\"$S\"]"), ifa->ifa_name, (ifa->ifa_flags & IFF_RUNNING) ? 1 : 0, ip);
+		}
+		freeifaddrs(ifaddr);
+	}
+	bfill.emit_p(PSTR("]}"));
+	handle_return(HTML_OK);
+}
+
+/** Check if the web UI is served from the override directory */
+static bool webui_override()
+{
+	struct stat st;
+	return !stat(get_filename_fullpath(WEBUI_OVERRIDE_DIR), &st) && S_ISDIR(st.st_mode);
+}
+
+/**
+ * Built-in web UI
+ * Command: /ui or /ui/<file>
+ *
+ * Serves the built-in status and control page, see webui.cpp.
+ * Files in WEBUI_OVERRIDE_DIR replace the built-in ones, for working on the UI without rebuilding.
+ * Only text files are served.
+ * WEBUI_LANG holds the UI strings of the configured language, see lang.cpp.
+ */
+void server_webui()
+{
+	char name[64];
+	char *p = get_buffer - 1; // character after the command
+	byte n = 0;
+	if (*p == '/')
+	{
+		for (p++; *p && *p != ' ' && *p != '?' && n < sizeof(name) - 1; p++)
+			name[n++] = *p;
+	}
+	name[n] = 0;
+	if (!n)
+		strcpy(name, WEBUI_INDEX);
+	if (strstr(name, ".."))
+		handle_return(HTML_PAGE_NOT_FOUND);
+
+	bool lang = !strcmp(name, WEBUI_LANG);
+	FILE *fp = NULL;
+	const WebAsset *asset = NULL;
+	if (!lang && webui_override())
+	{
+		snprintf(tmp_buffer, TMP_BUFFER_SIZE, "%s%s", WEBUI_OVERRIDE_DIR, name);
+		fp = fopen(get_filename_fullpath(tmp_buffer), "rb");
+	}
+	if (!lang && !fp && !(asset = webui_find_asset(name)))
+		handle_return(HTML_PAGE_NOT_FOUND);
+
+	bfill.emit_p(PSTR("$FContent-Type: $S\r\nConnection: close\r\n$S$S\r\n"), html200OK, webui_content_type(name),
+				 htmlETag[0] ? htmlETag : "Cache-Control: no-cache\r\n", htmlAccessControl);
+
+	if (lang)
+	{
+		bfill.emit_p(PSTR("var LANG = {"));
+		const LangString *s;
+		for (byte i = 0; (s = lang_ui_string(i)); i++)
+		{
+			bfill.emit_p(PSTR("$S\"$S\":\"$S\""), i ? "," : "", s->key, lang_text(s));
+			if (available_ether_buffer() <= 0)
+				send_packet();
+		}
+		bfill.emit_p(PSTR("};\n"));
+		handle_return(HTML_OK);
+	}
+
+	const char *data = asset ? asset->data : NULL;
+	size_t len = asset ? strlen(data) : 0;
+	while (true)
+	{
+		size_t room = available_ether_buffer() - 1;
+		char *buf = ether_buffer + bfill.position();
+		size_t nread;
+		if (fp)
+		{
+			nread = fread(buf, 1, room, fp);
+		}
+		else
+		{
+			nread = (len < room) ? len : room;
+			memcpy(buf, data, nread);
+			data += nread;
+			len -= nread;
+		}
+		buf[nread] = 0;
+		bfill.emit_p(PSTR("$S"), buf);
+		if (nread < room)
+			break;
+		send_packet();
+	}
+	if (fp)
+		fclose(fp);
+	handle_return(HTML_OK);
+}
+
+/**
+ * Output scheduled rain delay windows
+ * Command: /jw?pw=xxx
+ *
+ * pw:	password
+ * Each window is output as [weekday bits, start time, duration], times in minutes
+ */
+void server_json_rdwindows()
+{
+	RainDelayWindow win;
+	print_json_header();
+	bfill.emit_p(PSTR("\"mnw\":$D,\"rdw\":["), MAX_RD_WINDOWS);
+	for (byte wid = 0; wid < MAX_RD_WINDOWS; wid++)
+	{
+		os.get_rdwindow(wid, &win);
+		bfill.emit_p(PSTR("[$D,$D,$D]"), win.days, win.start, win.dur);
+		if (wid != MAX_RD_WINDOWS - 1)
+			bfill.emit_p(PSTR(","));
+	}
+	bfill.emit_p(PSTR("],\"rdwend\":$L}"), os.rdwindow_end(os.now_tz()));
+	handle_return(HTML_OK);
+}
+
+/**
+ * Change a scheduled rain delay window
+ * Command: /cw?pw=xxx&wid=x&days=x&st=x&dur=x
+ *
+ * pw:	password
+ * wid: window index (0 to MAX_RD_WINDOWS-1)
+ * days: weekday bits (bit 0 is Monday, 0 removes the window)
+ * st:	start time (in minutes from midnight)
+ * dur: duration (in minutes, at most one day)
+ */
+void server_change_rdwindow()
+{
+	char *p = get_buffer;
+	RainDelayWindow win;
+	memset(&win, 0, sizeof(RainDelayWindow));
+
+	if (!findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("wid"), true))
+		handle_return(HTML_DATA_MISSING);
+	int wid = atoi(tmp_buffer);
+	if (wid < 0 || wid >= MAX_RD_WINDOWS)
+		handle_return(HTML_DATA_OUTOFBOUND);
+
+	if (!findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("days"), true))
+		handle_return(HTML_DATA_MISSING);
+	int days = atoi(tmp_buffer);
+	if (days < 0 || days > 0x7F)
+		handle_return(HTML_DATA_OUTOFBOUND);
+	win.days = days;
+
+	if (win.days)
+	{
+		if (!findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("st"), true))
+			handle_return(HTML_DATA_MISSING);
+		long start = atol(tmp_buffer);
+		if (!findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("dur"), true))
+			handle_return(HTML_DATA_MISSING);
+		long dur = atol(tmp_buffer);
+		if (start < 0 || start >= 1440 || dur <= 0 || dur > 1440)
+			handle_return(HTML_DATA_OUTOFBOUND);
+		win.start = start;
+		win.dur = dur;
+	}
+	os.set_rdwindow(wid, &win);
+	handle_return(HTML_SUCCESS);
+}
+
+/**
+ * Output scheduled tasks
+ * Command: /jk?pw=xxx
+ *
+ * pw:	password
+ * Each task is output as [type, "cron expression", "argument", last run time, last result], see timer.h
+ */
+void server_json_tasks()
+{
+	TimerTask task;
+	print_json_header();
+	bfill.emit_p(PSTR("\"mnt\":$D,\"tasks\":["), MAX_NUM_TASKS);
+	for (byte tid = 0; tid < MAX_NUM_TASKS; tid++)
+	{
+		timer_get_task(tid, &task);
+		bfill.emit_p(PSTR("[$D,\"$S\",\"$S\",$L,$D]"), task.type, task.cron, task.arg, task_lastrun[tid], task_result[tid]);
+		if (tid != MAX_NUM_TASKS - 1)
+			bfill.emit_p(PSTR(","));
+	}
+	bfill.emit_p(PSTR("]}"));
+	handle_return(HTML_OK);
+}
+
+/**
+ * Change a scheduled task
+ * Command: /ck?pw=xxx&tid=x&type=x&cron=xxx&arg=xxx
+ *
+ * pw:	password
+ * tid: task index (0 to MAX_NUM_TASKS-1)
+ * type: task type (0 removes the task, see timer.h)
+ * cron: cron expression (minute hour day-of-month month day-of-week), or @hourly, @daily, @weekly, @monthly
+ * arg: days of logs to keep (log rotation) or url (http request)
+ */
+void server_change_task()
+{
+	char *p = get_buffer;
+	TimerTask task;
+	memset(&task, 0, sizeof(TimerTask));
+
+	if (!findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("tid"), true))
+		handle_return(HTML_DATA_MISSING);
+	int tid = atoi(tmp_buffer);
+	if (tid < 0 || tid >= MAX_NUM_TASKS)
+		handle_return(HTML_DATA_OUTOFBOUND);
+
+	if (!findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("type"), true))
+		handle_return(HTML_DATA_MISSING);
+	int type = atoi(tmp_buffer);
+	if (type < 0 || type >= NUM_TASK_TYPES)
+		handle_return(HTML_DATA_OUTOFBOUND);
+	task.type = type;
+
+	if (task.type != TASK_NONE)
+	{
+		if (!findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("cron"), true))
+			handle_return(HTML_DATA_MISSING);
+		urlDecode(tmp_buffer);
+		if (strlen(tmp_buffer) >= TASK_CRON_SIZE)
+			handle_return(HTML_DATA_OUTOFBOUND);
+		if (cron_match(tmp_buffer, 0) < 0)
+			handle_return(HTML_DATA_FORMATERROR);
+		strcpy(task.cron, tmp_buffer);
+
+		tmp_buffer[0] = 0;
+		findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("arg"), true);
+		urlDecode(tmp_buffer);
+		if (strlen(tmp_buffer) >= TASK_ARG_SIZE)
+			handle_return(HTML_DATA_OUTOFBOUND);
+		// the argument is output inside a json string
+		if (strchr(tmp_buffer, '"') || strchr(tmp_buffer, '\\'))
+			handle_return(HTML_DATA_FORMATERROR);
+		if (task.type == TASK_ROTATE_LOGS && atol(tmp_buffer) <= 0)
+			handle_return(HTML_DATA_OUTOFBOUND);
+		if (task.type == TASK_HTTP_GET && !tmp_buffer[0])
+			handle_return(HTML_DATA_MISSING);
+		strcpy(task.arg, tmp_buffer);
+	}
+	timer_set_task(tid, &task);
+	handle_return(HTML_SUCCESS);
+}
+
+/**
+ * Output weather call diagnostics
+ * Command: /jh?pw=xxx
+ *
+ * pw:	password
+ * nfail: number of consecutive failed weather calls
+ * wtmsg: description of the error code of the last call (wterr)
+ * Recent weather calls are output as [time, error code, water level], oldest first,
+ * with logging enabled all calls and their raw data are kept in the weather log (/jy)
+ */
+void server_json_weather()
+{
+	print_json_header();
+	bfill.emit_p(PSTR("\"uwt\":$D,\"wl\":$D,\"lwc\":$L,\"lswc\":$L,\"wterr\":$D,\"wtmsg\":\"$S\",\"nfail\":$D,\"wtdata\":$S,\"hist\":["),
+				 os.iopts[IOPT_USE_WEATHER],
+				 os.iopts[IOPT_WATER_PERCENTAGE],
+				 os.checkwt_lasttime,
+				 os.checkwt_success_lasttime,
+				 wt_errCode,
+				 request_error_message(wt_errCode),
+				 wt_nfails,
+				 strlen(wt_rawData) == 0 ? "{}" : wt_rawData);
+	ulong i = (wt_nhistory > WEATHER_HISTORY_SIZE) ? (wt_nhistory - WEATHER_HISTORY_SIZE) : 0;
+	for (; i < wt_nhistory; i++)
+	{
+		WeatherResult *r = &wt_history[i % WEATHER_HISTORY_SIZE];
+		bfill.emit_p(PSTR("[$L,$D,$D]"), r->time, r->errCode, r->wl);
+		if (i != wt_nhistory - 1)
+			bfill.emit_p(PSTR(","));
+	}
+	bfill.emit_p(PSTR("]}"));
+	handle_return(HTML_OK);
+}
+
+/**
+ * Output station moisture budgets
+ * Command: /jb?pw=xxx
+ *
+ * pw:	password
+ * day, eto, rain: day (since epoch) whose ET was accounted last, with its ET and rainfall
+ * Each station with a budget is output as [sid, rate, allowed depletion, deficit, water time],
+ * where the water time is what a station programmed for one hour would get now (0: skipped).
+ * Depths are in 1/100 inch, the rate is per hour.
+ */
+void server_json_budget()
+{
+	BudgetState st;
+	budget_get_state(&st);
+	print_json_header();
+	bfill.emit_p(PSTR("\"day\":$L,\"eto\":$D,\"rain\":$D,\"stations\":["), st.day, st.eto, st.rain);
+	byte comma = 0;
+	StationBudget b;
+	for (byte sid = 0; sid < os.nstations; sid++)
+	{
+		budget_get(sid, &b);
+		if (!b.rate)
+			continue;
+		ulong water_time = 3600;
+		budget_water_time(sid, &water_time);
+		bfill.emit_p(comma ? PSTR(",[$D,$D,$D,$D,$L]") : PSTR("[$D,$D,$D,$D,$L]"), sid, b.rate, b.capacity, b.deficit, water_time);
+		comma = 1;
+		if (available_ether_buffer() <= 0)
+			send_packet();
+	}
+	bfill.emit_p(PSTR("]}"));
+	handle_return(HTML_OK);
+}
+
+/**
+ * Change the moisture budget of a station
+ * Command: /cb?pw=xxx&sid=x&rate=x&cap=x&def=x
+ *
+ * pw:	password
+ * sid:	station index
+ * rate: precipitation rate in 1/100 inch per hour (0 stops using the budget)
+ * cap:	allowed depletion in 1/100 inch, runs are skipped until the deficit reaches it
+ * def:	current deficit in 1/100 inch (optional, e.g. 0 after a soaking rain)
+ * Programs that use weather adjustment water budgeted stations by their deficit,
+ * which grows with the daily ET reported by the ETo adjustment method.
+ */
+void server_change_budget()
+{
+	char *p = get_buffer;
+	if (!findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("sid"), true))
+		handle_return(HTML_DATA_MISSING);
+	int sid = atoi(tmp_buffer);
+	if (sid < 0 || sid >= os.nstations)
+		handle_return(HTML_DATA_OUTOFBOUND);
+
+	StationBudget b;
+	budget_get(sid, &b);
+	long v;
+	if (findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("rate"), true))
+	{
+		v = atol(tmp_buffer);
+		if (v < 0 || v > 65535)
+			handle_return(HTML_DATA_OUTOFBOUND);
+		b.rate = v;
+	}
+	if (findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("cap"), true))
+	{
+		v = atol(tmp_buffer);
+		if (v < 0 || v > BUDGET_MAX_DEFICIT)
+			handle_return(HTML_DATA_OUTOFBOUND);
+		b.capacity = v;
+	}
+	if (findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("def"), true))
+	{
+		v = atol(tmp_buffer);
+		if (v < 0 || v > BUDGET_MAX_DEFICIT)
+			handle_return(HTML_DATA_OUTOFBOUND);
+		b.deficit = v;
+	}
+	budget_set(sid, &b);
+	handle_return(HTML_SUCCESS);
+}
+
+/**
+ * Output station metadata
+ * Command: /jm?pw=xxx
+ *
+ * pw:	password
+ * Each station with metadata is output as [sid, notes, emitter type, rated flow, photo],
+ * the rated flow is in 1/100 of the volume unit per minute (0: unknown).
+ */
+void server_json_station_meta()
+{
+	print_json_header();
+	bfill.emit_p(PSTR("\"unit\":\"$S\",\"stations\":["), flow_unit());
+	byte comma = 0;
+	StationMeta meta;
+	for (byte sid = 0; sid < os.nstations; sid++)
+	{
+		os.get_station_meta(sid, &meta);
+		if (!meta.notes[0] && !meta.emitter[0] && !meta.photo[0] && !meta.flow)
+			continue;
+		if (comma)
+			bfill.emit_p(PSTR(","));
+		bfill.emit_p(PSTR("[$D,\"$S\",\"$S\",$D,\"$S\"]"), sid, meta.notes, meta.emitter, meta.flow, meta.photo);
+		comma = 1;
+		if (available_ether_buffer() <= 0)
+			send_packet();
+	}
+	bfill.emit_p(PSTR("]}"));
+	handle_return(HTML_OK);
+}
+
+/** Read a station metadata string, if the key is given
+ * Quotes, backslashes and control characters are replaced, so the string can be output in json.
+ * Returns false if the string does not fit.
+ */
+static bool read_station_meta_string(char *p, const char *key, char *field, byte size)
+{
+	uint8_t keyfound = 0;
+	findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, key, true, &keyfound);
+	if (!keyfound)
+		return true;
+	urlDecode(tmp_buffer);
+	if (strlen(tmp_buffer) >= size)
+		return false;
+	for (char *c = tmp_buffer; *c; c++)
+	{
+		if (*c == '"')
+			*c = '\'';
+		else if (*c == '\\')
+			*c = '/';
+		else if ((unsigned char)*c < ' ')
+			*c = ' ';
+	}
+	memset(field, 0, size);
+	strcpy(field, tmp_buffer);
+	return true;
+}
+
+/**
+ * Change station metadata
+ * Command: /cn?pw=xxx&sid=x&notes=xxx&emit=xxx&flow=x&photo=xxx
+ *
+ * pw:	 password
+ * sid:	 station index
+ * notes: freeform notes
+ * emit: emitter type, e.g. rotor, spray, drip
+ * flow: rated flow in 1/100 of the volume unit per minute (0: unknown)
+ * photo: path or url of a photo of the station
+ * Parameters that are left out keep their value, empty ones clear it.
+ * With a rated flow, runs that count less than NO_FLOW_RATED_PERCENT of it are reported as no flow.
+ */
+void server_change_station_meta()
+{
+	char *p = get_buffer;
+	if (!findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("sid"), true))
+		handle_return(HTML_DATA_MISSING);
+	int sid = atoi(tmp_buffer);
+	if (sid < 0 || sid >= os.nstations)
+		handle_return(HTML_DATA_OUTOFBOUND);
+
+	StationMeta meta;
+	os.get_station_meta(sid, &meta);
+	if (!read_station_meta_string(p, PSTR("notes"), meta.notes, STATION_NOTES_SIZE) ||
+		!read_station_meta_string(p, PSTR("emit"), meta.emitter, STATION_EMITTER_SIZE) ||
+		!read_station_meta_string(p, PSTR("photo"), meta.photo, STATION_PHOTO_SIZE))
+		handle_return(HTML_DATA_OUTOFBOUND);
+	if (findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("flow"), true))
+	{
+		long v = atol(tmp_buffer);
+		if (v < 0 || v > 65535)
+			handle_return(HTML_DATA_OUTOFBOUND);
+		meta.flow = v;
+	}
+	os.set_station_meta(sid, &meta);
+	handle_return(HTML_SUCCESS);
+}
+
+/**
+ * Output run queue
+ * Command: /jq?pw=xxx
+ *
+ * pw: password
+ * Each queue element is output as [qid, sid, pid, start time, duration, remaining time]
+ * Elements with a start time in the past are running
+ */
+void server_json_queue()
+{
+	ulong curr_time = os.now_tz();
+	print_json_header();
+	bfill.emit_p(PSTR("\"nqueue\":$D,\"queue\":["), pd.nqueue);
+	for (byte qid = 0; qid < pd.nqueue; qid++)
+	{
+		RuntimeQueueStruct *q = pd.queue + qid;
+		bfill.emit_p(PSTR("[$D,$D,$D,$L,$L,$L]"), qid, q->sid, q->pid, q->st, q->dur, queue_remaining(q, curr_time));
+		if (qid != pd.nqueue - 1)
+			bfill.emit_p(PSTR(","));
+		if (available_ether_buffer() <= 0)
+			send_packet();
+	}
+	bfill.emit_p(PSTR("]}"));
+	handle_return(HTML_OK);
+}
+
+/** Find the queue element given by the qid parameter, returns NULL if missing or invalid */
+static RuntimeQueueStruct *find_queue_param(char *p, byte *ret)
+{
+	if (!findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("qid"), true))
+	{
+		*ret = HTML_DATA_MISSING;
+		return NULL;
+	}
+	int qid = atoi(tmp_buffer);
+	if (qid < 0 || qid >= pd.nqueue)
+	{
+		*ret = HTML_DATA_OUTOFBOUND;
+		return NULL;
+	}
+	return pd.queue + qid;
+}
+
+/**
+ * Cancel a queue element
+ * Command: /dq?pw=xxx&qid=x
+ *
+ * pw:	password
+ * qid: queue element index (see /jq)
+ * A running station is turned off, later sequential stations move up
+ */
+void server_delete_queue()
+{
+	byte ret = HTML_SUCCESS;
+	RuntimeQueueStruct *q = find_queue_param(get_buffer, &ret);
+	if (!q)
+		handle_return(ret);
+
+	ulong curr_time = os.now_tz();
+	byte qid = q - pd.queue;
+	if (pd.station_qid[q->sid] == qid)
+	{
+		turn_off_station(q->sid, curr_time);
+	}
+	else
+	{
+		pd.dequeue(qid);
+	}
+	reschedule_waiting_stations(curr_time, 0xFF);
+	handle_return(HTML_SUCCESS);
+}
+
+/**
+ * Move a waiting sequential station to the front
+ * Command: /uq?pw=xxx&qid=x
+ *
+ * pw:	password
+ * qid: queue element index (see /jq)
+ */
+void server_elevate_queue()
+{
+	byte ret = HTML_SUCCESS;
+	RuntimeQueueStruct *q = find_queue_param(get_buffer, &ret);
+	if (!q)
+		handle_return(ret);
+
+	ulong curr_time = os.now_tz();
+	byte sid = q->sid;
+	// only sequential stations that have not started can be moved
+	if (q->st <= curr_time || !(os.attrib_seq[sid >> 3] & (1 << (sid & 0x07))) || os.iopts[IOPT_REMOTE_EXT_MODE])
+		handle_return(HTML_NOT_PERMITTED);
+
+	reschedule_waiting_stations(curr_time, q - pd.queue);
+	handle_return(HTML_SUCCESS);
+}
+
+/** Parse the day range of a log request (hist, or start and end)
+ * Returns HTML_SUCCESS, or the error code of an invalid range
+ */
+static byte parse_log_days(char *p, unsigned int &start, unsigned int &end)
+{
+	// past n day history
+	if (findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("hist"), true))
+	{
+		int hist = atoi(tmp_buffer);
+		if (hist < 0 || hist > 365)
+			return HTML_DATA_OUTOFBOUND;
+		end = os.now_tz() / 86400L;
+		start = end - hist;
+	}
+	else
+	{
+		if (!findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("start"), true))
+			return HTML_DATA_MISSING;
+
+		start = strtoul(tmp_buffer, NULL, 0) / 86400L;
+
+		if (!findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("end"), true))
+			return HTML_DATA_MISSING;
+
+		end = strtoul(tmp_buffer, NULL, 0) / 86400L;
+
+		// start must be prior to end, and can't retrieve more than 365 days of data
+		if ((start > end) || (end - start) > 365)
+			return HTML_DATA_OUTOFBOUND;
+	}
+	return HTML_SUCCESS;
+}
+
+/**
+ * Get log data
+ * Command: /jl?start=x&end=x&hist=x&type=x
+ *
+ * hist:	history (past n days)
+ *				when hist is speceified, the start
+ *				and end parameters below will be ignored
+ * start: start time (epoch time)
+ * end:		end time (epoch time)
+ * type:	type of log records (optional)
+ *				rs, rd, wl, ww, nf
+ *				if unspecified, output all records
+ */
+void server_json_log()
+{
+	char *p = get_buffer;
+
+	unsigned int start, end;
+	byte ret = parse_log_days(p, start, end);
+	if (ret != HTML_SUCCESS)
+		handle_return(ret);
+
+	// extract the type parameter
+	char type[4] = {0};
+	bool type_specified = false;
+	if (findKeyVal(p, type, 4, PSTR("type"), true))
+		type_specified = true;
+
+	print_json_header(false);
+
+	bfill.emit_p(PSTR("["));
+
+	bool comma = 0;
+	for (unsigned int i = start; i <= end; i++)
+	{
+		itoa(i, tmp_buffer, 10);
+		make_logfile_name(tmp_buffer);
+
+		// prepare to open log file for RPI
+		FILE *file = fopen(get_filename_fullpath(tmp_buffer), "rb");
+		if (!file)
+			continue;
+
+		int res;
+		while (true)
+		{
+			if (fgets(tmp_buffer, TMP_BUFFER_SIZE, file))
+			{
+				res = strlen(tmp_buffer);
+			}
+			else
+			{
+				res = 0;
+			}
+			if (res <= 0)
+			{
+				fclose(file);
+				break;
+			}
+			// check record type
+			// records are all in the form of [x,"xx",...]
+			// where x is program index (>0) if this is a station record
+			// and "xx" is the type name if this is a special record (e.g. wl, fl, rs)
+
+			// search string until we find the first comma
+			char *ptype = tmp_buffer;
+			tmp_buffer[TMP_BUFFER_SIZE - 1] = 0; // make sure the search will end
+			while (*ptype && *ptype != ',')
+				ptype++;
+			if (*ptype != ',')
+				continue; // didn't find comma, move on
+			ptype++;	  // move past comma
+
+			if (type_specified && strncmp(type, ptype + 1, 2))
+				continue;
+			// if type is not specified, output everything except "wl", "fl" and "ww" records
+			if (!type_specified && (!strncmp("wl", ptype + 1, 2) || !strncmp("fl", ptype + 1, 2) || !strncmp("ww", ptype + 1, 2)))
+				continue;
+			// if this is the first record, do not print comma
+			if (comma)
+				bfill.emit_p(PSTR(","));
+			else
+			{
+				comma = 1;
+			}
+			bfill.emit_p(PSTR("$S"), tmp_buffer);
+			// if the available ether buffer size is getting small
+			// push out a packet
+			if (available_ether_buffer() <= 0)
+			{
+				send_packet();
+			}
+		}
+	}
+
+	bfill.emit_p(PSTR("]"));
+	handle_return(HTML_OK);
+}
+
+/**
+ * Get the run history of a station
+ * Command: /ji?pw=xxx&sid=x&days=x&skip=x&n=x
+ *
+ * sid:		station index (starting from 0)
+ * days:	history (past n days, default 7),
+ *				hist or start and end as in /jl are also accepted
+ * skip:	number of runs to skip, for paging (optional)
+ * n:		maximum number of runs to return (optional, default 50)
+ * Runs are listed oldest first in the form of [start,duration,program,volume],
+ * where volume is null unless a flow sensor measured the run.
+ * total is the number of runs in the whole day range.
+ */
+void server_json_station_history()
+{
+	char *p = get_buffer;
+
+	if (!findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("sid"), true))
+		handle_return(HTML_DATA_MISSING);
+	int sid = atoi(tmp_buffer);
+	if (sid < 0 || sid >= os.nstations)
+		handle_return(HTML_DATA_OUTOFBOUND);
+
+	unsigned int start, end;
+	if (findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("days"), true))
+	{
+		int days = atoi(tmp_buffer);
+		if (days < 0 || days > 365)
+			handle_return(HTML_DATA_OUTOFBOUND);
+		end = os.now_tz() / 86400L;
+		start = end - days;
+	}
+	else if (findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("hist"), true) || findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("start"), true))
+	{
+		byte ret = parse_log_days(p, start, end);
+		if (ret != HTML_SUCCESS)
+			handle_return(ret);
+	}
+	else
+	{
+		end = os.now_tz() / 86400L;
+		start = end - 7;
+	}
+
+	ulong skip = 0, n = 50;
+	if (findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("skip"), true))
+		skip = strtoul(tmp_buffer, NULL, 0);
+	if (findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("n"), true))
+		n = strtoul(tmp_buffer, NULL, 0);
+
+	print_json_header();
+	bfill.emit_p(PSTR("\"sid\":$D,\"unit\":\"$S\",\"runs\":["), sid, flow_unit());
+
+	ulong total = 0;
+	char volume[16];
+	for (unsigned int i = start; i <= end; i++)
+	{
+		itoa(i, tmp_buffer, 10);
+		make_logfile_name(tmp_buffer);
+		FILE *file = fopen(get_filename_fullpath(tmp_buffer), "rb");
+		if (!file)
+			continue;
+
+		while (fgets(tmp_buffer, TMP_BUFFER_SIZE, file))
+		{
+			// station records are in the form of [pid,sid,duration,end,"program",run] or [pid,sid,duration,end,flow rate,"program",run],
+			// older ones have no program name and run id
+			// other records have a type name as the second field, so they do not match
+			int rpid, rsid;
+			ulong dur, rend;
+			float rate;
+			int fields = sscanf(tmp_buffer, "[%d,%d,%lu,%lu,%f", &rpid, &rsid, &dur, &rend, &rate);
+			if (fields < 4 || rsid != sid)
+				continue;
+			total++;
+			if (total <= skip || total > skip + n)
+				continue;
+
+			if (fields == 5)
+				snprintf(volume, sizeof(volume), "%.2f", rate * dur / 60);
+			else
+				strcpy(volume, "null");
+			bfill.emit_p(PSTR("$S[$L,$L,$D,$S]"), (total == skip + 1) ? "" : ",", rend - dur, dur, rpid, volume);
+			if (available_ether_buffer() <= 0)
+			{
+				send_packet();
+			}
+		}
+		fclose(file);
+	}
+
+	bfill.emit_p(PSTR("],\"total\":$L}"), total);
+	handle_return(HTML_OK);
+}
+
+/**
+ * Get audit log
+ * Command: /jt?pw=xxx&start=x&end=x&hist=x
+ *
+ * pw:		password
+ * hist:	history (past n days)
+ * start: start time (epoch time)
+ * end:		end time (epoch time)
+ * Records are in the form of [time,"client","command","parameters",result]
+ * Guests cannot read it, as it shows the addresses of the clients.
+ */
+void server_json_audit()
+{
+	if (guest_request)
+		handle_return_item(HTML_NOT_PERMITTED, "guest", -1);
+
+	char *p = get_buffer;
+
+	unsigned int start, end;
+	byte ret = parse_log_days(p, start, end);
+	if (ret != HTML_SUCCESS)
+		handle_return(ret);
+
+	print_json_header(false);
+	bfill.emit_p(PSTR("["));
+
+	bool comma = 0;
+	char line[TMP_BUFFER_SIZE * 2];
+	for (unsigned int i = start; i <= end; i++)
+	{
+		itoa(i, tmp_buffer, 10);
+		make_auditfile_name(tmp_buffer);
+		FILE *file = fopen(get_filename_fullpath(tmp_buffer), "rb");
+		if (!file)
+			continue;
+
+		while (fgets(line, sizeof(line), file))
+		{
+			// strip line ending
+			line[strcspn(line, "\r\n")] = 0;
+			if (line[0] != '[')
+				continue;
+			if (comma)
+				bfill.emit_p(PSTR(","));
+			comma = 1;
+			bfill.emit_p(PSTR("$S"), line);
+			if (available_ether_buffer() <= (int)sizeof(line))
+				send_packet();
+		}
+		fclose(file);
+	}
+
+	bfill.emit_p(PSTR("]"));
+	handle_return(HTML_OK);
 }
 
 /**
- * Get log data
- * Command: /jl?start=x&end=x&hist=x&type=x
+ * Get weather log
+ * Command: /jy?pw=xxx&start=x&end=x&hist=x
  *
+ * pw:		password
  * hist:	history (past n days)
- *				when hist is speceified, the start
- *				and end parameters below will be ignored
  * start: start time (epoch time)
  * end:		end time (epoch time)
- * type:	type of log records (optional)
- *				rs, rd, wl
- *				if unspecified, output all records
+ * Records are in the form of [time,error code,water level,raw data]
  */
-void server_json_log()
+void server_json_weather_log()
 {
 	char *p = get_buffer;
 
 	unsigned int start, end;
-
-	// past n day history
-	if (findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("hist"), true))
-	{
-		int hist = atoi(tmp_buffer);
-		if (hist < 0 || hist > 365)
-			handle_return(HTML_DATA_OUTOFBOUND);
-		end = os.now_tz() / 86400L;
-		start = end - hist;
-	}
-	else
-	{
-		if (!findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("start"), true))
-			handle_return(HTML_DATA_MISSING);
-
-		start = strtoul(tmp_buffer, NULL, 0) / 86400L;
-
-		if (!findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("end"), true))
-			handle_return(HTML_DATA_MISSING);
-
-		end = strtoul(tmp_buffer, NULL, 0) / 86400L;
-
-		// start must be prior to end, and can't retrieve more than 365 days of data
-		if ((start > end) || (end - start) > 365)
-			handle_return(HTML_DATA_OUTOFBOUND);
-	}
-
-	// extract the type parameter
-	char type[4] = {0};
-	bool type_specified = false;
-	if (findKeyVal(p, type, 4, PSTR("type"), true))
-		type_specified = true;
+	byte ret = parse_log_days(p, start, end);
+	if (ret != HTML_SUCCESS)
+		handle_return(ret);
 
 	print_json_header(false);
-
 	bfill.emit_p(PSTR("["));
 
 	bool comma = 0;
+	char line[TMP_BUFFER_SIZE * 2];
 	for (unsigned int i = start; i <= end; i++)
 	{
 		itoa(i, tmp_buffer, 10);
-		make_logfile_name(tmp_buffer);
-
-		// prepare to open log file for RPI
+		make_weatherlog_name(tmp_buffer);
 		FILE *file = fopen(get_filename_fullpath(tmp_buffer), "rb");
 		if (!file)
 			continue;
 
-		int res;
-		while (true)
+		while (fgets(line, sizeof(line), file))
 		{
-			if (fgets(tmp_buffer, TMP_BUFFER_SIZE, file))
-			{
-				res = strlen(tmp_buffer);
-			}
-			else
-			{
-				res = 0;
-			}
-			if (res <= 0)
-			{
-				fclose(file);
-				break;
-			}
-			// check record type
-			// records are all in the form of [x,"xx",...]
-			// where x is program index (>0) if this is a station record
-			// and "xx" is the type name if this is a special record (e.g. wl, fl, rs)
-
-			// search string until we find the first comma
-			char *ptype = tmp_buffer;
-			tmp_buffer[TMP_BUFFER_SIZE - 1] = 0; // make sure the search will end
-			while (*ptype && *ptype != ',')
-				ptype++;
-			if (*ptype != ',')
-				continue; // didn't find comma, move on
-			ptype++;	  // move past comma
-
-			if (type_specified && strncmp(type, ptype + 1, 2))
+			// strip line ending
+			line[strcspn(line, "\r\n")] = 0;
+			if (line[0] != '[')
 				continue;
-			// if type is not specified, output everything except "wl" and "fl" records
-			if (!type_specified && (!strncmp("wl", ptype + 1, 2) || !strncmp("fl", ptype + 1, 2)))
-				continue;
-			// if this is the first record, do not print comma
 			if (comma)
 				bfill.emit_p(PSTR(","));
-			else
-			{
-				comma = 1;
-			}
-			bfill.emit_p(PSTR("$S"), tmp_buffer);
-			// if the available ether buffer size is getting small
-			// push out a packet
-			if (available_ether_buffer() <= 0)
-			{
+			comma = 1;
+			bfill.emit_p(PSTR("$S"), line);
+			if (available_ether_buffer() <= (int)sizeof(line))
 				send_packet();
-			}
 		}
+		fclose(file);
 	}
 
 	bfill.emit_p(PSTR("]"));
 	handle_return(HTML_OK);
 }
+
+/**
+ * Output the debug log
+ * Command: /jx?pw=xxx&n=x
+ *
+ * pw:	password
+ * n:	number of recent lines (default: all that are kept)
+ * Lines are in the form of [time,module,level,text], oldest first
+ */
+void server_json_debug_log()
+{
+	// debug output may show settings, such as the MQTT user
+	if (guest_request)
+		handle_return_item(HTML_NOT_PERMITTED, "guest", -1);
+
+	char *p = get_buffer;
+	int max = DEBUG_LOG_LINES;
+	if (findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("n"), true))
+	{
+		max = atoi(tmp_buffer);
+		if (max < 0)
+			handle_return_item(HTML_DATA_OUTOFBOUND, "n", -1);
+		if (max > DEBUG_LOG_LINES)
+			max = DEBUG_LOG_LINES;
+	}
+	static DebugLine lines[DEBUG_LOG_LINES];
+	byte n = debug_get_lines(lines, max);
+
+	print_json_header();
+	bfill.emit_p(PSTR("\"levels\":{"));
+	for (byte mid = 0; mid < debug_num_modules(); mid++)
+		bfill.emit_p(PSTR("$S\"$S\":$D"), mid ? "," : "", debug_module_name(mid), debug_get_level(mid));
+	bfill.emit_p(PSTR("},\"log\":["));
+	for (byte i = 0; i < n; i++)
+	{
+		bfill.emit_p(PSTR("$S[$L,\"$S\",$D,\"$S\"]"), i ? "," : "", lines[i].time, debug_module_name(lines[i].module),
+					 lines[i].level, lines[i].text);
+		if (available_ether_buffer() <= DEBUG_LINE_SIZE * 2)
+			send_packet();
+	}
+	bfill.emit_p(PSTR("]}"));
+	handle_return(HTML_OK);
+}
+
+/**
+ * Change debug output levels
+ * Command: /cx?pw=xxx&m=x&lv=x
+ *
+ * pw:	password
+ * m:	module, as listed by /jx (default: all modules)
+ * lv:	level (0: off, 1: messages, 2: detailed tracing)
+ * Levels are kept until the controller restarts.
+ */
+void server_change_debug_levels()
+{
+	char *p = get_buffer;
+	if (!findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("lv"), true))
+		handle_return_item(HTML_DATA_MISSING, "lv", -1);
+	int level = atoi(tmp_buffer);
+	if (level < DEBUG_LEVEL_OFF || level > DEBUG_LEVEL_TRACE)
+		handle_return_item(HTML_DATA_OUTOFBOUND, "lv", -1);
+
+	if (findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("m"), true))
+	{
+		int mid = debug_find_module(tmp_buffer);
+		if (mid < 0)
+			handle_return_item(HTML_DATA_OUTOFBOUND, "m", -1);
+		debug_set_level(mid, level);
+	}
+	else
+	{
+		for (byte mid = 0; mid < debug_num_modules(); mid++)
+			debug_set_level(mid, level);
+	}
+	handle_return(HTML_SUCCESS);
+}
+
 /**
  * Delete log
  * Command: /dl?pw=xxx&day=xxx
@@ -1546,6 +4113,39 @@ void server_json_all()
 	handle_return(HTML_OK);
 }
 
+#if !defined(OSPI)
+/**
+ * Simulated hardware
+ * Command: /sm?pw=xxx&sn1=x&sn2=x
+ *
+ * pw: password
+ * sn1:activate (1) or deactivate (0) simulated sensor 1 (optional)
+ * sn2:activate (1) or deactivate (0) simulated sensor 2 (optional)
+ * Outputs the simulated sensor and valve states
+ */
+void server_simulation()
+{
+	char *p = get_buffer;
+
+	if (findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("sn1"), true))
+		sim_set_sensor(0, atoi(tmp_buffer));
+	if (findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("sn2"), true))
+		sim_set_sensor(1, atoi(tmp_buffer));
+
+	print_json_header();
+	bfill.emit_p(PSTR("\"sn1\":$D,\"sn2\":$D,\"fpr\":$D,\"valves\":["),
+				 sim_get_sensor(0), sim_get_sensor(1), sim_count_open_valves() * SIM_FLOW_PULSE_RATE);
+	for (byte bid = 0; bid < os.nboards; bid++)
+	{
+		bfill.emit_p(PSTR("$D"), sim_get_valves(bid));
+		if (bid != os.nboards - 1)
+			bfill.emit_p(PSTR(","));
+	}
+	bfill.emit_p(PSTR("]}"));
+	handle_return(HTML_OK);
+}
+#endif
+
 typedef void (*URLHandler)(void);
 
 /* Server function urls
@@ -1575,7 +4175,41 @@ const char _url_keys[] PROGMEM =
 	"dl"
 	"su"
 	"cu"
-	"ja";
+	"ja"
+	"rl"
+	"jt"
+	"jq"
+	"dq"
+	"uq"
+	"cg"
+	"ag"
+	"vs"
+	"jr"
+	"jw"
+	"cw"
+	"jh"
+	"zt"
+	"jg"
+	"jd"
+	"ui"
+	"jk"
+	"ck"
+	"ji"
+	"so"
+	"jb"
+	"cb"
+	"jy"
+	"jf"
+	"ip"
+	"sw"
+	"jx"
+	"cx"
+	"jm"
+	"cn"
+#if !defined(OSPI)
+	"sm"
+#endif
+	;
 
 // Server function handlers
 URLHandler urls[] = {
@@ -1600,12 +4234,273 @@ URLHandler urls[] = {
 	server_view_scripturl,		 // su
 	server_change_scripturl,	 // cu
 	server_json_all,			 // ja
+	server_reload_config,		 // rl
+	server_json_audit,			 // jt
+	server_json_queue,			 // jq
+	server_delete_queue,		 // dq
+	server_elevate_queue,		 // uq
+	server_change_group,		 // cg
+	server_apply_group,			 // ag
+	server_virtual_switch,		 // vs
+	server_json_realtime,		 // jr
+	server_json_rdwindows,		 // jw
+	server_change_rdwindow,		 // cw
+	server_json_weather,		 // jh
+	server_zone_test,			 // zt
+	server_json_gpio,			 // jg
+	server_json_diagnostics,	 // jd
+	server_webui,				 // ui
+	server_json_tasks,			 // jk
+	server_change_task,			 // ck
+	server_json_station_history, // ji
+	server_sensor_override,		 // so
+	server_json_budget,			 // jb
+	server_change_budget,		 // cb
+	server_json_weather_log,	 // jy
+	server_json_find_stations,	 // jf
+	server_import_programs,		 // ip
+	server_setup,				 // sw
+	server_json_debug_log,		 // jx
+	server_change_debug_levels,	 // cx
+	server_json_station_meta,	 // jm
+	server_change_station_meta,	 // cn
+#if !defined(OSPI)
+	server_simulation, // sm
+#endif
 };
 
+/** Check if a command changes the controller state and should be audited */
+static bool audit_command(const char *cmd)
+{
+	if (cmd[0] == 'j')
+		return false; // json outputs
+	if ((cmd[0] == 's' && cmd[1] == 'u') || (cmd[0] == 'd' && cmd[1] == 'b') || (cmd[0] == 'u' && cmd[1] == 'i'))
+		return false;
+	return true;
+}
+
+/** Copy request parameters for the audit log, leaving out passwords */
+static void audit_copy_params(const char *dat, char *buf, int maxlen)
+{
+	int len = 0;
+	buf[0] = 0;
+	while (*dat && *dat != ' ' && *dat != '\r' && *dat != '\n')
+	{
+		int n = strcspn(dat, "& \r\n");
+		bool secret = (strncmp(dat, "pw=", 3) == 0 || strncmp(dat, "npw=", 4) == 0 || strncmp(dat, "cpw=", 4) == 0 ||
+					   strncmp(dat, "rkey=", 5) == 0 || strncmp(dat, "gkey=", 5) == 0);
+		if (!secret && n > 0 && len + n + 1 < maxlen)
+		{
+			if (len)
+				buf[len++] = '&';
+			strncpy(buf + len, dat, n);
+			len += n;
+			buf[len] = 0;
+		}
+		dat += n;
+		if (*dat == '&')
+			dat++;
+	}
+}
+
+/** Get the address of the current web client */
+static void get_client_address(char *buf, int maxlen)
+{
+	struct sockaddr_in6 addr;
+	socklen_t len = sizeof(addr);
+	strcpy(buf, "?");
+	if (!m_client || getpeername(m_client->GetSocket(), (struct sockaddr *)&addr, &len) < 0)
+		return;
+	if (addr.sin6_family == AF_INET6)
+	{
+		inet_ntop(AF_INET6, &addr.sin6_addr, buf, maxlen);
+		// show ipv4 clients without the mapping prefix
+		if (strncmp(buf, "::ffff:", 7) == 0 && strchr(buf, '.'))
+			memmove(buf, buf + 7, strlen(buf + 7) + 1);
+	}
+	else if (addr.sin6_family == AF_INET)
+	{
+		inet_ntop(AF_INET, &((struct sockaddr_in *)&addr)->sin_addr, buf, maxlen);
+	}
+}
+
+/** Copy the value of a request header into buf
+ * Returns false if the request does not have the header
+ */
+static bool find_request_header(const char *req, const char *name, char *buf, size_t len)
+{
+	const char *end = strstr(req, "\r\n\r\n");
+	size_t nlen = strlen(name);
+	for (const char *h = strstr(req, "\r\n"); h && (!end || h < end); h = strstr(h + 2, "\r\n"))
+	{
+		if (strncasecmp(h + 2, name, nlen) || h[2 + nlen] != ':')
+			continue;
+		const char *v = h + 3 + nlen;
+		while (*v == ' ')
+			v++;
+		size_t i = 0;
+		while (v[i] && v[i] != '\r' && v[i] != '\n' && i < len - 1)
+		{
+			buf[i] = v[i];
+			i++;
+		}
+		buf[i] = 0;
+		return true;
+	}
+	return false;
+}
+
+// origin of a web request, see check_request_origin
+#define ORIGIN_ALLOWED 0 // the controller itself, or an origin allowed by the cors option
+#define ORIGIN_NONE 1	 // neither Origin nor Referer was sent
+#define ORIGIN_OTHER 2	 // another site, which may read the outputs as the cors option is empty
+#define ORIGIN_DENIED 3	 // another site not in the cors option
+
+/** Check the origin of a web request and prepare the CORS headers of the response
+ * Browsers send the origin of cross-site requests, older ones only the referer.
+ * The cors option is a comma separated list of the origins allowed besides the controller itself,
+ * * allows all origins. When it is empty (the default), all sites may read the outputs as with
+ * earlier firmwares, but their commands need the password, see dispatch_web_request.
+ */
+static byte check_request_origin(const char *req)
+{
+	char origin[MAX_SOPTS_SIZE + 1];
+	char host[MAX_SOPTS_SIZE + 1];
+	char list[MAX_SOPTS_SIZE + 1];
+
+	list[0] = 0;
+	os.sopt_load(SOPT_CORS_ORIGINS, list);
+	if (!list[0] || !strcmp(list, "*"))
+		strcpy(htmlAccessControl, "Access-Control-Allow-Origin: *\r\n");
+	else
+		htmlAccessControl[0] = 0;
+	if (!strcmp(list, "*"))
+		return ORIGIN_ALLOWED;
+
+	if (!find_request_header(req, "Origin", origin, sizeof(origin)))
+	{
+		if (!find_request_header(req, "Referer", origin, sizeof(origin)))
+			return ORIGIN_NONE;
+		// keep the scheme and host of the referer
+		char *s = strstr(origin, "://");
+		if (s && (s = strchr(s + 3, '/')))
+			*s = 0;
+	}
+
+	// same origin
+	char *o = strstr(origin, "://");
+	if (o && find_request_header(req, "Host", host, sizeof(host)) && !strcmp(o + 3, host))
+		return ORIGIN_ALLOWED;
+
+	if (!list[0])
+		return ORIGIN_OTHER;
+	for (char *t = strtok(list, ", "); t; t = strtok(NULL, ", "))
+	{
+		if (!strcmp(t, origin))
+		{
+			snprintf(htmlAccessControl, sizeof(htmlAccessControl), "Access-Control-Allow-Origin: %s\r\nVary: Origin\r\n", origin);
+			return ORIGIN_ALLOWED;
+		}
+	}
+	return ORIGIN_DENIED;
+}
+
+/** ETag of a command output that can be cached until it changes, empty if it cannot be cached
+ * Configuration outputs change with config_version, which restarts with the controller.
+ * /jp also changes with the day, as the days left of interval programs count down at midnight.
+ * /je is not cached, as it also reports the status of the special stations.
+ * The built-in web UI changes with the firmware and the language, unless it is served from the override directory.
+ */
+static void command_etag(const char *cmd, char *etag, size_t len)
+{
+	etag[0] = 0;
+	if (cmd[0] == 'j' && cmd[1] == 'n')
+		snprintf(etag, len, "\"%lx-%lx\"", os.powerup_lasttime, config_version);
+	else if (cmd[0] == 'j' && cmd[1] == 'p')
+		snprintf(etag, len, "\"%lx-%lx-%lx\"", os.powerup_lasttime, config_version, os.now_tz() / 86400L);
+	else if (cmd[0] == 'u' && cmd[1] == 'i' && !webui_override())
+		snprintf(etag, len, "\"fw%d.%d-%d\"", OS_FW_VERSION, OS_FW_MINOR, os.iopts[IOPT_LANGUAGE]);
+}
+
+/** Principal of a request that passed the password check */
+static const char *password_principal()
+{
+	return bearer_request ? "token" : "admin";
+}
+
+/** Print one line for each handled request: handler, principal, duration and result
+ * Failed or slow requests are printed as messages, the others only when tracing.
+ */
+static void trace_request(const char *handler, ulong start)
+{
+#if defined(ENABLE_DEBUG)
+	ulong duration = millis() - start;
+	bool failed = (request_result != HTML_OK && request_result != HTML_SUCCESS);
+	debug_printf(__FILE__, (failed || duration >= SLOW_REQUEST_MS) ? DEBUG_LEVEL_INFO : DEBUG_LEVEL_TRACE,
+				 "request /%s by %s: %s in %lu ms\n", handler, request_principal, result_message(request_result), duration);
+#endif
+}
+
+static void dispatch_web_request(char *p);
+
 void handle_web_request(char *p)
 {
+	ulong start = millis();
+	char handler[3] = {0};
+	if (p[5] != ' ')
+		strncpy(handler, p + 5, 2);
+	debug_set_request(++request_id);
+	request_principal = "none";
+	request_result = HTML_OK;
+	dispatch_web_request(p);
+	trace_request(handler, start);
+	debug_set_request(0);
+}
+
+static void dispatch_web_request(char *p)
+{
+	// the request is overwritten by the response, check its headers first
+	byte origin = check_request_origin(p);
+	bool origin_allowed = (origin != ORIGIN_DENIED);
+	bool preflight = !strncmp(p, "OPTIONS ", 8);
+	return_item = NULL;
+	guest_request = false;
+	// remote controllers may send the password as a bearer token, see switch_remotestation
+	char auth[MAX_SOPTS_SIZE + 8];
+	bearer_request = find_request_header(p, "Authorization", auth, sizeof(auth)) && !strncmp(auth, "Bearer ", 7) &&
+					 os.password_verify(auth + 7);
+	char etag[32];
+	bool not_modified = false;
+	htmlETag[0] = 0;
+	command_etag(p + 5, etag, sizeof(etag));
+	if (etag[0])
+	{
+		snprintf(htmlETag, sizeof(htmlETag), "Cache-Control: no-cache\r\nETag: %s\r\n", etag);
+		char match[sizeof(etag)];
+		// the web UI is public, other outputs need the password
+		not_modified = find_request_header(p, "If-None-Match", match, sizeof(match)) && !strcmp(match, etag) &&
+					   (p[5] == 'u' || check_password(p + 8) || check_guest(p + 8));
+	}
+	char encoding[64];
+	gzip_response = find_request_header(p, "Accept-Encoding", encoding, sizeof(encoding)) && strstr(encoding, "gzip");
+
 	rewind_ether_buffer();
 
+	if (not_modified && origin_allowed)
+	{
+		gzip_response = false;
+		bfill.emit_p(PSTR("HTTP/1.1 304 Not Modified\r\n$S$S\r\n"), htmlETag, htmlAccessControl);
+		send_packet(true);
+		return;
+	}
+
+	if (preflight)
+	{
+		bfill.emit_p(PSTR("$F$S\r\n"), htmlCorsPreflight, htmlAccessControl);
+		send_packet(true);
+		return;
+	}
+
 	// assume this is a GET request
 	// GET /xx?xxxx
 	char *com = p + 5;
@@ -1624,12 +4519,42 @@ void handle_web_request(char *p)
 		{
 			if (pgm_read_byte(_url_keys + 2 * i) == com[0] && pgm_read_byte(_url_keys + 2 * i + 1) == com[1])
 			{
+				// the request buffer is reused for output, so keep the parameters for the audit log
+				bool audit = audit_command(com);
+				char audit_params[TMP_BUFFER_SIZE];
+				char client[INET6_ADDRSTRLEN];
+				if (audit)
+				{
+					audit_copy_params(dat, audit_params, sizeof(audit_params));
+					get_client_address(client, sizeof(client));
+				}
 
 				// check password
 				int ret = HTML_UNAUTHORIZED;
 
-				if (com[0] == 's' && com[1] == 'u')
-				{ // for /su do not require password
+				if (audit && !origin_allowed)
+				{ // do not change the controller state for other sites
+					ret = HTML_NOT_PERMITTED;
+				}
+				else if (audit && origin != ORIGIN_ALLOWED && os.iopts[IOPT_IGNORE_PASSWORD] && !check_password_key(dat))
+				{ // with the password ignored, a request of unknown origin could be forged by any site
+					ret = HTML_NOT_PERMITTED;
+					return_item = "origin";
+				}
+				else if (com[0] == 's' && com[1] == 'w' && setup_pending())
+				{ // the first-boot setup needs no password
+					request_principal = "setup";
+					get_buffer = dat;
+					(urls[i])();
+					ret = return_code;
+				}
+				else if (setup_pending() && !(com[0] == 'u' && com[1] == 'i'))
+				{ // other commands wait for the setup
+					ret = HTML_NOT_PERMITTED;
+					return_item = "setup";
+				}
+				else if ((com[0] == 's' && com[1] == 'u') || (com[0] == 'u' && com[1] == 'i'))
+				{ // for /su and /ui do not require password
 					get_buffer = dat;
 					(urls[i])();
 					ret = return_code;
@@ -1638,7 +4563,15 @@ void handle_web_request(char *p)
 						 (com[0] == 'j' && com[1] == 'a'))
 				{ // for /jo and /ja we output fwv if password fails
 
-					if (check_password(dat) == false)
+					if (check_password(dat) == false && check_guest(dat))
+					{ // guests read all options
+						guest_request = true;
+						request_principal = "guest";
+						get_buffer = dat;
+						(urls[i])();
+						ret = return_code;
+					}
+					else if (check_password(dat) == false)
 					{
 						print_json_header();
 						bfill.emit_p(PSTR("\"$F\":$D}"),
@@ -1647,6 +4580,7 @@ void handle_web_request(char *p)
 					}
 					else
 					{
+						request_principal = password_principal();
 						get_buffer = dat;
 						(urls[i])();
 						ret = return_code;
@@ -1663,19 +4597,50 @@ void handle_web_request(char *p)
 					// first check password
 					if (check_password(dat) == false)
 					{
-						ret = HTML_UNAUTHORIZED;
+						if (!audit && check_guest(dat))
+						{ // guests may read the json outputs
+							guest_request = true;
+							request_principal = "guest";
+							get_buffer = dat;
+							(urls[i])();
+							ret = return_code;
+						}
+						else if (audit && check_guest_key(dat))
+						{ // but not change anything
+							request_principal = "guest";
+							ret = HTML_NOT_PERMITTED;
+							return_item = "guest";
+						}
+						else
+						{
+							ret = HTML_UNAUTHORIZED;
+						}
 					}
 					else
 					{
+						request_principal = password_principal();
 						get_buffer = dat;
 						(urls[i])();
 						ret = return_code;
 					}
 				}
+				request_result = (ret == HTML_REDIRECT_HOME) ? HTML_SUCCESS : (ret == -1) ? HTML_OK : ret;
+				if (audit)
+				{
+					write_audit_log(client, com, audit_params, ret, os.now_tz());
+					if (ret == HTML_SUCCESS || ret == HTML_REDIRECT_HOME)
+						config_version++;
+				}
 				if (ret == -1)
 				{
 					if (m_client)
 						m_client->stop();
+					if (gzip_started)
+					{
+						deflateEnd(&gzip_stream);
+						gzip_started = false;
+					}
+					gzip_response = false;
 					return;
 				}
 				switch (ret)
@@ -1687,8 +4652,9 @@ void handle_web_request(char *p)
 					bfill.emit_p(PSTR("$F"), htmlReturnHome);
 					break;
 				default:
+					htmlETag[0] = 0; // do not cache errors
 					print_json_header();
-					bfill.emit_p(PSTR("\"result\":$D}"), ret);
+					emit_result(ret);
 				}
 				break;
 			}
@@ -1697,9 +4663,70 @@ void handle_web_request(char *p)
 		if (i == sizeof(urls) / sizeof(URLHandler))
 		{
 			// no server funtion found
+			request_result = HTML_PAGE_NOT_FOUND;
 			print_json_header();
-			bfill.emit_p(PSTR("\"result\":$D}"), HTML_PAGE_NOT_FOUND);
+			emit_result(HTML_PAGE_NOT_FOUND);
 		}
 		send_packet(true);
 	}
 }
+
+/** Handle a request from the local control socket
+ * The handler output is wrapped into a JSON-RPC response,
+ * no password is checked.
+ */
+void handle_local_request(EthernetClient *client, const char *cmd, char *dat, const char *id)
+{
+	ulong start = millis();
+	debug_set_request(++request_id);
+	request_principal = "local";
+	request_result = HTML_PAGE_NOT_FOUND;
+	m_client = client;
+	local_request = true;
+	rewind_ether_buffer();
+	return_item = NULL;
+	guest_request = false;
+	bearer_request = false;
+	bfill.emit_p(PSTR("{\"jsonrpc\":\"2.0\",\"id\":$S,"), id);
+
+	byte i;
+	for (i = 0; i < sizeof(urls) / sizeof(URLHandler); i++)
+	{
+		if (strlen(cmd) == 2 && pgm_read_byte(_url_keys + 2 * i) == cmd[0] && pgm_read_byte(_url_keys + 2 * i + 1) == cmd[1])
+		{
+			bfill.emit_p(PSTR("\"result\":"));
+			char audit_params[TMP_BUFFER_SIZE];
+			bool audit = audit_command(cmd);
+			if (audit)
+				audit_copy_params(dat, audit_params, sizeof(audit_params));
+			get_buffer = dat;
+			(urls[i])();
+			byte ret = return_code;
+			request_result = (ret == HTML_REDIRECT_HOME) ? HTML_SUCCESS : ret;
+			if (audit)
+			{
+				write_audit_log("local", cmd, audit_params, ret, os.now_tz());
+				if (ret == HTML_SUCCESS || ret == HTML_REDIRECT_HOME)
+					config_version++;
+			}
+			if (ret == HTML_REDIRECT_HOME)
+				ret = HTML_SUCCESS;
+			if (ret != HTML_OK)
+			{
+				bfill.emit_p(PSTR("{"));
+				emit_result(ret);
+			}
+			bfill.emit_p(PSTR("}\n"));
+			break;
+		}
+	}
+	if (i == sizeof(urls) / sizeof(URLHandler))
+	{
+		bfill.emit_p(PSTR("\"error\":{\"code\":-32601,\"message\":\"Method not found\"}}\n"));
+	}
+	send_packet(true);
+	local_request = false;
+	m_client = 0;
+	trace_request(cmd, start);
+	debug_set_request(0);
+}
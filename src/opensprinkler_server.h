@@ -26,6 +26,8 @@
 
 #include <stdarg.h>
 
+// return error codes (HTML_*) are defined in errors.h
+
 char dec2hexchar(byte dec);
 
 class BufferFiller
@@ -82,7 +84,10 @@ public:
 			case 'O':
 			{
 				uint16_t oid = va_arg(ap, int);
+				// options added after the file was created may not be stored yet
+				ptr[0] = 0;
 				file_read_block(SOPTS_FILENAME, (char *)ptr, oid * MAX_SOPTS_SIZE, MAX_SOPTS_SIZE);
+				ptr[MAX_SOPTS_SIZE] = 0;
 			}
 			break;
 			default:
@@ -99,4 +104,13 @@ public:
 	unsigned int position() const { return ptr - start; }
 };
 
+class EthernetClient;
+
+byte findKeyVal(const char *str, char *strbuf, uint16_t maxlen, const char *key, bool key_in_pgm = false, uint8_t *keyfound = NULL);
+byte apply_group_command(byte gid, char *p);
+byte trigger_virtual_switch(byte sw);
+byte rerun_last_runonce();
+void emit_station_bits(BufferFiller &bf, bool pad);
+void handle_local_request(EthernetClient *client, const char *cmd, char *dat, const char *id);
+
 #endif // _OPENSPRINKLER_SERVER_H
@@ -23,6 +23,7 @@
 
 #include <limits.h>
 #include "program.h"
+#include "main.h"
 
 #if !defined(SECS_PER_DAY)
 #define SECS_PER_MIN (60UL)
@@ -33,16 +34,21 @@
 // Declare static data members
 byte ProgramData::nprograms = 0;
 byte ProgramData::nqueue = 0;
-RuntimeQueueStruct ProgramData::queue[RUNTIME_QUEUE_SIZE];
+RuntimeQueueStruct ProgramData::queue[RUNTIME_QUEUE_MAX];
 byte ProgramData::station_qid[MAX_NUM_STATIONS];
 LogStruct ProgramData::lastrun;
 ulong ProgramData::last_seq_stop_time;
-extern char tmp_buffer[];
+ulong ProgramData::last_run_id;
+byte ProgramData::wwin[MAX_NUM_PROGRAMS][2];
 
 void ProgramData::init()
 {
+	// run ids count up from the boot time, so they do not repeat after a restart
+	// unless more than one run per second was queued on average
+	last_run_id = os.now_tz();
 	reset_runtime();
 	load_count();
+	load_lastrun();
 }
 
 void ProgramData::reset_runtime()
@@ -52,21 +58,113 @@ void ProgramData::reset_runtime()
 	last_seq_stop_time = 0;
 }
 
+/** Priority of a run when the queue overflows
+ * This is the higher of the station and the program priority.
+ * Manual, run-once and test runs have the highest priority.
+ * prog_pri caches the program priorities, so each program is read once; 0xFF marks one not read yet.
+ */
+byte ProgramData::priority(byte sid, byte pid, byte *prog_pri)
+{
+	if (pid == 0 || pid > MAX_NUM_PROGRAMS)
+		return 0xFF;
+	if (prog_pri[pid - 1] == 0xFF)
+	{
+		ProgramExtra px;
+		read_extra(pid - 1, &px);
+		prog_pri[pid - 1] = px.pri;
+	}
+	byte pri = prog_pri[pid - 1];
+	return (pri > os.attrib_pri[sid]) ? pri : os.attrib_pri[sid];
+}
+
+/** Notify a queue overflow, at most once every QUEUE_OVERFLOW_NOTIFY_INTERVAL
+ * A program that overflows the queue would otherwise send a notification for each of its stations.
+ */
+static void notify_queue_overflow(byte sid, byte pid, const char *what, ulong curr_time)
+{
+	static ulong notified = 0;
+	if (notified && curr_time >= notified && curr_time < notified + QUEUE_OVERFLOW_NOTIFY_INTERVAL)
+		return;
+	notified = curr_time;
+	push_message(NOTIFY_QUEUE_OVERFLOW, sid, pid, what);
+}
+
 /** Insert a new element to the queue
- * This function returns pointer to the next available element in the queue
- * and returns NULL if the queue is full
+ * This function returns pointer to the queue element of the run, with its station,
+ * program, program name, water time and soak filled in, and returns NULL if the run is rejected.
+ * Without a program name, manual and run-once runs are named after their kind.
+ * When the queue is full, what happens depends on the queue overflow option.
+ * If the run was merged into a waiting run, merged is set and the element belongs to that run,
+ * so the caller must not change its start time or other fields of the run.
  */
-RuntimeQueueStruct *ProgramData::enqueue()
+RuntimeQueueStruct *ProgramData::enqueue(byte sid, byte pid, uint16_t dur, const char *pname, uint16_t soak, bool *merged)
 {
-	if (nqueue < RUNTIME_QUEUE_SIZE)
+	if (merged)
+		*merged = false;
+	byte policy = os.iopts[IOPT_QUEUE_OVERFLOW];
+	byte size = (policy == QUEUE_OVERFLOW_EXTEND) ? RUNTIME_QUEUE_MAX : RUNTIME_QUEUE_SIZE;
+	ulong curr_time = os.now_tz();
+	RuntimeQueueStruct *q = NULL;
+	if (nqueue < size)
 	{
+		q = queue + nqueue;
 		nqueue++;
-		return queue + (nqueue - 1);
 	}
-	else
+	else if (policy == QUEUE_OVERFLOW_MERGE)
+	{
+		// add the water time to a run of the same station and program that has not started yet.
+		// cycles of a cycle and soak program are not merged, that would take away the soak
+		for (RuntimeQueueStruct *e = queue; soak == 0 && e < queue + nqueue; e++)
+		{
+			if (e->sid == sid && e->pid == pid && !e->soak && (!e->st || e->st > curr_time) && (ulong)e->dur + dur <= 0xFFFF)
+			{
+				e->dur += dur;
+				if (merged)
+					*merged = true;
+				return e;
+			}
+		}
+	}
+	else if (policy == QUEUE_OVERFLOW_DROP)
+	{
+		// replace the waiting run with the lowest priority, if it is lower than the new one
+		byte prog_pri[MAX_NUM_PROGRAMS];
+		memset(prog_pri, 0xFF, sizeof(prog_pri));
+		byte pri = priority(sid, pid, prog_pri);
+		byte lowest = pri;
+		for (RuntimeQueueStruct *e = queue; e < queue + nqueue; e++)
+		{
+			if (e->st && e->st <= curr_time)
+				continue; // never drop a run that has started
+			byte epri = priority(e->sid, e->pid, prog_pri);
+			if (epri < lowest)
+			{
+				lowest = epri;
+				q = e;
+			}
+		}
+		if (q)
+		{
+			notify_queue_overflow(q->sid, q->pid, "dropped", curr_time);
+			if (station_qid[q->sid] == q - queue)
+				station_qid[q->sid] = 0xFF;
+		}
+	}
+	if (!q)
 	{
+		notify_queue_overflow(sid, pid, "rejected", curr_time);
 		return NULL;
 	}
+	memset(q, 0, sizeof(RuntimeQueueStruct));
+	q->sid = sid;
+	q->pid = pid;
+	q->dur = dur;
+	q->soak = soak;
+	if (!pname)
+		pname = (pid == 99) ? "Manual Program" : (pid == ZONETEST_PID) ? "Zone Test" : (pid == 254) ? "Run-Once Program" : "";
+	strncpy(q->pname, pname, PROGRAM_NAME_SIZE);
+	q->rid = ++last_run_id;
+	return q;
 }
 
 /** Remove an element from the queue
@@ -79,6 +177,8 @@ void ProgramData::dequeue(byte qid)
 {
 	if (qid >= nqueue)
 		return;
+	if (station_qid[queue[qid].sid] == qid) // the station no longer has a queue element
+		station_qid[queue[qid].sid] = 0xFF;
 	if (qid < nqueue - 1)
 	{
 		queue[qid] = queue[nqueue - 1];				   // copy the last element to the dequeud element to fill the space
@@ -92,6 +192,19 @@ void ProgramData::dequeue(byte qid)
 void ProgramData::load_count()
 {
 	nprograms = file_read_byte(PROG_FILENAME, 0);
+	load_windows();
+}
+
+/** Load the watering windows of the programs into RAM */
+void ProgramData::load_windows()
+{
+	ProgramExtra px;
+	for (byte pid = 0; pid < MAX_NUM_PROGRAMS; pid++)
+	{
+		read_extra(pid, &px);
+		wwin[pid][0] = px.wwbeg;
+		wwin[pid][1] = px.wwend;
+	}
 }
 
 /** Save program count to program file */
@@ -100,6 +213,20 @@ void ProgramData::save_count()
 	file_write_byte(PROG_FILENAME, 0, nprograms);
 }
 
+/** Load the most recent station run, which is kept across restarts */
+void ProgramData::load_lastrun()
+{
+	memset(&lastrun, 0, sizeof(LogStruct));
+	if (file_exists(LASTRUN_FILENAME))
+		file_read_block(LASTRUN_FILENAME, &lastrun, 0, sizeof(LogStruct));
+}
+
+/** Save the most recent station run */
+void ProgramData::save_lastrun()
+{
+	file_write_block(LASTRUN_FILENAME, &lastrun, 0, sizeof(LogStruct));
+}
+
 /** Erase all program data */
 void ProgramData::eraseall()
 {
@@ -122,6 +249,9 @@ byte ProgramData::add(ProgramStruct *buf)
 	if (nprograms >= MAX_NUM_PROGRAMS)
 		return 0;
 	file_write_block(PROG_FILENAME, buf, 1 + (ulong)nprograms * PROGRAMSTRUCT_SIZE, PROGRAMSTRUCT_SIZE);
+	ProgramExtra px;
+	memset(&px, 0, sizeof(ProgramExtra));
+	write_extra(nprograms, &px);
 	nprograms++;
 	save_count();
 	return 1;
@@ -140,6 +270,12 @@ void ProgramData::moveup(byte pid)
 	file_read_block(PROG_FILENAME, buf2, next, PROGRAMSTRUCT_SIZE);
 	file_write_block(PROG_FILENAME, tmp_buffer, next, PROGRAMSTRUCT_SIZE);
 	file_write_block(PROG_FILENAME, buf2, pos, PROGRAMSTRUCT_SIZE);
+	// swap the extra data as well
+	ProgramExtra px1, px2;
+	read_extra(pid - 1, &px1);
+	read_extra(pid, &px2);
+	write_extra(pid, &px1);
+	write_extra(pid - 1, &px2);
 }
 
 /** Modify a program */
@@ -165,11 +301,48 @@ byte ProgramData::del(byte pid)
 	{
 		file_copy_block(PROG_FILENAME, pos, pos - PROGRAMSTRUCT_SIZE, PROGRAMSTRUCT_SIZE, tmp_buffer);
 	}
+	ProgramExtra px;
+	for (byte i = pid + 1; i < nprograms; i++)
+	{
+		read_extra(i, &px);
+		write_extra(i - 1, &px);
+	}
 	nprograms--;
 	save_count();
 	return 1;
 }
 
+/** Read the extra data (cycle and soak, watering window) of a program */
+void ProgramData::read_extra(byte pid, ProgramExtra *px)
+{
+	memset(px, 0, sizeof(ProgramExtra));
+	if (pid >= nprograms)
+		return;
+	file_read_block(PROGEXT_FILENAME, px, (ulong)pid * sizeof(ProgramExtra), sizeof(ProgramExtra));
+}
+
+/** Write the extra data of a program */
+void ProgramData::write_extra(byte pid, ProgramExtra *px)
+{
+	if (pid >= MAX_NUM_PROGRAMS)
+		return;
+	file_write_block(PROGEXT_FILENAME, px, (ulong)pid * sizeof(ProgramExtra), sizeof(ProgramExtra));
+	wwin[pid][0] = px->wwbeg;
+	wwin[pid][1] = px->wwend;
+}
+
+/** Watering window of a program (see ProgramExtra), from RAM */
+void ProgramData::water_window(byte pid, byte *wwbeg, byte *wwend)
+{
+	if (pid >= nprograms)
+	{
+		*wwbeg = *wwend = 0;
+		return;
+	}
+	*wwbeg = wwin[pid][0];
+	*wwend = wwin[pid][1];
+}
+
 // set the enable bit
 byte ProgramData::set_flagbit(byte pid, byte bid, byte value)
 {
@@ -207,19 +380,39 @@ int16_t ProgramStruct::starttime_decode(int16_t t)
 	return t;
 }
 
-/** Check if a given time matches the program's start day */
-byte ProgramStruct::check_day_match(time_t t)
+/** Check a day of the month against an odd/even restriction
+ * Returns true if the program may run on that day (0: none, 1: odd, 2: even).
+ * Odd days skip the 31st and Feb 29, so that no two runs fall on consecutive days.
+ */
+static bool check_odd_even(byte oddeven, byte dt, byte month)
+{
+	switch (oddeven)
+	{
+	case 1:
+		if (dt == 31 || (dt == 29 && month == 2))
+			return false;
+		return (dt % 2) == 1;
+	case 2:
+		return (dt % 2) == 0;
+	default:
+		return true;
+	}
+}
+
+/** Check if a given time matches the program's start day
+ * anchor is the anchor day of interval programs (see ProgramExtra), 0 if they have none
+ */
+byte ProgramStruct::check_day_match(time_t t, uint16_t anchor)
 {
 
 	// get current time from RPI
 	time_t ct = t;
 	struct tm *ti = gmtime(&ct);
-	byte weekday_t = (ti->tm_wday + 1) % 7; // tm_wday ranges from [0,6] with Sunday being 0
 	byte day_t = ti->tm_mday;
 	byte month_t = ti->tm_mon + 1; // tm_mon ranges from [0,11]
 								   // get current time
 
-	byte wd = (weekday_t + 5) % 7;
+	byte wd = os.weekday_index(t);
 	byte dt = day_t;
 
 	// check day match
@@ -242,39 +435,30 @@ byte ProgramStruct::check_day_match(time_t t)
 
 	case PROGRAM_TYPE_INTERVAL:
 		// this is an inverval program
-		if (((t / SECS_PER_DAY) % days[1]) != days[0])
+		if (!days[1])
+			return 0;
+		if (anchor)
+		{
+			// every days[1] days, from the anchor day on
+			ulong day = t / SECS_PER_DAY;
+			if (day < anchor || (day - anchor) % days[1])
+				return 0;
+		}
+		else if (((t / SECS_PER_DAY) % days[1]) != days[0])
 			return 0;
 		break;
 	}
 
 	// check odd/even day restriction
-	if (!oddeven)
-	{
-	}
-	else if (oddeven == 2)
-	{
-		// even day restriction
-		if ((dt % 2) != 0)
-			return 0;
-	}
-	else if (oddeven == 1)
-	{
-		// odd day restriction
-		// skip 31st and Feb 29
-		if (dt == 31)
-			return 0;
-		else if (dt == 29 && month_t == 2)
-			return 0;
-		else if ((dt % 2) != 1)
-			return 0;
-	}
+	if (!check_odd_even(oddeven, dt, month_t))
+		return 0;
 	return 1;
 }
 
 // Check if a given time matches program's start time
 // this also checks for programs that started the previous
 // day and ran over night
-byte ProgramStruct::check_match(time_t t)
+byte ProgramStruct::check_match(time_t t, uint16_t anchor)
 {
 
 	// check program enable status
@@ -287,7 +471,7 @@ byte ProgramStruct::check_match(time_t t)
 	int16_t current_minute = (t % 86400L) / 60;
 
 	// first assume program starts today
-	if (check_day_match(t))
+	if (check_day_match(t, anchor))
 	{
 		// t matches the program's start day
 
@@ -325,7 +509,7 @@ byte ProgramStruct::check_match(time_t t)
 		return 0;
 
 	// next, assume program started the previous day and ran over night
-	if (check_day_match(t - 86400L))
+	if (check_day_match(t - 86400L, anchor))
 	{
 		// t-86400L matches the program's start day
 		int16_t c = (current_minute - start + 1440) / interval;
@@ -337,21 +521,27 @@ byte ProgramStruct::check_match(time_t t)
 	return 0;
 }
 
-// convert absolute remainder (reference time 1970 01-01) to relative remainder (reference time today)
-// absolute remainder is stored in flash, relative remainder is presented to web
-void ProgramData::drem_to_relative(byte days[2])
+/** Anchor day of an interval program whose next run is days_ahead days from today
+ * The web API presents interval programs by the days until their next run (days[0] of /jp and /cp).
+ */
+uint16_t ProgramData::interval_anchor(byte days_ahead)
 {
-	byte rem_abs = days[0];
-	byte inv = days[1];
-	// TODO future: use now_tz()?
-	days[0] = (byte)((rem_abs + inv - (os.now_tz() / SECS_PER_DAY) % inv) % inv);
+	return os.now_tz() / SECS_PER_DAY + days_ahead;
 }
 
-// relative remainder -> absolute remainder
-void ProgramData::drem_to_absolute(byte days[2])
+/** Days from today until the next run day of an interval program, as presented by the web API
+ * Without an anchor, the run days are those whose remainder is days[0].
+ * An anchor day more than 254 days ahead is presented as 254 days ahead.
+ */
+byte ProgramData::interval_days_ahead(byte days[2], uint16_t anchor)
 {
-	byte rem_rel = days[0];
-	byte inv = days[1];
-	// TODO future: use now_tz()?
-	days[0] = (byte)(((os.now_tz() / SECS_PER_DAY) + rem_rel) % inv);
+	byte interval = days[1];
+	if (!interval)
+		return 0;
+	ulong today = os.now_tz() / SECS_PER_DAY;
+	if (!anchor)
+		return (days[0] + interval - today % interval) % interval;
+	if (anchor >= today)
+		return (anchor - today > 254) ? 254 : anchor - today;
+	return (interval - (today - anchor) % interval) % interval;
 }
@@ -25,10 +25,15 @@
 #define _PROGRAM_H
 
 #define MAX_NUM_PROGRAMS 40 // maximum number of programs
+#define ZONETEST_PID 100 // program index of zone test runs, which run like manual runs (99)
 #define MAX_NUM_STARTTIMES 4
 #define PROGRAM_NAME_SIZE 32
 #define RUNTIME_QUEUE_SIZE MAX_NUM_STATIONS
+#define RUNTIME_QUEUE_MAX 250 // queue size when the overflow policy extends the queue (station_qid uses 255 for none)
 #define PROGRAMSTRUCT_SIZE sizeof(ProgramStruct)
+#define MAX_PROGRAM_CYCLES 10 // maximum number of cycles a station's water time is split into
+#define MAX_PROGRAM_SOAK 240  // maximum soak time between cycles (in minutes)
+#define WATER_WINDOW_UNITS 96 // number of 15 minute units in a day, used by watering windows
 #include "OpenSprinkler.h"
 
 /** Log data structure */
@@ -51,6 +56,33 @@ struct LogStruct
 
 #define PROGRAMSTRUCT_EN_BIT 0
 #define PROGRAMSTRUCT_UWT_BIT 1
+#define PROGRAMSTRUCT_CAL_BIT 7
+
+#define FORECAST_MODE_SKIP 0	// skip the run
+#define FORECAST_MODE_REDUCE 1 // reduce the water time by the forecast probability
+
+#define PROGRAM_COLLIDE_STACK 0 // queue the runs after those of a program still running
+#define PROGRAM_COLLIDE_SKIP 1	// skip the start while a program is still running
+#define PROGRAM_COLLIDE_CUT 2	// cut the runs of a program still running short
+
+/** Program extra data structure
+ * Stored separately from the program data to keep the program file format unchanged
+ */
+struct ProgramExtra
+{
+	byte cycles; // number of cycles each station's water time is split into (0 or 1: no split)
+	byte soak;	 // minimum soak time between the cycles of a station (in minutes)
+	byte wwbeg;	 // watering window start (in 15 minute units from midnight)
+	byte wwend;	 // watering window end (in 15 minute units, equal to wwbeg: use the global window)
+	byte pri;	 // priority when the run-time queue overflows (0 is the lowest)
+	byte fcpop;	 // rain forecast probability (in percent) that skips or reduces the run (0: not checked)
+	byte fcqpf;	 // rain forecast amount (in 1/100 inch) that skips or reduces the run (0: not checked)
+	byte fcmode; // what an exceeded rain forecast does (FORECAST_MODE_*)
+	byte collide; // what a start does while a program is still running (PROGRAM_COLLIDE_*)
+	uint16_t ivanchor; // interval programs: day (since 1970, local time) the runs count from (0: see ProgramStruct::days)
+};
+
+#define PROGRAM_EXTRA_BYTES 9 // byte fields at the start of ProgramExtra, listed before ivanchor in /jp
 
 /** Program data structure */
 class ProgramStruct
@@ -75,13 +107,15 @@ public:
 	// 1: fixed start time (give arbitrary start times up to MAX_NUM_STARTTIMEs)
 	byte starttime_type : 1;
 
-	// misc. data
-	byte dummy1 : 1;
+	// skip the program on days suppressed by the calendar
+	byte use_calendar : 1;
 
-	// weekly:	 days[0][0..6] correspond to Monday till Sunday
+	// weekly:	 days[0][0..6] correspond to Monday till Sunday (bit WEEKDAY_MONDAY to WEEKDAY_SUNDAY)
 	// bi-weekly:days[0][0..6] and [1][0..6] store two weeks
 	// monthly:  days[0][0..5] stores the day of the month (32 means last day of month)
-	// interval: days[0] stores the interval (0 to 255), days[1] stores the starting day remainder (0 to 254)
+	// interval: days[1] stores the interval (1 to 255), days[0] the remainder of the run days since 1970 (0 to 254)
+	//			 programs with an anchor day (ProgramExtra::ivanchor) run every days[1] days from that day on,
+	//			 days[0] is kept up to date for older firmwares
 	byte days[2];
 
 	// When the program is a fixed start time type:
@@ -103,15 +137,13 @@ public:
 
 	char name[PROGRAM_NAME_SIZE];
 
-	byte check_match(time_t t);
+	byte check_match(time_t t, uint16_t anchor);
 	int16_t starttime_decode(int16_t t);
 
 protected:
-	byte check_day_match(time_t t);
+	byte check_day_match(time_t t, uint16_t anchor);
 };
 
-extern OpenSprinkler os;
-
 class RuntimeQueueStruct
 {
 public:
@@ -119,6 +151,9 @@ public:
 	uint16_t dur; // water time
 	byte sid;
 	byte pid;
+	uint16_t soak; // minimum time (in seconds) after the previous run of the same station
+	char pname[PROGRAM_NAME_SIZE + 1]; // name of the program the run belongs to, for the station events
+	ulong rid;						   // run id, shared by the station events and the log record of the run
 };
 
 class ProgramData
@@ -130,12 +165,18 @@ public:
 	static byte nprograms;	   // number of programs
 	static LogStruct lastrun;
 	static ulong last_seq_stop_time; // the last stop time of a sequential station
+	static ulong last_run_id;		 // id of the most recently queued run
 
 	static void reset_runtime();
-	static RuntimeQueueStruct *enqueue(); // this returns a pointer to the next available slot in the queue
-	static void dequeue(byte qid);		  // this removes an element from the queue
+	static RuntimeQueueStruct *enqueue(byte sid, byte pid, uint16_t dur, const char *pname = NULL,
+									   uint16_t soak = 0, bool *merged = NULL); // this returns a pointer to the queue element of the run
+	static void dequeue(byte qid);											 // this removes an element from the queue
+	static byte priority(byte sid, byte pid, byte *prog_pri);
 
 	static void init();
+	static void load_count();
+	static void load_lastrun();
+	static void save_lastrun();
 	static void eraseall();
 	static void read(byte pid, ProgramStruct *buf);
 	static byte add(ProgramStruct *buf);
@@ -143,12 +184,16 @@ public:
 	static byte set_flagbit(byte pid, byte bid, byte value);
 	static void moveup(byte pid);
 	static byte del(byte pid);
-	static void drem_to_relative(byte days[2]); // absolute to relative reminder conversion
-	static void drem_to_absolute(byte days[2]);
+	static void read_extra(byte pid, ProgramExtra *px);
+	static void write_extra(byte pid, ProgramExtra *px);
+	static void water_window(byte pid, byte *wwbeg, byte *wwend);
+	static uint16_t interval_anchor(byte days_ahead);
+	static byte interval_days_ahead(byte days[2], uint16_t anchor);
 
 private:
-	static void load_count();
+	static byte wwin[MAX_NUM_PROGRAMS][2]; // watering window of each program, see ProgramExtra, kept in RAM as it is checked every second
 	static void save_count();
+	static void load_windows();
 };
 
 #endif // _PROGRAM_H
@@ -0,0 +1,163 @@
+/* OpenSprinkler Unified (RPI/LINUX) Firmware
+ * Copyright (C) 2015 by Ray Wang (ray@opensprinkler.com)
+ *
+ * RF transmitter, driven by a thread of its own so the bit timing is not disturbed by the main loop
+ *
+ * This file is part of the OpenSprinkler library
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see
+ * <http://www.gnu.org/licenses/>.
+ */
+
+#include <pthread.h>
+#include <sched.h>
+#include "OpenSprinkler.h"
+#include "rf.h"
+
+struct RFCode
+{
+	ulong code;
+	uint16_t len;
+	byte repeat;
+	byte sid; // special station switched by the code, 0xFF if none
+};
+static RFCode rf_queue[RF_QUEUE_SIZE];
+static byte rf_queue_head = 0; // index of the next code to send
+static byte rf_queue_len = 0;
+static bool rf_started = false;
+static pthread_mutex_t rf_mutex = PTHREAD_MUTEX_INITIALIZER;
+static pthread_cond_t rf_cond = PTHREAD_COND_INITIALIZER;
+
+static int rf_gpio_fd = -1;
+
+/** Transmit one RF signal bit */
+static void transmit_rfbit(ulong lenH, ulong lenL)
+{
+	gpio_write(rf_gpio_fd, 1);
+	delayMicrosecondsHard(lenH);
+	gpio_write(rf_gpio_fd, 0);
+	delayMicrosecondsHard(lenL);
+}
+
+/** Transmit RF signal */
+static void send_rfsignal(ulong code, ulong len, byte repeat)
+{
+	ulong len3 = len * 3;
+	ulong len31 = len * 31;
+	for (byte n = 0; n < repeat; n++)
+	{
+		int i = 23;
+		// send code
+		while (i >= 0)
+		{
+			if ((code >> i) & 1)
+			{
+				transmit_rfbit(len3, len);
+			}
+			else
+			{
+				transmit_rfbit(len, len3);
+			}
+			i--;
+		};
+		// send sync
+		transmit_rfbit(len, len31);
+	}
+}
+
+static void *rf_worker(void *)
+{
+	// real-time priority keeps the main loop from stretching the pulses,
+	// it needs root or CAP_SYS_NICE, otherwise the thread runs at normal priority
+	struct sched_param param;
+	param.sched_priority = sched_get_priority_max(SCHED_FIFO);
+	if (pthread_setschedparam(pthread_self(), SCHED_FIFO, &param) != 0)
+		DEBUG_PRINTLN(F("RF thread runs without real-time priority"));
+
+	RFCode r;
+	for (;;)
+	{
+		pthread_mutex_lock(&rf_mutex);
+		while (rf_queue_len == 0)
+			pthread_cond_wait(&rf_cond, &rf_mutex);
+		r = rf_queue[rf_queue_head];
+		pthread_mutex_unlock(&rf_mutex);
+
+		// pre-open gpio file to minimize overhead
+		rf_gpio_fd = gpio_fd_open(PIN_RFTX);
+		send_rfsignal(r.code, r.len, r.repeat);
+		gpio_fd_close(rf_gpio_fd);
+		rf_gpio_fd = -1;
+
+		pthread_mutex_lock(&rf_mutex);
+		OpenSprinkler::set_spe_status(r.sid, SPE_STATUS_SENT);
+		rf_queue_head = (rf_queue_head + 1) % RF_QUEUE_SIZE;
+		rf_queue_len--;
+		pthread_mutex_unlock(&rf_mutex);
+	}
+	return NULL;
+}
+
+/** Queue an RF code for the transmit thread
+ * Codes are sent one at a time in the order they were queued, each repeat times (0 is taken as 1).
+ * Returns false if the thread cannot be started or the queue is full.
+ */
+bool rf_transmit(ulong code, uint16_t len, byte repeat, byte sid)
+{
+	bool queued = false;
+	pthread_mutex_lock(&rf_mutex);
+	if (!rf_started)
+	{
+		pthread_t thread;
+		if (pthread_create(&thread, NULL, rf_worker, NULL) == 0)
+		{
+			pthread_detach(thread);
+			rf_started = true;
+		}
+	}
+	if (rf_started && rf_queue_len < RF_QUEUE_SIZE)
+	{
+		RFCode *r = &rf_queue[(rf_queue_head + rf_queue_len) % RF_QUEUE_SIZE];
+		r->code = code;
+		r->len = len;
+		r->repeat = repeat ? repeat : 1;
+		r->sid = sid;
+		rf_queue_len++;
+		pthread_cond_signal(&rf_cond);
+		queued = true;
+	}
+	// job_mutex is taken inside rf_mutex here, the worker never takes rf_mutex
+	OpenSprinkler::set_spe_status(sid, queued ? SPE_STATUS_PENDING : SPE_STATUS_FAILED);
+	pthread_mutex_unlock(&rf_mutex);
+	return queued;
+}
+
+/** Wait until the transmit thread has sent all queued codes
+ * Used at shutdown, so that the codes turning off RF stations are not lost when the process exits.
+ * Returns false if codes are left after timeout milliseconds.
+ */
+bool rf_drain(ulong timeout)
+{
+	ulong start = millis();
+	pthread_mutex_lock(&rf_mutex);
+	while (rf_queue_len > 0 && millis() - start < timeout)
+	{
+		pthread_mutex_unlock(&rf_mutex);
+		delay(10);
+		pthread_mutex_lock(&rf_mutex);
+	}
+	bool drained = (rf_queue_len == 0);
+	pthread_mutex_unlock(&rf_mutex);
+	return drained;
+}
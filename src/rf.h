@@ -0,0 +1,35 @@
+/* OpenSprinkler Unified (RPI/LINUX) Firmware
+ * Copyright (C) 2015 by Ray Wang (ray@opensprinkler.com)
+ *
+ * RF transmitter functions header file
+ *
+ * This file is part of the OpenSprinkler library
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see
+ * <http://www.gnu.org/licenses/>.
+ */
+
+#ifndef _RF_H
+#define _RF_H
+
+#include "defines.h"
+
+#define RF_QUEUE_SIZE 8		  // number of codes that can wait for the transmit thread
+#define RF_DEFAULT_REPEAT 15 // times a code is sent when no repetition count is set
+#define RF_DRAIN_TIMEOUT 10000 // milliseconds to wait at shutdown for the queued codes
+
+bool rf_transmit(ulong code, uint16_t len, byte repeat, byte sid = 0xFF);
+bool rf_drain(ulong timeout);
+
+#endif // _RF_H
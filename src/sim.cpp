@@ -0,0 +1,122 @@
+/* OpenSprinkler Unified (RPI/LINUX) Firmware
+ * Copyright (C) 2015 by Ray Wang (ray@opensprinkler.com)
+ *
+ * Simulated hardware for demo builds
+ * Feb 2015 @ OpenSprinkler.com
+ *
+ * This file is part of the OpenSprinkler library
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see
+ * <http://www.gnu.org/licenses/>.
+ */
+
+#include "OpenSprinkler.h"
+#include "sim.h"
+#include "main.h"
+
+#if !defined(OSPI)
+
+
+static byte sim_pins[SIM_NUM_PINS];
+static byte sim_shift[MAX_NUM_BOARDS];	// bits shifted into the virtual shift register
+static byte sim_valves[MAX_NUM_BOARDS]; // latched valve states
+static byte sim_sensors[2];				// binary sensor activation, set through the /sm command
+
+/** Write to a virtual pin
+ * Writes to the shift register pins are decoded into valve states
+ */
+void sim_pin_write(int pin, byte value)
+{
+	if (pin < 0 || pin >= SIM_NUM_PINS)
+		return;
+	byte prev = sim_pins[pin];
+	sim_pins[pin] = value;
+	if (prev || !value)
+		return;
+
+	// rising edges
+	if (pin == PIN_SR_CLOCK)
+	{
+		// the last board is shifted in first, so bits move towards the last board
+		for (int bid = MAX_NUM_BOARDS - 1; bid >= 0; bid--)
+		{
+			sim_shift[bid] = (sim_shift[bid] << 1) | ((bid > 0) ? (sim_shift[bid - 1] >> 7) : sim_pins[PIN_SR_DATA]);
+		}
+	}
+	else if (pin == PIN_SR_LATCH)
+	{
+		memcpy(sim_valves, sim_shift, MAX_NUM_BOARDS);
+	}
+}
+
+/** Read a virtual pin
+ * A flow sensor produces pulses proportional to the number of open valves,
+ * binary sensors report the level matching their simulated activation.
+ */
+byte sim_pin_read(int pin)
+{
+	if (pin == PIN_SENSOR1 && os.iopts[IOPT_SENSOR1_TYPE] == SENSOR_TYPE_FLOW)
+	{
+		ulong rate = (ulong)sim_count_open_valves() * SIM_FLOW_PULSE_RATE;
+		if (!rate)
+			return HIGH;
+		ulong period = 1000 / rate;
+		return (millis() % period) < (period / 2) ? HIGH : LOW;
+	}
+	if (pin == PIN_SENSOR1)
+		return sim_sensors[0] ? !os.iopts[IOPT_SENSOR1_OPTION] : os.iopts[IOPT_SENSOR1_OPTION];
+	if (pin == PIN_SENSOR2)
+		return sim_sensors[1] ? !os.iopts[IOPT_SENSOR2_OPTION] : os.iopts[IOPT_SENSOR2_OPTION];
+	if (pin < 0 || pin >= SIM_NUM_PINS)
+		return LOW;
+	return sim_pins[pin];
+}
+
+void sim_set_sensor(byte idx, byte active)
+{
+	if (idx < 2)
+		sim_sensors[idx] = active ? 1 : 0;
+}
+
+byte sim_get_sensor(byte idx)
+{
+	return (idx < 2) ? sim_sensors[idx] : 0;
+}
+
+byte sim_get_valves(byte bid)
+{
+	return (bid < MAX_NUM_BOARDS) ? sim_valves[bid] : 0;
+}
+
+byte sim_count_open_valves()
+{
+	byte n = 0;
+	for (byte bid = 0; bid < MAX_NUM_BOARDS; bid++)
+	{
+		for (byte s = 0; s < SHIFT_REGISTER_LINES; s++)
+		{
+			if ((sim_valves[bid] >> s) & 1)
+				n++;
+		}
+	}
+	return n;
+}
+
+/** Current (in mA) drawn by the open valves */
+uint16_t sim_read_current()
+{
+	return (uint16_t)sim_count_open_valves() * SIM_VALVE_CURRENT;
+}
+
+#endif
@@ -0,0 +1,45 @@
+/* OpenSprinkler Unified (RPI/LINUX) Firmware
+ * Copyright (C) 2015 by Ray Wang (ray@opensprinkler.com)
+ *
+ * Simulated hardware header file
+ * Feb 2015 @ OpenSprinkler.com
+ *
+ * This file is part of the OpenSprinkler library
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see
+ * <http://www.gnu.org/licenses/>.
+ */
+
+#ifndef _SIM_H
+#define _SIM_H
+
+#if !defined(OSPI)
+
+#include "defines.h"
+
+#define SIM_NUM_PINS 8
+#define SIM_FLOW_PULSE_RATE 2	// flow sensor pulses per second for each open valve
+#define SIM_VALVE_CURRENT 250 // current (in mA) drawn by each open valve
+
+void sim_pin_write(int pin, byte value);
+byte sim_pin_read(int pin);
+void sim_set_sensor(byte idx, byte active);
+byte sim_get_sensor(byte idx);
+byte sim_get_valves(byte bid);
+byte sim_count_open_valves();
+uint16_t sim_read_current();
+
+#endif
+
+#endif // _SIM_H
@@ -0,0 +1,234 @@
+/* OpenSprinkler Unified (RPI/LINUX) Firmware
+ * Copyright (C) 2015 by Ray Wang (ray@opensprinkler.com)
+ *
+ * Scheduled tasks (timer) for maintenance work, run when their cron expression matches
+ *
+ * This file is part of the OpenSprinkler library
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see
+ * <http://www.gnu.org/licenses/>.
+ */
+
+#include <stdlib.h>
+#include <ctype.h>
+#include <dirent.h>
+#include "OpenSprinkler.h"
+#include "utils.h"
+#include "timer.h"
+#include "main.h"
+
+ulong task_lastrun[MAX_NUM_TASKS]; // time each task last ran
+int task_result[MAX_NUM_TASKS];	   // result of each task's last run, see run_task
+
+/** Get a scheduled task */
+void timer_get_task(byte tid, TimerTask *task)
+{
+	memset(task, 0, sizeof(TimerTask));
+	if (tid >= MAX_NUM_TASKS)
+		return;
+	file_read_block(TASKS_FILENAME, task, (ulong)tid * sizeof(TimerTask), sizeof(TimerTask));
+	task->cron[TASK_CRON_SIZE - 1] = 0;
+	task->arg[TASK_ARG_SIZE - 1] = 0;
+}
+
+/** Set a scheduled task */
+void timer_set_task(byte tid, TimerTask *task)
+{
+	if (tid >= MAX_NUM_TASKS)
+		return;
+	file_write_block(TASKS_FILENAME, task, (ulong)tid * sizeof(TimerTask), sizeof(TimerTask));
+	task_lastrun[tid] = 0;
+	task_result[tid] = 0;
+}
+
+/** Match a value against one field of a cron expression
+ * A field is a list of '*', 'n' or 'a-b', each optionally followed by '/step'.
+ * p is moved to the next field. Returns 1 if the value matches, 0 if not, -1 if the field is invalid.
+ */
+static int8_t cron_field(const char *&p, int v, int lo, int hi)
+{
+	bool match = false;
+	while (true)
+	{
+		int a = lo, b = hi, step = 1;
+		if (*p == '*')
+		{
+			p++;
+		}
+		else
+		{
+			if (!isdigit(*p))
+				return -1;
+			a = b = strtol(p, (char **)&p, 10);
+			if (*p == '-')
+			{
+				p++;
+				if (!isdigit(*p))
+					return -1;
+				b = strtol(p, (char **)&p, 10);
+			}
+			else if (*p == '/')
+			{
+				b = hi; // 'n/step' starts at n
+			}
+		}
+		if (*p == '/')
+		{
+			p++;
+			if (!isdigit(*p))
+				return -1;
+			step = strtol(p, (char **)&p, 10);
+		}
+		if (a < lo || b > hi || a > b || step <= 0)
+			return -1;
+		if (v >= a && v <= b && (v - a) % step == 0)
+			match = true;
+		if (*p != ',')
+			break;
+		p++;
+	}
+	if (*p && *p != ' ')
+		return -1;
+	while (*p == ' ')
+		p++;
+	return match;
+}
+
+/** Match a time (in local time) against a cron expression
+ * Returns 1 if it matches, 0 if not, -1 if the expression is invalid.
+ * As in cron, if both day fields are restricted, a day matching either of them matches.
+ */
+int8_t cron_match(const char *expr, ulong t)
+{
+	if (!strcmp(expr, "@hourly"))
+		expr = "0 * * * *";
+	else if (!strcmp(expr, "@daily"))
+		expr = "0 0 * * *";
+	else if (!strcmp(expr, "@weekly"))
+		expr = "0 0 * * 0";
+	else if (!strcmp(expr, "@monthly"))
+		expr = "0 0 1 * *";
+
+	time_t tt = t;
+	struct tm *ti = gmtime(&tt);
+	const char *p = expr;
+	while (*p == ' ')
+		p++;
+	int8_t min = cron_field(p, ti->tm_min, 0, 59);
+	int8_t hour = (min < 0) ? -1 : cron_field(p, ti->tm_hour, 0, 23);
+	bool dom_any = (*p == '*');
+	int8_t dom = (hour < 0) ? -1 : cron_field(p, ti->tm_mday, 1, 31);
+	int8_t mon = (dom < 0) ? -1 : cron_field(p, ti->tm_mon + 1, 1, 12);
+	bool dow_any = (*p == '*');
+	const char *q = p;
+	int8_t dow = (mon < 0) ? -1 : cron_field(p, ti->tm_wday, 0, 7);
+	if (dow == 0 && ti->tm_wday == 0)
+		dow = cron_field(q, 7, 0, 7); // both 0 and 7 are Sunday
+	if (dow < 0 || *p)
+		return -1;
+	bool day = (dom_any || dow_any) ? (dom && dow) : (dom || dow);
+	return min && hour && mon && day;
+}
+
+/** Delete log files older than the given number of days, returns the number of files deleted */
+static int rotate_logs(ulong curr_time, long keep_days)
+{
+	DIR *dir = opendir(get_filename_fullpath(LOG_PREFIX));
+	if (!dir)
+		return 0;
+	long oldest = (long)(curr_time / 86400L) - keep_days + 1;
+	int n = 0;
+	char name[64];
+	struct dirent *ent;
+	while ((ent = readdir(dir)) != NULL)
+	{
+		// log files are named xxxxx.txt, where xxxxx is the day in epoch time
+		char *end;
+		long day = strtol(ent->d_name, &end, 10);
+		if (end == ent->d_name || strcmp(end, ".txt") || day >= oldest)
+			continue;
+		snprintf(name, sizeof(name), "%s%s", LOG_PREFIX, ent->d_name);
+		if (!remove(get_filename_fullpath(name)))
+			n++;
+	}
+	closedir(dir);
+	return n;
+}
+
+/** Queue a request of a url for the HTTP worker, the response is ignored
+ * Returns HTTP_RQT_NOT_RECEIVED once queued, or the error if it could not be queued
+ */
+static int8_t http_get(const char *url)
+{
+	char server[TASK_ARG_SIZE];
+	if (strncmp(url, "http://", 7) == 0)
+		url += 7;
+	strncpy(server, url, sizeof(server) - 1);
+	server[sizeof(server) - 1] = 0;
+
+	// split host[:port] and path
+	char *path = strchr(server, '/');
+	snprintf(ether_buffer, ETHER_BUFFER_SIZE, "GET %s", path ? path : "/");
+	if (path)
+		*path = 0;
+	os.http_request_head(ether_buffer, ETHER_BUFFER_SIZE, server, false);
+	strcat(ether_buffer, "\r\n");
+	char *port = strchr(server, ':');
+	if (port)
+		*port++ = 0;
+	return os.post_http_request(server, port ? atoi(port) : 80, ether_buffer);
+}
+
+/** Run a task and record its result
+ * Results: number of log files deleted, or the HTTP request result (-1 once queued, as the request
+ * is sent by the HTTP worker)
+ */
+static void run_task(byte tid, TimerTask *task, ulong curr_time)
+{
+	int result = 0;
+	switch (task->type)
+	{
+	case TASK_ROTATE_LOGS:
+		result = rotate_logs(curr_time, atol(task->arg));
+		break;
+	case TASK_WEATHER:
+		os.checkwt_lasttime = 0; // the main loop fetches the weather data
+		break;
+	case TASK_HTTP_GET:
+		result = http_get(task->arg);
+		break;
+	}
+	task_lastrun[tid] = curr_time;
+	task_result[tid] = result;
+}
+
+/** Run the scheduled tasks whose cron expression matches the current minute */
+void timer_check(ulong curr_time)
+{
+	static ulong last_minute = 0;
+	ulong minute = curr_time / 60;
+	if (minute == last_minute)
+		return;
+	last_minute = minute;
+
+	TimerTask task;
+	for (byte tid = 0; tid < MAX_NUM_TASKS; tid++)
+	{
+		timer_get_task(tid, &task);
+		if (task.type == TASK_NONE || task.type >= NUM_TASK_TYPES)
+			continue;
+		if (cron_match(task.cron, curr_time) == 1)
+			run_task(tid, &task, curr_time);
+	}
+}
@@ -0,0 +1,52 @@
+/* OpenSprinkler Unified (RPI/LINUX) Firmware
+ * Copyright (C) 2015 by Ray Wang (ray@opensprinkler.com)
+ *
+ * Scheduled task (timer) functions header file
+ *
+ * This file is part of the OpenSprinkler library
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see
+ * <http://www.gnu.org/licenses/>.
+ */
+
+#ifndef _TIMER_H
+#define _TIMER_H
+
+#define MAX_NUM_TASKS 8
+#define TASK_CRON_SIZE 32 // cron expression: minute hour day-of-month month day-of-week, or @hourly etc.
+#define TASK_ARG_SIZE 96
+
+/** Scheduled task types */
+#define TASK_NONE 0		   // task slot not used
+#define TASK_ROTATE_LOGS 1 // delete log files older than arg days
+#define TASK_WEATHER 2	   // refresh the weather data now
+#define TASK_HTTP_GET 3	   // request the url in arg, e.g. a DDNS update or a webhook ping
+#define NUM_TASK_TYPES 4
+
+/** Scheduled task, stored in TASKS_FILENAME */
+struct TimerTask
+{
+	byte type;
+	char cron[TASK_CRON_SIZE];
+	char arg[TASK_ARG_SIZE];
+};
+
+void timer_get_task(byte tid, TimerTask *task);
+void timer_set_task(byte tid, TimerTask *task);
+int8_t cron_match(const char *expr, ulong t);
+void timer_check(ulong curr_time);
+
+extern ulong task_lastrun[MAX_NUM_TASKS];
+extern int task_result[MAX_NUM_TASKS];
+#endif // _TIMER_H
@@ -21,9 +21,12 @@
  * <http://www.gnu.org/licenses/>.
  */
 
+#include <errno.h>
+#include <dirent.h>
+#include <sys/stat.h>
 #include "utils.h"
 #include "OpenSprinkler.h"
-extern OpenSprinkler os;
+#include "main.h"
 
 char *get_runtime_path()
 {
@@ -53,14 +56,150 @@ char *get_runtime_path()
 	return path;
 }
 
+static char data_path[PATH_MAX]; // folder of the data files, logs and control socket
+static bool data_in_memory = false;
+
+/** Folder the data files, logs and control socket are kept in
+ * This is the folder of the executable, unless setup_data_path() had to fall back to another one.
+ */
+char *get_data_path()
+{
+	if (!data_path[0])
+	{
+		char *path = get_runtime_path();
+		if (path)
+			strcpy(data_path, path);
+	}
+	return data_path;
+}
+
+/** Whether the data folder is in memory, so changes are lost when the system restarts */
+bool data_path_in_memory()
+{
+	return data_in_memory;
+}
+
 char *get_filename_fullpath(const char *filename)
 {
 	static char fullpath[PATH_MAX];
-	strcpy(fullpath, get_runtime_path());
+	strcpy(fullpath, get_data_path());
 	strcat(fullpath, filename);
 	return fullpath;
 }
 
+/** Create a folder and its parents, returns true if it exists afterwards */
+static bool make_folder(const char *path)
+{
+	char tmp[PATH_MAX];
+	strncpy(tmp, path, sizeof(tmp) - 1);
+	tmp[sizeof(tmp) - 1] = 0;
+	for (char *p = tmp + 1; *p; p++)
+	{
+		if (*p != '/')
+			continue;
+		*p = 0;
+		mkdir(tmp, 0755);
+		*p = '/';
+	}
+	mkdir(tmp, 0755);
+	struct stat st;
+	return !stat(tmp, &st) && S_ISDIR(st.st_mode);
+}
+
+/** Check that the data files can be written in a folder (ending with /), creating its data folder if needed */
+static bool data_path_writable(const char *path)
+{
+	char data[PATH_MAX];
+	snprintf(data, sizeof(data), "%sdata/", path);
+	return make_folder(data) && !access(path, W_OK) && !access(data, W_OK);
+}
+
+/** Copy the data files of a folder to another one that has none yet */
+static void copy_data_files(const char *from, const char *to)
+{
+	char src[PATH_MAX], dst[PATH_MAX];
+	snprintf(dst, sizeof(dst), "%s%s", to, DONE_FILENAME);
+	if (!access(dst, F_OK))
+		return;
+	snprintf(src, sizeof(src), "%sdata/", from);
+	DIR *dir = opendir(src);
+	if (!dir)
+		return;
+	struct dirent *entry;
+	char buf[1024];
+	while ((entry = readdir(dir)) != NULL)
+	{
+		if (entry->d_name[0] == '.')
+			continue;
+		snprintf(src, sizeof(src), "%sdata/%s", from, entry->d_name);
+		snprintf(dst, sizeof(dst), "%sdata/%s", to, entry->d_name);
+		FILE *in = fopen(src, "rb");
+		if (!in)
+			continue;
+		FILE *out = fopen(dst, "wb");
+		if (out)
+		{
+			size_t n;
+			while ((n = fread(buf, 1, sizeof(buf), in)) > 0)
+				fwrite(buf, 1, n, out);
+			fclose(out);
+		}
+		fclose(in);
+	}
+	closedir(dir);
+}
+
+/** Make sure the data files can be written
+ * If the folder of the executable cannot be written, the data files are kept in
+ * $XDG_DATA_HOME/opensprinkler/ (~/.local/share/opensprinkler/), or else in memory
+ * (/dev/shm), where changes are lost when the system restarts. Either starts with a
+ * copy of the data files of the executable folder. Returns false if nothing can be written.
+ */
+bool setup_data_path()
+{
+	char *path = get_data_path();
+	if (data_path_writable(path))
+		return true;
+	char original[PATH_MAX];
+	strcpy(original, path);
+	fprintf(stderr, "Cannot write the data files in %sdata/ (%s)\n", original, strerror(errno));
+
+	char fallback[PATH_MAX];
+	const char *xdg = getenv("XDG_DATA_HOME");
+	const char *home = getenv("HOME");
+	if (xdg && xdg[0])
+		snprintf(fallback, sizeof(fallback), "%s/opensprinkler/", xdg);
+	else if (home && home[0])
+		snprintf(fallback, sizeof(fallback), "%s/.local/share/opensprinkler/", home);
+	else
+		fallback[0] = 0;
+	if (fallback[0] && data_path_writable(fallback))
+	{
+		fprintf(stderr, "Using %s instead\n", fallback);
+	}
+	else
+	{
+		snprintf(fallback, sizeof(fallback), "/dev/shm/opensprinkler-%d/", (int)getuid());
+		if (!data_path_writable(fallback))
+		{
+			fprintf(stderr, "Cannot write the data files anywhere, changes are lost\n");
+			return false;
+		}
+		fprintf(stderr, "Keeping the data files in memory (%s), changes are lost when the system restarts\n", fallback);
+		data_in_memory = true;
+	}
+	copy_data_files(original, fallback);
+	strcpy(data_path, fallback);
+	return true;
+}
+
+/** Warn about a write to the data files while they are kept in memory */
+void data_write_warning(const char *fn)
+{
+	if (data_in_memory)
+		fprintf(stderr, "Warning: %s is only changed in memory\n", fn);
+}
+
 void delay(ulong howLong)
 {
 	struct timespec sleeper, dummy;
@@ -137,6 +276,7 @@ ulong micros(void)
 
 void write_to_file(const char *fn, const char *data, ulong size, ulong pos, bool trunc)
 {
+	data_write_warning(fn);
 	FILE *file;
 	if (trunc)
 	{
@@ -219,6 +359,7 @@ void file_read_block(const char *fn, void *dst, ulong pos, ulong len)
 
 void file_write_block(const char *fn, const void *src, ulong pos, ulong len)
 {
+	data_write_warning(fn);
 	FILE *fp = fopen(get_filename_fullpath(fn), "rb+");
 	if (!fp)
 	{
@@ -315,6 +456,14 @@ ulong water_time_resolve(uint16_t v)
 	}
 }
 
+/** Check a station duration from a client
+ * Valid are 0 to MAX_WATER_TIME seconds and the sunrise/sunset special values.
+ */
+bool water_time_valid(long v)
+{
+	return (v >= 0 && v <= MAX_WATER_TIME) || v == 65534 || v == 65535;
+}
+
 // encode a 16-bit signed water time (-600 to 600)
 // to unsigned byte (0 to 240)
 byte water_time_encode_signed(int16_t i)
@@ -350,6 +499,61 @@ static unsigned char h2int(char c)
 	return (0);
 }
 
+/** Length of the UTF-8 sequence started by byte c, 0 if c cannot start one */
+static int utf8_seq_len(unsigned char c)
+{
+	if (c < 0x80)
+		return 1;
+	if (c >= 0xC2 && c <= 0xDF)
+		return 2;
+	if (c >= 0xE0 && c <= 0xEF)
+		return 3;
+	if (c >= 0xF0 && c <= 0xF4)
+		return 4;
+	return 0;
+}
+
+/** Check that a string is well-formed UTF-8 without control characters */
+bool utf8_valid(const char *s)
+{
+	const unsigned char *p = (const unsigned char *)s;
+	while (*p)
+	{
+		if (*p < 0x20 || *p == 0x7F)
+			return false;
+		int n = utf8_seq_len(*p);
+		if (!n)
+			return false;
+		for (int i = 1; i < n; i++)
+		{
+			if ((p[i] & 0xC0) != 0x80)
+				return false;
+		}
+		// no overlong forms, surrogates or code points above U+10FFFF
+		if ((p[0] == 0xE0 && p[1] < 0xA0) || (p[0] == 0xED && p[1] >= 0xA0) ||
+			(p[0] == 0xF0 && p[1] < 0x90) || (p[0] == 0xF4 && p[1] >= 0x90))
+			return false;
+		p += n;
+	}
+	return true;
+}
+
+/** Cut a string to at most maxlen bytes without splitting a UTF-8 character
+ * A character already cut at the end, e.g. by an older firmware, is removed.
+ */
+void utf8_truncate(char *s, int maxlen)
+{
+	int n = strlen(s);
+	if (n > maxlen)
+		n = maxlen;
+	int i = n;
+	while (i > 0 && ((unsigned char)s[i - 1] & 0xC0) == 0x80)
+		i--;
+	if (i > 0 && utf8_seq_len(s[i - 1]) > n - (i - 1))
+		n = i - 1;
+	s[n] = 0;
+}
+
 /** Decode a url string e.g "hello%20joe" or "hello+joe" becomes "hello joe" */
 void urlDecode(char *urlbuf)
 {
@@ -407,3 +611,58 @@ void peel_http_header(char *buffer)
 		i++;
 	}
 }
+
+/** Locate the value of a field in a flat json object (without the enclosing braces)
+ * On success, *start points to the first character of the value
+ * and *end points right after the last character of the value
+ */
+bool json_field_find(const char *obj, const char *field, const char **start, const char **end)
+{
+	size_t flen = strlen(field);
+	const char *p = obj;
+	bool quoted = false;
+	for (; *p; p++)
+	{
+		if (*p == '"' && (p == obj || *(p - 1) != '\\'))
+		{
+			// only match field names outside of string values
+			if (!quoted && strncmp(p + 1, field, flen) == 0 && p[flen + 1] == '"' && p[flen + 2] == ':')
+			{
+				const char *v = p + flen + 3;
+				const char *e = v;
+				if (*e == '"')
+				{
+					for (e++; *e && !(*e == '"' && *(e - 1) != '\\'); e++)
+						;
+					if (*e)
+						e++;
+				}
+				else
+				{
+					while (*e && *e != ',' && *e != '}')
+						e++;
+				}
+				*start = v;
+				*end = e;
+				return true;
+			}
+			quoted = !quoted;
+		}
+	}
+	return false;
+}
+
+/** Copy the value of a json field into buf, with string quotes removed */
+void json_value_copy(char *buf, const char *start, const char *end)
+{
+	if (*start == '"' && end - start >= 2 && *(end - 1) == '"')
+	{
+		start++;
+		end--;
+	}
+	size_t len = end - start;
+	if (len > MAX_SOPTS_SIZE)
+		len = MAX_SOPTS_SIZE;
+	strncpy(buf, start, len);
+	buf[len] = 0;
+}
@@ -47,13 +47,22 @@ byte file_cmp_block(const char *fname, const char *buf, ulong pos);
 // misc. string and time converstion functions
 void strncpy_P0(char *dest, const char *src, int n);
 ulong water_time_resolve(uint16_t v);
+bool water_time_valid(long v);
 byte water_time_encode_signed(int16_t i);
 int16_t water_time_decode_signed(byte i);
 void urlDecode(char *);
+bool utf8_valid(const char *s);
+void utf8_truncate(char *s, int maxlen);
 void peel_http_header(char *);
+bool json_field_find(const char *obj, const char *field, const char **start, const char **end);
+void json_value_copy(char *buf, const char *start, const char *end);
 
 // Arduino compatible functions for RPI
 char *get_runtime_path();
+char *get_data_path();
+bool data_path_in_memory();
+bool setup_data_path();
+void data_write_warning(const char *fname);
 char *get_filename_fullpath(const char *filename);
 void delay(ulong ms);
 void delayMicroseconds(ulong us);
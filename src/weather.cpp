@@ -22,123 +22,178 @@
  */
 
 #include <stdlib.h>
+#include <math.h>
+#include <pthread.h>
 #include "OpenSprinkler.h"
 #include "utils.h"
 #include "opensprinkler_server.h"
 #include "weather.h"
+#include "budget.h"
+#include "main.h"
 
-extern OpenSprinkler os; // OpenSprinkler object
-extern char tmp_buffer[];
-extern char ether_buffer[];
 char wt_rawData[TMP_BUFFER_SIZE];
 int wt_errCode = HTTP_RQT_NOT_RECEIVED;
+WeatherResult wt_history[WEATHER_HISTORY_SIZE];
+ulong wt_nhistory = 0; // total number of weather calls recorded
+byte wt_nfails = 0;	   // number of consecutive failed weather calls
 
-byte findKeyVal(const char *str, char *strbuf, uint16_t maxlen, const char *key, bool key_in_pgm = false, uint8_t *keyfound = NULL);
-void write_log(byte type, ulong curr_time);
+ulong fc_lasttime = 0;			   // time of the last forecast fetch
+ulong fc_success_lasttime = 0;	   // time of the last successful forecast fetch
+int fc_errCode = HTTP_RQT_NOT_RECEIVED;
+byte fc_pop = 0;	 // highest precipitation probability (in percent) of the forecast period
+uint16_t fc_qpf = 0; // precipitation amount of the forecast period (in 1/100 inch)
+static int fc_mint = 0;			 // lowest temperature of the forecast period (in F)
+static bool fc_has_mint = false; // whether the forecast reported it
+static int wt_temp = 0;			 // temperature reported by the last successful weather call (in F)
+static ulong wt_temp_time = 0;	 // time of that call, 0 if no call reported a temperature
+
+// weather, forecast and calendar requests run one at a time on a worker thread, which only touches
+// the buffers below; the response is applied on the main thread once the worker is done
+#define WEATHER_WORKER_IDLE 0
+#define WEATHER_WORKER_BUSY 1
+#define WEATHER_WORKER_DONE 2
+static pthread_mutex_t wt_mutex = PTHREAD_MUTEX_INITIALIZER;
+static byte wt_worker_state = WEATHER_WORKER_IDLE;
+static char wt_host[MAX_SOPTS_SIZE + 1];
+static uint16_t wt_port;
+static uint16_t wt_timeout;
+static char wt_request[2 * TMP_BUFFER_SIZE + MAX_SOPTS_SIZE];
+static char wt_response[ETHER_BUFFER_SIZE];
+static int8_t wt_ret;
+static void (*wt_apply)(int8_t ret, char *response); // applies the response on the main thread
 
 // The weather function calls getweather.py on remote server to retrieve weather data
 // the default script is WEATHER_SCRIPT_HOST/weather?.py
 // static char website[] PROGMEM = DEFAULT_WEATHER_URL ;
 
-static void getweather_callback(char *buffer)
+/** Find a field of a form-urlencoded response and copy its value to buf
+ * Only whole field names match, values end at '&' or the end of the line.
+ * Returns false if the field is missing or its value does not fit in buf.
+ */
+static bool weather_field(const char *p, const char *key, char *buf, int maxlen)
+{
+	int klen = strlen(key);
+	while (*p && *p != '\r' && *p != '\n')
+	{
+		while (*p == '&')
+			p++;
+		if (strncmp(p, key, klen) == 0 && p[klen] == '=')
+		{
+			p += klen + 1;
+			int len = strcspn(p, "&\r\n");
+			if (len >= maxlen)
+				return false;
+			memcpy(buf, p, len);
+			buf[len] = 0;
+			return true;
+		}
+		p += strcspn(p, "&\r\n");
+	}
+	return false;
+}
+
+/** Parse an integer field of the weather response into v
+ * Returns false if the field is missing, or with a warning, if its value is not an integer from min to max.
+ */
+static bool weather_int(const char *p, const char *key, long min, long max, long *v)
 {
-	char *p = buffer;
-	/* scan the buffer until the first & symbol */
-	while (*p && *p != '&')
+	if (!weather_field(p, key, tmp_buffer, TMP_BUFFER_SIZE))
+		return false;
+	char *end;
+	*v = strtol(tmp_buffer, &end, 10);
+	if (end == tmp_buffer || *end || *v < min || *v > max)
 	{
-		p++;
+		DEBUG_PRINT(F("Invalid weather field "));
+		DEBUG_PRINT(key);
+		DEBUG_PRINT(F(": "));
+		DEBUG_PRINTLN(tmp_buffer);
+		return false;
 	}
-	if (*p != '&')
+	return true;
+}
+
+/** Apply a weather response of the form &errCode=x&scale=x&...
+ * Each field is checked on its own: a malformed field is skipped with a warning,
+ * the valid ones are still applied.
+ */
+static void getweather_callback(char *buffer)
+{
+	char *p = strchr(buffer, '&');
+	if (p == NULL)
 		return;
-	int v;
+	long v;
 	bool save_nvdata = false;
 
 	// first check errCode, only update lswc timestamp if errCode is 0
-	if (findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("errCode"), true))
+	if (weather_int(p, "errCode", INT_MIN, INT_MAX, &v))
 	{
-		wt_errCode = atoi(tmp_buffer);
+		wt_errCode = v;
 		if (wt_errCode == 0)
 			os.checkwt_success_lasttime = os.now_tz();
 	}
 
 	// then only parse scale if errCode is 0
-	if (wt_errCode == 0 && findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("scale"), true))
-	{
-		v = atoi(tmp_buffer);
-		if (v >= 0 && v <= 250 && v != os.iopts[IOPT_WATER_PERCENTAGE])
-		{
-			// only save if the value has changed
-			os.iopts[IOPT_WATER_PERCENTAGE] = v;
-			os.iopts_save();
-			os.weather_update_flag |= WEATHER_UPDATE_WL;
-		}
-	}
+	if (wt_errCode == 0 && weather_int(p, "scale", 0, 250, &v))
+		os.set_water_scale(v);
 
-	if (findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("sunrise"), true))
+	if (weather_int(p, "sunrise", 0, 1440, &v) && v != os.nvdata.sunrise_time)
 	{
-		v = atoi(tmp_buffer);
-		if (v >= 0 && v <= 1440 && v != os.nvdata.sunrise_time)
-		{
-			os.nvdata.sunrise_time = v;
-			save_nvdata = true;
-			os.weather_update_flag |= WEATHER_UPDATE_SUNRISE;
-		}
+		os.nvdata.sunrise_time = v;
+		save_nvdata = true;
+		os.weather_update_flag |= WEATHER_UPDATE_SUNRISE;
 	}
 
-	if (findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("sunset"), true))
+	if (weather_int(p, "sunset", 0, 1440, &v) && v != os.nvdata.sunset_time)
 	{
-		v = atoi(tmp_buffer);
-		if (v >= 0 && v <= 1440 && v != os.nvdata.sunset_time)
-		{
-			os.nvdata.sunset_time = v;
-			save_nvdata = true;
-			os.weather_update_flag |= WEATHER_UPDATE_SUNSET;
-		}
+		os.nvdata.sunset_time = v;
+		save_nvdata = true;
+		os.weather_update_flag |= WEATHER_UPDATE_SUNSET;
 	}
 
-	if (findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("eip"), true))
+	if (weather_field(p, "eip", tmp_buffer, TMP_BUFFER_SIZE))
 	{
-		uint32_t l = strtoul(tmp_buffer, NULL, 0);
-		if (l != os.nvdata.external_ip)
+		char *end;
+		unsigned long ip = strtoul(tmp_buffer, &end, 0);
+		if (end == tmp_buffer || *end || ip > 0xFFFFFFFFUL)
 		{
-			os.nvdata.external_ip = l;
-			save_nvdata = true;
-			os.weather_update_flag |= WEATHER_UPDATE_EIP;
+			DEBUG_PRINT(F("Invalid weather field eip: "));
+			DEBUG_PRINTLN(tmp_buffer);
 		}
+		else if (os.set_external_ip(ip))
+			save_nvdata = true;
 	}
 
-	if (findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("tz"), true))
+	if (weather_int(p, "tz", 0, 108, &v) && v != os.iopts[IOPT_TIMEZONE])
 	{
-		v = atoi(tmp_buffer);
-		if (v >= 0 && v <= 108)
-		{
-			if (v != os.iopts[IOPT_TIMEZONE])
-			{
-				// if timezone changed, save change and force ntp sync
-				os.iopts[IOPT_TIMEZONE] = v;
-				os.iopts_save();
-				os.weather_update_flag |= WEATHER_UPDATE_TZ;
-			}
-		}
+		// if timezone changed, save change and force ntp sync
+		os.iopts[IOPT_TIMEZONE] = v;
+		os.iopts_save();
+		os.weather_update_flag |= WEATHER_UPDATE_TZ;
 	}
 
-	if (findKeyVal(p, tmp_buffer, TMP_BUFFER_SIZE, PSTR("rd"), true))
+	if (weather_int(p, "rd", 0, INT_MAX, &v))
 	{
-		v = atoi(tmp_buffer);
 		if (v > 0)
 		{
 			os.nvdata.rd_stop_time = os.now_tz() + (unsigned long)v * 3600;
 			os.raindelay_start();
 		}
-		else if (v == 0)
+		else
 		{
 			os.raindelay_stop();
 		}
 	}
 
-	if (findKeyVal(p, wt_rawData, TMP_BUFFER_SIZE, PSTR("rawData"), true))
+	if (weather_field(p, "rawData", wt_rawData, TMP_BUFFER_SIZE) && wt_errCode == 0)
 	{
-		wt_rawData[TMP_BUFFER_SIZE - 1] = 0; // make sure the buffer ends properly
+		budget_weather(wt_rawData, os.now_tz());
+		// the temperature (t) is reported by the adjustment methods that use it, in F
+		const char *start, *end;
+		if (json_field_find(wt_rawData, "t", &start, &end))
+		{
+			wt_temp = (int)floor(atof(start) + 0.5);
+			wt_temp_time = os.now_tz();
+		}
 	}
 
 	if (save_nvdata)
@@ -148,19 +203,140 @@ static void getweather_callback(char *buffer)
 
 static void getweather_callback_with_peel_header(char *buffer)
 {
+	// only accept the response if the HTTP status is 200
+	char *p = strchr(buffer, ' ');
+	if (p == NULL || atoi(p + 1) != 200)
+	{
+		wt_errCode = HTTP_RQT_BAD_STATUS;
+		return;
+	}
 	peel_http_header(buffer);
 	getweather_callback(buffer);
 }
 
-void GetWeather()
+static byte get_worker_state()
+{
+	pthread_mutex_lock(&wt_mutex);
+	byte state = wt_worker_state;
+	pthread_mutex_unlock(&wt_mutex);
+	return state;
+}
+
+static void set_worker_state(byte state)
+{
+	pthread_mutex_lock(&wt_mutex);
+	wt_worker_state = state;
+	pthread_mutex_unlock(&wt_mutex);
+}
+
+static void *weather_worker(void *)
+{
+	wt_ret = os.fetch_http(wt_host, wt_port, wt_request, wt_response, ETHER_BUFFER_SIZE, wt_timeout);
+	set_worker_state(WEATHER_WORKER_DONE);
+	return NULL;
+}
+
+/** Start a request on the worker thread
+ * host may include a port (host:port). apply is called on the main thread with the result of
+ * the request and the response, see weather_worker_result().
+ * Returns false if a request is already in progress or the worker cannot be started
+ */
+bool weather_worker_start(const char *host, const char *request, uint16_t timeout, void (*apply)(int8_t ret, char *response))
 {
+	if (get_worker_state() != WEATHER_WORKER_IDLE)
+		return false;
+	if (strlen(request) >= sizeof(wt_request))
+	{
+		DEBUG_PRINTLN(F("weather worker: request too long"));
+		return false;
+	}
+
+	strcpy(wt_request, request);
+	strncpy(wt_host, host, MAX_SOPTS_SIZE);
+	wt_host[MAX_SOPTS_SIZE] = 0;
+	char *port = strchr(wt_host, ':');
+	wt_port = 80;
+	if (port)
+	{
+		*port = 0;
+		wt_port = atoi(port + 1);
+	}
+	wt_timeout = timeout;
+	wt_apply = apply;
+
+	pthread_t thread;
+	set_worker_state(WEATHER_WORKER_BUSY);
+	if (pthread_create(&thread, NULL, weather_worker, NULL))
+	{
+		set_worker_state(WEATHER_WORKER_IDLE);
+		return false;
+	}
+	pthread_detach(thread);
+	return true;
+}
+
+/** Apply the result of a finished worker request
+ * Returns true if a result has been applied
+ */
+bool weather_worker_result()
+{
+	if (get_worker_state() != WEATHER_WORKER_DONE)
+		return false;
+	wt_apply(wt_ret, wt_response);
+	set_worker_state(WEATHER_WORKER_IDLE);
+	return true;
+}
+
+static uint16_t weather_timeout()
+{
+	return os.iopts[IOPT_WEATHER_TIMEOUT] ? (uint16_t)os.iopts[IOPT_WEATHER_TIMEOUT] * 1000 : 3000;
+}
+
+/** Apply the result of a weather request */
+static void apply_weather(int8_t ret, char *response)
+{
+	wt_errCode = HTTP_RQT_NOT_RECEIVED;
+	if (ret == HTTP_RQT_SUCCESS)
+		getweather_callback_with_peel_header(response);
+	else if (wt_errCode < 0)
+		wt_errCode = ret;
+	// if wt_errCode > 0, the call is successful but weather script may return error
+
+	// record the result in the history ring buffer
+	WeatherResult *r = &wt_history[wt_nhistory % WEATHER_HISTORY_SIZE];
+	r->time = os.now_tz();
+	r->errCode = wt_errCode;
+	r->wl = os.iopts[IOPT_WATER_PERCENTAGE];
+	wt_nhistory++;
+	write_weather_log(r->errCode, r->wl, (wt_errCode == 0) ? wt_rawData : NULL, r->time);
+
+	if (wt_errCode == 0)
+		wt_nfails = 0;
+	else if (wt_nfails < 255)
+		wt_nfails++;
+
+	// notify once when the number of consecutive failures reaches the threshold
+	if (wt_nfails && wt_nfails == os.iopts[IOPT_WEATHER_RETRIES])
+	{
+		push_message(NOTIFY_WEATHER_FAIL, wt_nfails, 0, NULL);
+	}
+}
+
+/** Start a weather request on the worker thread
+ * Returns false if a request is already in progress or the worker cannot be started
+ */
+bool GetWeather()
+{
+	if (get_worker_state() != WEATHER_WORKER_IDLE)
+		return false;
+
 	// use temp buffer to construct get command
 	BufferFiller bf = tmp_buffer;
 	bf.emit_p(PSTR("$D?loc=$O&wto=$O&fwv=$D"),
 			  (int)os.iopts[IOPT_USE_WEATHER],
 			  SOPT_LOCATION,
 			  SOPT_WEATHER_OPTS,
-			  (int)os.iopts[IOPT_FW_VERSION]);
+			  (int)os.reported_fw_version());
 
 	char *src = tmp_buffer + strlen(tmp_buffer);
 	char *dst = tmp_buffer + TMP_BUFFER_SIZE - 12;
@@ -194,16 +370,150 @@ void GetWeather()
 	char *host = tmp_buffer;
 	os.sopt_load(SOPT_WEATHERURL, host);
 
-	strcat(ether_buffer, " HTTP/1.0\r\nHOST: ");
-	strcat(ether_buffer, host);
-	strcat(ether_buffer, "\r\n\r\n");
+	os.http_request_head(ether_buffer, ETHER_BUFFER_SIZE, host, true);
+	strcat(ether_buffer, "\r\n");
 
-	wt_errCode = HTTP_RQT_NOT_RECEIVED;
-	int ret = os.send_http_request(host, ether_buffer, getweather_callback_with_peel_header);
+	return weather_worker_start(host, ether_buffer, weather_timeout(), apply_weather);
+}
+
+/** Parse a forecast response: the highest precipitation probability (pop, in percent),
+ * the total amount (qpf, in inches) and, optionally, the lowest temperature (mint, in F) of the forecast period
+ */
+static void getforecast_callback(char *buffer)
+{
+	char *p = strchr(buffer, ' ');
+	if (p == NULL || atoi(p + 1) != 200)
+	{
+		fc_errCode = HTTP_RQT_BAD_STATUS;
+		return;
+	}
+	peel_http_header(buffer);
+	p = strchr(buffer, '&');
+	if (p == NULL)
+	{
+		fc_errCode = HTTP_RQT_EMPTY_RETURN;
+		return;
+	}
+	long v;
+	if (weather_int(p, "errCode", INT_MIN, INT_MAX, &v))
+		fc_errCode = v;
+	if (fc_errCode != 0)
+		return;
+	if (!weather_int(p, "pop", 0, 100, &v))
+	{
+		fc_errCode = HTTP_RQT_EMPTY_RETURN;
+		return;
+	}
+	fc_pop = v;
+	fc_qpf = 0;
+	if (weather_field(p, "qpf", tmp_buffer, TMP_BUFFER_SIZE))
+	{
+		char *end;
+		double f = strtod(tmp_buffer, &end);
+		if (end == tmp_buffer || *end || f < 0)
+		{
+			DEBUG_PRINT(F("Invalid weather field qpf: "));
+			DEBUG_PRINTLN(tmp_buffer);
+		}
+		else
+			fc_qpf = (f >= 655) ? 65500 : (uint16_t)(f * 100 + 0.5);
+	}
+	fc_has_mint = false;
+	if (weather_field(p, "mint", tmp_buffer, TMP_BUFFER_SIZE))
+	{
+		char *end;
+		double f = strtod(tmp_buffer, &end);
+		if (end == tmp_buffer || *end || f < -200 || f > 200)
+		{
+			DEBUG_PRINT(F("Invalid weather field mint: "));
+			DEBUG_PRINTLN(tmp_buffer);
+		}
+		else
+		{
+			fc_mint = (int)floor(f + 0.5);
+			fc_has_mint = true;
+		}
+	}
+}
+
+/** Apply the result of a forecast request */
+static void apply_forecast(int8_t ret, char *response)
+{
+	fc_errCode = HTTP_RQT_NOT_RECEIVED;
 	if (ret != HTTP_RQT_SUCCESS)
 	{
-		if (wt_errCode < 0)
-			wt_errCode = ret;
-		// if wt_errCode > 0, the call is successful but weather script may return error
+		fc_errCode = ret;
+		return;
+	}
+	getforecast_callback(response);
+	if (fc_errCode == 0)
+		fc_success_lasttime = os.now_tz();
+}
+
+/** Start fetching the rain forecast of the next IOPT_FORECAST_HOURS hours from the weather service
+ * The request runs on the worker thread, programs fall back to no forecast until its result is applied.
+ * Returns false if a request is already in progress or the worker cannot be started
+ */
+bool GetForecast()
+{
+	if (get_worker_state() != WEATHER_WORKER_IDLE)
+		return false;
+
+	BufferFiller bf = tmp_buffer;
+	bf.emit_p(PSTR("/forecast?loc=$O&wto=$O&hours=$D&fwv=$D"),
+			  SOPT_LOCATION,
+			  SOPT_WEATHER_OPTS,
+			  (int)os.iopts[IOPT_FORECAST_HOURS],
+			  (int)os.reported_fw_version());
+
+	// url encode. convert SPACE to %20
+	strcpy(ether_buffer, "GET ");
+	char *dst = ether_buffer + 4;
+	for (char *src = tmp_buffer; *src && dst < ether_buffer + ETHER_BUFFER_SIZE - MAX_SOPTS_SIZE - 32; src++)
+	{
+		if (*src == ' ')
+		{
+			strcpy(dst, "%20");
+			dst += 3;
+		}
+		else
+		{
+			*dst++ = *src;
+		}
+	}
+	*dst = 0;
+
+	char *host = tmp_buffer;
+	os.sopt_load(SOPT_WEATHERURL, host);
+	os.http_request_head(ether_buffer, ETHER_BUFFER_SIZE, host, true);
+	strcat(ether_buffer, "\r\n");
+
+	return weather_worker_start(host, ether_buffer, weather_timeout(), apply_forecast);
+}
+
+/** Lowest recent temperature (in F), for frost protection
+ * This is the lower of the forecast minimum and the temperature of the last weather call,
+ * leaving out either if it is outdated. Returns false if neither is known.
+ */
+bool frost_temperature(ulong curr_time, int *temp)
+{
+	bool known = false;
+	if (wt_temp_time && curr_time < wt_temp_time + FROST_TEMP_MAX_AGE)
+	{
+		*temp = wt_temp;
+		known = true;
+	}
+	if (fc_has_mint && forecast_valid(curr_time) && (!known || fc_mint < *temp))
+	{
+		*temp = fc_mint;
+		known = true;
 	}
+	return known;
+}
+
+/** Check if the forecast is recent enough for programs to use */
+bool forecast_valid(ulong curr_time)
+{
+	return os.iopts[IOPT_FORECAST_HOURS] && fc_success_lasttime &&
+		   curr_time < fc_success_lasttime + FORECAST_MAX_AGE;
 }
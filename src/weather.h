@@ -31,8 +31,40 @@
 #define WEATHER_UPDATE_TZ 0x10
 #define WEATHER_UPDATE_RD 0x20
 
-void GetWeather();
+#define WEATHER_HISTORY_SIZE 8 // number of weather call results kept in memory
+
+#define FORECAST_MAX_HOURS 72		 // longest forecast period programs can check
+#define FORECAST_CHECK_INTERVAL 3600L // forecast fetch interval (in seconds)
+#define FORECAST_MAX_AGE 10800L		 // a forecast older than this (in seconds) is not used
+
+#define FROST_MAX_TEMP 60			 // highest frost temperature (in F)
+#define FROST_HYSTERESIS 2			 // frost ends this much (in F) above the frost temperature
+#define FROST_CYCLE_INTERVAL 3600L	 // the frost program repeats at this interval (in seconds) while frost lasts
+#define FROST_TEMP_MAX_AGE 25200L	 // a weather call temperature older than this (in seconds) is not used
+
+/** Weather call result */
+struct WeatherResult
+{
+	ulong time;	 // time of the call
+	int errCode; // error code of the call (0 if successful)
+	byte wl;	 // water level after the call
+};
+
+bool weather_worker_start(const char *host, const char *request, uint16_t timeout, void (*apply)(int8_t ret, char *response));
+bool weather_worker_result();
+bool GetWeather();
+bool GetForecast();
+bool forecast_valid(ulong curr_time);
+bool frost_temperature(ulong curr_time, int *temp);
 
 extern char wt_rawData[];
 extern int wt_errCode;
+extern WeatherResult wt_history[];
+extern ulong wt_nhistory;
+extern byte wt_nfails;
+extern ulong fc_lasttime;
+extern ulong fc_success_lasttime;
+extern int fc_errCode;
+extern byte fc_pop;
+extern uint16_t fc_qpf;
 #endif // _WEATHER_H
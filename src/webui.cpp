@@ -0,0 +1,479 @@
+/* OpenSprinkler Unified (RPI/LINUX) Firmware
+ * Copyright (C) 2015 by Ray Wang (ray@opensprinkler.com)
+ *
+ * Built-in web UI, served at /ui
+ * Status, manual run and program editor pages that work without the hosted app.
+ * It uses the same JSON commands as the hosted app.
+ *
+ * This file is part of the OpenSprinkler library
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see
+ * <http://www.gnu.org/licenses/>.
+ */
+
+#include <string.h>
+#include "webui.h"
+
+static const char webui_index[] = R"(<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width,initial-scale=1">
+<title>OpenSprinkler</title>
+<link rel="stylesheet" href="/ui/ui.css">
+</head>
+<body>
+<header><h1>OpenSprinkler</h1><span id="clock"></span></header>
+<nav id="nav" hidden>
+<a href="#status" data-t="status">Status</a><a href="#run" data-t="run">Run</a><a href="#programs" data-t="programs">Programs</a>
+</nav>
+<main>
+<section id="login" hidden>
+<form id="loginform"><input type="password" id="pw" placeholder="Password" data-tp="password" autofocus><button data-t="login">Log in</button></form>
+<p id="loginerr" class="err"></p>
+</section>
+<section id="status" hidden>
+<div id="summary"></div>
+<ul id="stations"></ul>
+<button id="stopall" class="stop" data-t="stop_all">Stop all stations</button>
+<button id="logout" data-t="logout">Log out</button>
+</section>
+<section id="run" hidden>
+<form id="runform">
+<label><span data-t="station">Station</span> <select id="runsid"></select></label>
+<label><span data-t="minutes">Minutes</span> <input type="number" id="runmin" min="1" max="1080" value="5"></label>
+<button data-t="start">Start</button>
+</form>
+<p id="runmsg" class="err"></p>
+</section>
+<section id="programs" hidden>
+<ul id="proglist"></ul>
+<button id="progadd" data-t="new_program">New program</button>
+</section>
+<section id="edit" hidden>
+<form id="editform">
+<label><span data-t="name">Name</span> <input id="pname" maxlength="32"></label>
+<label><input type="checkbox" id="pen"> <span data-t="enabled">Enabled</span></label>
+<label><input type="checkbox" id="puwt"> <span data-t="use_weather">Use weather adjustment</span></label>
+<label><span data-t="repeat">Repeat</span> <select id="ptype"><option value="0" data-t="weekdays">Days of the week</option><option value="3" data-t="interval">Every n days</option></select></label>
+<div id="pdays"></div>
+<div id="pinterval"><label><span data-t="every">Every</span> <input type="number" id="pint" min="2" max="128"> <span data-t="days_starting">days, starting in</span> <input type="number" id="prem" min="0" max="127"> <span data-t="days">days</span></label></div>
+<fieldset><legend data-t="start_times">Start times</legend><div id="pstarts"></div></fieldset>
+<fieldset><legend data-t="durations">Minutes per station</legend><div id="pdurs"></div></fieldset>
+<button data-t="save">Save</button> <button type="button" id="pcancel" data-t="cancel">Cancel</button>
+</form>
+<p id="editmsg" class="err"></p>
+</section>
+</main>
+<script src="/ui/md5.js"></script>
+<script src="/ui/lang.js"></script>
+<script src="/ui/ui.js"></script>
+</body>
+</html>
+)";
+
+static const char webui_css[] = R"(body{margin:0;font-family:sans-serif;background:#f4f4f4;color:#222}
+header{display:flex;justify-content:space-between;align-items:center;background:#1c6e3d;color:#fff;padding:0 1em}
+header h1{font-size:1.2em}
+nav{display:flex;background:#2d8a52}
+nav a{flex:1;text-align:center;padding:.7em 0;color:#fff;text-decoration:none}
+nav a.sel{background:#1c6e3d}
+main{max-width:40em;margin:0 auto;padding:1em}
+ul{list-style:none;padding:0}
+li{display:flex;justify-content:space-between;align-items:center;background:#fff;margin:.25em 0;padding:.6em;border-radius:4px}
+li.on{background:#d8f5d0}
+li.off{color:#888}
+li .rem{color:#555}
+li button{margin:0 0 0 .3em;padding:.4em .7em}
+label{display:block;margin:.5em 0}
+fieldset{border:1px solid #ccc;border-radius:4px;margin:.5em 0}
+fieldset label{display:flex;justify-content:space-between}
+#pdays label{display:inline-block;margin-right:.8em}
+button{padding:.6em 1em;margin:.25em 0;border:0;border-radius:4px;background:#ddd;font-size:1em}
+button.stop{background:#c0392b;color:#fff}
+input,select{padding:.5em;font-size:1em}
+input[type=number]{width:5em}
+.err{color:#c0392b}
+)";
+
+static const char webui_md5[] = R"(// MD5 of a string, the controller expects the md5 hash of the password
+function md5(s) {
+	s = unescape(encodeURIComponent(s));
+	var r = [7, 12, 17, 22, 5, 9, 14, 20, 4, 11, 16, 23, 6, 10, 15, 21];
+	var k = [], w = [], h = [1732584193, -271733879, -1732584194, 271733878];
+	var n = s.length, m = (((n + 8) >> 6) + 1) * 16, i, j;
+	for (i = 0; i < 64; i++) k[i] = Math.floor(Math.abs(Math.sin(i + 1)) * 4294967296) | 0;
+	for (i = 0; i < m; i++) w[i] = 0;
+	for (i = 0; i < n; i++) w[i >> 2] |= s.charCodeAt(i) << ((i % 4) * 8);
+	w[n >> 2] |= 0x80 << ((n % 4) * 8);
+	w[m - 2] = n * 8;
+	for (i = 0; i < m; i += 16) {
+		var a = h[0], b = h[1], c = h[2], d = h[3];
+		for (j = 0; j < 64; j++) {
+			var f, g, q = j >> 4;
+			if (q == 0) { f = (b & c) | (~b & d); g = j; }
+			else if (q == 1) { f = (d & b) | (~d & c); g = (5 * j + 1) % 16; }
+			else if (q == 2) { f = b ^ c ^ d; g = (3 * j + 5) % 16; }
+			else { f = c ^ (b | ~d); g = (7 * j) % 16; }
+			var t = d, x = (a + f + k[j] + w[i + g]) | 0, sh = r[q * 4 + j % 4];
+			d = c; c = b;
+			b = (b + ((x << sh) | (x >>> (32 - sh)))) | 0;
+			a = t;
+		}
+		h[0] = (h[0] + a) | 0; h[1] = (h[1] + b) | 0; h[2] = (h[2] + c) | 0; h[3] = (h[3] + d) | 0;
+	}
+	var o = '';
+	for (i = 0; i < 16; i++) o += ('0' + ((h[i >> 2] >> ((i % 4) * 8)) & 255).toString(16)).slice(-2);
+	return o;
+}
+)";
+
+static const char webui_js[] = R"(var pw = localStorage.getItem('ospw') || '';
+var data = null;
+var DAYS = ['mon', 'tue', 'wed', 'thu', 'fri', 'sat', 'sun'];
+
+function $(id) { return document.getElementById(id); }
+
+// string of the configured language, see lang.js
+function t(key) {
+	return (window.LANG && LANG[key]) || key;
+}
+
+function translate() {
+	var e = document.querySelectorAll('[data-t]');
+	for (var i = 0; i < e.length; i++) e[i].textContent = t(e[i].getAttribute('data-t'));
+	e = document.querySelectorAll('[data-tp]');
+	for (var i = 0; i < e.length; i++) e[i].placeholder = t(e[i].getAttribute('data-tp'));
+}
+
+function el(tag, text, cls) {
+	var e = document.createElement(tag);
+	if (text !== undefined) e.textContent = text;
+	if (cls) e.className = cls;
+	return e;
+}
+
+function button(text, fn, cls) {
+	var b = el('button', text, cls);
+	b.type = 'button';
+	b.onclick = fn;
+	return b;
+}
+
+var PAGES = ['login', 'status', 'run', 'programs', 'edit'];
+
+function show(id) {
+	PAGES.forEach(function (p) { $(p).hidden = p != id; });
+	$('nav').hidden = id == 'login';
+	var links = $('nav').getElementsByTagName('a');
+	for (var i = 0; i < links.length; i++)
+		links[i].className = links[i].getAttribute('href') == '#' + id ? 'sel' : '';
+}
+
+function page() {
+	var id = location.hash.substr(1);
+	return PAGES.indexOf(id) > 0 ? id : 'status';
+}
+
+// call a controller command, resolves with the json output
+function api(cmd, params) {
+	var q = '/' + cmd + '?pw=' + encodeURIComponent(pw);
+	for (var k in params || {}) q += '&' + k + '=' + encodeURIComponent(params[k]);
+	return fetch(q, {cache: 'no-cache'}).then(function (r) { return r.json(); }).then(function (d) {
+		if (d.result == 2 || (cmd == 'ja' && !d.settings)) {
+			show('login');
+			throw new Error('unauthorized');
+		}
+		return d;
+	});
+}
+
+// call a command that changes the controller, rejects with the result code if it fails
+function change(cmd, params) {
+	return api(cmd, params).then(function (d) {
+		if (d.result != 1) throw new Error(t('error') + ' ' + d.result);
+		return refresh();
+	});
+}
+
+function fmt_time(sec) {
+	var m = Math.floor(sec / 60), s = sec % 60;
+	return m + ':' + (s < 10 ? '0' : '') + s;
+}
+
+function fmt_start(v) {
+	if (v < 0) return '';
+	if (v & (1 << 14) || v & (1 << 13)) {
+		var off = v & 0x7ff;
+		if (v & (1 << 12)) off = -off;
+		return t(v & (1 << 14) ? 'sunrise' : 'sunset') + (off ? (off > 0 ? '+' : '') + off + 'min' : '');
+	}
+	var h = Math.floor(v / 60), m = v % 60;
+	return (h < 10 ? '0' : '') + h + ':' + (m < 10 ? '0' : '') + m;
+}
+
+function disabled(sid) {
+	return (data.stations.stn_dis[sid >> 3] >> (sid & 7)) & 1;
+}
+
+function render_status() {
+	var st = data.settings, names = data.stations.snames;
+	$('summary').textContent = t(st.en ? 'enabled' : 'disabled') +
+		', ' + t('water_level') + ' ' + data.options.wl + '%' + (st.rd ? ', ' + t('rain_delay') : '');
+	var ul = $('stations');
+	ul.textContent = '';
+	for (var sid = 0; sid < data.status.nstations; sid++) {
+		var on = data.status.sn[sid], ps = data.status.ps[sid];
+		var li = el('li', undefined, on ? 'on' : (disabled(sid) ? 'off' : ''));
+		li.appendChild(el('span', names[sid]));
+		var right = el('span');
+		right.appendChild(el('span', ps[1] ? t(on ? 'running' : 'waiting') + ' ' + fmt_time(ps[1]) : '', 'rem'));
+		if (on || ps[1]) right.appendChild(button(t('stop'), stop_station.bind(null, sid)));
+		li.appendChild(right);
+		ul.appendChild(li);
+	}
+}
+
+function stop_station(sid) {
+	change('cm', {sid: sid, en: 0}).catch(function () {});
+}
+
+function render_run() {
+	var sel = $('runsid'), cur = sel.value;
+	sel.textContent = '';
+	data.stations.snames.forEach(function (name, sid) {
+		if (sid + 1 == st_master(1) || sid + 1 == st_master(2) || disabled(sid)) return;
+		var o = el('option', name);
+		o.value = sid;
+		sel.appendChild(o);
+	});
+	if (cur) sel.value = cur;
+}
+
+function st_master(n) {
+	return n == 1 ? data.options.mas : data.options.mas2;
+}
+
+function render_programs() {
+	var ul = $('proglist');
+	ul.textContent = '';
+	data.programs.pd.forEach(function (p, pid) {
+		var li = el('li', undefined, p[0] & 1 ? '' : 'off');
+		var info = el('span', p[5]);
+		var starts = p[3][0] < 0 ? [] : ((p[0] >> 6) & 1 ? p[3].map(fmt_start) : [fmt_start(p[3][0])]);
+		info.appendChild(el('div', starts.filter(Boolean).join(' '), 'rem'));
+		li.appendChild(info);
+		var right = el('span');
+		right.appendChild(button(t('run'), function () {
+			change('mp', {pid: pid, uwt: (p[0] >> 1) & 1}).then(function () { location.hash = '#status'; }).catch(function () {});
+		}));
+		right.appendChild(button(t('edit'), function () { edit(pid); }));
+		right.appendChild(button(t('delete'), function () {
+			if (confirm(p[5] + ': ' + t('delete_confirm'))) change('dp', {pid: pid}).catch(function () {});
+		}, 'stop'));
+		li.appendChild(right);
+		ul.appendChild(li);
+	});
+}
+
+var editing = null;
+
+// open the program editor, pid -1 for a new program
+function edit(pid) {
+	editing = pid;
+	var n = data.stations.snames.length;
+	var p = pid >= 0 ? data.programs.pd[pid] : [3, 127, 0, [360, -1, -1, -1], new Array(n).fill(0), t('program') + ' ' + (data.programs.nprogs + 1)];
+	var flag = p[0], type = (flag >> 4) & 3;
+	$('pname').value = p[5];
+	$('pen').checked = flag & 1;
+	$('puwt').checked = (flag >> 1) & 1;
+	$('ptype').value = type == 3 ? 3 : 0;
+	$('pint').value = type == 3 ? p[1] : 2;
+	$('prem').value = type == 3 ? p[2] : 0;
+	var days = $('pdays');
+	days.textContent = '';
+	DAYS.forEach(function (d, i) {
+		var l = el('label'), c = el('input');
+		c.type = 'checkbox';
+		c.checked = type == 3 ? false : (p[1] >> i) & 1;
+		l.appendChild(c);
+		l.appendChild(document.createTextNode(' ' + t(d)));
+		days.appendChild(l);
+	});
+	// fixed start times, a repeating program keeps only its first start time
+	var starts = $('pstarts'), fixed = (flag >> 6) & 1;
+	starts.textContent = '';
+	for (var i = 0; i < data.programs.mnst; i++) {
+		var inp = el('input'), v = fixed || i == 0 ? p[3][i] : -1;
+		inp.type = 'time';
+		inp.value = v >= 0 && v < 1440 ? fmt_start(v) : '';
+		starts.appendChild(inp);
+	}
+	var durs = $('pdurs');
+	durs.textContent = '';
+	data.stations.snames.forEach(function (name, sid) {
+		var l = el('label', name), d = el('input');
+		d.type = 'number';
+		d.min = 0;
+		d.max = 1080;
+		d.value = Math.round(p[4][sid] / 60);
+		l.appendChild(d);
+		durs.appendChild(l);
+	});
+	$('editmsg').textContent = '';
+	show_type();
+	location.hash = '#edit';
+}
+
+function show_type() {
+	var interval = $('ptype').value == 3;
+	$('pdays').hidden = interval;
+	$('pinterval').hidden = !interval;
+}
+
+function save() {
+	var type = +$('ptype').value, days0 = 0, days1 = 0;
+	if (type == 3) {
+		days0 = +$('pint').value;
+		days1 = +$('prem').value;
+	} else {
+		var boxes = $('pdays').getElementsByTagName('input');
+		for (var i = 0; i < boxes.length; i++) if (boxes[i].checked) days0 |= 1 << i;
+	}
+	var starts = [];
+	var inputs = $('pstarts').getElementsByTagName('input');
+	for (var i = 0; i < inputs.length; i++) {
+		var v = inputs[i].value.split(':');
+		if (v.length == 2) starts.push(+v[0] * 60 + +v[1]);
+	}
+	while (starts.length < inputs.length) starts.push(-1);
+	var durs = [];
+	var dinputs = $('pdurs').getElementsByTagName('input');
+	for (var i = 0; i < dinputs.length; i++) durs.push(Math.round(+dinputs[i].value * 60));
+	var old = editing >= 0 ? data.programs.pd[editing][0] : 0;
+	// keep the odd/even restriction and the calendar flag of the program
+	var flag = (old & 0x8c) | ($('pen').checked ? 1 : 0) | ($('puwt').checked ? 2 : 0) | (type << 4) | (1 << 6);
+	var v = '[' + [flag, days0, days1].join(',') + ',[' + starts.join(',') + '],[' + durs.join(',') + ']]';
+	change('cp', {pid: editing, v: v, name: $('pname').value}).then(function () {
+		location.hash = '#programs';
+	}).catch(function (e) { $('editmsg').textContent = e.message; });
+}
+
+function render() {
+	var d = new Date(data.settings.devt * 1000);
+	$('clock').textContent = d.toISOString().substr(11, 5);
+	render_status();
+	render_run();
+	render_programs();
+}
+
+function refresh() {
+	return api('ja').then(function (d) {
+		data = d;
+		render();
+	});
+}
+
+$('loginform').onsubmit = function (e) {
+	e.preventDefault();
+	pw = md5($('pw').value);
+	api('ja').then(function (d) {
+		localStorage.setItem('ospw', pw);
+		$('loginerr').textContent = '';
+		data = d;
+		render();
+		show(page());
+	}).catch(function () { $('loginerr').textContent = t('wrong_pw'); });
+};
+
+$('logout').onclick = function () {
+	pw = '';
+	localStorage.removeItem('ospw');
+	show('login');
+};
+
+$('stopall').onclick = function () {
+	change('cv', {rsn: 1}).catch(function () {});
+};
+
+$('runform').onsubmit = function (e) {
+	e.preventDefault();
+	change('cm', {sid: $('runsid').value, en: 1, t: Math.round(+$('runmin').value * 60)}).then(function () {
+		$('runmsg').textContent = '';
+		location.hash = '#status';
+	}).catch(function (e) { $('runmsg').textContent = e.message; });
+};
+
+$('progadd').onclick = function () { edit(-1); };
+$('ptype').onchange = show_type;
+$('editform').onsubmit = function (e) {
+	e.preventDefault();
+	save();
+};
+$('pcancel').onclick = function () { location.hash = '#programs'; };
+
+window.onhashchange = function () {
+	if (!data) return;
+	// the editor is only opened by edit()
+	show(page() == 'edit' && editing === null ? 'programs' : page());
+};
+
+translate();
+api('ja').then(function (d) {
+	data = d;
+	render();
+	show(page() == 'edit' ? 'programs' : page());
+}).catch(function () {});
+// the editor is not refreshed, so changes being made are kept
+setInterval(function () { if (data && $('edit').hidden) refresh().catch(function () {}); }, 5000);
+)";
+
+static const WebAsset webui_assets[] = {
+	{WEBUI_INDEX, webui_index},
+	{"ui.css", webui_css},
+	{"md5.js", webui_md5},
+	{"ui.js", webui_js},
+};
+
+/** Find a built-in asset by its file name */
+const WebAsset *webui_find_asset(const char *name)
+{
+	for (unsigned i = 0; i < sizeof(webui_assets) / sizeof(WebAsset); i++)
+	{
+		if (!strcmp(webui_assets[i].name, name))
+			return webui_assets + i;
+	}
+	return NULL;
+}
+
+/** Content type of a file, by its extension */
+const char *webui_content_type(const char *name)
+{
+	const char *ext = strrchr(name, '.');
+	if (!ext)
+		return "application/octet-stream";
+	if (!strcmp(ext, ".html"))
+		return "text/html";
+	if (!strcmp(ext, ".css"))
+		return "text/css";
+	if (!strcmp(ext, ".js"))
+		return "application/javascript";
+	if (!strcmp(ext, ".json"))
+		return "application/json";
+	if (!strcmp(ext, ".png"))
+		return "image/png";
+	if (!strcmp(ext, ".svg"))
+		return "image/svg+xml";
+	return "application/octet-stream";
+}
@@ -0,0 +1,40 @@
+/* OpenSprinkler Unified (RPI/LINUX) Firmware
+ * Copyright (C) 2015 by Ray Wang (ray@opensprinkler.com)
+ *
+ * Built-in web UI header file
+ *
+ * This file is part of the OpenSprinkler library
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see
+ * <http://www.gnu.org/licenses/>.
+ */
+
+#ifndef _WEBUI_H
+#define _WEBUI_H
+
+#define WEBUI_OVERRIDE_DIR "static/" // files here replace the built-in assets, for development
+#define WEBUI_INDEX "index.html"
+#define WEBUI_LANG "lang.js" // generated from the strings of the configured language
+
+/** Built-in web UI asset */
+struct WebAsset
+{
+	const char *name; // file name, e.g. ui.js
+	const char *data;
+};
+
+const WebAsset *webui_find_asset(const char *name);
+const char *webui_content_type(const char *name);
+
+#endif // _WEBUI_H